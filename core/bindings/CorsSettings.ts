@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface CorsSettings { allowed_origins: Array<string> | null, allowed_methods: Array<string>, allowed_headers: Array<string>, allow_credentials: boolean, }
\ No newline at end of file
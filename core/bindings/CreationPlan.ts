@@ -0,0 +1,5 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Flavour } from "./Flavour";
+import type { JarChecksum } from "./JarChecksum";
+
+export interface CreationPlan { name: string, version: string, flavour: Flavour, port: number, jar_url: string, jar_name: string, jar_checksum: JarChecksum | null, jre_url: string, jre_major_version: bigint, jre_installed: boolean, required_disk_space: bigint | null, }
\ No newline at end of file
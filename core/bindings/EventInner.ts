@@ -1,8 +1,9 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { CoreEvent } from "./CoreEvent";
 import type { FSEvent } from "./FSEvent";
 import type { InstanceEvent } from "./InstanceEvent";
 import type { MacroEvent } from "./MacroEvent";
 import type { ProgressionEvent } from "./ProgressionEvent";
 import type { UserEvent } from "./UserEvent";
 
-export type EventInner = { type: "InstanceEvent" } & InstanceEvent | { type: "UserEvent" } & UserEvent | { type: "MacroEvent" } & MacroEvent | { type: "FSEvent" } & FSEvent | { type: "ProgressionEvent" } & ProgressionEvent;
\ No newline at end of file
+export type EventInner = { type: "InstanceEvent" } & InstanceEvent | { type: "UserEvent" } & UserEvent | { type: "MacroEvent" } & MacroEvent | { type: "FSEvent" } & FSEvent | { type: "ProgressionEvent" } & ProgressionEvent | { type: "CoreEvent" } & CoreEvent;
\ No newline at end of file
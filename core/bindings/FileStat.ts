@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { FileType } from "./FileType";
+
+export interface FileStat { name: string, file_stem: string, extension: string | null, path: string, size: bigint | null, creation_time: bigint | null, modification_time: bigint | null, file_type: FileType, mode: string | null, symlink_target: string | null, readable: boolean, writable: boolean, }
\ No newline at end of file
@@ -1,3 +1,3 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export type FileType = "File" | "Directory" | "Unknown";
\ No newline at end of file
+export type FileType = "File" | "Directory" | "Symlink" | "Unknown";
\ No newline at end of file
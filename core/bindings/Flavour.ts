@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { FabricInstallerVersion } from "./FabricInstallerVersion";
+import type { FabricLoaderVersion } from "./FabricLoaderVersion";
+import type { ForgeBuildVersion } from "./ForgeBuildVersion";
+import type { PaperBuildVersion } from "./PaperBuildVersion";
+
+export type Flavour = "vanilla" | { fabric: { loader_version: FabricLoaderVersion | null, installer_version: FabricInstallerVersion | null, } } | { paper: { build_version: PaperBuildVersion | null, } } | "spigot" | { forge: { build_version: ForgeBuildVersion | null, } };
\ No newline at end of file
@@ -0,0 +1,10 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { CorsSettings } from "./CorsSettings";
+import type { InstancePermissionTemplate } from "./InstancePermissionTemplate";
+import type { MacroLimitPolicy } from "./MacroLimitPolicy";
+import type { OrphanPolicy } from "./OrphanPolicy";
+import type { PortRange } from "./PortRange";
+import type { TlsSettings } from "./TlsSettings";
+import type { UploadRule } from "./UploadRule";
+
+export interface GlobalSettingsUpdate { core_name: string | null, safe_mode: boolean | null, domain?: string | null, soft_delete: boolean | null, trash_retention_days: number | null, instance_stop_timeout: number | null, orphan_policy: OrphanPolicy | null, max_concurrent_macros: number | null, macro_limit_policy: MacroLimitPolicy | null, macro_history_size: number | null, macro_max_heap_mb: number | null, upload_rules: Array<UploadRule> | null, unrestricted_macro_imports: boolean | null, max_write_size: bigint | null, zip_workers: number | null, cors: CorsSettings | null, update_check_interval_hours?: number | null, port_range?: PortRange | null, instances_path?: string | null, tls?: TlsSettings | null, instance_permission_template: InstancePermissionTemplate | null, }
\ No newline at end of file
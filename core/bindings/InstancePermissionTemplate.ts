@@ -0,0 +1,5 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { UserActionKind } from "./UserActionKind";
+import type { UserId } from "./UserId";
+
+export interface InstancePermissionTemplate { creator: Array<UserActionKind> | null, users: Record<UserId, Array<UserActionKind>>, }
\ No newline at end of file
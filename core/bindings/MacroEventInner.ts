@@ -1,4 +1,5 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { EventLevel } from "./EventLevel";
 import type { ExitStatus } from "./ExitStatus";
 
-export type MacroEventInner = { type: "Started" } | { type: "Detach" } | { type: "Stopped", exit_status: ExitStatus, };
\ No newline at end of file
+export type MacroEventInner = { type: "Queued" } | { type: "Started" } | { type: "Detach" } | { type: "Stopped", exit_status: ExitStatus, } | { type: "Notification", level: EventLevel, title: string, body: string, };
\ No newline at end of file
@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { ConsoleLogRotation } from "./ConsoleLogRotation";
+import type { IdleShutdown } from "./IdleShutdown";
+import type { JvmFlagsPreset } from "./JvmFlagsPreset";
+import type { StartupMilestone } from "./StartupMilestone";
+
+export interface MinecraftInstanceConfigUpdate { name: string | null, description: string | null, version: string | null, port: number | null, min_ram: number | null, max_ram: number | null, cmd_args: Array<string> | null, jvm_flags: JvmFlagsPreset | null, auto_start: boolean | null, restart_on_crash: boolean | null, backup_period?: number | null, idle_shutdown?: IdleShutdown | null, console_log?: ConsoleLogRotation | null, cpu_affinity: Array<number> | null, startup_milestones?: Array<StartupMilestone> | null, }
\ No newline at end of file
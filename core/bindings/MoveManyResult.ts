@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { ErrorBody } from "./ErrorBody";
+
+export interface MoveManyResult { src: string, dest: string | null, error: ErrorBody | null, }
\ No newline at end of file
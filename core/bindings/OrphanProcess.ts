@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { InstanceUuid } from "./InstanceUuid";
+
+export interface OrphanProcess { pid: number, instance_uuid: InstanceUuid, instance_name: string, cmd: Array<string>, memory: number, start_time: number, }
\ No newline at end of file
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { PortRange } from "./PortRange";
+
+export interface PortAllocations { range: PortRange | null, allocated: Array<number>, }
\ No newline at end of file
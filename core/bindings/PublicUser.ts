@@ -2,4 +2,4 @@
 import type { UserId } from "./UserId";
 import type { UserPermission } from "./UserPermission";
 
-export interface PublicUser { uid: UserId, username: string, is_owner: boolean, is_admin: boolean, permissions: UserPermission, }
\ No newline at end of file
+export interface PublicUser { uid: UserId, username: string, is_owner: boolean, is_admin: boolean, is_read_only: boolean, permissions: UserPermission, }
\ No newline at end of file
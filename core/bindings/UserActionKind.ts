@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type UserActionKind = "ViewInstance" | "StartInstance" | "StopInstance" | "AccessConsole" | "AccessSetting" | "ReadResource" | "WriteResource" | "AccessMacro" | "ReadInstanceFile" | "WriteInstanceFile" | "CreateInstance" | "DeleteInstance" | "ReadGlobalFile" | "WriteGlobalFile" | "ManageUser" | "ManagePermission" | "ManageSystem";
\ No newline at end of file
@@ -0,0 +1,56 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// FNV-1a, stable across builds and toolchains unlike `DefaultHasher`
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn collect_sources(dir: &Path, sources: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.is_dir() {
+            collect_sources(&path, sources);
+        } else if path.extension().map_or(false, |ext| ext == "rs") {
+            sources.push(path);
+        }
+    }
+}
+
+/// Hashes the Rust sources defining the types ts-rs exports, so clients can tell
+/// whether their bindings match the types of this build.
+///
+/// The committed `.ts` files are only regenerated by `cargo test`, hashing them
+/// would miss type changes that weren't exported yet
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set");
+    let src_dir = Path::new(&manifest_dir).join("src");
+    println!("cargo:rerun-if-changed={}", src_dir.display());
+
+    let mut sources = Vec::new();
+    collect_sources(&src_dir, &mut sources);
+    // read_dir order is platform dependent
+    sources.sort();
+
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    for path in sources {
+        let content = fs::read_to_string(&path).expect("Failed to read source");
+        if !content.contains("ts(export") {
+            continue;
+        }
+        let relative_path = path.strip_prefix(&src_dir).unwrap().to_string_lossy();
+        // separators depend on the platform, not on the types
+        hash = fnv1a(hash, relative_path.replace('\\', "/").as_bytes());
+        // line endings depend on the checkout, not on the types
+        let content: Vec<u8> = content.bytes().filter(|byte| *byte != b'\r').collect();
+        hash = fnv1a(hash, &content);
+    }
+    println!("cargo:rustc-env=LODESTONE_BINDINGS_HASH={hash:016x}");
+}
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { CPUInfo } from "./CPUInfo.ts";
+import type { DiskInfo } from "./DiskInfo.ts";
+import type { MemInfo } from "./MemInfo.ts";
+
+export interface SystemInfo { cpu: CPUInfo, ram: MemInfo, disk: DiskInfo, }
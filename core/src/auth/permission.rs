@@ -1,9 +1,15 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
+use color_eyre::eyre::eyre;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
-use crate::types::InstanceUuid;
+use crate::{
+    auth::{user::UserActionKind, user_id::UserId},
+    error::{Error, ErrorKind},
+    types::InstanceUuid,
+    util::wildcard_match,
+};
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, TS, Debug)]
 #[ts(export)]
 pub struct UserPermission {
@@ -28,6 +34,11 @@ pub struct UserPermission {
     pub can_write_global_file: bool,
     // owner exclusive unless explicitly granted
     pub can_manage_permission: bool,
+    /// Console commands the user may send to an instance, as patterns where `*` matches anything
+    ///
+    /// If an instance has no entry, any command is allowed to users who can access its console
+    #[serde(default)]
+    pub allowed_console_commands: HashMap<InstanceUuid, Vec<String>>,
 }
 
 impl UserPermission {
@@ -48,8 +59,41 @@ impl UserPermission {
             can_read_global_file: false,
             can_write_global_file: false,
             can_manage_permission: false,
+            allowed_console_commands: HashMap::new(),
         }
     }
+
+    /// Returns whether the allow list for the instance, if any, permits the command
+    pub fn is_console_command_allowed(&self, instance_uuid: &InstanceUuid, command: &str) -> bool {
+        match self.allowed_console_commands.get(instance_uuid) {
+            Some(patterns) => {
+                // commands may or may not be sent with a leading slash
+                let command = command.trim().trim_start_matches('/');
+                patterns
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern.trim().trim_start_matches('/'), command))
+            }
+            None => true,
+        }
+    }
+
+    /// Grants an instance specific action on the instance, global actions are ignored
+    pub fn grant_instance_action(&mut self, kind: UserActionKind, instance_uuid: &InstanceUuid) {
+        let set = match kind {
+            UserActionKind::ViewInstance => &mut self.can_view_instance,
+            UserActionKind::StartInstance => &mut self.can_start_instance,
+            UserActionKind::StopInstance => &mut self.can_stop_instance,
+            UserActionKind::AccessConsole => &mut self.can_access_instance_console,
+            UserActionKind::AccessSetting => &mut self.can_access_instance_setting,
+            UserActionKind::ReadResource => &mut self.can_read_instance_resource,
+            UserActionKind::WriteResource => &mut self.can_write_instance_resource,
+            UserActionKind::AccessMacro => &mut self.can_access_instance_macro,
+            UserActionKind::ReadInstanceFile => &mut self.can_read_instance_file,
+            UserActionKind::WriteInstanceFile => &mut self.can_write_instance_file,
+            _ => return,
+        };
+        set.insert(instance_uuid.clone());
+    }
 }
 
 impl Default for UserPermission {
@@ -57,3 +101,54 @@ impl Default for UserPermission {
         Self::new()
     }
 }
+
+/// Actions granted on new instances
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default, TS)]
+#[serde(default)]
+#[ts(export)]
+pub struct InstancePermissionTemplate {
+    /// Granted to whoever creates the instance, `None` grants viewing, starting and stopping it
+    /// and reading and writing its files
+    pub creator: Option<Vec<UserActionKind>>,
+    /// Granted to other users, such as moderators who should see every instance
+    pub users: HashMap<UserId, Vec<UserActionKind>>,
+}
+
+impl InstancePermissionTemplate {
+    /// Only instance specific actions can be granted
+    pub fn validate(&self) -> Result<(), Error> {
+        for kind in self.creator.iter().chain(self.users.values()).flatten() {
+            if !matches!(
+                kind,
+                UserActionKind::ViewInstance
+                    | UserActionKind::StartInstance
+                    | UserActionKind::StopInstance
+                    | UserActionKind::AccessConsole
+                    | UserActionKind::AccessSetting
+                    | UserActionKind::ReadResource
+                    | UserActionKind::WriteResource
+                    | UserActionKind::AccessMacro
+                    | UserActionKind::ReadInstanceFile
+                    | UserActionKind::WriteInstanceFile
+            ) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("{:?} can't be granted on an instance", kind),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn creator_actions(&self) -> Vec<UserActionKind> {
+        self.creator.clone().unwrap_or_else(|| {
+            vec![
+                UserActionKind::StartInstance,
+                UserActionKind::StopInstance,
+                UserActionKind::ViewInstance,
+                UserActionKind::ReadInstanceFile,
+                UserActionKind::WriteInstanceFile,
+            ]
+        })
+    }
+}
@@ -35,6 +35,10 @@ pub struct User {
     pub hashed_psw: HashedPassword,
     pub is_owner: bool,
     pub is_admin: bool,
+    /// Read-only users can only perform actions that do not modify anything,
+    /// regardless of their permissions
+    #[serde(default)]
+    pub is_read_only: bool,
     pub permissions: UserPermission,
     pub secret: UserSecret,
 }
@@ -53,6 +57,7 @@ impl User {
             hashed_psw: hash_password(password),
             is_owner,
             is_admin,
+            is_read_only: false,
             permissions,
             secret: UserSecret::default(),
         }
@@ -110,6 +115,9 @@ impl User {
         if self.is_owner {
             return true;
         }
+        if self.is_read_only && !action.is_read_only() {
+            return false;
+        }
         match action {
             UserAction::ViewInstance(instance_id) => {
                 self.is_admin || self.permissions.can_view_instance.contains(instance_id)
@@ -172,12 +180,18 @@ impl User {
             UserAction::WriteGlobalFile => self.permissions.can_write_global_file,
             UserAction::ManageUser => self.is_owner,
             UserAction::ManagePermission => self.permissions.can_manage_permission,
+            UserAction::ManageSystem => self.is_admin,
         }
     }
 
     pub fn try_action(&self, action: &UserAction) -> Result<(), Error> {
         if self.can_perform_action(action) {
             Ok(())
+        } else if !self.is_owner && self.is_read_only && !action.is_read_only() {
+            Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Read-only users can't perform this action"),
+            })
         } else {
             Err(Error {
                 kind: ErrorKind::PermissionDenied,
@@ -228,13 +242,51 @@ impl User {
                     UserAction::ManagePermission => {
                         eyre!("You don't have permission to manage permission")
                     }
+                    UserAction::ManageSystem => {
+                        eyre!("You don't have permission to manage the system")
+                    }
                 },
             })
         }
     }
 
+    /// Checks that the user can access the instance's console and is allowed to send this command
+    pub fn try_console_command(
+        &self,
+        instance_uuid: &InstanceUuid,
+        command: &str,
+    ) -> Result<(), Error> {
+        self.try_action(&UserAction::AccessConsole(instance_uuid.clone()))?;
+        // a line break would send the rest as a separate command, which the allow-list never saw
+        if command.contains(['\n', '\r']) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Commands can't contain line breaks"),
+            });
+        }
+        if self.is_owner
+            || self
+                .permissions
+                .is_console_command_allowed(instance_uuid, command)
+        {
+            Ok(())
+        } else {
+            Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!(
+                    "You don't have permission to run the command \"{}\"",
+                    command
+                ),
+            })
+        }
+    }
+
     pub fn can_view_event(&self, event: impl AsRef<Event>) -> bool {
-        match &event.as_ref().event_inner {
+        self.can_view_event_inner(&event.as_ref().event_inner)
+    }
+
+    pub fn can_view_event_inner(&self, event_inner: &EventInner) -> bool {
+        match event_inner {
             EventInner::InstanceEvent(event) => {
                 self.can_perform_action(&UserAction::ViewInstance(event.instance_uuid.clone()))
             }
@@ -245,6 +297,7 @@ impl User {
             }
             // TODO!,
             EventInner::ProgressionEvent(_progression_event) => true,
+            EventInner::CoreEvent(_) => true,
         }
     }
 
@@ -262,6 +315,8 @@ impl User {
     }
 }
 
+#[derive(enum_kinds::EnumKind)]
+#[enum_kind(UserActionKind, derive(Serialize, Deserialize, TS, Hash), ts(export))]
 pub enum UserAction {
     // instance specific actions:
     ViewInstance(InstanceUuid),
@@ -282,6 +337,59 @@ pub enum UserAction {
     WriteGlobalFile,
     ManageUser,
     ManagePermission,
+    /// Core wide operations such as changing the log level, owners and admins only
+    ManageSystem,
+}
+
+impl UserAction {
+    /// Builds the action of `kind` on `instance_uuid`, which instance specific actions require
+    pub fn from_kind(
+        kind: UserActionKind,
+        instance_uuid: Option<InstanceUuid>,
+    ) -> Result<UserAction, Error> {
+        let instance_uuid_or_err = || {
+            instance_uuid.clone().ok_or_else(|| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("{:?} requires an instance uuid", kind),
+            })
+        };
+        Ok(match kind {
+            UserActionKind::ViewInstance => UserAction::ViewInstance(instance_uuid_or_err()?),
+            UserActionKind::StartInstance => UserAction::StartInstance(instance_uuid_or_err()?),
+            UserActionKind::StopInstance => UserAction::StopInstance(instance_uuid_or_err()?),
+            UserActionKind::AccessConsole => UserAction::AccessConsole(instance_uuid_or_err()?),
+            UserActionKind::AccessSetting => UserAction::AccessSetting(instance_uuid_or_err()?),
+            UserActionKind::ReadResource => UserAction::ReadResource(instance_uuid_or_err()?),
+            UserActionKind::WriteResource => UserAction::WriteResource(instance_uuid_or_err()?),
+            UserActionKind::AccessMacro => UserAction::AccessMacro(instance_uuid.clone()),
+            UserActionKind::ReadInstanceFile => {
+                UserAction::ReadInstanceFile(instance_uuid_or_err()?)
+            }
+            UserActionKind::WriteInstanceFile => {
+                UserAction::WriteInstanceFile(instance_uuid_or_err()?)
+            }
+            UserActionKind::CreateInstance => UserAction::CreateInstance,
+            UserActionKind::DeleteInstance => UserAction::DeleteInstance,
+            UserActionKind::ReadGlobalFile => UserAction::ReadGlobalFile,
+            UserActionKind::WriteGlobalFile => UserAction::WriteGlobalFile,
+            UserActionKind::ManageUser => UserAction::ManageUser,
+            UserActionKind::ManagePermission => UserAction::ManagePermission,
+            UserActionKind::ManageSystem => UserAction::ManageSystem,
+        })
+    }
+
+    /// Whether the action only reads state, and is therefore allowed for read-only users
+    ///
+    /// Accessing macros is never read-only, since a macro can control instances
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            UserAction::ViewInstance(_)
+                | UserAction::ReadResource(_)
+                | UserAction::ReadInstanceFile(_)
+                | UserAction::ReadGlobalFile
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, TS)]
@@ -291,6 +399,7 @@ pub struct PublicUser {
     pub username: String,
     pub is_owner: bool,
     pub is_admin: bool,
+    pub is_read_only: bool,
     pub permissions: UserPermission,
 }
 
@@ -301,6 +410,7 @@ impl From<&User> for PublicUser {
             username: user.username.clone(),
             is_owner: user.is_owner,
             is_admin: user.is_admin,
+            is_read_only: user.is_read_only,
             permissions: user.permissions.clone(),
         }
     }
@@ -313,6 +423,7 @@ impl From<User> for PublicUser {
             username: user.username,
             is_owner: user.is_owner,
             is_admin: user.is_admin,
+            is_read_only: user.is_read_only,
             permissions: user.permissions,
         }
     }
@@ -642,6 +753,46 @@ impl UsersManager {
         }
     }
 
+    pub async fn set_read_only(
+        &mut self,
+        uid: impl AsRef<UserId>,
+        is_read_only: bool,
+        caused_by: CausedBy,
+    ) -> Result<(), Error> {
+        let user = self.users.get_mut(uid.as_ref()).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("User id not found"),
+        })?;
+        if user.is_owner {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The owner cannot be made read-only"),
+            });
+        }
+        let old_is_read_only = user.is_read_only;
+        user.is_read_only = is_read_only;
+        match self.write_to_file().await {
+            Ok(_) => {
+                self.event_broadcaster.send(Event {
+                    event_inner: EventInner::UserEvent(UserEvent {
+                        user_id: uid.as_ref().to_owned(),
+                        user_event_inner: UserEventInner::ReadOnlyChanged { is_read_only },
+                    }),
+                    details: "".to_string(),
+                    snowflake: Snowflake::default(),
+                    caused_by,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(user) = self.users.get_mut(uid.as_ref()) {
+                    user.is_read_only = old_is_read_only;
+                }
+                Err(e)
+            }
+        }
+    }
+
     pub fn try_auth(&self, token: &str) -> Option<User> {
         let claimed_uid = decode_no_verify(token)?;
         let claimed_requester = self.users.get(&claimed_uid)?;
@@ -773,6 +924,71 @@ mod tests {
         users_manager.login("test_user1", "54321").unwrap();
     }
 
+    #[test]
+    fn test_read_only() {
+        use super::*;
+        let mut permissions = UserPermission::default();
+        let instance_uuid = InstanceUuid::default();
+        permissions.can_view_instance.insert(instance_uuid.clone());
+        permissions.can_start_instance.insert(instance_uuid.clone());
+        let mut user = User::new("test_user".to_string(), "12345", false, true, permissions);
+        assert!(user.can_perform_action(&UserAction::StartInstance(instance_uuid.clone())));
+        assert!(user.can_perform_action(&UserAction::ManageSystem));
+
+        user.is_read_only = true;
+        assert!(!user.can_perform_action(&UserAction::ManageSystem));
+        assert!(user.can_perform_action(&UserAction::ViewInstance(instance_uuid.clone())));
+        assert!(!user.can_perform_action(&UserAction::StartInstance(instance_uuid.clone())));
+        assert!(!user.can_perform_action(&UserAction::AccessMacro(Some(instance_uuid))));
+        assert!(!user.can_perform_action(&UserAction::CreateInstance));
+    }
+
+    #[test]
+    fn test_console_command_allow_list() {
+        use super::*;
+        let instance_uuid = InstanceUuid::default();
+        let mut permissions = UserPermission::default();
+        permissions
+            .can_access_instance_console
+            .insert(instance_uuid.clone());
+        let mut user = User::new("test_user".to_string(), "12345", false, false, permissions);
+        assert!(user
+            .try_console_command(&instance_uuid, "op someone")
+            .is_ok());
+
+        user.permissions.allowed_console_commands.insert(
+            instance_uuid.clone(),
+            vec!["kick *".to_string(), "say*".to_string(), "list".to_string()],
+        );
+        assert!(user
+            .try_console_command(&instance_uuid, "kick someone")
+            .is_ok());
+        assert!(user
+            .try_console_command(&instance_uuid, "/say hello")
+            .is_ok());
+        assert!(user.try_console_command(&instance_uuid, "list").is_ok());
+        assert!(user
+            .try_console_command(&instance_uuid, "list uuids")
+            .is_err());
+        assert!(user
+            .try_console_command(&instance_uuid, "op someone")
+            .is_err());
+        assert!(user
+            .try_console_command(&instance_uuid, "/execute run stop")
+            .is_err());
+        assert!(user
+            .try_console_command(&instance_uuid, "kick x\nop attacker")
+            .is_err());
+        assert!(user
+            .try_console_command(&instance_uuid, "say hi\rop attacker")
+            .is_err());
+
+        user.is_owner = true;
+        assert!(user
+            .try_console_command(&instance_uuid, "say hi\nop attacker")
+            .is_err());
+    }
+
     #[tokio::test]
     async fn test_persistent() {
         use super::*;
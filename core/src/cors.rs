@@ -0,0 +1,142 @@
+use std::str::FromStr;
+
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+/// Which browser origins can call the API, for dashboards served from another origin
+///
+/// WebSockets aren't subject to CORS, so the console and event streams work from any origin.
+///
+/// The layer is built when the core starts, changes take effect after a restart
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, TS)]
+#[serde(default)]
+#[ts(export)]
+pub struct CorsSettings {
+    /// Origins such as `https://example.com`. `None`, the default, allows any origin,
+    /// as the dashboard is served from its own origin rather than by the core.
+    /// An empty list only allows same-origin requests
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// Whether browsers may send credentials such as cookies, requires explicit origins
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            allowed_origins: None,
+            allowed_methods: ["GET", "POST", "PATCH", "PUT", "DELETE", "OPTIONS"]
+                .iter()
+                .map(|method| method.to_string())
+                .collect(),
+            allowed_headers: [header::ORIGIN, header::CONTENT_TYPE, header::AUTHORIZATION]
+                .iter()
+                .map(|header| header.to_string())
+                .collect(),
+            allow_credentials: false,
+        }
+    }
+}
+
+fn bad_request(message: String) -> Error {
+    Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!(message),
+    }
+}
+
+impl CorsSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        self.to_layer().map(|_| ())
+    }
+
+    pub fn to_layer(&self) -> Result<CorsLayer, Error> {
+        let methods = self
+            .allowed_methods
+            .iter()
+            .map(|method| {
+                Method::from_str(&method.to_uppercase())
+                    .map_err(|_| bad_request(format!("Invalid HTTP method {}", method)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers = self
+            .allowed_headers
+            .iter()
+            .map(|header| {
+                HeaderName::from_str(header)
+                    .map_err(|_| bad_request(format!("Invalid header name {}", header)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let origins = match &self.allowed_origins {
+            Some(origins) => AllowOrigin::list(
+                origins
+                    .iter()
+                    .map(|origin| {
+                        HeaderValue::from_str(origin.trim_end_matches('/'))
+                            .map_err(|_| bad_request(format!("Invalid origin {}", origin)))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            // tower-http refuses to send credentials to any origin
+            None if self.allow_credentials => {
+                return Err(bad_request(
+                    "Credentials can only be allowed for explicit origins".to_string(),
+                ))
+            }
+            None => Any.into(),
+        };
+        Ok(CorsLayer::new()
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_origin(origins)
+            .allow_credentials(self.allow_credentials)
+            // read by the dashboard when downloading files and revalidating cached ones
+            .expose_headers([
+                header::CONTENT_DISPOSITION,
+                header::CONTENT_LENGTH,
+                header::ETAG,
+                header::LAST_MODIFIED,
+            ]))
+    }
+}
+
+#[test]
+fn test_cors_settings() {
+    assert!(CorsSettings::default().validate().is_ok());
+    assert_eq!(CorsSettings::default().allowed_origins, None);
+    // settings files from before the field existed keep allowing any origin
+    let cors: CorsSettings = serde_json::from_str(r#"{"allow_credentials": false}"#).unwrap();
+    assert_eq!(cors.allowed_origins, None);
+    assert!(CorsSettings {
+        allowed_origins: Some(Vec::new()),
+        ..Default::default()
+    }
+    .validate()
+    .is_ok());
+    assert!(CorsSettings {
+        allowed_origins: None,
+        allow_credentials: true,
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+    assert!(CorsSettings {
+        allowed_origins: Some(vec!["https://example.com/".to_string()]),
+        allow_credentials: true,
+        ..Default::default()
+    }
+    .validate()
+    .is_ok());
+    assert!(CorsSettings {
+        allowed_methods: vec!["NOT A METHOD".to_string()],
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+}
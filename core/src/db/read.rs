@@ -1,11 +1,20 @@
 use crate::{
-    error::Error, output_types::ClientEvent,
-    prelude::LODESTONE_EPOCH_MIL, events::EventQuery,
+    auth::user_id::UserId,
+    error::Error,
+    events::{EventQuery, EventType},
+    output_types::ClientEvent,
+    prelude::LODESTONE_EPOCH_MIL,
+    types::InstanceUuid,
 };
 
 use color_eyre::eyre::Context;
-use sqlx::sqlite::SqlitePool;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    sqlite::{Sqlite, SqlitePool},
+    QueryBuilder, Row,
+};
 use tracing::error;
+use ts_rs::TS;
 
 // TODO clean up all unwraps
 
@@ -69,6 +78,199 @@ FROM ClientEvents"#
     Ok(filtered)
 }
 
+pub const DEFAULT_EVENT_PAGE_SIZE: u32 = 100;
+pub const MAX_EVENT_PAGE_SIZE: u32 = 1000;
+/// Rows looked at for one page before it is returned short, so a page can't scan the whole history
+const MAX_EVENT_PAGE_SCAN: usize = 10_000;
+
+/// A page of the event history, newest first. All filters are optional
+#[derive(Deserialize, Clone, Debug, Default, TS)]
+#[ts(export)]
+pub struct EventPageQuery {
+    /// Only return events older than this cursor, as returned in `next_cursor`
+    pub before: Option<i64>,
+    /// Defaults to 100, at most 1000
+    pub limit: Option<u32>,
+    /// The user who caused the event
+    pub user_id: Option<UserId>,
+    pub instance_uuid: Option<InstanceUuid>,
+    pub kind: Option<EventType>,
+    /// Unix timestamp in milliseconds, inclusive
+    pub start: Option<i64>,
+    /// Unix timestamp in milliseconds, inclusive
+    pub end: Option<i64>,
+}
+
+#[derive(Serialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct EventPage {
+    /// Can be fewer than the limit while `next_cursor` is set, if most older events aren't visible
+    pub events: Vec<ClientEvent>,
+    /// Pass as `before` to get the next page, `None` once there are no older events
+    pub next_cursor: Option<i64>,
+}
+
+/// The events a user could see, narrowed down in the query so rows of instances
+/// they have no access to aren't read at all. `None` doesn't restrict
+///
+/// Only has to be a superset of what the user can see, `page_events` still checks every event
+#[derive(Clone, Debug)]
+pub struct EventVisibility {
+    pub instances: Option<Vec<InstanceUuid>>,
+    pub macro_instances: Option<Vec<InstanceUuid>>,
+    pub user_and_fs_events: bool,
+}
+
+impl EventVisibility {
+    pub fn unrestricted() -> Self {
+        Self {
+            instances: None,
+            macro_instances: None,
+            user_and_fs_events: true,
+        }
+    }
+}
+
+fn push_instance_list(builder: &mut QueryBuilder<'_, Sqlite>, instances: &[InstanceUuid]) {
+    builder.push("(");
+    let mut separated = builder.separated(", ");
+    for instance in instances {
+        separated.push_bind(instance.clone());
+    }
+    builder.push(")");
+}
+
+fn push_visibility(builder: &mut QueryBuilder<'_, Sqlite>, visibility: &EventVisibility) {
+    builder.push(
+        " AND (json_extract(event_value, '$.event_inner.type') IN ('ProgressionEvent', 'CoreEvent')",
+    );
+    // only instance events have an instance_id, which is indexed
+    match &visibility.instances {
+        Some(instances) => {
+            builder.push(" OR instance_id IN ");
+            push_instance_list(builder, instances);
+        }
+        None => {
+            builder.push(" OR instance_id IS NOT NULL");
+        }
+    }
+    match &visibility.macro_instances {
+        Some(instances) => {
+            builder.push(
+                " OR (json_extract(event_value, '$.event_inner.type') = 'MacroEvent' AND json_extract(event_value, '$.event_inner.instance_uuid') IN ",
+            );
+            push_instance_list(builder, instances);
+            builder.push(")");
+        }
+        None => {
+            builder.push(" OR json_extract(event_value, '$.event_inner.type') = 'MacroEvent'");
+        }
+    }
+    if visibility.user_and_fs_events {
+        builder.push(
+            " OR json_extract(event_value, '$.event_inner.type') IN ('UserEvent', 'FSEvent')",
+        );
+    }
+    builder.push(")");
+}
+
+fn page_query<'a>(
+    event_query: &'a EventPageQuery,
+    visibility: &EventVisibility,
+    before: Option<i64>,
+    limit: i64,
+) -> QueryBuilder<'a, Sqlite> {
+    let mut builder = QueryBuilder::new("SELECT id, event_value FROM ClientEvents WHERE 1 = 1");
+    push_visibility(&mut builder, visibility);
+    if let Some(before) = before {
+        builder.push(" AND id < ").push_bind(before);
+    }
+    if let Some(user_id) = &event_query.user_id {
+        builder.push(" AND caused_by_user_id = ").push_bind(user_id);
+    }
+    if let Some(instance_uuid) = &event_query.instance_uuid {
+        builder.push(" AND instance_id = ").push_bind(instance_uuid);
+    }
+    if let Some(kind) = &event_query.kind {
+        // EventInner is internally tagged, so the kind is stored as its "type"
+        builder
+            .push(" AND json_extract(event_value, '$.event_inner.type') = ")
+            .push_bind(
+                serde_json::to_value(kind)
+                    .ok()
+                    .and_then(|kind| kind.as_str().map(str::to_owned)),
+            );
+    }
+    if let Some(start) = event_query.start {
+        builder
+            .push(" AND snowflake >= ")
+            .push_bind((start - LODESTONE_EPOCH_MIL.with(|p| *p)) << 22);
+    }
+    if let Some(end) = event_query.end {
+        builder
+            .push(" AND snowflake < ")
+            .push_bind((end + 1 - LODESTONE_EPOCH_MIL.with(|p| *p)) << 22);
+    }
+    // the row id only grows, so pages are found through the primary key instead of a scan
+    builder.push(" ORDER BY id DESC LIMIT ").push_bind(limit);
+    builder
+}
+
+/// Events the user can't see are skipped, so rows are fetched until the page is full,
+/// the history runs out or `MAX_EVENT_PAGE_SCAN` rows were looked at
+pub async fn page_events(
+    pool: &SqlitePool,
+    event_query: &EventPageQuery,
+    visibility: &EventVisibility,
+    can_view: impl Fn(&ClientEvent) -> bool,
+) -> Result<EventPage, Error> {
+    let mut connection = pool
+        .acquire()
+        .await
+        .context("Failed to aquire connection to db")?;
+    let limit = event_query
+        .limit
+        .unwrap_or(DEFAULT_EVENT_PAGE_SIZE)
+        .clamp(1, MAX_EVENT_PAGE_SIZE) as usize;
+    let mut events = Vec::new();
+    let mut cursor = event_query.before;
+    let mut scanned = 0;
+    loop {
+        let rows = page_query(event_query, visibility, cursor, limit as i64)
+            .build()
+            .fetch_all(&mut connection)
+            .await
+            .context("Failed to fetch events")?;
+        let exhausted = rows.len() < limit;
+        for row in rows {
+            let id: i64 = row.try_get("id").context("Failed to read event id")?;
+            let event_value: String = row.try_get("event_value").context("Failed to read event")?;
+            cursor = Some(id);
+            scanned += 1;
+            match serde_json::from_str::<ClientEvent>(&event_value) {
+                Ok(client_event) => {
+                    if can_view(&client_event) {
+                        events.push(client_event);
+                    }
+                }
+                Err(_) => error!("Failed to parse client event: {}", event_value),
+            }
+            if events.len() == limit || scanned == MAX_EVENT_PAGE_SCAN {
+                return Ok(EventPage {
+                    events,
+                    next_cursor: cursor,
+                });
+            }
+        }
+        if exhausted {
+            return Ok(EventPage {
+                events,
+                next_cursor: None,
+            });
+        }
+    }
+}
+
 #[cfg(test)]
 #[allow(unused_imports)]
 mod tests {
@@ -128,6 +330,111 @@ mod tests {
         // let row_1 = row_1_result.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_page_events() {
+        // a single connection, every connection to :memory: opens its own db
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        init_client_events_table(&pool).await.unwrap();
+        for i in 0..25 {
+            let client_event = ClientEvent {
+                event_inner: EventInner::FSEvent(FSEvent {
+                    operation: FSOperation::Read,
+                    target: FSTarget::File(PathBuf::from(format!("/test{}", i))),
+                }),
+                details: i.to_string(),
+                snowflake: Snowflake::new(),
+                level: if i % 2 == 0 {
+                    EventLevel::Info
+                } else {
+                    EventLevel::Warning
+                },
+                caused_by: CausedBy::System,
+            };
+            crate::db::write::write_client_event(&pool, client_event)
+                .await
+                .unwrap();
+        }
+
+        let query = EventPageQuery {
+            limit: Some(10),
+            kind: Some(EventType::FSEvent),
+            ..Default::default()
+        };
+        let first = page_events(&pool, &query, &EventVisibility::unrestricted(), |_| true)
+            .await
+            .unwrap();
+        assert_eq!(first.events.len(), 10);
+        assert_eq!(first.events[0].details, "24");
+        let second = page_events(
+            &pool,
+            &EventPageQuery {
+                before: first.next_cursor,
+                ..query.clone()
+            },
+            &EventVisibility::unrestricted(),
+            |_| true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(second.events[0].details, "14");
+
+        // skipped events don't shorten the page
+        let visible = page_events(&pool, &query, &EventVisibility::unrestricted(), |event| {
+            event.level == EventLevel::Info
+        })
+        .await
+        .unwrap();
+        assert_eq!(visible.events.len(), 10);
+        assert!(visible.next_cursor.is_some());
+        let rest = page_events(
+            &pool,
+            &EventPageQuery {
+                before: visible.next_cursor,
+                ..query.clone()
+            },
+            &EventVisibility::unrestricted(),
+            |event| event.level == EventLevel::Info,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rest.events.len(), 3);
+        assert!(rest.next_cursor.is_none());
+
+        let none = page_events(
+            &pool,
+            &EventPageQuery {
+                kind: Some(EventType::UserEvent),
+                ..Default::default()
+            },
+            &EventVisibility::unrestricted(),
+            |_| true,
+        )
+        .await
+        .unwrap();
+        assert!(none.events.is_empty());
+        assert!(none.next_cursor.is_none());
+
+        // filtered out by the query, before `can_view` sees them
+        let restricted = page_events(
+            &pool,
+            &query,
+            &EventVisibility {
+                instances: Some(Vec::new()),
+                macro_instances: Some(Vec::new()),
+                user_and_fs_events: false,
+            },
+            |_| panic!("hidden event was fetched"),
+        )
+        .await
+        .unwrap();
+        assert!(restricted.events.is_empty());
+        assert!(restricted.next_cursor.is_none());
+    }
+
     // TODO should properly implement tests, with dummy values
     // #[tokio::test]
     // async fn test_read() {
@@ -6,14 +6,24 @@ use crate::{
 
 use color_eyre::eyre::Context;
 use sqlx::sqlite::SqlitePool;
-use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::sync::broadcast::{
+    error::{RecvError, TryRecvError},
+    Receiver,
+};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, warn};
 
 use super::types::ClientEventRow;
 
 // TODO clean up all unwraps
 
-pub async fn write_event_to_db_task(mut event_receiver: Receiver<Event>, sqlite_pool: SqlitePool) {
+/// Writes events to the db until `shutdown` is cancelled,
+/// after which events already sent are written before returning
+pub async fn write_event_to_db_task(
+    mut event_receiver: Receiver<Event>,
+    sqlite_pool: SqlitePool,
+    shutdown: CancellationToken,
+) {
     let init_result = init_client_events_table(&sqlite_pool).await;
     if let Err(error) = init_result.as_ref() {
         warn!("Failed to initialize client events table: {}", error);
@@ -21,7 +31,10 @@ pub async fn write_event_to_db_task(mut event_receiver: Receiver<Event>, sqlite_
     }
 
     loop {
-        let result = event_receiver.recv().await;
+        let result = tokio::select! {
+            result = event_receiver.recv() => result,
+            _ = shutdown.cancelled() => break,
+        };
         if let Err(error) = result.as_ref() {
             match error {
                 RecvError::Lagged(_) => {
@@ -30,26 +43,48 @@ pub async fn write_event_to_db_task(mut event_receiver: Receiver<Event>, sqlite_
                 }
                 RecvError::Closed => {
                     warn!("Event buffer closed");
-                    break;
+                    return;
                 }
             }
         }
 
-        let client_event: ClientEvent = result.unwrap().into();
-        if let EventInner::ProgressionEvent(pe) = &client_event.event_inner {
-            if let ProgressionEventInner::ProgressionUpdate { .. } = pe.progression_event_inner() {
+        if let Err(e) = write_event(&sqlite_pool, result.unwrap()).await {
+            error!("Error inserting into database: {}", e);
+            return;
+        }
+    }
+
+    loop {
+        match event_receiver.try_recv() {
+            Ok(event) => {
+                if let Err(e) = write_event(&sqlite_pool, event).await {
+                    error!("Error inserting into database: {}", e);
+                    return;
+                }
+            }
+            Err(TryRecvError::Lagged(_)) => {
+                warn!("Event buffer lagged");
                 continue;
             }
+            Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => return,
         }
-        let insertion_result = write_client_event(&sqlite_pool, client_event).await;
-        if let Err(e) = insertion_result.as_ref() {
-            error!("Error inserting into database: {}", e);
-            break;
+    }
+}
+
+async fn write_event(pool: &SqlitePool, event: Event) -> Result<(), Error> {
+    let client_event: ClientEvent = event.into();
+    if let EventInner::ProgressionEvent(pe) = &client_event.event_inner {
+        if let ProgressionEventInner::ProgressionUpdate { .. } = pe.progression_event_inner() {
+            return Ok(());
         }
     }
+    write_client_event(pool, client_event).await.map(|_| ())
 }
 
-async fn write_client_event(pool: &SqlitePool, client_event: ClientEvent) -> Result<i64, Error> {
+pub(crate) async fn write_client_event(
+    pool: &SqlitePool,
+    client_event: ClientEvent,
+) -> Result<i64, Error> {
     let mut connection = pool
         .acquire()
         .await
@@ -100,6 +135,20 @@ pub async fn init_client_events_table(pool: &SqlitePool) -> Result<(), Error> {
     .await
     .context("Failed to create table")?;
 
+    // pages filtered by user or instance walk these instead of every row
+    sqlx::query!(
+        r#"CREATE INDEX IF NOT EXISTS ClientEventsUser ON ClientEvents (caused_by_user_id, id);"#
+    )
+    .execute(&mut connection)
+    .await
+    .context("Failed to create index")?;
+    sqlx::query!(
+        r#"CREATE INDEX IF NOT EXISTS ClientEventsInstance ON ClientEvents (instance_id, id);"#
+    )
+    .execute(&mut connection)
+    .await
+    .context("Failed to create index")?;
+
     Ok(())
 }
 
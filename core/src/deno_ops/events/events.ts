@@ -6,9 +6,11 @@ import { InstanceState } from "../../../deno_bindings/InstanceState.ts";
 import { ProgressionStartValue } from "../../../deno_bindings/ProgressionStartValue.ts";
 import { ProgressionEndValue } from "../../../deno_bindings/ProgressionEndValue.ts";
 import { ProgressionEventID } from "../../../deno_bindings/ProgressionEventID.ts";
+import { EventQuery } from "../../../deno_bindings/EventQuery.ts";
+import { EventLevel } from "../../../deno_bindings/EventLevel.ts";
 
 // re-exports 
-export type { ClientEvent, TaskPID, InstanceControl, InstanceEvent, InstanceState };
+export type { ClientEvent, TaskPID, InstanceControl, InstanceEvent, InstanceState, EventQuery, EventLevel };
 
 // deno-lint-ignore no-explicit-any
 declare const Deno: any;
@@ -20,8 +22,33 @@ export interface PlayerMessage {
     message: string;
 }
 
-export function nextEvent(): Promise<ClientEvent> {
-    return core.opAsync("next_event");
+/** Waits for the next event matching `filter`, all events match if it is omitted.
+ *
+ * Events emitted between two calls are not seen, use `subscribeEvents` to handle every event.
+ */
+export function nextEvent(filter: Partial<EventQuery> | null = null): Promise<ClientEvent> {
+    return core.opAsync("next_event", filter);
+}
+
+/** Yields every event matching `filter`, in order, until the loop is exited.
+ *
+ * Throws if the macro falls so far behind that events had to be dropped.
+ *
+ * ```ts
+ * for await (const event of subscribeEvents({ event_instance_ids: [getCurrentInstanceUUID()] })) {
+ *     ...
+ * }
+ * ```
+ */
+export async function* subscribeEvents(filter: Partial<EventQuery> | null = null): AsyncGenerator<ClientEvent> {
+    const rid: number = ops.subscribe_events(filter);
+    try {
+        while (true) {
+            yield await core.opAsync("next_subscribed_event", rid);
+        }
+    } finally {
+        ops.unsubscribe_events(rid);
+    }
 }
 
 export function nextInstanceEvent(instanceUuid: string): Promise<InstanceEvent> {
@@ -76,4 +103,28 @@ export function emitProgressiontEventUpdate(eventId : ProgressionEventID, progre
 
 export function emitProgressionEventEnd(eventId : ProgressionEventID, success : boolean, message : string | null, inner : ProgressionEndValue | null) {
     ops.emit_progression_event_end(eventId, success, message, inner);
-}
\ No newline at end of file
+}
+/** Starts a progress bar for this macro, shown like the ones of uploads and instance creation.
+ *
+ * A macro can only drive one progress bar at a time. If the macro exits without calling `progressEnd`, the progress bar is closed for it.
+ */
+export function progressStart(title: string, total: number | null = null) {
+    ops.progress_start(title, total);
+}
+
+/** Advances the progress bar by `delta` */
+export function progressUpdate(delta: number, message: string) {
+    ops.progress_update(delta, message);
+}
+
+export function progressEnd(success: boolean, message: string | null = null) {
+    ops.progress_end(success, message);
+}
+
+/** Shows a notification to the user, such as the result of a backup, apart from the console output.
+ *
+ * At most 5 notifications are shown every 10 seconds, returns `false` if this one was dropped.
+ */
+export function notify(level: EventLevel, title: string, body: string): boolean {
+    return ops.notify(level, title, body);
+}
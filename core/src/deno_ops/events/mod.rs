@@ -1,30 +1,101 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::VecDeque,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use dashmap::{DashMap, DashSet};
 use deno_core::{
-    anyhow::{self, Context},
-    op, OpState,
+    anyhow::{self, bail},
+    op, OpState, Resource, ResourceId,
 };
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::warn;
 
 use crate::{
     event_broadcaster::{EventBroadcaster, PlayerChange, PlayerMessage},
     events::{
-        CausedBy, Event, InstanceEvent, ProgressionEndValue, ProgressionEventID,
-        ProgressionStartValue,
+        CausedBy, Event, EventLevel, EventQuery, InstanceEvent, MacroEvent, MacroEventInner,
+        ProgressionEndValue, ProgressionEventID, ProgressionStartValue,
     },
     macro_executor::MacroPID,
+    output_types::ClientEvent,
     traits::t_server::State,
     types::InstanceUuid,
 };
 
+/// Waits for the next event matching `filter`, failing if events were dropped
+/// because the receiver fell behind, so a macro never misses one silently
+async fn recv_matching(
+    rx: &mut Receiver<Event>,
+    filter: Option<&EventQuery>,
+) -> Result<Event, anyhow::Error> {
+    loop {
+        match rx.recv().await {
+            Ok(event) => {
+                if filter.map_or(true, |filter| filter.filter(ClientEvent::from(&event))) {
+                    return Ok(event);
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                bail!("Event subscription fell behind, {skipped} events were dropped")
+            }
+            Err(RecvError::Closed) => bail!("Event broadcaster closed"),
+        }
+    }
+}
+
 #[op]
-async fn next_event(state: Rc<RefCell<OpState>>) -> Result<Event, anyhow::Error> {
-    let rx = state.borrow().borrow::<EventBroadcaster>().clone();
-    let event = rx
-        .subscribe()
-        .recv()
-        .await
-        .context("Failed to receive event")?;
-    Ok(event)
+async fn next_event(
+    state: Rc<RefCell<OpState>>,
+    filter: Option<EventQuery>,
+) -> Result<Event, anyhow::Error> {
+    let mut rx = state.borrow().borrow::<EventBroadcaster>().subscribe();
+    recv_matching(&mut rx, filter.as_ref()).await
+}
+
+/// A receiver kept between calls, so no event is missed while the macro handles the previous one
+///
+/// Closed with the macro's resource table, which unsubscribes it
+struct EventSubscription {
+    rx: tokio::sync::Mutex<Receiver<Event>>,
+    filter: Option<EventQuery>,
+}
+
+impl Resource for EventSubscription {
+    fn name(&self) -> Cow<str> {
+        "eventSubscription".into()
+    }
+}
+
+#[op]
+fn subscribe_events(state: &mut OpState, filter: Option<EventQuery>) -> ResourceId {
+    let rx = state.borrow::<EventBroadcaster>().subscribe();
+    state.resource_table.add(EventSubscription {
+        rx: tokio::sync::Mutex::new(rx),
+        filter,
+    })
+}
+
+#[op]
+async fn next_subscribed_event(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+) -> Result<Event, anyhow::Error> {
+    let subscription = state
+        .borrow()
+        .resource_table
+        .get::<EventSubscription>(rid)?;
+    let mut rx = subscription.rx.lock().await;
+    recv_matching(&mut rx, subscription.filter.as_ref()).await
+}
+
+#[op]
+fn unsubscribe_events(state: &mut OpState, rid: ResourceId) -> Result<(), anyhow::Error> {
+    state.resource_table.close(rid)
 }
 
 #[op]
@@ -88,7 +159,10 @@ async fn next_instance_player_change(
 
 #[op]
 fn emit_detach(state: Rc<RefCell<OpState>>, macro_pid: MacroPID) {
-    let tx = state.borrow().borrow::<EventBroadcaster>().clone();
+    let state = state.borrow();
+    // recorded before the event is sent, so a caller attaching later doesn't wait for it
+    state.borrow::<MacroDetachedSet>().insert(macro_pid);
+    let tx = state.borrow::<EventBroadcaster>().clone();
     tx.send(Event::new_macro_detach_event(macro_pid));
 }
 
@@ -118,6 +192,7 @@ fn emit_state_change(
     tx.send(Event::new_instance_state_transition(
         instance_uuid,
         instance_name,
+        None,
         new_state,
     ))
 }
@@ -165,14 +240,147 @@ fn emit_progression_event_end(
     ));
 }
 
+/// Running macros that asked to run in the background through `emit_detach`
+pub type MacroDetachedSet = Arc<DashSet<MacroPID>>;
+
+/// The progression each macro is driving through the `progress_*` ops,
+/// the executor ends it if the macro exits without calling `progress_end`
+pub type MacroProgressionTable = Arc<DashMap<MacroPID, ProgressionEventID>>;
+
+struct MacroProgression {
+    macro_pid: MacroPID,
+    progression_table: MacroProgressionTable,
+}
+
+#[op]
+fn progress_start(
+    state: Rc<RefCell<OpState>>,
+    title: String,
+    total: Option<f64>,
+) -> Result<(), anyhow::Error> {
+    let state = state.borrow();
+    let tx = state.borrow::<EventBroadcaster>().clone();
+    let MacroProgression {
+        macro_pid,
+        progression_table,
+    } = state.borrow::<MacroProgression>();
+    if progression_table.contains_key(macro_pid) {
+        bail!("A progress is already running, end it with progress_end first");
+    }
+    let (event, event_id) = Event::new_progression_event_start(
+        title,
+        total,
+        None,
+        CausedBy::Macro {
+            macro_pid: *macro_pid,
+        },
+    );
+    progression_table.insert(*macro_pid, event_id);
+    tx.send(event);
+    Ok(())
+}
+
+#[op]
+fn progress_update(
+    state: Rc<RefCell<OpState>>,
+    delta: f64,
+    message: String,
+) -> Result<(), anyhow::Error> {
+    let state = state.borrow();
+    let tx = state.borrow::<EventBroadcaster>().clone();
+    let MacroProgression {
+        macro_pid,
+        progression_table,
+    } = state.borrow::<MacroProgression>();
+    let event_id = match progression_table.get(macro_pid) {
+        Some(event_id) => event_id.clone(),
+        None => bail!("No progress is running, start one with progress_start first"),
+    };
+    tx.send(Event::new_progression_event_update(
+        &event_id, message, delta,
+    ));
+    Ok(())
+}
+
+#[op]
+fn progress_end(
+    state: Rc<RefCell<OpState>>,
+    success: bool,
+    message: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let state = state.borrow();
+    let tx = state.borrow::<EventBroadcaster>().clone();
+    let MacroProgression {
+        macro_pid,
+        progression_table,
+    } = state.borrow::<MacroProgression>();
+    let (_, event_id) = match progression_table.remove(macro_pid) {
+        Some(entry) => entry,
+        None => bail!("No progress is running, start one with progress_start first"),
+    };
+    tx.send(Event::new_progression_event_end(
+        event_id, success, message, None,
+    ));
+    Ok(())
+}
+
+/// Notifications a macro can send within `NOTIFICATION_WINDOW`, further ones are dropped
+const NOTIFICATION_BURST: usize = 5;
+const NOTIFICATION_WINDOW: Duration = Duration::from_secs(10);
+
+struct MacroNotifier {
+    macro_pid: MacroPID,
+    instance_uuid: Option<InstanceUuid>,
+    /// When the notifications within the current window were sent, oldest first
+    recent: VecDeque<Instant>,
+}
+
+/// Returns whether the notification was sent, `false` if the macro sent too many recently
+#[op]
+fn notify(state: &mut OpState, level: EventLevel, title: String, body: String) -> bool {
+    let tx = state.borrow::<EventBroadcaster>().clone();
+    let notifier = state.borrow_mut::<MacroNotifier>();
+    while notifier
+        .recent
+        .front()
+        .map_or(false, |sent_at| sent_at.elapsed() >= NOTIFICATION_WINDOW)
+    {
+        notifier.recent.pop_front();
+    }
+    if notifier.recent.len() >= NOTIFICATION_BURST {
+        warn!(
+            "Dropping notification \"{}\" from {}, it sent too many",
+            title, notifier.macro_pid
+        );
+        return false;
+    }
+    notifier.recent.push_back(Instant::now());
+    tx.send(
+        MacroEvent {
+            instance_uuid: notifier.instance_uuid.clone(),
+            macro_pid: notifier.macro_pid,
+            macro_event_inner: MacroEventInner::Notification { level, title, body },
+        }
+        .into(),
+    );
+    true
+}
+
 pub fn register_all_event_ops(
     worker_options: &mut deno_runtime::worker::WorkerOptions,
     event_broadcaster: EventBroadcaster,
+    macro_pid: MacroPID,
+    instance_uuid: Option<InstanceUuid>,
+    progression_table: MacroProgressionTable,
+    detached_pids: MacroDetachedSet,
 ) {
     worker_options.extensions.push(
         deno_core::Extension::builder("event_ops")
             .ops(vec![
                 next_event::decl(),
+                subscribe_events::decl(),
+                next_subscribed_event::decl(),
+                unsubscribe_events::decl(),
                 emit_console_out::decl(),
                 emit_detach::decl(),
                 emit_state_change::decl(),
@@ -185,9 +393,23 @@ pub fn register_all_event_ops(
                 emit_progression_event_start::decl(),
                 emit_progression_event_update::decl(),
                 emit_progression_event_end::decl(),
+                progress_start::decl(),
+                progress_update::decl(),
+                progress_end::decl(),
+                notify::decl(),
             ])
             .state(|state| {
                 state.put(event_broadcaster);
+                state.put(detached_pids);
+                state.put(MacroProgression {
+                    macro_pid,
+                    progression_table,
+                });
+                state.put(MacroNotifier {
+                    macro_pid,
+                    instance_uuid,
+                    recent: VecDeque::new(),
+                });
             })
             .build(),
     );
@@ -0,0 +1,251 @@
+use std::{
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use deno_runtime::{
+    deno_fs::{FileSystem, FsDirEntry, FsFileType, OpenOptions},
+    deno_io::fs::{File, FsResult, FsStat},
+};
+
+/// Resolves the relative paths of a macro's file APIs against the macro's own working directory.
+///
+/// The process' working directory is shared by the whole core, so `Deno.chdir` only moves
+/// this one instead of changing it for every macro and the core itself.
+/// Permissions still check relative paths against the process' working directory,
+/// which only matters for macros spawned with restricted permissions
+#[derive(Debug)]
+pub struct MacroFs {
+    inner: Arc<dyn FileSystem>,
+    cwd: RwLock<PathBuf>,
+}
+
+impl MacroFs {
+    pub fn new(inner: Arc<dyn FileSystem>, cwd: PathBuf) -> Self {
+        Self {
+            inner,
+            cwd: RwLock::new(cwd),
+        }
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        if path.is_absolute() {
+            path.to_owned()
+        } else {
+            self.cwd.read().unwrap().join(path)
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl FileSystem for MacroFs {
+    fn cwd(&self) -> FsResult<PathBuf> {
+        Ok(self.cwd.read().unwrap().clone())
+    }
+
+    fn tmp_dir(&self) -> FsResult<PathBuf> {
+        self.inner.tmp_dir()
+    }
+
+    fn chdir(&self, path: &Path) -> FsResult<()> {
+        let path = self.inner.realpath_sync(&self.resolve(path))?;
+        if !self.inner.stat_sync(&path)?.is_directory {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("{} is not a directory", path.display()),
+            )
+            .into());
+        }
+        *self.cwd.write().unwrap() = path;
+        Ok(())
+    }
+
+    fn umask(&self, mask: Option<u32>) -> FsResult<u32> {
+        self.inner.umask(mask)
+    }
+
+    fn open_sync(&self, path: &Path, options: OpenOptions) -> FsResult<Rc<dyn File>> {
+        self.inner.open_sync(&self.resolve(path), options)
+    }
+
+    async fn open_async(&self, path: PathBuf, options: OpenOptions) -> FsResult<Rc<dyn File>> {
+        self.inner.open_async(self.resolve(&path), options).await
+    }
+
+    fn mkdir_sync(&self, path: &Path, recursive: bool, mode: u32) -> FsResult<()> {
+        self.inner.mkdir_sync(&self.resolve(path), recursive, mode)
+    }
+
+    async fn mkdir_async(&self, path: PathBuf, recursive: bool, mode: u32) -> FsResult<()> {
+        self.inner
+            .mkdir_async(self.resolve(&path), recursive, mode)
+            .await
+    }
+
+    fn chmod_sync(&self, path: &Path, mode: u32) -> FsResult<()> {
+        self.inner.chmod_sync(&self.resolve(path), mode)
+    }
+
+    async fn chmod_async(&self, path: PathBuf, mode: u32) -> FsResult<()> {
+        self.inner.chmod_async(self.resolve(&path), mode).await
+    }
+
+    fn chown_sync(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+        self.inner.chown_sync(&self.resolve(path), uid, gid)
+    }
+
+    async fn chown_async(&self, path: PathBuf, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+        self.inner.chown_async(self.resolve(&path), uid, gid).await
+    }
+
+    fn remove_sync(&self, path: &Path, recursive: bool) -> FsResult<()> {
+        self.inner.remove_sync(&self.resolve(path), recursive)
+    }
+
+    async fn remove_async(&self, path: PathBuf, recursive: bool) -> FsResult<()> {
+        self.inner
+            .remove_async(self.resolve(&path), recursive)
+            .await
+    }
+
+    fn copy_file_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+        self.inner
+            .copy_file_sync(&self.resolve(oldpath), &self.resolve(newpath))
+    }
+
+    async fn copy_file_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+        self.inner
+            .copy_file_async(self.resolve(&oldpath), self.resolve(&newpath))
+            .await
+    }
+
+    fn stat_sync(&self, path: &Path) -> FsResult<FsStat> {
+        self.inner.stat_sync(&self.resolve(path))
+    }
+
+    async fn stat_async(&self, path: PathBuf) -> FsResult<FsStat> {
+        self.inner.stat_async(self.resolve(&path)).await
+    }
+
+    fn lstat_sync(&self, path: &Path) -> FsResult<FsStat> {
+        self.inner.lstat_sync(&self.resolve(path))
+    }
+
+    async fn lstat_async(&self, path: PathBuf) -> FsResult<FsStat> {
+        self.inner.lstat_async(self.resolve(&path)).await
+    }
+
+    fn realpath_sync(&self, path: &Path) -> FsResult<PathBuf> {
+        self.inner.realpath_sync(&self.resolve(path))
+    }
+
+    async fn realpath_async(&self, path: PathBuf) -> FsResult<PathBuf> {
+        self.inner.realpath_async(self.resolve(&path)).await
+    }
+
+    fn read_dir_sync(&self, path: &Path) -> FsResult<Vec<FsDirEntry>> {
+        self.inner.read_dir_sync(&self.resolve(path))
+    }
+
+    async fn read_dir_async(&self, path: PathBuf) -> FsResult<Vec<FsDirEntry>> {
+        self.inner.read_dir_async(self.resolve(&path)).await
+    }
+
+    fn rename_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+        self.inner
+            .rename_sync(&self.resolve(oldpath), &self.resolve(newpath))
+    }
+
+    async fn rename_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+        self.inner
+            .rename_async(self.resolve(&oldpath), self.resolve(&newpath))
+            .await
+    }
+
+    fn link_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+        self.inner
+            .link_sync(&self.resolve(oldpath), &self.resolve(newpath))
+    }
+
+    async fn link_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+        self.inner
+            .link_async(self.resolve(&oldpath), self.resolve(&newpath))
+            .await
+    }
+
+    // a relative symlink target is relative to the link, not to the working directory
+    fn symlink_sync(
+        &self,
+        oldpath: &Path,
+        newpath: &Path,
+        file_type: Option<FsFileType>,
+    ) -> FsResult<()> {
+        self.inner
+            .symlink_sync(oldpath, &self.resolve(newpath), file_type)
+    }
+
+    async fn symlink_async(
+        &self,
+        oldpath: PathBuf,
+        newpath: PathBuf,
+        file_type: Option<FsFileType>,
+    ) -> FsResult<()> {
+        self.inner
+            .symlink_async(oldpath, self.resolve(&newpath), file_type)
+            .await
+    }
+
+    fn read_link_sync(&self, path: &Path) -> FsResult<PathBuf> {
+        self.inner.read_link_sync(&self.resolve(path))
+    }
+
+    async fn read_link_async(&self, path: PathBuf) -> FsResult<PathBuf> {
+        self.inner.read_link_async(self.resolve(&path)).await
+    }
+
+    fn truncate_sync(&self, path: &Path, len: u64) -> FsResult<()> {
+        self.inner.truncate_sync(&self.resolve(path), len)
+    }
+
+    async fn truncate_async(&self, path: PathBuf, len: u64) -> FsResult<()> {
+        self.inner.truncate_async(self.resolve(&path), len).await
+    }
+
+    fn utime_sync(
+        &self,
+        path: &Path,
+        atime_secs: i64,
+        atime_nanos: u32,
+        mtime_secs: i64,
+        mtime_nanos: u32,
+    ) -> FsResult<()> {
+        self.inner.utime_sync(
+            &self.resolve(path),
+            atime_secs,
+            atime_nanos,
+            mtime_secs,
+            mtime_nanos,
+        )
+    }
+
+    async fn utime_async(
+        &self,
+        path: PathBuf,
+        atime_secs: i64,
+        atime_nanos: u32,
+        mtime_secs: i64,
+        mtime_nanos: u32,
+    ) -> FsResult<()> {
+        self.inner
+            .utime_async(
+                self.resolve(&path),
+                atime_secs,
+                atime_nanos,
+                mtime_secs,
+                mtime_nanos,
+            )
+            .await
+    }
+}
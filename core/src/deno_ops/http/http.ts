@@ -0,0 +1,44 @@
+// deno-lint-ignore no-explicit-any
+declare const Deno: any;
+const core = Deno[Deno.internal].core;
+
+export type HttpRequest = {
+    /** `GET` by default */
+    method?: string;
+    url: string;
+    headers?: Record<string, string>;
+    body?: string;
+    /** 30 seconds by default, at most 5 minutes */
+    timeoutMs?: number;
+};
+
+export type HttpResponse = {
+    status: number;
+    /** Header names are lowercase, repeated headers are joined with `, ` */
+    headers: Record<string, string>;
+    body: string;
+};
+
+/**
+ * Sends an HTTP request the macro's net permission allows, without the rest of `fetch`.
+ *
+ * Rejects with `Deno.errors.PermissionDenied` if the host isn't allowed,
+ * with `Deno.errors.TimedOut` after `timeoutMs`, and if the response is over 16 MiB.
+ * Redirects aren't followed, the 3xx response is returned instead
+ *
+ * ```ts
+ * const { status, body } = await httpRequest({ url: "https://api.mojang.com/users/profiles/minecraft/Notch" });
+ * if (status === 200) {
+ *     const { id } = JSON.parse(body);
+ * }
+ * ```
+ */
+export function httpRequest(request: HttpRequest): Promise<HttpResponse> {
+    return core.opAsync("http_request", {
+        method: request.method,
+        url: request.url,
+        headers: request.headers,
+        body: request.body,
+        timeout_ms: request.timeoutMs,
+    });
+}
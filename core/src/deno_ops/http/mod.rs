@@ -0,0 +1,238 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc, time::Duration};
+
+use deno_core::{
+    anyhow::{self, bail, Context},
+    op,
+    url::Url,
+    OpState,
+};
+use deno_runtime::{deno_fetch::FetchPermissions, permissions::PermissionsContainer};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// Largest response body a macro can receive, larger responses fail instead of being cut
+const MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Redirects aren't followed, as the host they point to wasn't checked against the macro's permissions
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("Failed to build HTTP client")
+});
+
+#[derive(Deserialize)]
+struct HttpRequest {
+    /// `GET` if omitted
+    method: Option<String>,
+    url: String,
+    headers: Option<HashMap<String, String>>,
+    body: Option<String>,
+    /// Capped at `MAX_TIMEOUT`, `DEFAULT_TIMEOUT` if omitted
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct HttpResponse {
+    status: u16,
+    /// Repeated headers are joined with `, `
+    headers: HashMap<String, String>,
+    /// Decoded as UTF-8, invalid sequences are replaced
+    body: String,
+}
+
+/// A `Deno.errors.TimedOut` for the macro to catch
+fn timed_out(url: &Url, timeout: Duration) -> anyhow::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!("Request to {url} timed out after {}ms", timeout.as_millis()),
+    )
+    .into()
+}
+
+/// Parses the URL and checks it against the macro's `--allow-net` permission,
+/// failing with `Deno.errors.PermissionDenied` if the host isn't allowed
+fn check_url(permissions: &mut PermissionsContainer, url: &str) -> Result<Url, anyhow::Error> {
+    let url = Url::parse(url).context(format!("Invalid URL {url}"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!(
+            "Unsupported URL scheme {}, only http and https are allowed",
+            url.scheme()
+        );
+    }
+    permissions.check_net_url(&url, "http_request()")?;
+    Ok(url)
+}
+
+/// Sends a request to an already checked URL, failing if the body is larger than `max_size`
+async fn send_request(
+    url: Url,
+    request: HttpRequest,
+    max_size: usize,
+) -> Result<HttpResponse, anyhow::Error> {
+    let method = match request.method {
+        Some(method) => reqwest::Method::from_bytes(method.to_uppercase().as_bytes())
+            .context(format!("Invalid HTTP method {method}"))?,
+        None => reqwest::Method::GET,
+    };
+    let timeout = request
+        .timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_TIMEOUT)
+        .min(MAX_TIMEOUT);
+
+    let mut builder = CLIENT.request(method, url.clone());
+    for (name, value) in request.headers.iter().flatten() {
+        builder = builder.header(name, value);
+    }
+    if let Some(body) = request.body {
+        builder = builder.body(body);
+    }
+    let response = async {
+        let mut response = builder.send().await?;
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for (name, value) in response.headers() {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            headers
+                .entry(name.to_string())
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(&value);
+                })
+                .or_insert_with(|| value.into_owned());
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > max_size {
+                bail!("Response from {url} is larger than {max_size} bytes");
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(HttpResponse {
+            status: response.status().as_u16(),
+            headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    };
+    tokio::time::timeout(timeout, response)
+        .await
+        .map_err(|_| timed_out(&url, timeout))?
+}
+
+/// A request checked against the macro's `--allow-net` permission,
+/// failing with `Deno.errors.PermissionDenied` if the host isn't allowed
+#[op]
+async fn http_request(
+    state: Rc<RefCell<OpState>>,
+    request: HttpRequest,
+) -> Result<HttpResponse, anyhow::Error> {
+    let url = check_url(
+        state.borrow_mut().borrow_mut::<PermissionsContainer>(),
+        &request.url,
+    )?;
+    send_request(url, request, MAX_RESPONSE_SIZE).await
+}
+
+pub fn register_http_ops(worker_options: &mut deno_runtime::worker::WorkerOptions) {
+    worker_options.extensions.push(
+        deno_core::Extension::builder("http_ops")
+            .ops(vec![http_request::decl()])
+            .build(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use deno_runtime::permissions::{Permissions, PermissionsContainer, PermissionsOptions};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{check_url, send_request, HttpRequest};
+
+    fn permissions(allow_net: &[&str]) -> PermissionsContainer {
+        PermissionsContainer::new(
+            Permissions::from_options(&PermissionsOptions {
+                allow_net: Some(allow_net.iter().map(|host| host.to_string()).collect()),
+                ..Default::default()
+            })
+            .unwrap(),
+        )
+    }
+
+    fn get(url: &str) -> HttpRequest {
+        HttpRequest {
+            method: None,
+            url: url.to_string(),
+            headers: None,
+            body: None,
+            timeout_ms: Some(5000),
+        }
+    }
+
+    /// Answers a single request with `response`, returning the server's address
+    async fn serve_once(response: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream.write_all(&response).await;
+            let _ = stream.shutdown().await;
+        });
+        format!("http://{addr}/")
+    }
+
+    #[test]
+    fn test_check_url() {
+        let mut permissions = permissions(&["allowed.example"]);
+        assert!(check_url(&mut permissions, "https://allowed.example/path").is_ok());
+        let err = check_url(&mut permissions, "https://denied.example/").unwrap_err();
+        assert_eq!(
+            deno_runtime::errors::get_error_class_name(&err),
+            Some("PermissionDenied")
+        );
+        assert!(check_url(&mut permissions, "file:///etc/passwd").is_err());
+        assert!(check_url(&mut permissions, "not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_not_followed() {
+        let url = serve_once(
+            b"HTTP/1.1 302 Found\r\nLocation: http://denied.example/\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+        )
+        .await;
+        let response = send_request(url.parse().unwrap(), get(&url), 1024)
+            .await
+            .unwrap();
+        assert_eq!(response.status, 302);
+        assert_eq!(
+            response.headers.get("location").map(String::as_str),
+            Some("http://denied.example/")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_size_cap() {
+        let body = "a".repeat(1024);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        let url = serve_once(response.clone().into_bytes()).await;
+        let response_at_cap = send_request(url.parse().unwrap(), get(&url), 1024)
+            .await
+            .unwrap();
+        assert_eq!(response_at_cap.body, body);
+
+        let url = serve_once(response.into_bytes()).await;
+        assert!(send_request(url.parse().unwrap(), get(&url), 1023)
+            .await
+            .is_err());
+    }
+}
@@ -20,22 +20,42 @@ export function allInstanceUuids(): string[] {
     return ops.all_instances();
 }
 
-export function startInstance(block: boolean, instanceUuid: string): Promise<void> {
-    return core.opAsync("start_instance", instanceUuid, getCurrentTaskPid(), block);
+/**
+ * Rejects with `Deno.errors.TimedOut` after `timeoutMs`, 10 minutes by default.
+ * An instance still starting by then is killed
+ */
+export function startInstance(block: boolean, instanceUuid: string, timeoutMs?: number): Promise<void> {
+    return core.opAsync("start_instance", instanceUuid, getCurrentTaskPid(), block, timeoutMs ?? null);
 }
 
-export function stopInstance(block: boolean, instanceUuid: string): Promise<void> {
-    return core.opAsync("stop_instance", instanceUuid, getCurrentTaskPid(), block);
+/**
+ * Rejects with `Deno.errors.TimedOut` after `timeoutMs`, 10 minutes by default.
+ * The instance keeps stopping, use `killInstance` to stop it right away
+ */
+export function stopInstance(block: boolean, instanceUuid: string, timeoutMs?: number): Promise<void> {
+    return core.opAsync("stop_instance", instanceUuid, getCurrentTaskPid(), block, timeoutMs ?? null);
 }
 
-export function restartInstance(block: boolean, instanceUuid: string): Promise<void> {
-    return core.opAsync("restart_instance", instanceUuid, getCurrentTaskPid(), block);
+/**
+ * Rejects with `Deno.errors.TimedOut` after `timeoutMs`, 10 minutes by default.
+ * An instance still starting by then is killed
+ */
+export function restartInstance(block: boolean, instanceUuid: string, timeoutMs?: number): Promise<void> {
+    return core.opAsync("restart_instance", instanceUuid, getCurrentTaskPid(), block, timeoutMs ?? null);
 }
 
 export function killInstance(instanceUuid: string): Promise<void> {
     return core.opAsync("kill_instance", instanceUuid, getCurrentTaskPid());
 }
 
+/**
+ * Resolves once the instance is in `targetState`, rejects with `Deno.errors.TimedOut` after `timeoutMs`,
+ * 10 minutes by default. A macro bound to an instance can only wait on that instance
+ */
+export function waitForState(instanceUuid: string, targetState: InstanceState, timeoutMs?: number): Promise<void> {
+    return core.opAsync("wait_for_state", instanceUuid, targetState, timeoutMs ?? null);
+}
+
 export function getInstanceState(instanceUuid: string): Promise<InstanceState> {
     return core.opAsync("get_instance_state", instanceUuid);
 }
@@ -48,14 +68,30 @@ export function monitorInstance(instanceUuid: string): Promise<PerformanceReport
     return core.opAsync("monitor_instance", instanceUuid);
 }
 
+/**
+ * A macro bound to an instance can only read that instance
+ */
 export function getInstancePlayerCount(instanceUuid: string): Promise<number> {
     return core.opAsync("get_instance_player_count", instanceUuid);
 }
 
+/**
+ * A macro bound to an instance can only read that instance
+ */
 export function getInstanceMaxPlayers(instanceUuid: string): Promise<number> {
     return core.opAsync("get_instance_max_players", instanceUuid);
 }
 
+/**
+ * The change is persisted, a macro bound to an instance can only change that instance
+ */
+export function setInstanceMaxPlayers(instanceUuid: string, maxPlayers: number): Promise<void> {
+    return core.opAsync("set_instance_max_players", instanceUuid, maxPlayers);
+}
+
+/**
+ * A macro bound to an instance can only read that instance
+ */
 export function getInstancePlayerList(instanceUuid: string): Promise<Player[]> {
     return core.opAsync("get_instance_player_list", instanceUuid);
 }
@@ -112,6 +148,44 @@ export function sendRconCommand(command: string, instanceUuid: string): Promise<
     return core.opAsync("send_rcon_command", instanceUuid, command);
 }
 
-export function waitTillRconAvailable(instanceUuid: string): Promise<void> {
-    return core.opAsync("wait_till_rcon_available", instanceUuid);
-}
\ No newline at end of file
+/**
+ * Rejects with `Deno.errors.TimedOut` after `timeoutMs`, 10 minutes by default
+ */
+export function waitTillRconAvailable(instanceUuid: string, timeoutMs?: number): Promise<void> {
+    return core.opAsync("wait_till_rcon_available", instanceUuid, timeoutMs ?? null);
+}
+
+/**
+ * Reads a file of the instance this macro belongs to, `relativePath` can't leave the instance directory
+ */
+export function readInstanceFile(relativePath: string): Promise<string> {
+    return core.opAsync("read_instance_file", relativePath, getCurrentTaskPid());
+}
+
+/**
+ * Writes a file of the instance this macro belongs to, `relativePath` can't leave the instance directory
+ */
+export function writeInstanceFile(relativePath: string, content: string): Promise<void> {
+    return core.opAsync("write_instance_file", relativePath, content, getCurrentTaskPid());
+}
+
+/**
+ * Reads the metadata of the instance this macro belongs to
+ */
+export function getInstanceMetadata(): Promise<Record<string, unknown>> {
+    return core.opAsync("get_instance_metadata");
+}
+
+/**
+ * Sets a metadata value of the instance this macro belongs to, values are limited to 16 KiB
+ */
+export function setInstanceMetadata(key: string, value: unknown): Promise<void> {
+    return core.opAsync("set_instance_metadata", key, value);
+}
+
+/**
+ * Removes a metadata value of the instance this macro belongs to
+ */
+export function removeInstanceMetadata(key: string): Promise<void> {
+    return core.opAsync("remove_instance_metadata", key);
+}
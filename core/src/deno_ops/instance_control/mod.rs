@@ -1,22 +1,50 @@
+use std::cell::RefCell;
 use std::collections::HashSet;
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
 
 use deno_core::{
     anyhow::{self, bail, Context},
-    op,
+    op, OpState,
 };
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
 
 use crate::{
-    events::CausedBy,
+    events::{
+        new_fs_event, CausedBy, Event, EventInner, FSOperation, FSTarget, InstanceEvent,
+        InstanceEventInner,
+    },
+    instance_metadata::{get_metadata, remove_metadata, set_metadata, InstanceMetadata},
     macro_executor::MacroPID,
-    prelude::app_state,
+    prelude::{app_state, GameInstance},
     traits::{
         t_configurable::{Game, TConfigurable},
         t_player::{Player, TPlayerManagement},
         t_server::{MonitorReport, State, TServer},
     },
     types::InstanceUuid,
+    util::scoped_join_win_safe,
 };
 
+/// The instance the macro was spawned for, kept in the op state so a macro can't claim another one
+struct MacroInstance(Option<InstanceUuid>);
+
+/// Macros bound to an instance may only act on that instance, other macros on any instance
+fn check_instance_capability(
+    state: &Rc<RefCell<OpState>>,
+    instance_uuid: &InstanceUuid,
+) -> Result<(), anyhow::Error> {
+    match &state.borrow().borrow::<MacroInstance>().0 {
+        Some(macro_instance_uuid) if macro_instance_uuid != instance_uuid => {
+            bail!("This macro can only access its own instance")
+        }
+        _ => Ok(()),
+    }
+}
+
 #[op]
 fn instance_exists(instance_uuid: InstanceUuid) -> bool {
     app_state().instances.contains_key(&instance_uuid)
@@ -31,25 +59,79 @@ fn all_instances() -> Vec<InstanceUuid> {
         .collect()
 }
 
+/// Used when a macro doesn't pass a timeout to an op that waits on an instance
+const DEFAULT_INSTANCE_OP_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// A `Deno.errors.TimedOut` for the macro to catch
+fn timed_out(action: &str, timeout: Duration) -> anyhow::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!(
+            "Timed out after {}ms waiting for the instance to {}",
+            timeout.as_millis(),
+            action
+        ),
+    )
+    .into()
+}
+
+/// Runs `operation` on the core's runtime and waits at most `timeout_ms` for it
+///
+/// The operation keeps going if the op times out or the macro exits, so it isn't cancelled halfway.
+/// An instance still starting when the op times out is killed instead of being left half-started
+async fn run_with_timeout<F, Fut>(
+    instance_uuid: &InstanceUuid,
+    task_pid: MacroPID,
+    timeout_ms: Option<u64>,
+    action: &str,
+    operation: F,
+) -> Result<(), anyhow::Error>
+where
+    F: FnOnce(GameInstance, CausedBy) -> Fut,
+    Fut: Future<Output = Result<(), crate::Error>> + Send + 'static,
+{
+    let instance = app_state()
+        .instances
+        .get(instance_uuid)
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .value()
+        .clone();
+    let caused_by = CausedBy::Macro {
+        macro_pid: task_pid,
+    };
+    let timeout = timeout_ms.map_or(DEFAULT_INSTANCE_OP_TIMEOUT, Duration::from_millis);
+    let handle = tokio::task::spawn(operation(instance.clone(), caused_by.clone()));
+    match tokio::time::timeout(timeout, handle).await {
+        Ok(res) => res
+            .context(format!("Failed to {} instance", action))?
+            .context(format!("Failed to {} instance", action)),
+        Err(_) => {
+            if instance.state().await == State::Starting {
+                // fails if there's no process yet, but the instance is marked as stopped either way
+                if let Err(e) = instance.kill(caused_by).await {
+                    warn!("Failed to kill instance that timed out starting: {}", e);
+                }
+            }
+            Err(timed_out(action, timeout))
+        }
+    }
+}
+
 #[op]
 async fn start_instance(
     instance_uuid: InstanceUuid,
     task_pid: MacroPID,
     block: bool,
+    timeout_ms: Option<u64>,
 ) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
-    instance
-        .start(
-            CausedBy::Macro {
-                macro_pid: task_pid,
-            },
-            block,
-        )
-        .await
-        .context("Failed to start instance")
+    run_with_timeout(
+        &instance_uuid,
+        task_pid,
+        timeout_ms,
+        "start",
+        |instance, caused_by| async move { instance.start(caused_by, block).await },
+    )
+    .await
 }
 
 #[op]
@@ -57,20 +139,16 @@ async fn stop_instance(
     instance_uuid: InstanceUuid,
     task_pid: MacroPID,
     block: bool,
+    timeout_ms: Option<u64>,
 ) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
-    instance
-        .stop(
-            CausedBy::Macro {
-                macro_pid: task_pid,
-            },
-            block,
-        )
-        .await
-        .context("Failed to start instance")
+    run_with_timeout(
+        &instance_uuid,
+        task_pid,
+        timeout_ms,
+        "stop",
+        |instance, caused_by| async move { instance.stop(caused_by, block).await },
+    )
+    .await
 }
 
 #[op]
@@ -78,20 +156,16 @@ async fn restart_instance(
     instance_uuid: InstanceUuid,
     task_pid: MacroPID,
     block: bool,
+    timeout_ms: Option<u64>,
 ) -> Result<(), anyhow::Error> {
-    let instance = app_state()
-        .instances
-        .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
-    instance
-        .restart(
-            CausedBy::Macro {
-                macro_pid: task_pid,
-            },
-            block,
-        )
-        .await
-        .context("Failed to start instance")
+    run_with_timeout(
+        &instance_uuid,
+        task_pid,
+        timeout_ms,
+        "restart",
+        |instance, caused_by| async move { instance.restart(caused_by, block).await },
+    )
+    .await
 }
 
 #[op]
@@ -111,6 +185,62 @@ async fn kill_instance(
         .context("Failed to start instance")
 }
 
+/// Resolves once the instance is in `target_state`, which may already be the case
+///
+/// Macros bound to an instance may only wait on that instance
+#[op]
+async fn wait_for_state(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    target_state: State,
+    timeout_ms: Option<u64>,
+) -> Result<(), anyhow::Error> {
+    check_instance_capability(&state, &instance_uuid)?;
+    // subscribed before reading the state, so a transition in between isn't missed
+    let mut event_receiver = app_state().event_broadcaster.subscribe();
+    let instance = app_state()
+        .instances
+        .get(&instance_uuid)
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .clone();
+    if instance.state().await == target_state {
+        return Ok(());
+    }
+    let timeout = timeout_ms.map_or(DEFAULT_INSTANCE_OP_TIMEOUT, Duration::from_millis);
+    tokio::time::timeout(timeout, async {
+        loop {
+            match event_receiver.recv().await {
+                Ok(Event {
+                    event_inner:
+                        EventInner::InstanceEvent(InstanceEvent {
+                            instance_uuid: event_instance_uuid,
+                            instance_event_inner: InstanceEventInner::StateTransition { to, .. },
+                            ..
+                        }),
+                    ..
+                }) if event_instance_uuid == instance_uuid && to == target_state => {
+                    return Ok(());
+                }
+                Ok(_) => {}
+                // missed events may include the transition
+                Err(RecvError::Lagged(_)) => {
+                    if instance.state().await == target_state {
+                        return Ok(());
+                    }
+                }
+                Err(RecvError::Closed) => bail!("Event broadcaster closed"),
+            }
+        }
+    })
+    .await
+    .map_err(|_| {
+        timed_out(
+            &format!("become {}", target_state.to_string().to_lowercase()),
+            timeout,
+        )
+    })?
+}
+
 #[op]
 async fn get_instance_state(instance_uuid: InstanceUuid) -> Result<State, anyhow::Error> {
     let instance = app_state()
@@ -151,32 +281,68 @@ async fn monitor_instance(instance_uuid: InstanceUuid) -> Result<MonitorReport,
     Ok(instance.monitor().await)
 }
 
+/// Macros bound to an instance may only read that instance
 #[op]
-async fn get_instance_player_count(instance_uuid: InstanceUuid) -> Result<u32, anyhow::Error> {
+async fn get_instance_player_count(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<u32, anyhow::Error> {
+    check_instance_capability(&state, &instance_uuid)?;
+    // cloned out of the map so its shard isn't locked while a generic instance answers
     let instance = app_state()
         .instances
         .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .clone();
     Ok(instance.get_player_count().await?)
 }
 
+/// Macros bound to an instance may only read that instance
 #[op]
-async fn get_instance_max_players(instance_uuid: InstanceUuid) -> Result<u32, anyhow::Error> {
+async fn get_instance_max_players(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+) -> Result<u32, anyhow::Error> {
+    check_instance_capability(&state, &instance_uuid)?;
     let instance = app_state()
         .instances
         .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .clone();
     Ok(instance.get_max_player_count().await?)
 }
 
+/// Macros bound to an instance may only change that instance
+#[op]
+async fn set_instance_max_players(
+    state: Rc<RefCell<OpState>>,
+    instance_uuid: InstanceUuid,
+    max_players: u32,
+) -> Result<(), anyhow::Error> {
+    check_instance_capability(&state, &instance_uuid)?;
+    let instance = app_state()
+        .instances
+        .get(&instance_uuid)
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .clone();
+    instance
+        .set_max_player_count(max_players)
+        .await
+        .context("Failed to set max player count")
+}
+
+/// Macros bound to an instance may only read that instance
 #[op]
 async fn get_instance_player_list(
+    state: Rc<RefCell<OpState>>,
     instance_uuid: InstanceUuid,
 ) -> Result<HashSet<Player>, anyhow::Error> {
+    check_instance_capability(&state, &instance_uuid)?;
     let instance = app_state()
         .instances
         .get(&instance_uuid)
-        .ok_or(anyhow::anyhow!("Instance not found"))?;
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .clone();
     Ok(instance.get_player_list().await?)
 }
 
@@ -265,6 +431,7 @@ async fn set_instance_description(
 
 #[op]
 async fn set_instance_port(instance_uuid: InstanceUuid, port: u32) -> Result<(), anyhow::Error> {
+    app_state().port_manager.lock().await.check_in_range(port)?;
     let instance = app_state()
         .instances
         .get(&instance_uuid)
@@ -351,7 +518,10 @@ async fn send_rcon_command(
 }
 
 #[op]
-async fn wait_till_rcon_available(instance_uuid: InstanceUuid) -> Result<(), anyhow::Error> {
+async fn wait_till_rcon_available(
+    instance_uuid: InstanceUuid,
+    timeout_ms: Option<u64>,
+) -> Result<(), anyhow::Error> {
     let instance = app_state()
         .instances
         .get(&instance_uuid)
@@ -359,12 +529,14 @@ async fn wait_till_rcon_available(instance_uuid: InstanceUuid) -> Result<(), any
     match instance.value() {
         crate::prelude::GameInstance::MinecraftInstance(v) => {
             let rcon = v.get_rcon();
-            loop {
-                if rcon.lock().await.is_some() {
-                    break Ok(());
+            let timeout = timeout_ms.map_or(DEFAULT_INSTANCE_OP_TIMEOUT, Duration::from_millis);
+            tokio::time::timeout(timeout, async {
+                while rcon.lock().await.is_none() {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
                 }
-                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-            }
+            })
+            .await
+            .map_err(|_| timed_out("accept RCON connections", timeout))
         }
         crate::prelude::GameInstance::GenericInstance(_) => {
             bail!("RCON not available for atom instances")
@@ -372,7 +544,115 @@ async fn wait_till_rcon_available(instance_uuid: InstanceUuid) -> Result<(), any
     }
 }
 
-pub fn register_instance_control_ops(worker_options: &mut deno_runtime::worker::WorkerOptions) {
+/// The directory of the instance a macro belongs to
+async fn macro_instance_root(state: &Rc<RefCell<OpState>>) -> Result<PathBuf, anyhow::Error> {
+    let instance_uuid = match state.borrow().borrow::<MacroInstance>().0.clone() {
+        Some(instance_uuid) => instance_uuid,
+        None => bail!("This macro is not associated with an instance"),
+    };
+    Ok(app_state()
+        .instances
+        .get(&instance_uuid)
+        .ok_or(anyhow::anyhow!("Instance not found"))?
+        .path()
+        .await)
+}
+
+/// Resolve a path relative to the directory of the instance a macro belongs to
+///
+/// Unlike the HTTP handlers, which clamp `..` to the instance root,
+/// any path that would leave the instance directory is an error
+async fn resolve_macro_instance_path(
+    state: &Rc<RefCell<OpState>>,
+    relative_path: &str,
+) -> Result<PathBuf, anyhow::Error> {
+    if Path::new(relative_path)
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        bail!(
+            "Path {} must be relative to and stay within the instance directory",
+            relative_path
+        );
+    }
+    let root = macro_instance_root(state).await?;
+    Ok(scoped_join_win_safe(root, relative_path)?)
+}
+
+#[op]
+async fn read_instance_file(
+    state: Rc<RefCell<OpState>>,
+    relative_path: String,
+    task_pid: MacroPID,
+) -> Result<String, anyhow::Error> {
+    let path = resolve_macro_instance_path(&state, &relative_path).await?;
+    let content = tokio::fs::read_to_string(&path)
+        .await
+        .context(format!("Failed to read file {}", relative_path))?;
+    app_state().event_broadcaster.send(new_fs_event(
+        FSOperation::Read,
+        FSTarget::File(path),
+        CausedBy::Macro {
+            macro_pid: task_pid,
+        },
+    ));
+    Ok(content)
+}
+
+#[op]
+async fn write_instance_file(
+    state: Rc<RefCell<OpState>>,
+    relative_path: String,
+    content: String,
+    task_pid: MacroPID,
+) -> Result<(), anyhow::Error> {
+    let path = resolve_macro_instance_path(&state, &relative_path).await?;
+    let app_state = app_state();
+    let _lock = app_state.path_locks.lock(&path).await;
+    tokio::fs::write(&path, content)
+        .await
+        .context(format!("Failed to write file {}", relative_path))?;
+    app_state.event_broadcaster.send(new_fs_event(
+        FSOperation::Write,
+        FSTarget::File(path),
+        CausedBy::Macro {
+            macro_pid: task_pid,
+        },
+    ));
+    Ok(())
+}
+
+#[op]
+async fn get_instance_metadata(
+    state: Rc<RefCell<OpState>>,
+) -> Result<InstanceMetadata, anyhow::Error> {
+    let root = macro_instance_root(&state).await?;
+    Ok(get_metadata(&root).await?)
+}
+
+#[op]
+async fn set_instance_metadata(
+    state: Rc<RefCell<OpState>>,
+    key: String,
+    value: serde_json::Value,
+) -> Result<(), anyhow::Error> {
+    let root = macro_instance_root(&state).await?;
+    Ok(set_metadata(&app_state().path_locks, &root, key, value).await?)
+}
+
+#[op]
+async fn remove_instance_metadata(
+    state: Rc<RefCell<OpState>>,
+    key: String,
+) -> Result<(), anyhow::Error> {
+    let root = macro_instance_root(&state).await?;
+    Ok(remove_metadata(&app_state().path_locks, &root, &key).await?)
+}
+
+pub fn register_instance_control_ops(
+    worker_options: &mut deno_runtime::worker::WorkerOptions,
+    instance_uuid: Option<InstanceUuid>,
+) {
     worker_options.extensions.push(
         deno_core::Extension::builder("instance_control_ops")
             .ops(vec![
@@ -391,6 +671,7 @@ pub fn register_instance_control_ops(worker_options: &mut deno_runtime::worker::
                 set_instance_name::decl(),
                 set_instance_description::decl(),
                 set_instance_port::decl(),
+                set_instance_max_players::decl(),
                 set_instance_auto_start::decl(),
                 start_instance::decl(),
                 stop_instance::decl(),
@@ -398,11 +679,20 @@ pub fn register_instance_control_ops(worker_options: &mut deno_runtime::worker::
                 monitor_instance::decl(),
                 send_command::decl(),
                 kill_instance::decl(),
+                wait_for_state::decl(),
                 is_rcon_available::decl(),
                 try_send_rcon_command::decl(),
                 send_rcon_command::decl(),
                 wait_till_rcon_available::decl(),
+                read_instance_file::decl(),
+                write_instance_file::decl(),
+                get_instance_metadata::decl(),
+                set_instance_metadata::decl(),
+                remove_instance_metadata::decl(),
             ])
+            .state(|state| {
+                state.put(MacroInstance(instance_uuid));
+            })
             .build(),
     );
 }
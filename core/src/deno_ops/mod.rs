@@ -1,3 +1,5 @@
 pub mod events;
+pub mod fs;
+pub mod http;
 pub mod instance_control;
 pub mod prelude;
\ No newline at end of file
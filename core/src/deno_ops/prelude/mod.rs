@@ -1,16 +1,144 @@
-use deno_core::op;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
-use crate::prelude::VERSION;
+use deno_core::{anyhow, op, CancelFuture, CancelHandle, OpState};
+use once_cell::sync::Lazy;
+use tokio::sync::Mutex;
+
+use crate::handlers::system::{read_system_info, SystemInfo};
+use crate::prelude::{app_state, VERSION};
+
+/// Samples older than this are taken again, so polling macros don't each probe the system
+const SYSTEM_INFO_MAX_AGE: Duration = Duration::from_secs(5);
+
+static SYSTEM_INFO: Lazy<Mutex<Option<(Instant, SystemInfo)>>> = Lazy::new(|| Mutex::new(None));
+
+/// Structured arguments the macro was spawned with
+struct MacroArgs(Option<serde_json::Value>);
+
+#[op]
+fn get_macro_args(state: &mut OpState) -> Option<serde_json::Value> {
+    state.borrow::<MacroArgs>().0.clone()
+}
 
 #[op]
 fn get_lodestone_version() -> String {
     VERSION.with(|v| v.to_string())
 }
 
-pub fn register_prelude_ops(worker_options: &mut deno_runtime::worker::WorkerOptions) {
+/// Resolves after `ms` milliseconds without blocking the event loop,
+/// which keeps running the pending timer until it fires
+#[op]
+async fn sleep_ms(ms: u64) {
+    tokio::time::sleep(Duration::from_millis(ms)).await;
+}
+
+struct Timer {
+    deadline: tokio::time::Instant,
+    cancel: Rc<CancelHandle>,
+}
+
+/// Pending one-shot timers by the id the macro gave them.
+///
+/// They live in the macro's runtime, so aborting the macro drops them along with it
+#[derive(Default)]
+struct TimerTable(HashMap<String, Rc<Timer>>);
+
+#[op]
+fn set_timeout(state: &mut OpState, id: String, ms: u64) -> Result<(), anyhow::Error> {
+    let timers = state.borrow_mut::<TimerTable>();
+    if timers.0.contains_key(&id) {
+        anyhow::bail!("A timer with id {id} is already pending");
+    }
+    timers.0.insert(
+        id,
+        Rc::new(Timer {
+            deadline: tokio::time::Instant::now() + Duration::from_millis(ms),
+            cancel: CancelHandle::new_rc(),
+        }),
+    );
+    Ok(())
+}
+
+/// Resolves to `true` once the timer fires, or `false` if it was cleared first.
+///
+/// While this is pending the event loop keeps running
+#[op]
+async fn await_timer(state: Rc<RefCell<OpState>>, id: String) -> Result<bool, anyhow::Error> {
+    let timer = state
+        .borrow()
+        .borrow::<TimerTable>()
+        .0
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("No pending timer with id {id}"))?;
+    let fired = tokio::time::sleep_until(timer.deadline)
+        .or_cancel(timer.cancel.clone())
+        .await
+        .is_ok();
+    let mut state = state.borrow_mut();
+    let timers = state.borrow_mut::<TimerTable>();
+    // the id may have been reused by a new timer after this one was cleared
+    if timers
+        .0
+        .get(&id)
+        .map_or(false, |pending| Rc::ptr_eq(pending, &timer))
+    {
+        timers.0.remove(&id);
+    }
+    Ok(fired)
+}
+
+/// Returns whether a pending timer was cleared
+#[op]
+fn clear_timeout(state: &mut OpState, id: String) -> bool {
+    match state.borrow_mut::<TimerTable>().0.remove(&id) {
+        Some(timer) => {
+            timer.cancel.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// CPU, RAM and disk usage of the host, up to `SYSTEM_INFO_MAX_AGE` old
+#[op]
+async fn get_system_info() -> SystemInfo {
+    // held while sampling, so concurrent calls wait for the same sample
+    let mut cache = SYSTEM_INFO.lock().await;
+    if let Some((sampled_at, system_info)) = cache.as_ref() {
+        if sampled_at.elapsed() < SYSTEM_INFO_MAX_AGE {
+            return system_info.clone();
+        }
+    }
+    let system_info = read_system_info(&app_state().system).await;
+    *cache = Some((Instant::now(), system_info.clone()));
+    system_info
+}
+
+pub fn register_prelude_ops(
+    worker_options: &mut deno_runtime::worker::WorkerOptions,
+    structured_args: Option<serde_json::Value>,
+) {
     worker_options.extensions.push(
         deno_core::Extension::builder("prelude_ops")
-            .ops(vec![get_lodestone_version::decl()])
+            .ops(vec![
+                get_lodestone_version::decl(),
+                sleep_ms::decl(),
+                set_timeout::decl(),
+                await_timer::decl(),
+                clear_timeout::decl(),
+                get_system_info::decl(),
+                get_macro_args::decl(),
+            ])
+            .state(|state| {
+                state.put(MacroArgs(structured_args));
+                state.put(TimerTable::default());
+            })
             .build(),
     );
 }
@@ -1,8 +1,12 @@
 import { TaskPID } from "../../../deno_bindings/TaskPID.ts";
+import { SystemInfo } from "../../../deno_bindings/SystemInfo.ts";
+
+export type { SystemInfo };
 
 
 declare const __macro_pid: TaskPID;
 declare const __instance_uuid: string | null;
+declare const __cwd: string;
 
 // deno-lint-ignore no-explicit-any
 declare const Deno: any;
@@ -17,6 +21,101 @@ export function getCurrentInstanceUUID(): string | null {
     return __instance_uuid;
 }
 
+/**
+ * The directory the macro was started in, usually its instance's directory.
+ *
+ * Relative paths given to Deno's file APIs resolve from it, e.g. `Deno.readTextFile("server.properties")`.
+ * Unlike `Deno.cwd()`, this doesn't change when the macro calls `Deno.chdir`
+ */
+export function cwd(): string {
+    return __cwd;
+}
+
+/**
+ * The structured arguments the macro was started with, `null` if it was only given string arguments.
+ *
+ * ```ts
+ * const { target_ram } = getMacroArgs<{ target_ram: number }>() ?? { target_ram: 2048 };
+ * ```
+ */
+export function getMacroArgs<T = Record<string, unknown>>(): T | null {
+    return ops.get_macro_args();
+}
+
 export function lodestoneVersion(): string {
     return ops.get_lodestone_version();
-}
\ No newline at end of file
+}
+
+/**
+ * Waits for `ms` milliseconds, use it instead of looping until some time has passed
+ *
+ * ```ts
+ * while (true) {
+ *     await sleep(1000);
+ *     // runs about once a second without keeping a core busy
+ * }
+ * ```
+ */
+export function sleep(ms: number): Promise<void> {
+    return core.opAsync("sleep_ms", ms);
+}
+// started as soon as a timer is set, so the pending op keeps the macro alive until it fires,
+// settled ones are kept until awaited so awaiting a timer after it fired still resolves
+const pendingTimers = new Map<string, Promise<boolean>>();
+
+/**
+ * Starts a one-shot timer that fires after `ms` milliseconds, wait for it with `awaitTimer`.
+ *
+ * The timer runs on the host while the macro awaits other things, and a macro with
+ * a pending timer keeps running until it fires or is cleared
+ *
+ * ```ts
+ * setTimer("warn-5m", 5 * 60 * 1000);
+ * setTimer("warn-1m", 9 * 60 * 1000);
+ * setTimer("restart", 10 * 60 * 1000);
+ * // ... other work ...
+ * await awaitTimer("warn-5m");
+ * ```
+ */
+export function setTimer(id: string, ms: number): void {
+    ops.set_timeout(id, ms);
+    pendingTimers.set(id, core.opAsync("await_timer", id));
+}
+
+/**
+ * Resolves to `true` once the timer fires, or `false` if it's cleared with `clearTimer` meanwhile.
+ *
+ * A timer can be awaited once, its id can then be used for a new timer
+ */
+export function awaitTimer(id: string): Promise<boolean> {
+    const timer = pendingTimers.get(id);
+    if (timer === undefined) {
+        return Promise.reject(new Error(`No timer with id ${id}`));
+    }
+    pendingTimers.delete(id);
+    return timer;
+}
+
+/**
+ * Cancels a pending timer, returning whether there was one
+ */
+export function clearTimer(id: string): boolean {
+    pendingTimers.delete(id);
+    return ops.clear_timeout(id);
+}
+
+/**
+ * CPU, RAM and disk usage of the host.
+ *
+ * Samples are shared between macros and can be up to 5 seconds old, so polling this is cheap
+ *
+ * ```ts
+ * const { ram } = await systemInfo();
+ * if (ram.free < 2 * 1024 * 1024 * 1024) {
+ *     // not enough memory for another server
+ * }
+ * ```
+ */
+export function systemInfo(): Promise<SystemInfo> {
+    return core.opAsync("get_system_info");
+}
@@ -9,6 +9,8 @@ use serde_json::json;
 use thiserror::Error;
 use ts_rs::TS;
 
+use crate::prelude::try_lodestone_path;
+
 #[derive(Debug, Clone, Deserialize, Serialize, TS)]
 #[ts(export)]
 pub enum ErrorKind {
@@ -17,6 +19,14 @@ pub enum ErrorKind {
     BadRequest,
     PermissionDenied,
     Unauthorized,
+    TooManyRequests,
+    Conflict,
+    InsufficientStorage,
+    PayloadTooLarge,
+    /// The Minecraft EULA has to be accepted before the instance can start
+    EulaNotAccepted,
+    /// A download didn't match the checksum it was published with
+    ChecksumMismatch,
     Internal,
 }
 
@@ -35,11 +45,94 @@ impl Display for ErrorKind {
             ErrorKind::BadRequest => write!(f, "Bad Request"),
             ErrorKind::PermissionDenied => write!(f, "Permission Denied"),
             ErrorKind::Unauthorized => write!(f, "Unauthorized"),
+            ErrorKind::TooManyRequests => write!(f, "Too Many Requests"),
+            ErrorKind::Conflict => write!(f, "Conflict"),
+            ErrorKind::InsufficientStorage => write!(f, "Insufficient Storage"),
+            ErrorKind::PayloadTooLarge => write!(f, "Payload Too Large"),
+            ErrorKind::EulaNotAccepted => write!(f, "EULA Not Accepted"),
+            ErrorKind::ChecksumMismatch => write!(f, "Checksum Mismatch"),
             ErrorKind::Internal => write!(f, "Internal Error"),
         }
     }
 }
 
+impl ErrorKind {
+    /// Stable identifier for clients to match on, unlike the `Display` text
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::UnsupportedOperation => "unsupported_operation",
+            ErrorKind::BadRequest => "bad_request",
+            ErrorKind::PermissionDenied => "permission_denied",
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::TooManyRequests => "too_many_requests",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::InsufficientStorage => "insufficient_storage",
+            ErrorKind::PayloadTooLarge => "payload_too_large",
+            ErrorKind::EulaNotAccepted => "eula_not_accepted",
+            ErrorKind::ChecksumMismatch => "checksum_mismatch",
+            ErrorKind::Internal => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::UnsupportedOperation => StatusCode::NOT_IMPLEMENTED,
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorKind::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorKind::EulaNotAccepted => StatusCode::PRECONDITION_FAILED,
+            ErrorKind::ChecksumMismatch => StatusCode::BAD_GATEWAY,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The `error` field of an error response
+#[derive(Debug, Serialize, TS)]
+#[ts(export)]
+pub struct ErrorBody {
+    /// One of the codes returned by `ErrorKind::code`
+    pub code: String,
+    pub message: String,
+    /// Underlying causes, outermost first, left out if there are none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Vec<String>>,
+}
+
+/// Hides where the core and its user live on the host from error messages
+fn redact_paths(message: String) -> String {
+    let mut message = message;
+    let paths = [
+        (try_lodestone_path().cloned(), "<lodestone>"),
+        (home::home_dir(), "~"),
+    ];
+    for (path, replacement) in paths {
+        if let Some(path) = path {
+            let path = path.to_string_lossy();
+            // a home directory of `/` would otherwise replace every separator
+            if path.len() > 1 {
+                message = message.replace(path.as_ref(), replacement);
+            }
+        }
+    }
+    message
+}
+
+impl Error {
+    fn causes(&self) -> Vec<String> {
+        self.source
+            .chain()
+            .map(|cause| redact_paths(cause.to_string()))
+            .collect()
+    }
+}
+
 impl Serialize for Error {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -47,8 +140,7 @@ impl Serialize for Error {
     {
         let mut state = serializer.serialize_struct("Error", 2)?;
         state.serialize_field("kind", &self.kind)?;
-        let vec: Vec<String> = self.source.chain().map(|cause| cause.to_string()).collect();
-        state.serialize_field("causes", &vec)?;
+        state.serialize_field("causes", &self.causes())?;
         state.end()
     }
 }
@@ -63,20 +155,52 @@ fn test_error_serialization() {
     assert_eq!(json, r#"{"kind":"NotFound","causes":["Test"]}"#);
 }
 
+impl Error {
+    pub fn body(&self) -> ErrorBody {
+        let mut causes = self.causes().into_iter();
+        let message = causes.next().unwrap_or_else(|| self.kind.to_string());
+        let details: Vec<String> = causes.collect();
+        ErrorBody {
+            code: self.kind.code().to_string(),
+            message,
+            details: if details.is_empty() {
+                None
+            } else {
+                Some(details)
+            },
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        let status = match self.kind {
-            ErrorKind::NotFound => StatusCode::NOT_FOUND,
-            ErrorKind::UnsupportedOperation => StatusCode::NOT_IMPLEMENTED,
-            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
-            ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
-            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
-            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
-        };
-        (status, json!(self).to_string()).into_response()
+        // `kind` and `causes` are kept for clients that predate the `error` envelope
+        let body = json!({
+            "kind": self.kind,
+            "causes": self.causes(),
+            "error": self.body(),
+        });
+        (self.kind.status(), body.to_string()).into_response()
     }
 }
 
+#[test]
+fn test_error_body() {
+    let error = Error::from(Report::msg("Inner").wrap_err("Outer"));
+    assert_eq!(
+        serde_json::to_string(&error.body()).unwrap(),
+        r#"{"code":"internal","message":"Outer","details":["Inner"]}"#
+    );
+    let error = Error {
+        kind: ErrorKind::NotFound,
+        source: Report::msg("Test"),
+    };
+    assert_eq!(
+        serde_json::to_string(&error.body()).unwrap(),
+        r#"{"code":"not_found","message":"Test"}"#
+    );
+}
+
 impl From<Report> for Error {
     fn from(source: Report) -> Self {
         // try downcasting to a known error
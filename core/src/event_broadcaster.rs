@@ -78,7 +78,8 @@ impl EventBroadcaster {
     pub async fn next_instance_state_change(&self, instance_uuid: &InstanceUuid) -> State {
         loop {
             let instance_event = self.next_instance_event(instance_uuid).await;
-            if let InstanceEventInner::StateTransition { to } = instance_event.instance_event_inner
+            if let InstanceEventInner::StateTransition { to, .. } =
+                instance_event.instance_event_inner
             {
                 return to;
             }
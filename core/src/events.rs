@@ -7,6 +7,7 @@ use ts_rs::TS;
 
 use crate::{
     auth::{permission::UserPermission, user_id::UserId},
+    macro_daemon::DaemonState,
     macro_executor::MacroPID,
     output_types::ClientEvent,
     traits::{t_macro::ExitStatus, t_player::Player, t_server::State, InstanceInfo},
@@ -93,6 +94,8 @@ impl EventQuery {
 #[enum_kind(InstanceEventKind, derive(Serialize, Deserialize, TS))]
 pub enum InstanceEventInner {
     StateTransition {
+        /// `None` if the previous state isn't known, like for states reported by generic instances
+        from: Option<State>,
         to: State,
     },
     InstanceWarning {
@@ -120,6 +123,20 @@ pub enum InstanceEventInner {
         player: String,
         player_message: String,
     },
+    /// The instance is being stopped as no players were online for `idle_minutes`
+    IdleShutdown {
+        idle_minutes: u32,
+    },
+    /// The server process exited with a crash report, `path` is relative to the instance
+    CrashReport {
+        summary: String,
+        path: String,
+    },
+    /// A macro daemon of the instance changed state, see `macro_daemon`
+    DaemonStateChanged {
+        name: String,
+        state: DaemonState,
+    },
 }
 
 impl AsRef<InstanceEventInner> for InstanceEventInner {
@@ -151,6 +168,9 @@ pub enum UserEventInner {
     PermissionChanged {
         new_permissions: Box<UserPermission>,
     },
+    ReadOnlyChanged {
+        is_read_only: bool,
+    },
 }
 
 impl AsRef<UserEventInner> for UserEventInner {
@@ -159,6 +179,34 @@ impl AsRef<UserEventInner> for UserEventInner {
     }
 }
 
+/// Changes to the core itself, visible to every user
+#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct CoreEvent {
+    pub core_event_inner: CoreEventInner,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum CoreEventInner {
+    CoreNameChanged {
+        name: String,
+    },
+    /// Sent by `PUT /settings`, clients refetch the settings they show
+    SettingsChanged {
+        fields: Vec<String>,
+    },
+    /// A newer release of the core was found, sent once per release
+    UpdateAvailable {
+        #[ts(type = "string")]
+        current: semver::Version,
+        #[ts(type = "string")]
+        latest: semver::Version,
+        url: String,
+    },
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
 #[ts(export)]
 pub struct UserEvent {
@@ -170,12 +218,20 @@ pub struct UserEvent {
 #[ts(export)]
 #[serde(tag = "type")]
 pub enum MacroEventInner {
+    /// Macro is waiting for a slot as the maximum number of concurrent macros are running
+    Queued,
     Started,
     /// Macro requests to be detached, useful for macros that run in the background such as prelaunch script
     Detach,
     Stopped {
         exit_status: ExitStatus,
     },
+    /// A message for the user, shown as a toast rather than in the console
+    Notification {
+        level: EventLevel,
+        title: String,
+        body: String,
+    },
 }
 #[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
 #[ts(export)]
@@ -223,6 +279,10 @@ pub enum ProgressionStartValue {
     InstanceDelete {
         instance_uuid: InstanceUuid,
     },
+    /// Progress of a starting instance, read from its output
+    InstanceStart {
+        instance_uuid: InstanceUuid,
+    },
 }
 
 // the backend will keep exactly 1 copy of ProgressionStart, and 1 copy of ProgressionUpdate OR ProgressionEnd
@@ -250,7 +310,13 @@ pub enum ProgressionEventInner {
 pub enum FSOperation {
     Read,
     Write,
-    Move { source: PathBuf },
+    Move {
+        source: PathBuf,
+    },
+    /// Several files moved at once, the target is the directory they were moved in
+    MoveMany {
+        sources: Vec<PathBuf>,
+    },
     Create,
     Delete,
     Upload,
@@ -280,7 +346,7 @@ pub fn new_fs_event(operation: FSOperation, target: FSTarget, caused_by: CausedB
     }
 }
 
-#[derive(Serialize, Deserialize, TS)]
+#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq, Eq, Hash)]
 #[serde(transparent)]
 #[ts(export)]
 pub struct ProgressionEventID(Snowflake);
@@ -312,6 +378,7 @@ pub enum EventInner {
     MacroEvent(MacroEvent),
     FSEvent(FSEvent),
     ProgressionEvent(ProgressionEvent),
+    CoreEvent(CoreEvent),
 }
 
 impl AsRef<EventInner> for EventInner {
@@ -486,6 +553,7 @@ impl Event {
     pub fn new_instance_state_transition(
         instance_uuid: InstanceUuid,
         instance_name: String,
+        old_state: Option<State>,
         new_state: State,
     ) -> Event {
         Event {
@@ -494,7 +562,10 @@ impl Event {
             event_inner: EventInner::InstanceEvent(InstanceEvent {
                 instance_uuid,
                 instance_name,
-                instance_event_inner: InstanceEventInner::StateTransition { to: new_state },
+                instance_event_inner: InstanceEventInner::StateTransition {
+                    from: old_state,
+                    to: new_state,
+                },
             }),
             caused_by: CausedBy::System,
         }
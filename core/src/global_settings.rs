@@ -1,18 +1,80 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use color_eyre::eyre::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use color_eyre::eyre::{eyre, Context};
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
+use tracing::error;
 use ts_rs::TS;
 
-use crate::{error::Error, event_broadcaster::EventBroadcaster};
+use crate::{
+    auth::permission::InstancePermissionTemplate,
+    cors::CorsSettings,
+    error::{Error, ErrorKind},
+    event_broadcaster::EventBroadcaster,
+    macro_executor::{
+        set_unrestricted_module_hosts, MacroLimitPolicy, DEFAULT_EXIT_STATUS_RETENTION,
+        DEFAULT_MACRO_MAX_HEAP_MB, DEFAULT_MAX_CONCURRENT_MACROS,
+    },
+    orphans::OrphanPolicy,
+    port_manager::PortRange,
+    prelude::lodestone_path,
+    tls::TlsSettings,
+    upload_filter::UploadRule,
+    util::deserialize_some,
+};
 
 #[derive(Serialize, Deserialize, Clone, TS)]
+#[serde(default)]
 #[ts(export)]
 pub struct GlobalSettingsData {
     pub core_name: String,
     pub safe_mode: bool,
     pub domain: Option<String>,
+    /// Whether deleted files are moved to the trash instead of being removed, off by default
+    pub soft_delete: bool,
+    pub trash_retention_days: u32,
+    /// Seconds each instance is given to stop when the core shuts down before it is killed
+    pub instance_stop_timeout: u32,
+    /// What happens to server processes left running by a crash of the core when it starts again
+    pub orphan_policy: OrphanPolicy,
+    /// Takes effect after the core restarts
+    pub max_concurrent_macros: u32,
+    /// Whether macros over `max_concurrent_macros` wait for a slot or fail,
+    /// takes effect after the core restarts
+    pub macro_limit_policy: MacroLimitPolicy,
+    /// How many finished macros keep their exit status for the macro history, oldest are forgotten first
+    pub macro_history_size: u32,
+    /// Caps the V8 heap of each instance macro in MiB, applies to macros started afterwards
+    pub macro_max_heap_mb: u32,
+    /// Restrictions on what can be uploaded into specific directories, empty allows everything
+    pub upload_rules: Vec<UploadRule>,
+    /// Lets macros import remote modules from any host, including private and loopback addresses.
+    /// Only meant for trusted single-user setups
+    pub unrestricted_macro_imports: bool,
+    /// Largest file in bytes that can be written in a single request, 64 MiB by default.
+    /// Uploads are streamed to disk and aren't limited by this
+    pub max_write_size: u64,
+    /// Threads compressing a directory zipped for download, 1 zips it sequentially.
+    /// Small directories are always zipped sequentially
+    pub zip_workers: u32,
+    /// Takes effect after the core restarts
+    pub cors: CorsSettings,
+    /// Hours between checks for a newer release of the core, `None` disables the checks
+    pub update_check_interval_hours: Option<u32>,
+    /// Ports new instances are given and may be moved to, `None` allows any port
+    pub port_range: Option<PortRange>,
+    /// Where instances are stored, `None` uses `instances` in the lodestone path.
+    /// `LODESTONE_INSTANCES_PATH` takes precedence. Takes effect after the core restarts,
+    /// instances aren't moved: stop the core, move their directories to the new path, then start it
+    #[ts(type = "string | null")]
+    pub instances_path: Option<PathBuf>,
+    /// Serves the API over HTTPS, `None` uses `tls/cert.pem` and `tls/key.pem` in the lodestone path
+    /// if both exist and plain HTTP otherwise. Enabling or disabling TLS takes effect after the core
+    /// restarts, changed certificates are reloaded while it runs
+    pub tls: Option<TlsSettings>,
+    /// Granted on every new instance unless the creation request brings its own template
+    pub instance_permission_template: InstancePermissionTemplate,
 }
 
 impl Default for GlobalSettingsData {
@@ -21,10 +83,388 @@ impl Default for GlobalSettingsData {
             core_name: format!("{}'s Lodestone Core", whoami::realname()),
             safe_mode: true,
             domain: None,
+            soft_delete: false,
+            trash_retention_days: 30,
+            instance_stop_timeout: 60,
+            orphan_policy: OrphanPolicy::default(),
+            max_concurrent_macros: DEFAULT_MAX_CONCURRENT_MACROS as u32,
+            macro_limit_policy: MacroLimitPolicy::default(),
+            macro_history_size: DEFAULT_EXIT_STATUS_RETENTION as u32,
+            macro_max_heap_mb: DEFAULT_MACRO_MAX_HEAP_MB as u32,
+            upload_rules: Vec::new(),
+            unrestricted_macro_imports: false,
+            max_write_size: 64 * 1024 * 1024,
+            zip_workers: 4,
+            cors: CorsSettings::default(),
+            update_check_interval_hours: Some(24),
+            port_range: None,
+            instances_path: None,
+            tls: None,
+            instance_permission_template: InstancePermissionTemplate::default(),
         }
     }
 }
 
+/// A partial update of `GlobalSettingsData`, fields left out are unchanged
+#[derive(Debug, Clone, Default, Deserialize, TS)]
+#[ts(export)]
+pub struct GlobalSettingsUpdate {
+    #[serde(default)]
+    pub core_name: Option<String>,
+    #[serde(default)]
+    pub safe_mode: Option<bool>,
+    /// `null` or an empty string removes the domain
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[ts(type = "string | null", optional)]
+    pub domain: Option<Option<String>>,
+    #[serde(default)]
+    pub soft_delete: Option<bool>,
+    #[serde(default)]
+    pub trash_retention_days: Option<u32>,
+    #[serde(default)]
+    pub instance_stop_timeout: Option<u32>,
+    #[serde(default)]
+    pub orphan_policy: Option<OrphanPolicy>,
+    #[serde(default)]
+    pub max_concurrent_macros: Option<u32>,
+    #[serde(default)]
+    pub macro_limit_policy: Option<MacroLimitPolicy>,
+    #[serde(default)]
+    pub macro_history_size: Option<u32>,
+    #[serde(default)]
+    pub macro_max_heap_mb: Option<u32>,
+    #[serde(default)]
+    pub upload_rules: Option<Vec<UploadRule>>,
+    #[serde(default)]
+    pub unrestricted_macro_imports: Option<bool>,
+    #[serde(default)]
+    pub max_write_size: Option<u64>,
+    #[serde(default)]
+    pub zip_workers: Option<u32>,
+    #[serde(default)]
+    pub cors: Option<CorsSettings>,
+    /// `null` disables update checks
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[ts(type = "number | null", optional)]
+    pub update_check_interval_hours: Option<Option<u32>>,
+    /// `null` lets instances use any port
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[ts(optional)]
+    pub port_range: Option<Option<PortRange>>,
+    /// `null` goes back to the default directory
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[ts(type = "string | null", optional)]
+    pub instances_path: Option<Option<PathBuf>>,
+    /// `null` goes back to the default certificate paths
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[ts(optional)]
+    pub tls: Option<Option<TlsSettings>>,
+    #[serde(default)]
+    pub instance_permission_template: Option<InstancePermissionTemplate>,
+}
+
+impl GlobalSettingsData {
+    /// Checks settings that are only invalid together, returning one `field: reason` message
+    /// per conflict, blamed on the field that depends on the other
+    fn validate_combination(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.soft_delete && self.trash_retention_days == 0 {
+            errors.push(
+                "trash_retention_days: Must be at least a day while soft delete is on".to_string(),
+            );
+        }
+        errors
+    }
+}
+
+impl GlobalSettingsUpdate {
+    /// The names of the fields the update sets
+    pub fn fields(&self) -> Vec<String> {
+        [
+            ("core_name", self.core_name.is_some()),
+            ("safe_mode", self.safe_mode.is_some()),
+            ("domain", self.domain.is_some()),
+            ("soft_delete", self.soft_delete.is_some()),
+            ("trash_retention_days", self.trash_retention_days.is_some()),
+            (
+                "instance_stop_timeout",
+                self.instance_stop_timeout.is_some(),
+            ),
+            ("orphan_policy", self.orphan_policy.is_some()),
+            (
+                "max_concurrent_macros",
+                self.max_concurrent_macros.is_some(),
+            ),
+            ("macro_limit_policy", self.macro_limit_policy.is_some()),
+            ("macro_history_size", self.macro_history_size.is_some()),
+            ("macro_max_heap_mb", self.macro_max_heap_mb.is_some()),
+            ("upload_rules", self.upload_rules.is_some()),
+            (
+                "unrestricted_macro_imports",
+                self.unrestricted_macro_imports.is_some(),
+            ),
+            ("max_write_size", self.max_write_size.is_some()),
+            ("zip_workers", self.zip_workers.is_some()),
+            ("cors", self.cors.is_some()),
+            (
+                "update_check_interval_hours",
+                self.update_check_interval_hours.is_some(),
+            ),
+            ("port_range", self.port_range.is_some()),
+            ("instances_path", self.instances_path.is_some()),
+            ("tls", self.tls.is_some()),
+            (
+                "instance_permission_template",
+                self.instance_permission_template.is_some(),
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, is_set)| *is_set)
+        .map(|(field, _)| field.to_string())
+        .collect()
+    }
+
+    /// Checks every field the update sets the same way setting it alone does,
+    /// returning one `field: reason` message per invalid field
+    async fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut check = |field: &str, result: Result<(), Error>| {
+            if let Err(e) = result {
+                errors.push(format!("{field}: {}", e.source));
+            }
+        };
+        let at_least_one = |value: Option<u64>, message: &str| match value {
+            Some(0) => Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(message.to_string()),
+            }),
+            _ => Ok(()),
+        };
+        if let Some(name) = &self.core_name {
+            check("core_name", validate_core_name(name).map(|_| ()));
+        }
+        if let Some(Some(domain)) = &self.domain {
+            check("domain", validate_domain(domain));
+        }
+        check(
+            "max_concurrent_macros",
+            at_least_one(
+                self.max_concurrent_macros.map(u64::from),
+                "At least one macro must be allowed to run",
+            ),
+        );
+        check(
+            "macro_history_size",
+            at_least_one(
+                self.macro_history_size.map(u64::from),
+                "At least one finished macro must be kept",
+            ),
+        );
+        if let Some(max_heap_mb) = self.macro_max_heap_mb {
+            check("macro_max_heap_mb", validate_macro_max_heap_mb(max_heap_mb));
+        }
+        check(
+            "max_write_size",
+            at_least_one(
+                self.max_write_size,
+                "Max write size must be at least a byte",
+            ),
+        );
+        check(
+            "zip_workers",
+            at_least_one(
+                self.zip_workers.map(u64::from),
+                "At least one zip worker is needed",
+            ),
+        );
+        check(
+            "update_check_interval_hours",
+            at_least_one(
+                self.update_check_interval_hours.flatten().map(u64::from),
+                "Update check interval must be at least an hour",
+            ),
+        );
+        if let Some(rules) = &self.upload_rules {
+            check(
+                "upload_rules",
+                rules.iter().try_for_each(|rule| rule.validate()),
+            );
+        }
+        if let Some(cors) = &self.cors {
+            check("cors", cors.validate());
+        }
+        if let Some(Some(range)) = &self.port_range {
+            check("port_range", range.validate());
+        }
+        if let Some(Some(path)) = &self.instances_path {
+            check("instances_path", validate_instances_path(path));
+        }
+        if let Some(template) = &self.instance_permission_template {
+            check("instance_permission_template", template.validate());
+        }
+        if let Some(Some(tls)) = &self.tls {
+            let result = match tls.validate() {
+                Ok(_) => tls.load().await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            check("tls", result);
+        }
+        errors
+    }
+
+    fn apply(self, data: &mut GlobalSettingsData) {
+        if let Some(core_name) = self.core_name {
+            // only fails validation, which already passed
+            data.core_name = validate_core_name(&core_name).unwrap_or(core_name);
+        }
+        if let Some(safe_mode) = self.safe_mode {
+            data.safe_mode = safe_mode;
+        }
+        if let Some(domain) = self.domain {
+            data.domain = domain.filter(|domain| !domain.is_empty());
+        }
+        if let Some(soft_delete) = self.soft_delete {
+            data.soft_delete = soft_delete;
+        }
+        if let Some(trash_retention_days) = self.trash_retention_days {
+            data.trash_retention_days = trash_retention_days;
+        }
+        if let Some(instance_stop_timeout) = self.instance_stop_timeout {
+            data.instance_stop_timeout = instance_stop_timeout;
+        }
+        if let Some(orphan_policy) = self.orphan_policy {
+            data.orphan_policy = orphan_policy;
+        }
+        if let Some(max_concurrent_macros) = self.max_concurrent_macros {
+            data.max_concurrent_macros = max_concurrent_macros;
+        }
+        if let Some(macro_limit_policy) = self.macro_limit_policy {
+            data.macro_limit_policy = macro_limit_policy;
+        }
+        if let Some(macro_history_size) = self.macro_history_size {
+            data.macro_history_size = macro_history_size;
+        }
+        if let Some(macro_max_heap_mb) = self.macro_max_heap_mb {
+            data.macro_max_heap_mb = macro_max_heap_mb;
+        }
+        if let Some(upload_rules) = self.upload_rules {
+            data.upload_rules = upload_rules;
+        }
+        if let Some(unrestricted_macro_imports) = self.unrestricted_macro_imports {
+            data.unrestricted_macro_imports = unrestricted_macro_imports;
+        }
+        if let Some(max_write_size) = self.max_write_size {
+            data.max_write_size = max_write_size;
+        }
+        if let Some(zip_workers) = self.zip_workers {
+            data.zip_workers = zip_workers;
+        }
+        if let Some(cors) = self.cors {
+            data.cors = cors;
+        }
+        if let Some(update_check_interval_hours) = self.update_check_interval_hours {
+            data.update_check_interval_hours = update_check_interval_hours;
+        }
+        if let Some(port_range) = self.port_range {
+            data.port_range = port_range;
+        }
+        if let Some(instances_path) = self.instances_path {
+            data.instances_path = instances_path;
+        }
+        if let Some(tls) = self.tls {
+            data.tls = tls;
+        }
+        if let Some(instance_permission_template) = self.instance_permission_template {
+            data.instance_permission_template = instance_permission_template;
+        }
+    }
+}
+
+/// Longest core name in characters
+const MAX_CORE_NAME_LENGTH: usize = 32;
+
+/// The trimmed name if it can be used as the core name
+pub fn validate_core_name(name: &str) -> Result<String, Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Name cannot be empty"),
+        });
+    }
+    if name.chars().count() > MAX_CORE_NAME_LENGTH {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Name cannot be longer than {} characters",
+                MAX_CORE_NAME_LENGTH
+            ),
+        });
+    }
+    if name.chars().any(|c| c.is_control()) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Name cannot contain control characters"),
+        });
+    }
+    Ok(name.to_string())
+}
+
+pub fn validate_domain(domain: &str) -> Result<(), Error> {
+    if domain.len() > 253 {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Domain too long"),
+        });
+    }
+    Ok(())
+}
+
+/// Smallest macro heap limit in MiB, below it V8 can't even set up a runtime
+const MIN_MACRO_MAX_HEAP_MB: u32 = 16;
+
+fn validate_macro_max_heap_mb(max_heap_mb: u32) -> Result<(), Error> {
+    if max_heap_mb < MIN_MACRO_MAX_HEAP_MB {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Macro heap limit must be at least {} MiB",
+                MIN_MACRO_MAX_HEAP_MB
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// The certificate the server is served with when TLS isn't configured explicitly
+fn tls_or_default(tls: &Option<TlsSettings>) -> TlsSettings {
+    tls.clone()
+        .unwrap_or_else(|| TlsSettings::default_in(lodestone_path()))
+}
+
+/// A `BadRequest` with one detail per `field: reason` message
+fn invalid_settings(errors: Vec<String>) -> Error {
+    let mut errors = errors.into_iter().rev();
+    let last_error = errors.next().unwrap_or_default();
+    let source = errors
+        .fold(eyre!(last_error), |source, error| source.wrap_err(error))
+        .wrap_err("Invalid settings");
+    Error {
+        kind: ErrorKind::BadRequest,
+        source,
+    }
+}
+
+fn validate_instances_path(path: &Path) -> Result<(), Error> {
+    if !path.is_absolute() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Instances path {} must be absolute", path.display()),
+        });
+    }
+    crate::util::check_dir_writable(path)
+}
+
 pub struct GlobalSettings {
     path_to_global_settings: PathBuf,
     _event_broadcaster: EventBroadcaster,
@@ -80,12 +520,14 @@ impl GlobalSettings {
         }
         Ok(())
     }
+    /// Writes to a temporary file renamed over the settings, so a crash never leaves them half written
     async fn write_to_file(&self) -> Result<(), Error> {
-        let mut file = tokio::fs::File::create(&self.path_to_global_settings)
+        let path_to_tmp = self.path_to_global_settings.with_extension("json.tmp");
+        let mut file = tokio::fs::File::create(&path_to_tmp)
             .await
             .context(format!(
                 "Failed to create global settings file at {}",
-                self.path_to_global_settings.display()
+                path_to_tmp.display()
             ))?;
         file.write_all(
             serde_json::to_string_pretty(&self.global_settings_data)
@@ -95,20 +537,74 @@ impl GlobalSettings {
         .await
         .context(format!(
             "Failed to write to global settings file at {}",
-            self.path_to_global_settings.display()
+            path_to_tmp.display()
+        ))?;
+        file.sync_all().await.context(format!(
+            "Failed to sync global settings file at {}",
+            path_to_tmp.display()
         ))?;
+        tokio::fs::rename(&path_to_tmp, &self.path_to_global_settings)
+            .await
+            .context(format!(
+                "Failed to replace global settings file at {}",
+                self.path_to_global_settings.display()
+            ))?;
         Ok(())
     }
-    pub async fn set_core_name(&mut self, name: String) -> Result<(), Error> {
-        let old_name = self.global_settings_data.core_name.clone();
-        self.global_settings_data.core_name = name;
-        match self.write_to_file().await {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                self.global_settings_data.core_name = old_name;
-                Err(e)
+
+    /// Validates the whole update before applying any of it, failing with one detail per invalid field
+    ///
+    /// With `tls_config`, a changed certificate is loaded into the running server before
+    /// the settings are saved, so settings it couldn't be served with are never persisted.
+    ///
+    /// Returns the settings before the update
+    pub async fn update(
+        &mut self,
+        update: GlobalSettingsUpdate,
+        tls_config: Option<&RustlsConfig>,
+    ) -> Result<GlobalSettingsData, Error> {
+        let mut errors = update.validate().await;
+        let mut new_data = self.global_settings_data.clone();
+        // combinations are only checked once every field is valid on its own
+        if errors.is_empty() {
+            update.apply(&mut new_data);
+            errors = new_data.validate_combination();
+        }
+        if !errors.is_empty() {
+            return Err(invalid_settings(errors));
+        }
+        let tls_changed = new_data.tls != self.global_settings_data.tls;
+        if let (Some(tls_config), true) = (tls_config, tls_changed) {
+            tls_or_default(&new_data.tls)
+                .reload(tls_config)
+                .await
+                .map_err(|e| invalid_settings(vec![format!("tls: {}", e.source)]))?;
+        }
+        let old_data = std::mem::replace(&mut self.global_settings_data, new_data);
+        if let Err(e) = self.write_to_file().await {
+            self.global_settings_data = old_data;
+            if let (Some(tls_config), true) = (tls_config, tls_changed) {
+                // back to the certificate the saved settings point to
+                if let Err(e) = self.tls().reload(tls_config).await {
+                    error!("Failed to restore the previous TLS certificate : {e}");
+                }
             }
+            return Err(e);
         }
+        set_unrestricted_module_hosts(self.global_settings_data.unrestricted_macro_imports);
+        Ok(old_data)
+    }
+    /// Applies a single-field update, validated the same way as any other update
+    async fn set(&mut self, update: GlobalSettingsUpdate) -> Result<(), Error> {
+        self.update(update, None).await.map(|_| ())
+    }
+
+    pub async fn set_core_name(&mut self, name: String) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            core_name: Some(name),
+            ..Default::default()
+        })
+        .await
     }
 
     pub fn core_name(&self) -> String {
@@ -116,15 +612,11 @@ impl GlobalSettings {
     }
 
     pub async fn set_safe_mode(&mut self, safe_mode: bool) -> Result<(), Error> {
-        let old_safe_mode = self.global_settings_data.safe_mode;
-        self.global_settings_data.safe_mode = safe_mode;
-        match self.write_to_file().await {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                self.global_settings_data.safe_mode = old_safe_mode;
-                Err(e)
-            }
-        }
+        self.set(GlobalSettingsUpdate {
+            safe_mode: Some(safe_mode),
+            ..Default::default()
+        })
+        .await
     }
 
     pub fn safe_mode(&self) -> bool {
@@ -132,20 +624,253 @@ impl GlobalSettings {
     }
 
     pub async fn set_domain(&mut self, domain: Option<String>) -> Result<(), Error> {
-        let old_domain = self.global_settings_data.domain.clone();
-        self.global_settings_data.domain = domain;
-        match self.write_to_file().await {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                self.global_settings_data.domain = old_domain;
-                Err(e)
-            }
-        }
+        self.set(GlobalSettingsUpdate {
+            domain: Some(domain),
+            ..Default::default()
+        })
+        .await
     }
 
     pub fn domain(&self) -> Option<String> {
         self.global_settings_data.domain.clone()
     }
+
+    pub async fn set_soft_delete(&mut self, soft_delete: bool) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            soft_delete: Some(soft_delete),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn soft_delete(&self) -> bool {
+        self.global_settings_data.soft_delete
+    }
+
+    pub async fn set_trash_retention_days(&mut self, days: u32) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            trash_retention_days: Some(days),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn trash_retention_days(&self) -> u32 {
+        self.global_settings_data.trash_retention_days
+    }
+
+    pub async fn set_instance_stop_timeout(&mut self, seconds: u32) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            instance_stop_timeout: Some(seconds),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn instance_stop_timeout(&self) -> u32 {
+        self.global_settings_data.instance_stop_timeout
+    }
+
+    pub async fn set_orphan_policy(&mut self, policy: OrphanPolicy) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            orphan_policy: Some(policy),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn orphan_policy(&self) -> OrphanPolicy {
+        self.global_settings_data.orphan_policy
+    }
+
+    pub async fn set_max_concurrent_macros(&mut self, max: u32) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            max_concurrent_macros: Some(max),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn max_concurrent_macros(&self) -> u32 {
+        self.global_settings_data.max_concurrent_macros
+    }
+
+    pub async fn set_macro_limit_policy(&mut self, policy: MacroLimitPolicy) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            macro_limit_policy: Some(policy),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn macro_limit_policy(&self) -> MacroLimitPolicy {
+        self.global_settings_data.macro_limit_policy
+    }
+
+    pub async fn set_macro_history_size(&mut self, size: u32) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            macro_history_size: Some(size),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn macro_history_size(&self) -> u32 {
+        self.global_settings_data.macro_history_size
+    }
+
+    pub async fn set_macro_max_heap_mb(&mut self, max_heap_mb: u32) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            macro_max_heap_mb: Some(max_heap_mb),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn macro_max_heap_mb(&self) -> u32 {
+        self.global_settings_data.macro_max_heap_mb
+    }
+
+    pub async fn set_upload_rules(&mut self, rules: Vec<UploadRule>) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            upload_rules: Some(rules),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn upload_rules(&self) -> Vec<UploadRule> {
+        self.global_settings_data.upload_rules.clone()
+    }
+
+    pub async fn set_unrestricted_macro_imports(
+        &mut self,
+        unrestricted: bool,
+    ) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            unrestricted_macro_imports: Some(unrestricted),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn unrestricted_macro_imports(&self) -> bool {
+        self.global_settings_data.unrestricted_macro_imports
+    }
+
+    pub async fn set_max_write_size(&mut self, bytes: u64) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            max_write_size: Some(bytes),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn max_write_size(&self) -> u64 {
+        self.global_settings_data.max_write_size
+    }
+
+    pub async fn set_zip_workers(&mut self, workers: u32) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            zip_workers: Some(workers),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn zip_workers(&self) -> u32 {
+        self.global_settings_data.zip_workers
+    }
+
+    pub async fn set_cors(&mut self, cors: CorsSettings) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            cors: Some(cors),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn cors(&self) -> CorsSettings {
+        self.global_settings_data.cors.clone()
+    }
+
+    pub async fn set_update_check_interval_hours(
+        &mut self,
+        hours: Option<u32>,
+    ) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            update_check_interval_hours: Some(hours),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn update_check_interval_hours(&self) -> Option<u32> {
+        self.global_settings_data.update_check_interval_hours
+    }
+
+    pub async fn set_port_range(&mut self, range: Option<PortRange>) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            port_range: Some(range),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn port_range(&self) -> Option<PortRange> {
+        self.global_settings_data.port_range
+    }
+
+    pub async fn set_instances_path(&mut self, path: Option<PathBuf>) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            instances_path: Some(path),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn instances_path(&self) -> Option<PathBuf> {
+        self.global_settings_data.instances_path.clone()
+    }
+
+    /// See `update` for `tls_config`
+    pub async fn set_tls(
+        &mut self,
+        tls: Option<TlsSettings>,
+        tls_config: Option<&RustlsConfig>,
+    ) -> Result<(), Error> {
+        self.update(
+            GlobalSettingsUpdate {
+                tls: Some(tls),
+                ..Default::default()
+            },
+            tls_config,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn set_instance_permission_template(
+        &mut self,
+        template: InstancePermissionTemplate,
+    ) -> Result<(), Error> {
+        self.set(GlobalSettingsUpdate {
+            instance_permission_template: Some(template),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub fn instance_permission_template(&self) -> InstancePermissionTemplate {
+        self.global_settings_data
+            .instance_permission_template
+            .clone()
+    }
+
+    /// The configured TLS settings or the defaults in the lodestone path
+    pub fn tls(&self) -> TlsSettings {
+        tls_or_default(&self.global_settings_data.tls)
+    }
 }
 
 impl AsRef<GlobalSettingsData> for GlobalSettings {
@@ -213,4 +938,99 @@ mod tests {
 
         assert_eq!(global_settings.core_name(), "test_core_name");
     }
+
+    #[tokio::test]
+    async fn test_update_global_settings() {
+        use super::*;
+
+        let temp_dir = tempdir::TempDir::new("test_update_global_settings").unwrap();
+        let path_to_global_settings = temp_dir.path().join("global_settings.json");
+        let (event_broadcaster, _) = EventBroadcaster::new(10);
+        let mut global_settings = GlobalSettings::new(
+            path_to_global_settings.clone(),
+            event_broadcaster,
+            GlobalSettingsData::default(),
+        );
+        global_settings.load_from_file().await.unwrap();
+
+        let update: GlobalSettingsUpdate = serde_json::from_str(
+            r#"{"core_name": "", "port_range": {"start": 30000, "end": 20000}, "safe_mode": false}"#,
+        )
+        .unwrap();
+        let error = global_settings.update(update, None).await.unwrap_err();
+        let details = error.body().details.unwrap();
+        assert_eq!(details.len(), 2);
+        assert!(details[0].starts_with("core_name: "));
+        assert!(details[1].starts_with("port_range: "));
+        // nothing is applied when a field is invalid
+        assert!(global_settings.safe_mode());
+
+        let update: GlobalSettingsUpdate = serde_json::from_str(
+            r#"{"core_name": " renamed ", "domain": null, "port_range": {"start": 20000, "end": 30000}}"#,
+        )
+        .unwrap();
+        assert_eq!(update.fields(), vec!["core_name", "domain", "port_range"]);
+        global_settings.update(update, None).await.unwrap();
+        assert_eq!(global_settings.core_name(), "renamed");
+        assert_eq!(global_settings.domain(), None);
+
+        let persisted: GlobalSettingsData =
+            serde_json::from_slice(&std::fs::read(&path_to_global_settings).unwrap()).unwrap();
+        assert_eq!(persisted.core_name, "renamed");
+        assert_eq!(
+            persisted.port_range,
+            Some(PortRange {
+                start: 20000,
+                end: 30000
+            })
+        );
+
+        // each field is valid alone, but not with what is already set
+        let update: GlobalSettingsUpdate =
+            serde_json::from_str(r#"{"trash_retention_days": 0}"#).unwrap();
+        global_settings.update(update, None).await.unwrap();
+        let update: GlobalSettingsUpdate =
+            serde_json::from_str(r#"{"soft_delete": true}"#).unwrap();
+        let error = global_settings.update(update, None).await.unwrap_err();
+        let details = error.body().details.unwrap();
+        assert_eq!(details.len(), 1);
+        assert!(details[0].starts_with("trash_retention_days: "));
+        assert!(!global_settings.as_ref().soft_delete);
+
+        // the single field setters are checked the same way
+        global_settings.set_trash_retention_days(7).await.unwrap();
+        global_settings.set_soft_delete(true).await.unwrap();
+        assert!(global_settings.set_trash_retention_days(0).await.is_err());
+        assert_eq!(global_settings.trash_retention_days(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_macro_max_heap_mb() {
+        use super::*;
+
+        let temp_dir = tempdir::TempDir::new("test_macro_max_heap_mb").unwrap();
+        let (event_broadcaster, _) = EventBroadcaster::new(10);
+        let mut global_settings = GlobalSettings::new(
+            temp_dir.path().join("global_settings.json"),
+            event_broadcaster,
+            GlobalSettingsData::default(),
+        );
+        global_settings.load_from_file().await.unwrap();
+        assert_eq!(
+            global_settings.macro_max_heap_mb() as u64,
+            DEFAULT_MACRO_MAX_HEAP_MB
+        );
+
+        assert!(global_settings.set_macro_max_heap_mb(8).await.is_err());
+        let update: GlobalSettingsUpdate =
+            serde_json::from_str(r#"{"macro_max_heap_mb": 0}"#).unwrap();
+        assert!(global_settings.update(update, None).await.is_err());
+        assert_eq!(
+            global_settings.macro_max_heap_mb() as u64,
+            DEFAULT_MACRO_MAX_HEAP_MB
+        );
+
+        global_settings.set_macro_max_heap_mb(256).await.unwrap();
+        assert_eq!(global_settings.macro_max_heap_mb(), 256);
+    }
 }
@@ -1,7 +1,8 @@
 use std::env;
 
-use crate::{prelude::VERSION, AppState};
-use axum::{routing::get, Json, Router};
+use crate::{error::Error, prelude::VERSION, update_check::UpdateStatus, AppState};
+use axum::{extract::Query, http::StatusCode, routing::get, Json, Router};
+use axum_auth::AuthBearer;
 use serde::{Deserialize, Serialize};
 use sysinfo::{CpuExt, DiskExt, System, SystemExt};
 
@@ -19,6 +20,8 @@ pub struct CoreInfo {
     uuid: String,
     core_name: String,
     up_since: i64,
+    /// The core was booted without starting anything automatically
+    recovery_mode: bool,
 }
 
 pub async fn get_core_info(
@@ -50,11 +53,102 @@ pub async fn get_core_info(
         core_name: state.global_settings.lock().await.core_name(),
         uuid: state.uuid.clone(),
         up_since: state.up_since,
+        recovery_mode: state.recovery_mode,
     })
 }
 
+/// Bumped on breaking changes to the HTTP API, matches the `/api/v1` prefix
+const API_VERSION: &str = "1";
+
+#[derive(Serialize, Deserialize)]
+pub struct ApiVersion {
+    api_version: String,
+    /// Hash of the sources of the TypeScript bindings of this core, see `build.rs`.
+    /// Differs from the client's if the exported types changed
+    bindings_hash: String,
+}
+
+pub async fn get_api_version() -> Json<ApiVersion> {
+    Json(ApiVersion {
+        api_version: API_VERSION.to_string(),
+        bindings_hash: env!("LODESTONE_BINDINGS_HASH").to_string(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct UpdateQuery {
+    /// Check again instead of answering from the cache, ignored while update checks are disabled
+    #[serde(default)]
+    refresh: bool,
+}
+
+pub async fn get_update_status(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Query(query): Query<UpdateQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<UpdateStatus>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    // air-gapped installs disable the checks, a refresh mustn't reach out anyway
+    let refresh = query.refresh
+        && state
+            .global_settings
+            .lock()
+            .await
+            .update_check_interval_hours()
+            .is_some();
+    Ok(Json(state.update_checker.status(refresh).await))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Health {
+    status: String,
+    /// Seconds since the core started
+    uptime: i64,
+}
+
+/// Liveness check for load balancers and container healthchecks,
+/// doesn't touch sysinfo, auth or any lock unlike `/info`
+pub async fn get_health(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Json<Health> {
+    Json(Health {
+        status: "ok".to_string(),
+        uptime: chrono::Utc::now().timestamp() - state.up_since,
+    })
+}
+
+/// Readiness check, 503 until first time setup is complete
+///
+/// Instances are restored before the core starts listening, so the instance list is always loaded here
+pub async fn get_ready(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> (StatusCode, Json<Health>) {
+    let uptime = chrono::Utc::now().timestamp() - state.up_since;
+    if state.first_time_setup_key.lock().await.is_none() {
+        (
+            StatusCode::OK,
+            Json(Health {
+                status: "ready".to_string(),
+                uptime,
+            }),
+        )
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(Health {
+                status: "setup_required".to_string(),
+                uptime,
+            }),
+        )
+    }
+}
+
 pub fn get_core_info_routes(state: AppState) -> Router {
     Router::new()
         .route("/info", get(get_core_info))
+        .route("/info/api_version", get(get_api_version))
+        .route("/info/update", get(get_update_status))
+        .route("/health", get(get_health))
+        .route("/ready", get(get_ready))
         .with_state(state)
 }
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
 use axum::{
     extract::{ws::WebSocket, Path, Query, WebSocketUpgrade},
@@ -8,26 +8,38 @@ use axum::{
 };
 use axum_auth::AuthBearer;
 
-use color_eyre::eyre::eyre;
+use color_eyre::eyre::{eyre, Context};
+use dashmap::DashMap;
 use futures::{SinkExt, StreamExt};
 use ringbuffer::{AllocRingBuffer, RingBufferExt};
 use tracing::{debug, error};
 
-use crate::output_types::ClientEvent;
-use crate::types::InstanceUuid;
+use crate::handlers::global_fs::DownloadableFile;
+use crate::output_types::{ClientEvent, EventEncoding};
+use crate::prelude::{path_to_tmp, GameInstance};
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::TServer;
+use crate::types::{InstanceUuid, Snowflake};
 use crate::{
-    auth::{user::UsersManager, user_id::UserId},
-    db::read::search_events,
+    auth::{
+        user::{User, UserAction, UsersManager},
+        user_id::UserId,
+    },
+    db::read::{page_events, search_events, EventPage, EventPageQuery, EventVisibility},
     error::{Error, ErrorKind},
-    events::EventQuery,
+    events::{CausedBy, EventQuery},
 };
 
 use crate::{
-    events::{Event, EventInner, UserEventInner},
+    events::{Event, EventInner, InstanceEvent, InstanceEventInner, UserEventInner},
+    util::rand_alphanumeric,
     AppState,
 };
-use serde::Deserialize;
-use tokio::sync::{broadcast::Receiver, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    RwLock,
+};
 use ts_rs::TS;
 
 use super::util::parse_bearer_token;
@@ -99,6 +111,45 @@ pub async fn get_event_search(
     search_events(&state.sqlite_pool, query).await.map(Json)
 }
 
+/// Narrows the history down to the instances the user has access to, mirrors `User::can_view_event_inner`
+fn event_visibility(user: &User) -> EventVisibility {
+    if user.is_owner {
+        return EventVisibility::unrestricted();
+    }
+    EventVisibility {
+        instances: if user.is_admin {
+            None
+        } else {
+            Some(user.permissions.can_view_instance.iter().cloned().collect())
+        },
+        macro_instances: Some(
+            user.permissions
+                .can_access_instance_macro
+                .iter()
+                .cloned()
+                .collect(),
+        ),
+        user_and_fs_events: user.can_perform_action(&UserAction::ManageUser),
+    }
+}
+
+/// The persisted event history, newest first, for the activity view and audit log
+pub async fn get_event_history(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<EventPageQuery>,
+) -> Result<Json<EventPage>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    page_events(
+        &state.sqlite_pool,
+        &query,
+        &event_visibility(&requester),
+        |event| requester.can_view_event_inner(&event.event_inner),
+    )
+    .await
+    .map(Json)
+}
+
 pub async fn get_console_buffer(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -133,6 +184,66 @@ pub async fn get_console_buffer(
     ))
 }
 
+/// Writes the console buffer of an instance to a log file and returns a key to download it with
+pub async fn download_console(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(uuid): Path<InstanceUuid>,
+) -> Result<String, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .map(|instance| instance.clone())
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?;
+    let instance_name = instance.name().await;
+
+    let mut log = String::new();
+    if let Some(buffer) = state.console_out_buffer.lock().await.get(&uuid) {
+        for event in buffer.iter() {
+            if let EventInner::InstanceEvent(InstanceEvent {
+                instance_event_inner,
+                ..
+            }) = &event.event_inner
+            {
+                match instance_event_inner {
+                    InstanceEventInner::InstanceOutput { message }
+                    | InstanceEventInner::SystemMessage { message } => log.push_str(message),
+                    InstanceEventInner::PlayerMessage {
+                        player,
+                        player_message,
+                    } => log.push_str(&format!("<{player}> {player_message}")),
+                    _ => continue,
+                }
+                log.push('\n');
+            }
+        }
+    }
+
+    let temp_dir =
+        tempfile::tempdir_in(path_to_tmp()).context("Failed to create temporary file")?;
+    let path = temp_dir.path().join(format!(
+        "{}-console-{}.log",
+        sanitize_filename::sanitize(&instance_name),
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
+    ));
+    tokio::fs::write(&path, log)
+        .await
+        .context("Failed to write console log")?;
+
+    let key = rand_alphanumeric(32);
+    state
+        .download_urls
+        .lock()
+        .await
+        .insert(key.clone(), DownloadableFile::TextFile((path, temp_dir)));
+    Ok(key)
+}
+
 #[derive(Deserialize)]
 pub struct WebsocketQuery {
     token: String,
@@ -208,6 +319,127 @@ async fn event_stream_ws(
     }
 }
 
+#[derive(Deserialize)]
+pub struct EventWebsocketQuery {
+    token: String,
+    /// JSON encoded `EventQuery`, all events the user can view are sent if omitted
+    filter: Option<String>,
+    #[serde(default)]
+    encoding: EventEncoding,
+}
+
+/// Control frames sent on the event websocket alongside the events themselves
+#[derive(Serialize, Clone, Debug, TS)]
+#[serde(tag = "control")]
+#[ts(export)]
+pub enum EventStreamControlFrame {
+    /// The client fell behind and `missed_events` events were dropped,
+    /// it should refetch whatever state it derives from the stream
+    Resync { missed_events: u64 },
+}
+
+pub async fn event_websocket(
+    ws: WebSocketUpgrade,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    query: Query<EventWebsocketQuery>,
+) -> Result<Response, Error> {
+    let event_query: Option<EventQuery> = query
+        .filter
+        .as_ref()
+        .map(|filter| serde_json::from_str(filter))
+        .transpose()
+        .map_err(|e| {
+            error!("Error deserializing event query: {}", e);
+            Error {
+                kind: ErrorKind::BadRequest,
+                source: e.into(),
+            }
+        })?;
+    let users_manager = state.users_manager.read().await;
+    let user = parse_bearer_token(query.token.as_str())
+        .and_then(|token| users_manager.try_auth(&token))
+        .ok_or_else(|| Error {
+            kind: ErrorKind::Unauthorized,
+            source: eyre!("Token error"),
+        })?;
+    drop(users_manager);
+    let event_receiver = state.event_broadcaster.subscribe();
+
+    Ok(ws.on_upgrade(move |socket| {
+        event_websocket_ws(
+            socket,
+            event_receiver,
+            event_query,
+            query.encoding,
+            user.uid,
+            state.users_manager,
+        )
+    }))
+}
+
+async fn event_websocket_ws(
+    stream: WebSocket,
+    mut event_receiver: Receiver<Event>,
+    query: Option<EventQuery>,
+    encoding: EventEncoding,
+    uid: UserId,
+    users_manager: Arc<RwLock<UsersManager>>,
+) {
+    let (mut sender, mut receiver) = stream.split();
+    loop {
+        tokio::select! {
+            result = event_receiver.recv() => {
+                let message = match result {
+                    Ok(event) => {
+                        if event.is_event_console_message() {
+                            continue;
+                        }
+                        let user = match users_manager.read().await.get_user(&uid) {
+                            Some(user) => user,
+                            None => break,
+                        };
+                        if !user.can_view_event(&event)
+                            || !query
+                                .as_ref()
+                                .map(|query| query.filter(ClientEvent::from(event.clone())))
+                                .unwrap_or(true)
+                        {
+                            continue;
+                        }
+                        encoding.encode(&event)
+                    }
+                    Err(RecvError::Lagged(missed_events)) => {
+                        debug!("Event websocket lagged behind by {} events", missed_events);
+                        serde_json::to_string(&EventStreamControlFrame::Resync { missed_events })
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let message = match message {
+                    Ok(message) => message,
+                    Err(e) => {
+                        error!("Failed to serialize event: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = sender.send(axum::extract::ws::Message::Text(message)).await {
+                    error!("Error sending event to websocket: {}", e);
+                    break;
+                }
+            }
+            ws_msg = receiver.next() => {
+                match ws_msg {
+                    Some(Ok(axum::extract::ws::Message::Close(_))) | Some(Err(_)) | None => {
+                        debug!("Websocket disconnected");
+                        break;
+                    }
+                    // pings are answered automatically, and the stream is read only
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
 pub async fn console_stream(
     ws: WebSocketUpgrade,
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -274,6 +506,7 @@ async fn console_stream_ws(
                     EventInner::MacroEvent(_) => continue,
                     EventInner::ProgressionEvent(_) => continue,
                     EventInner::FSEvent(_) => continue,
+                    EventInner::CoreEvent(_) => continue,
                 }
             }
             Some(Ok(ws_msg)) = receiver.next() => {
@@ -286,12 +519,182 @@ async fn console_stream_ws(
     }
 }
 
+pub async fn console_websocket(
+    ws: WebSocketUpgrade,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    query: Query<WebsocketQuery>,
+    Path(uuid): Path<InstanceUuid>,
+) -> Result<Response, Error> {
+    let users_manager = state.users_manager.read().await;
+    let user = parse_bearer_token(query.token.as_str())
+        .and_then(|token| users_manager.try_auth(&token))
+        .ok_or_else(|| Error {
+            kind: ErrorKind::Unauthorized,
+            source: eyre!("Token error"),
+        })?;
+    drop(users_manager);
+    user.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    if !state.instances.contains_key(&uuid) {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        });
+    }
+    // subscribe before taking the history so no line falls in between,
+    // lines that end up in both are skipped by snowflake
+    let event_receiver = state.event_broadcaster.subscribe();
+    let history: Vec<Event> = state
+        .console_out_buffer
+        .lock()
+        .await
+        .get(&uuid)
+        .map(|buffer| buffer.iter().cloned().collect())
+        .unwrap_or_default();
+
+    Ok(ws.on_upgrade(move |socket| {
+        console_websocket_ws(
+            socket,
+            event_receiver,
+            history,
+            user.uid,
+            uuid,
+            state.users_manager,
+            state.instances,
+        )
+    }))
+}
+
+async fn console_websocket_ws(
+    stream: WebSocket,
+    mut event_receiver: Receiver<Event>,
+    history: Vec<Event>,
+    uid: UserId,
+    uuid: InstanceUuid,
+    users_manager: Arc<RwLock<UsersManager>>,
+    instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+) {
+    let (mut sender, mut receiver) = stream.split();
+    let sent_snowflakes: HashSet<Snowflake> = history.iter().map(|event| event.snowflake).collect();
+    for event in history {
+        if let Err(e) = sender
+            .send(axum::extract::ws::Message::Text(
+                serde_json::to_string(&event).unwrap(),
+            ))
+            .await
+        {
+            error!("Failed to send console history: {}", e);
+            return;
+        }
+    }
+    loop {
+        tokio::select! {
+            result = event_receiver.recv() => {
+                let event = match result {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed_events)) => {
+                        debug!("Console websocket lagged behind by {} events", missed_events);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                match &event.event_inner {
+                    EventInner::InstanceEvent(instance_event) => {
+                        if !event.is_event_console_message()
+                            || instance_event.instance_uuid != uuid
+                            || sent_snowflakes.contains(&event.snowflake)
+                        {
+                            continue;
+                        }
+                        // permissions may have changed since the connection was opened
+                        let user = match users_manager.read().await.get_user(&uid) {
+                            Some(user) => user,
+                            None => break,
+                        };
+                        if !user.can_view_event(&event) {
+                            continue;
+                        }
+                        if let Err(e) = sender
+                            .send(axum::extract::ws::Message::Text(
+                                serde_json::to_string(&event).unwrap(),
+                            ))
+                            .await
+                        {
+                            error!("Failed to send event: {}", e);
+                            break;
+                        }
+                    }
+                    EventInner::UserEvent(user_event) => match user_event.user_event_inner {
+                        UserEventInner::UserLoggedOut | UserEventInner::UserDeleted => {
+                            if user_event.user_id == uid {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    },
+                    _ => continue,
+                }
+            }
+            ws_msg = receiver.next() => {
+                let command = match ws_msg {
+                    Some(Ok(axum::extract::ws::Message::Text(command))) => command,
+                    Some(Ok(axum::extract::ws::Message::Close(_))) | Some(Err(_)) | None => {
+                        debug!("Websocket disconnected");
+                        break;
+                    }
+                    Some(Ok(_)) => continue,
+                };
+                // permissions may have changed since the connection was opened
+                let user = match users_manager.read().await.get_user(&uid) {
+                    Some(user) => user,
+                    None => break,
+                };
+                let result = match user.try_console_command(&uuid, &command) {
+                    Ok(()) => match instances.get(&uuid).map(|instance| instance.clone()) {
+                        Some(instance) => {
+                            instance
+                                .send_command(
+                                    &command,
+                                    CausedBy::User {
+                                        user_id: user.uid.clone(),
+                                        user_name: user.username.clone(),
+                                    },
+                                )
+                                .await
+                        }
+                        None => Err(Error {
+                            kind: ErrorKind::NotFound,
+                            source: eyre!("Instance not found"),
+                        }),
+                    },
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    // errors are reported back on the socket instead of closing it
+                    if let Err(e) = sender
+                        .send(axum::extract::ws::Message::Text(
+                            serde_json::to_string(&e).unwrap(),
+                        ))
+                        .await
+                    {
+                        error!("Failed to send error: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub fn get_events_routes(state: AppState) -> Router {
     Router::new()
         .route("/events/:uuid/stream", get(event_stream))
+        .route("/events/ws", get(event_websocket))
         .route("/events/:uuid/buffer", get(get_event_buffer))
         .route("/events/search", get(get_event_search))
+        .route("/events/history", get(get_event_history))
         .route("/instance/:uuid/console/stream", get(console_stream))
+        .route("/instance/:uuid/console/ws", get(console_websocket))
         .route("/instance/:uuid/console/buffer", get(get_console_buffer))
+        .route("/instance/:uuid/console/download", get(download_console))
         .with_state(state)
 }
@@ -1,46 +1,78 @@
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use axum::{
-    body::{Bytes, StreamBody},
-    extract::{Multipart, Path},
-    http,
-    routing::{delete, get, put},
-    Json, Router,
+    body::StreamBody,
+    extract::{BodyStream, DefaultBodyLimit, Multipart, Path, Query},
+    http::{self, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router, TypedHeader,
 };
 use axum_auth::AuthBearer;
 
 use color_eyre::eyre::{eyre, Context};
-use headers::{HeaderMap, HeaderName};
+use headers::{ETag, HeaderMap, HeaderName, IfModifiedSince, IfNoneMatch, LastModified};
+use lazy_static::lazy_static;
 use reqwest::header::CONTENT_LENGTH;
 use serde::{Deserialize, Serialize};
 
 use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
 use tokio_util::io::ReaderStream;
+use tower_http::compression::{
+    predicate::{DefaultPredicate, NotForContentType, Predicate, SizeAbove},
+    CompressionLayer,
+};
 use ts_rs::TS;
 
 use crate::{
     auth::user::UserAction,
     error::{Error, ErrorKind},
     events::{new_fs_event, CausedBy, Event, FSOperation, FSTarget},
-    util::{list_dir, rand_alphanumeric, zip_files},
+    resumable_upload::{ResumableUpload, ResumableUploadStatus},
+    trash::{list_trash, move_to_trash, restore_from_trash, TrashEntry},
+    upload_filter::{check_upload, UploadHead},
+    util::{
+        list_dir, rand_alphanumeric, resolve_path_conflict, wildcard_match,
+        zip_files_parallel_async,
+    },
     AppState,
 };
 
-use super::util::decode_base64;
-use crate::prelude::path_to_tmp;
+use super::util::{
+    decode_base64, prepare_upload_dir, read_body_limited, read_text_file, ReadQuery, UploadQuery,
+};
+use crate::events::ProgressionEventID;
+use crate::prelude::{path_to_tmp, path_to_trash};
 use tempfile::TempDir;
+use walkdir::WalkDir;
+
+/// Responses smaller than this many bytes are not worth compressing
+const COMPRESSION_MIN_SIZE: u16 = 1024;
+
+/// How long the zip of a directory is kept around for refreshed download keys
+const ZIP_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+/// Total size of the cached zips, the oldest are dropped past it
+const ZIP_CACHE_MAX_BYTES: u64 = 4 * 1024 * 1024 * 1024;
 
 pub enum DownloadableFile {
     NormalFile(PathBuf),
-    ZippedFile((PathBuf, TempDir)),
+    /// The temporary directory is shared with the zip cache and other keys for the same zip
+    ZippedFile((PathBuf, Arc<TempDir>)),
+    /// Text generated for the download, such as a console snapshot
+    TextFile((PathBuf, TempDir)),
 }
 
-#[derive(Debug, Serialize, Deserialize, TS)]
+#[derive(Debug, Serialize, Deserialize, TS, PartialEq, Eq)]
 #[ts(export)]
 pub enum FileType {
     File,
     Directory,
+    Symlink,
     Unknown,
 }
 #[derive(Debug, Serialize, Deserialize, TS)]
@@ -55,16 +87,41 @@ pub struct FileEntry {
     pub creation_time: Option<u64>,
     pub modification_time: Option<u64>,
     pub file_type: FileType,
+    /// Permission bits in octal, e.g. `755`, always `None` on Windows
+    pub mode: Option<String>,
+    /// Where the link points to, as stored in the link, if `file_type` is `Symlink`
+    pub symlink_target: Option<String>,
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(format!("{:o}", metadata.permissions().mode() & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> Option<String> {
+    None
+}
+
+/// Seconds since the unix epoch, or `None` for times before it
+/// (some network mounts and FAT volumes report those)
+fn to_unix_timestamp(time: std::time::SystemTime) -> Option<u64> {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
 }
 
 impl From<&std::path::Path> for FileEntry {
     fn from(path: &std::path::Path) -> Self {
-        let file_type = if path.is_dir() {
-            FileType::Directory
-        } else if path.is_file() {
-            FileType::File
-        } else {
-            FileType::Unknown
+        // symlinks are not followed, so a link shows up as such
+        // and a cyclic link can't make us loop
+        let metadata = path.symlink_metadata().ok();
+        let file_type = match metadata.as_ref().map(|m| m.file_type()) {
+            Some(t) if t.is_symlink() => FileType::Symlink,
+            Some(t) if t.is_dir() => FileType::Directory,
+            Some(t) if t.is_file() => FileType::File,
+            _ => FileType::Unknown,
         };
         Self {
             name: path
@@ -75,10 +132,9 @@ impl From<&std::path::Path> for FileEntry {
                 .file_name()
                 .map(|s| s.to_string_lossy().into_owned())
                 .unwrap_or_default(),
-            size: if path.is_file() {
-                path.metadata().ok().map(|m| m.len())
-            } else {
-                None
+            size: match file_type {
+                FileType::File => metadata.as_ref().map(|m| m.len()),
+                _ => None,
             },
             file_stem: path
                 .file_stem()
@@ -87,25 +143,141 @@ impl From<&std::path::Path> for FileEntry {
             extension: path.extension().map(|s| s.to_string_lossy().into_owned()),
             // unix timestamp
             // if we cant get the time, return none
-            creation_time: path
-                .metadata()
-                .ok()
+            creation_time: metadata
+                .as_ref()
                 .and_then(|m| m.created().ok())
-                .map(|t| t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()),
-            modification_time: path
-                .metadata()
-                .ok()
+                .and_then(to_unix_timestamp),
+            modification_time: metadata
+                .as_ref()
                 .and_then(|m| m.modified().ok())
-                .map(|t| t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()),
-
+                .and_then(to_unix_timestamp),
+            mode: metadata.as_ref().and_then(file_mode),
+            symlink_target: match file_type {
+                FileType::Symlink => fs::read_link(path)
+                    .ok()
+                    .map(|target| target.to_string_lossy().into_owned()),
+                _ => None,
+            },
             file_type,
         }
     }
 }
 
+/// A single `FileEntry` along with what the core process can do with it
+#[derive(Debug, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct FileStat {
+    #[serde(flatten)]
+    pub entry: FileEntry,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl From<&std::path::Path> for FileStat {
+    fn from(path: &std::path::Path) -> Self {
+        let readable = if path.is_dir() {
+            fs::read_dir(path).is_ok()
+        } else {
+            fs::File::open(path).is_ok()
+        };
+        // opening for writing without truncating is the only reliable check for files,
+        // directories fall back to the permission bits
+        let writable = if path.is_file() {
+            fs::OpenOptions::new().write(true).open(path).is_ok()
+        } else {
+            path.metadata()
+                .map(|m| !m.permissions().readonly())
+                .unwrap_or(false)
+        };
+        Self {
+            entry: path.into(),
+            readable,
+            writable,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListSort {
+    Name,
+    Size,
+    Mtime,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ListOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Without any parameter, entries are listed as is
+#[derive(Deserialize, Default)]
+pub struct ListQuery {
+    sort: Option<ListSort>,
+    #[serde(default)]
+    order: ListOrder,
+    /// Only entries whose name matches, `*` matches any sequence of characters
+    filter: Option<String>,
+    #[serde(default)]
+    dirs_first: bool,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+fn compare_entries(a: &FileEntry, b: &FileEntry, query: &ListQuery) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    let is_dir = |entry: &FileEntry| entry.file_type == FileType::Directory;
+    let dirs = if query.dirs_first {
+        is_dir(b).cmp(&is_dir(a))
+    } else {
+        Ordering::Equal
+    };
+    dirs.then_with(|| {
+        let ordering = match query.sort {
+            Some(ListSort::Name) => a.name.cmp(&b.name),
+            Some(ListSort::Mtime) => a.modification_time.cmp(&b.modification_time),
+            Some(ListSort::Size) => match (a.size, b.size) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (None, None) => Ordering::Equal,
+                // entries without a size (directories) stay together whatever the order,
+                // first if directories are listed first and last otherwise
+                (None, Some(_)) if query.dirs_first => return Ordering::Less,
+                (None, Some(_)) => return Ordering::Greater,
+                (Some(_), None) if query.dirs_first => return Ordering::Greater,
+                (Some(_), None) => return Ordering::Less,
+            },
+            None => Ordering::Equal,
+        };
+        match query.order {
+            ListOrder::Asc => ordering,
+            ListOrder::Desc => ordering.reverse(),
+        }
+    })
+}
+
+/// Filters, sorts and paginates `entries` as requested by `query`
+fn apply_list_query(mut entries: Vec<FileEntry>, query: &ListQuery) -> Vec<FileEntry> {
+    if let Some(filter) = &query.filter {
+        entries.retain(|entry| wildcard_match(filter, &entry.name));
+    }
+    if query.sort.is_some() || query.dirs_first {
+        entries.sort_by(|a, b| compare_entries(a, b, query));
+    }
+    entries
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(usize::MAX))
+        .collect()
+}
+
 async fn list_files(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
+    Query(query): Query<ListQuery>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Json<Vec<FileEntry>>, Error> {
     let absolute_path = decode_base64(&base64_absolute_path)?;
@@ -134,6 +306,7 @@ async fn list_files(
             r
         })
         .collect();
+    let ret = apply_list_query(ret, &query);
     state.event_broadcaster.send(new_fs_event(
         FSOperation::Read,
         FSTarget::Directory(path),
@@ -142,11 +315,58 @@ async fn list_files(
     Ok(Json(ret))
 }
 
+async fn stat_file(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(base64_absolute_path): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<FileStat>, Error> {
+    let absolute_path = decode_base64(&base64_absolute_path)?;
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadGlobalFile)?;
+
+    let path = PathBuf::from(absolute_path);
+    // symlink_metadata so broken symlinks still count as existing
+    if tokio::fs::symlink_metadata(&path).await.is_err() {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("{} does not exist", path.display()),
+        });
+    }
+    Ok(Json(path.as_path().into()))
+}
+
+#[derive(Debug, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct FileExists {
+    pub exists: bool,
+    pub is_dir: bool,
+}
+
+/// Cheaper than `stat_file`, and doesn't fail if the path doesn't exist
+async fn file_exists(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(base64_absolute_path): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<FileExists>, Error> {
+    let absolute_path = decode_base64(&base64_absolute_path)?;
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadGlobalFile)?;
+
+    let metadata = tokio::fs::metadata(PathBuf::from(absolute_path)).await.ok();
+    Ok(Json(FileExists {
+        exists: metadata.is_some(),
+        is_dir: metadata.map_or(false, |metadata| metadata.is_dir()),
+    }))
+}
+
 async fn read_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
+    Query(ReadQuery { raw }): Query<ReadQuery>,
     AuthBearer(token): AuthBearer,
-) -> Result<String, Error> {
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+    if_modified_since: Option<TypedHeader<IfModifiedSince>>,
+) -> Result<Response, Error> {
     let absolute_path = decode_base64(&base64_absolute_path)?;
 
     let requester = state
@@ -161,11 +381,38 @@ async fn read_file(
     requester.try_action(&UserAction::ReadGlobalFile)?;
 
     let path = PathBuf::from(absolute_path);
-    let ret = tokio::fs::read_to_string(&path).await.context(
-        "
-        Failed to read file
-    ",
-    )?;
+
+    // the etag is derived from the size and modification time, so it is cheap to compute
+    // but won't catch a change that preserves both
+    let validators = tokio::fs::metadata(&path).await.ok().and_then(|metadata| {
+        let modified = metadata.modified().ok()?;
+        let mtime = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+        let etag: ETag = format!("\"{:x}-{:x}\"", metadata.len(), mtime.as_nanos())
+            .parse()
+            .ok()?;
+        Some((etag, modified))
+    });
+
+    if let Some((etag, modified)) = &validators {
+        // If-None-Match takes precedence over If-Modified-Since, as per RFC 7232
+        let not_modified = match (if_none_match, if_modified_since) {
+            (Some(TypedHeader(if_none_match)), _) => !if_none_match.precondition_passes(etag),
+            (None, Some(TypedHeader(if_modified_since))) => {
+                !if_modified_since.is_modified(*modified)
+            }
+            (None, None) => false,
+        };
+        if not_modified {
+            return Ok((
+                StatusCode::NOT_MODIFIED,
+                TypedHeader(etag.clone()),
+                TypedHeader(LastModified::from(*modified)),
+            )
+                .into_response());
+        }
+    }
+
+    let ret = read_text_file(&path, raw).await?;
     let caused_by = CausedBy::User {
         user_id: requester.uid,
         user_name: requester.username,
@@ -175,14 +422,22 @@ async fn read_file(
         FSTarget::File(path),
         caused_by,
     ));
-    Ok(ret)
+    Ok(match validators {
+        Some((etag, modified)) => (
+            TypedHeader(etag),
+            TypedHeader(LastModified::from(modified)),
+            ret,
+        )
+            .into_response(),
+        None => ret.into_response(),
+    })
 }
 
 async fn write_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
     AuthBearer(token): AuthBearer,
-    body: Bytes,
+    body: BodyStream,
 ) -> Result<Json<()>, Error> {
     let absolute_path = decode_base64(&base64_absolute_path)?;
 
@@ -199,6 +454,9 @@ async fn write_file(
 
     let path = PathBuf::from(absolute_path);
 
+    let max_write_size = state.global_settings.lock().await.max_write_size();
+    let body = read_body_limited(body, max_write_size).await?;
+    let _lock = state.path_locks.lock(&path).await;
     tokio::fs::write(&path, body)
         .await
         .context(format!("Failed to write to file {}", path.display()))?;
@@ -253,6 +511,104 @@ async fn make_directory(
     Ok(Json(()))
 }
 
+#[derive(Deserialize)]
+pub struct ChmodRequest {
+    /// Octal permission bits, e.g. `"755"`
+    mode: String,
+    #[serde(default)]
+    recursive: bool,
+}
+
+fn parse_mode(mode: &str) -> Result<u32, Error> {
+    if !(3..=4).contains(&mode.len()) || !mode.chars().all(|c| ('0'..='7').contains(&c)) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid mode {}, expected octal like 755", mode),
+        });
+    }
+    // can't fail after the check above
+    Ok(u32::from_str_radix(mode, 8).unwrap_or_default())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &std::path::Path, mode: u32, recursive: bool) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let set = |path: &std::path::Path| -> Result<(), Error> {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .context(format!("Failed to set permissions of {}", path.display()))?;
+        Ok(())
+    };
+    if !recursive || !path.is_dir() {
+        return set(path);
+    }
+    // symlinks are skipped so the change stays within the directory
+    for entry in walkdir::WalkDir::new(path) {
+        let entry = entry.context(format!("Failed to walk directory {}", path.display()))?;
+        if !entry.path_is_symlink() {
+            set(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &std::path::Path, _mode: u32, _recursive: bool) -> Result<(), Error> {
+    Err(Error {
+        kind: ErrorKind::UnsupportedOperation,
+        source: eyre!("File permissions can only be changed on Unix hosts, nothing was changed"),
+    })
+}
+
+async fn chmod(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(base64_absolute_path): Path<String>,
+    AuthBearer(token): AuthBearer,
+    Json(ChmodRequest { mode, recursive }): Json<ChmodRequest>,
+) -> Result<Json<()>, Error> {
+    let absolute_path = decode_base64(&base64_absolute_path)?;
+    let requester = state
+        .users_manager
+        .read()
+        .await
+        .try_auth(&token)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::Unauthorized,
+            source: eyre!("Token error"),
+        })?;
+    requester.try_action(&UserAction::WriteGlobalFile)?;
+
+    let mode = parse_mode(&mode)?;
+    let path = PathBuf::from(absolute_path);
+    if !path.is_absolute() || !path.exists() {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("{} does not exist", path.display()),
+        });
+    }
+
+    let _lock = state.path_locks.lock(&path).await;
+    tokio::task::spawn_blocking({
+        let path = path.clone();
+        move || set_mode(&path, mode, recursive)
+    })
+    .await
+    .context("Failed to set permissions")??;
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    let target = if path.is_dir() {
+        FSTarget::Directory(path)
+    } else {
+        FSTarget::File(path)
+    };
+    state
+        .event_broadcaster
+        .send(new_fs_event(FSOperation::Write, target, caused_by));
+    Ok(Json(()))
+}
+
 async fn move_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((base64_absolute_path_source, base64_absolute_path_dest)): Path<(String, String)>,
@@ -273,6 +629,13 @@ async fn move_file(
 
     requester.try_action(&UserAction::WriteGlobalFile)?;
 
+    let _locks = state
+        .path_locks
+        .lock_all(&[
+            std::path::Path::new(&path_source),
+            std::path::Path::new(&path_dest),
+        ])
+        .await;
     crate::util::fs::rename(&path_source, &path_dest).await?;
 
     let caused_by = CausedBy::User {
@@ -291,10 +654,26 @@ async fn move_file(
     Ok(Json(()))
 }
 
+#[derive(Deserialize)]
+pub struct RemoveQuery {
+    /// Skip the trash and delete immediately
+    #[serde(default)]
+    permanent: bool,
+}
+
+/// Whether a removal should go to the trash rather than delete the target
+async fn should_soft_delete(state: &AppState, path: &std::path::Path, query: &RemoveQuery) -> bool {
+    // deleting something that is already in the trash is always permanent
+    !query.permanent
+        && !path.starts_with(path_to_trash())
+        && state.global_settings.lock().await.soft_delete()
+}
+
 async fn remove_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
     AuthBearer(token): AuthBearer,
+    Query(query): Query<RemoveQuery>,
 ) -> Result<Json<()>, Error> {
     let absolute_path = decode_base64(&base64_absolute_path)?;
     let requester = state
@@ -310,9 +689,19 @@ async fn remove_file(
 
     let path = PathBuf::from(absolute_path);
 
-    tokio::fs::remove_file(&path)
-        .await
-        .context(format!("Failed to remove file {}", path.display()))?;
+    if should_soft_delete(&state, &path, &query).await {
+        if !path.is_file() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("{} is not a file", path.display()),
+            });
+        }
+        move_to_trash(&path).await?;
+    } else {
+        tokio::fs::remove_file(&path)
+            .await
+            .context(format!("Failed to remove file {}", path.display()))?;
+    }
 
     let caused_by = CausedBy::User {
         user_id: requester.uid,
@@ -330,6 +719,7 @@ async fn remove_dir(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
     AuthBearer(token): AuthBearer,
+    Query(query): Query<RemoveQuery>,
 ) -> Result<Json<()>, Error> {
     let absolute_path = decode_base64(&base64_absolute_path)?;
     let requester = state
@@ -345,9 +735,19 @@ async fn remove_dir(
 
     let path = PathBuf::from(absolute_path);
 
-    tokio::fs::remove_dir_all(&path)
-        .await
-        .context(format!("Failed to remove directory {}", path.display()))?;
+    if should_soft_delete(&state, &path, &query).await {
+        if !path.is_dir() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("{} is not a directory", path.display()),
+            });
+        }
+        move_to_trash(&path).await?;
+    } else {
+        tokio::fs::remove_dir_all(&path)
+            .await
+            .context(format!("Failed to remove directory {}", path.display()))?;
+    }
 
     let caused_by = CausedBy::User {
         user_id: requester.uid,
@@ -362,6 +762,43 @@ async fn remove_dir(
     Ok(Json(()))
 }
 
+async fn get_trash(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<TrashEntry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadGlobalFile)?;
+    list_trash().await.map(Json)
+}
+
+async fn restore_trash_entry(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PathBuf>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteGlobalFile)?;
+
+    let (entry, restored_path) = restore_from_trash(&id).await?;
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Move {
+            source: path_to_trash().join(&entry.id).join(&entry.name),
+        },
+        if entry.is_dir {
+            FSTarget::Directory(restored_path.clone())
+        } else {
+            FSTarget::File(restored_path.clone())
+        },
+        caused_by,
+    ));
+    Ok(Json(restored_path))
+}
+
 async fn new_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
@@ -398,17 +835,114 @@ async fn new_file(
     Ok(Json(()))
 }
 
-async fn download_file(
-    axum::extract::State(state): axum::extract::State<AppState>,
-    Path(base64_absolute_path): Path<String>,
-    AuthBearer(token): AuthBearer,
+struct CachedZip {
+    /// Latest modification time in the directory when it was zipped
+    modified: SystemTime,
+    zip_path: PathBuf,
+    temp_dir: Arc<TempDir>,
+    size: u64,
+    created: Instant,
+}
+
+/// Drops zips older than `ZIP_CACHE_MAX_AGE`, then the oldest ones until the cache fits
+/// in `ZIP_CACHE_MAX_BYTES`. A zip is only deleted once the download keys using it are gone too
+fn evict_cached_zips(cache: &mut HashMap<PathBuf, CachedZip>) {
+    cache.retain(|_, cached| cached.created.elapsed() < ZIP_CACHE_MAX_AGE);
+    while cache.values().map(|cached| cached.size).sum::<u64>() > ZIP_CACHE_MAX_BYTES {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, cached)| cached.created)
+            .map(|(path, _)| path.clone());
+        match oldest {
+            Some(oldest) => cache.remove(&oldest),
+            None => break,
+        };
+    }
+}
+
+lazy_static! {
+    // zips of downloaded directories, so refreshing a download key doesn't zip them again
+    static ref ZIP_CACHE: Mutex<HashMap<PathBuf, CachedZip>> = Mutex::new(HashMap::new());
+}
+
+/// Latest modification time of `path` or anything in it
+///
+/// Removing a file changes the modification time of its directory, so removals are covered too
+fn latest_modified(path: &std::path::Path) -> Option<SystemTime> {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+        .max()
+}
+
+/// Zips `path` into a temporary directory.
+///
+/// With `reuse_cached`, the cached zip is reused if nothing in the directory changed
+/// since it was made, and the new zip is cached otherwise
+async fn zip_for_download(
+    path: &std::path::Path,
+    reuse_cached: bool,
+    zip_workers: usize,
+) -> Result<(PathBuf, Arc<TempDir>), Error> {
+    let modified = latest_modified(path);
+    if reuse_cached {
+        let mut cache = ZIP_CACHE.lock().await;
+        evict_cached_zips(&mut cache);
+        if let Some(cached) = cache.get(path) {
+            if Some(cached.modified) == modified && cached.zip_path.is_file() {
+                return Ok((cached.zip_path.clone(), cached.temp_dir.clone()));
+            }
+        }
+    }
+
+    let temp_dir =
+        tempfile::tempdir_in(path_to_tmp()).context("Failed to create temporary file")?;
+    let mut zip_path: PathBuf = temp_dir.path().into();
+    zip_path.push(path.file_name().ok_or_else(|| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Cannot download {}", path.display()),
+    })?);
+    zip_path.set_extension("zip");
+    zip_files_parallel_async(&[path], zip_path.clone(), true, zip_workers)
+        .await
+        .context("Failed to zip file")?;
+    let temp_dir = Arc::new(temp_dir);
+
+    if let (true, Some(modified)) = (reuse_cached, modified) {
+        let size = fs::metadata(&zip_path).map_or(0, |metadata| metadata.len());
+        let mut cache = ZIP_CACHE.lock().await;
+        cache.insert(
+            path.to_path_buf(),
+            CachedZip {
+                modified,
+                zip_path: zip_path.clone(),
+                temp_dir: temp_dir.clone(),
+                size,
+                created: Instant::now(),
+            },
+        );
+        evict_cached_zips(&mut cache);
+        // dropped even if no other download comes to evict it
+        tokio::spawn(async {
+            tokio::time::sleep(ZIP_CACHE_MAX_AGE).await;
+            evict_cached_zips(&mut *ZIP_CACHE.lock().await);
+        });
+    }
+    Ok((zip_path, temp_dir))
+}
+
+async fn issue_download_key(
+    state: &AppState,
+    base64_absolute_path: &str,
+    token: &str,
+    reuse_cached: bool,
 ) -> Result<String, Error> {
-    let absolute_path = decode_base64(&base64_absolute_path)?;
+    let absolute_path = decode_base64(base64_absolute_path)?;
     let requester = state
         .users_manager
         .read()
         .await
-        .try_auth(&token)
+        .try_auth(token)
         .ok_or_else(|| Error {
             kind: ErrorKind::Unauthorized,
             source: eyre!("Token error"),
@@ -416,17 +950,17 @@ async fn download_file(
     requester.try_action(&UserAction::ReadGlobalFile)?;
     let path = PathBuf::from(absolute_path);
     let downloadable_file_path: PathBuf;
-    let downloadable_file = if fs::metadata(path.clone()).unwrap().is_dir() {
-        let lodestone_tmp = path_to_tmp().clone();
-        let temp_dir =
-            tempfile::tempdir_in(lodestone_tmp).context("Failed to create temporary file")?;
-        let mut temp_file_path: PathBuf = temp_dir.path().into();
-        temp_file_path.push(path.file_name().unwrap());
-        temp_file_path.set_extension("zip");
-        let files = Vec::from([path.clone()]);
-        zip_files(&files, temp_file_path.clone(), true).context("Failed to zip file")?;
-        downloadable_file_path = temp_file_path.clone();
-        DownloadableFile::ZippedFile((downloadable_file_path.clone(), temp_dir))
+    let downloadable_file = if fs::metadata(&path)
+        .map_err(|_| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("{} does not exist", path.display()),
+        })?
+        .is_dir()
+    {
+        let zip_workers = state.global_settings.lock().await.zip_workers() as usize;
+        let (zip_path, temp_dir) = zip_for_download(&path, reuse_cached, zip_workers).await?;
+        downloadable_file_path = zip_path.clone();
+        DownloadableFile::ZippedFile((zip_path, temp_dir))
     } else {
         downloadable_file_path = path.clone();
         DownloadableFile::NormalFile(path.clone())
@@ -450,13 +984,31 @@ async fn download_file(
     Ok(key)
 }
 
+async fn download_file(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(base64_absolute_path): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<String, Error> {
+    issue_download_key(&state, &base64_absolute_path, &token, false).await
+}
+
+/// A new download key, reusing the zip of a directory if it didn't change since it was last zipped
+async fn refresh_download_file(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(base64_absolute_path): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<String, Error> {
+    issue_download_key(&state, &base64_absolute_path, &token, true).await
+}
+
 async fn upload_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(base64_absolute_path): Path<String>,
+    Query(query): Query<UploadQuery>,
     headers: HeaderMap,
     AuthBearer(token): AuthBearer,
     mut multipart: Multipart,
-) -> Result<Json<()>, Error> {
+) -> Result<Json<Vec<String>>, Error> {
     let absolute_path = decode_base64(&base64_absolute_path)?;
     let requester = state
         .users_manager
@@ -471,13 +1023,9 @@ async fn upload_file(
     requester.try_action(&UserAction::WriteGlobalFile)?;
 
     let path_to_dir = PathBuf::from(absolute_path);
+    let upload_rules = state.global_settings.lock().await.upload_rules();
 
-    tokio::fs::create_dir_all(&path_to_dir)
-        .await
-        .context(format!(
-            "Failed to create directory {}",
-            path_to_dir.display()
-        ))?;
+    prepare_upload_dir(&path_to_dir, &query).await?;
 
     let total = headers
         .get(CONTENT_LENGTH)
@@ -494,41 +1042,51 @@ async fn upload_file(
         },
     );
     state.event_broadcaster.send(progression_start_event);
+    let cancellation = state
+        .upload_cancellations
+        .register(event_id.clone(), requester.uid.clone());
 
+    let mut stored_paths = Vec::new();
     while let Ok(Some(mut field)) = multipart.next_field().await {
-        let name = field
-            .file_name()
-            .ok_or_else(|| Error {
+        let name = field.file_name().ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Missing file name"),
+        })?;
+        // a name like `../x` would otherwise land outside the destination
+        let name = sanitize_filename::sanitize(name);
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(Error {
                 kind: ErrorKind::BadRequest,
-                source: eyre!("Missing file name"),
-            })?
-            .to_owned();
-        let path = path_to_dir.join(&name);
-        let path = if path.exists() {
-            // add a postfix to the file name
-            let mut postfix = 1;
-            // get the file name without the extension
-            let file_name = path.file_stem().unwrap().to_str().unwrap().to_string();
-            loop {
-                let new_path = path.with_file_name(format!(
-                    "{}_{}.{}",
-                    file_name,
-                    postfix,
-                    path.extension().unwrap().to_str().unwrap()
-                ));
-                if !new_path.exists() {
-                    break new_path;
-                }
-                postfix += 1;
-            }
-        } else {
-            path
-        };
+                source: eyre!("Invalid file name"),
+            });
+        }
+        // add a postfix to the file name if it already exists
+        let path = resolve_path_conflict(path_to_dir.join(&name), None);
         let mut file = tokio::fs::File::create(&path)
             .await
             .context(format!("Failed to create file {}", path.display()))?;
+        let mut upload_head = UploadHead::new();
+        let mut rejected = None;
 
-        while let Some(chunk) = match field.chunk().await {
+        while let Some(chunk) = match tokio::select! {
+            chunk = field.chunk() => chunk,
+            _ = cancellation.cancelled() => {
+                drop(file);
+                tokio::fs::remove_file(&path).await.ok();
+                state
+                    .event_broadcaster
+                    .send(Event::new_progression_event_end(
+                        event_id,
+                        false,
+                        Some("Cancelled"),
+                        None,
+                    ));
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Upload cancelled"),
+                });
+            }
+        } {
             Ok(v) => v,
             Err(e) => {
                 tokio::fs::remove_file(&path).await.ok();
@@ -557,12 +1115,37 @@ async fn upload_file(
                 std::fs::remove_file(&path).ok();
                 eyre!("Failed to write chunk")
             })?;
+            if let Some(head) = upload_head.feed(&chunk) {
+                if let Err(e) = check_upload(&upload_rules, &path, head) {
+                    rejected = Some(e);
+                    break;
+                }
+            }
+        }
+        let rejected = rejected.or_else(|| {
+            upload_head
+                .finish()
+                .and_then(|head| check_upload(&upload_rules, &path, head).err())
+        });
+        if let Some(e) = rejected {
+            drop(file);
+            tokio::fs::remove_file(&path).await.ok();
+            state
+                .event_broadcaster
+                .send(Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(&e.to_string()),
+                    None,
+                ));
+            return Err(e);
         }
 
         let caused_by = CausedBy::User {
             user_id: requester.uid.clone(),
             user_name: requester.username.clone(),
         };
+        stored_paths.push(path.to_string_lossy().into_owned());
         state.event_broadcaster.send(new_fs_event(
             FSOperation::Upload,
             FSTarget::File(path),
@@ -578,7 +1161,112 @@ async fn upload_file(
             None,
         ));
 
-    Ok(Json(()))
+    Ok(Json(stored_paths))
+}
+
+#[derive(Deserialize)]
+struct ResumableUploadInit {
+    name: String,
+    /// Total size in bytes, chunks past it are rejected and the upload only completes once it is reached
+    size: u64,
+}
+
+#[derive(Deserialize)]
+struct ChunkQuery {
+    offset: u64,
+}
+
+/// Starts an upload into the directory at the path that is sent in chunks,
+/// so an interrupted upload can resume where it stopped
+async fn init_resumable_upload(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(base64_absolute_path): Path<String>,
+    Query(query): Query<UploadQuery>,
+    AuthBearer(token): AuthBearer,
+    Json(init): Json<ResumableUploadInit>,
+) -> Result<Json<ResumableUploadStatus>, Error> {
+    let path_to_dir = PathBuf::from(decode_base64(&base64_absolute_path)?);
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteGlobalFile)?;
+    // a name like `../x` would otherwise land outside the destination
+    let name = sanitize_filename::sanitize(&init.name);
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid file name"),
+        });
+    }
+    prepare_upload_dir(&path_to_dir, &query).await?;
+    let upload = ResumableUpload::create(requester.uid, path_to_dir, name, init.size).await?;
+    Ok(Json(upload.status().await?))
+}
+
+/// Appends the body to the upload, `offset` must be the offset from its status
+async fn upload_chunk(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((base64_absolute_path, id)): Path<(String, String)>,
+    Query(ChunkQuery { offset }): Query<ChunkQuery>,
+    AuthBearer(token): AuthBearer,
+    body: BodyStream,
+) -> Result<Json<ResumableUploadStatus>, Error> {
+    let path_to_dir = PathBuf::from(decode_base64(&base64_absolute_path)?);
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteGlobalFile)?;
+    let upload = ResumableUpload::open(&id, &requester, &path_to_dir).await?;
+    let upload_rules = state.global_settings.lock().await.upload_rules();
+    upload.append(offset, body, &upload_rules).await?;
+    Ok(Json(upload.status().await?))
+}
+
+/// The offset to resume an interrupted upload from
+async fn get_upload_status(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((base64_absolute_path, id)): Path<(String, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ResumableUploadStatus>, Error> {
+    let path_to_dir = PathBuf::from(decode_base64(&base64_absolute_path)?);
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteGlobalFile)?;
+    let upload = ResumableUpload::open(&id, &requester, &path_to_dir).await?;
+    Ok(Json(upload.status().await?))
+}
+
+/// Moves the uploaded file into its directory, returning the path it was stored at
+async fn complete_upload(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((base64_absolute_path, id)): Path<(String, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<String>, Error> {
+    let path_to_dir = PathBuf::from(decode_base64(&base64_absolute_path)?);
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteGlobalFile)?;
+    let upload = ResumableUpload::open(&id, &requester, &path_to_dir).await?;
+    let upload_rules = state.global_settings.lock().await.upload_rules();
+    let path = {
+        let _lock = state.path_locks.lock(&path_to_dir).await;
+        upload.complete(&upload_rules).await?
+    };
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Upload,
+        FSTarget::File(path.clone()),
+        caused_by,
+    ));
+    Ok(Json(path.to_string_lossy().into_owned()))
+}
+
+/// Stops an upload of either the global or an instance file system, the partially uploaded
+/// file is removed while the files it completed are kept
+async fn cancel_upload(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(event_id): Path<ProgressionEventID>,
+    AuthBearer(token): AuthBearer,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    state.upload_cancellations.cancel(&event_id, &requester)
 }
 
 async fn download(
@@ -592,9 +1280,18 @@ async fn download(
     Error,
 > {
     if let Some(downloadable_file) = state.download_urls.lock().await.get(&key) {
-        let path = match downloadable_file {
-            DownloadableFile::NormalFile(path) => path,
-            DownloadableFile::ZippedFile((path, _)) => path,
+        let (path, content_type) = match downloadable_file {
+            // lets the compression layer skip files that are already compressed
+            DownloadableFile::NormalFile(path) => (
+                path,
+                mime_guess::from_path(path)
+                    .first_or_octet_stream()
+                    .to_string(),
+            ),
+            DownloadableFile::ZippedFile((path, _)) => (path, "application/zip".to_string()),
+            DownloadableFile::TextFile((path, _)) => {
+                (path, "text/plain; charset=utf-8".to_string())
+            }
         };
 
         let file = tokio::fs::File::open(&path)
@@ -602,10 +1299,7 @@ async fn download(
             .context(format!("Failed to open file {}", path.display()))?;
 
         let headers = [
-            (
-                http::header::CONTENT_DISPOSITION,
-                "application/octet-stream".to_string(),
-            ),
+            (http::header::CONTENT_TYPE, content_type),
             (
                 http::header::CONTENT_DISPOSITION,
                 format!(
@@ -639,18 +1333,153 @@ async fn download(
 pub fn get_global_fs_routes(state: AppState) -> Router {
     Router::new()
         .route("/fs/:base64_absolute_path/ls", get(list_files))
+        .route("/fs/:base64_absolute_path/stat", get(stat_file))
+        .route("/fs/:base64_absolute_path/exists", get(file_exists))
         .route("/fs/:base64_absolute_path/read", get(read_file))
         .route("/fs/:base64_absolute_path/write", put(write_file))
         .route("/fs/:base64_absolute_path/mkdir", put(make_directory))
+        .route("/fs/:base64_absolute_path/chmod", put(chmod))
         .route(
-            "/fs/:base64_absolute_path/move/:base64_relative_path_dest",
+            "/fs/:base64_absolute_path/move/:base64_absolute_path_dest",
             put(move_file),
         )
         .route("/fs/:base64_absolute_path/rm", delete(remove_file))
         .route("/fs/:base64_absolute_path/rmdir", delete(remove_dir))
         .route("/fs/:base64_absolute_path/new", put(new_file))
         .route("/fs/:base64_absolute_path/download", get(download_file))
-        .route("/fs/:base64_absolute_path/upload", put(upload_file))
+        .route(
+            "/fs/:base64_absolute_path/download/refresh",
+            post(refresh_download_file),
+        )
+        // uploads are streamed to disk, so the default body limit doesn't apply
+        .route(
+            "/fs/:base64_absolute_path/upload",
+            put(upload_file).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/fs/upload/:event_id/cancel", post(cancel_upload))
+        .route(
+            "/fs/:base64_absolute_path/upload/init",
+            post(init_resumable_upload),
+        )
+        .route(
+            "/fs/:base64_absolute_path/upload/:id/chunk",
+            put(upload_chunk).layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/fs/:base64_absolute_path/upload/:id/status",
+            get(get_upload_status),
+        )
+        .route(
+            "/fs/:base64_absolute_path/upload/:id/complete",
+            post(complete_upload),
+        )
         .route("/file/:key", get(download))
+        .route("/fs/trash", get(get_trash))
+        .route("/fs/trash/:id/restore", post(restore_trash_entry))
+        .layer(
+            CompressionLayer::new()
+                .gzip(true)
+                .deflate(true)
+                .compress_when(
+                    // keeps the default exclusions of gRPC and images
+                    DefaultPredicate::new()
+                        .and(SizeAbove::new(COMPRESSION_MIN_SIZE))
+                        // archives are already compressed
+                        .and(NotForContentType::new("application/zip"))
+                        .and(NotForContentType::new("application/gzip"))
+                        .and(NotForContentType::new("application/java-archive"))
+                        .and(NotForContentType::new("application/x-7z-compressed"))
+                        .and(NotForContentType::new("application/x-rar-compressed"))
+                        .and(NotForContentType::new("application/x-compressed"))
+                        // unknown binaries, mostly compressed world data such as region files
+                        .and(NotForContentType::new("application/octet-stream"))
+                        .and(NotForContentType::new("audio/"))
+                        .and(NotForContentType::new("video/")),
+                ),
+        )
         .with_state(state)
 }
+
+#[test]
+fn test_parse_mode() {
+    assert_eq!(parse_mode("755").unwrap(), 0o755);
+    assert_eq!(parse_mode("4755").unwrap(), 0o4755);
+    assert!(parse_mode("75").is_err());
+    assert!(parse_mode("789").is_err());
+    assert!(parse_mode("+x").is_err());
+}
+
+#[test]
+fn test_to_unix_timestamp() {
+    use std::time::{Duration, UNIX_EPOCH};
+    assert_eq!(
+        to_unix_timestamp(UNIX_EPOCH + Duration::from_secs(42)),
+        Some(42)
+    );
+    assert_eq!(to_unix_timestamp(UNIX_EPOCH), Some(0));
+    assert_eq!(
+        to_unix_timestamp(UNIX_EPOCH - Duration::from_secs(42)),
+        None
+    );
+}
+
+#[test]
+fn test_apply_list_query() {
+    let entry = |name: &str, size: Option<u64>| FileEntry {
+        name: name.to_string(),
+        file_stem: name.to_string(),
+        extension: None,
+        path: name.to_string(),
+        size,
+        creation_time: None,
+        modification_time: None,
+        file_type: if size.is_some() {
+            FileType::File
+        } else {
+            FileType::Directory
+        },
+        mode: None,
+        symlink_target: None,
+    };
+    let names = |entries: Vec<FileEntry>| -> Vec<String> {
+        entries.into_iter().map(|entry| entry.name).collect()
+    };
+    let entries = || {
+        vec![
+            entry("b.log", Some(3)),
+            entry("logs", None),
+            entry("a.log", Some(5)),
+            entry("c.txt", Some(1)),
+        ]
+    };
+
+    assert_eq!(
+        names(apply_list_query(entries(), &ListQuery::default())),
+        ["b.log", "logs", "a.log", "c.txt"]
+    );
+    let query = ListQuery {
+        sort: Some(ListSort::Size),
+        order: ListOrder::Desc,
+        ..Default::default()
+    };
+    assert_eq!(
+        names(apply_list_query(entries(), &query)),
+        ["a.log", "b.log", "c.txt", "logs"]
+    );
+    let query = ListQuery {
+        dirs_first: true,
+        ..query
+    };
+    assert_eq!(
+        names(apply_list_query(entries(), &query)),
+        ["logs", "a.log", "b.log", "c.txt"]
+    );
+    let query = ListQuery {
+        sort: Some(ListSort::Name),
+        filter: Some("*.log".to_string()),
+        offset: 1,
+        limit: Some(1),
+        ..Default::default()
+    };
+    assert_eq!(names(apply_list_query(entries(), &query)), ["b.log"]);
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use axum::{
     routing::{get, put},
     Json, Router,
@@ -5,7 +7,22 @@ use axum::{
 use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
 
-use crate::{error::ErrorKind, AppState, Error, GlobalSettingsData};
+use serde::Deserialize;
+
+use crate::{
+    auth::permission::InstancePermissionTemplate,
+    cors::CorsSettings,
+    error::ErrorKind,
+    events::{CausedBy, CoreEvent, CoreEventInner, Event, EventInner},
+    global_settings::GlobalSettingsUpdate,
+    macro_executor::MacroLimitPolicy,
+    orphans::OrphanPolicy,
+    port_manager::PortRange,
+    tls::TlsSettings,
+    types::Snowflake,
+    upload_filter::UploadRule,
+    AppState, Error, GlobalSettingsData,
+};
 
 pub async fn get_core_settings(
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -24,98 +41,628 @@ pub async fn get_core_settings(
     Ok(Json(state.global_settings.lock().await.as_ref().clone()))
 }
 
+async fn set_core_name(state: &AppState, token: &str, new_name: &str) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(token)?;
+
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change core name"),
+        });
+    }
+    let new_name = {
+        let mut global_settings = state.global_settings.lock().await;
+        global_settings.set_core_name(new_name.to_string()).await?;
+        // trimmed when set
+        global_settings.core_name()
+    };
+    state.event_broadcaster.send(Event {
+        event_inner: EventInner::CoreEvent(CoreEvent {
+            core_event_inner: CoreEventInner::CoreNameChanged {
+                name: new_name.clone(),
+            },
+        }),
+        snowflake: Snowflake::default(),
+        details: format!("Core renamed to {}", new_name),
+        caused_by: CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    });
+    Ok(())
+}
+
 pub async fn change_core_name(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
     Json(new_name): Json<String>,
+) -> Result<(), Error> {
+    set_core_name(&state, &token, &new_name).await
+}
+
+#[derive(Deserialize)]
+pub struct CoreNameRequest {
+    pub name: String,
+}
+
+pub async fn change_core_name_setting(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(CoreNameRequest { name }): Json<CoreNameRequest>,
+) -> Result<(), Error> {
+    set_core_name(&state, &token, &name).await
+}
+
+pub async fn change_core_safe_mode(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(safe_mode): Json<bool>,
 ) -> Result<(), Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
 
     if !requester.is_owner {
         return Err(Error {
             kind: ErrorKind::PermissionDenied,
-            source: eyre!("Not authorized to change core name"),
+            source: eyre!("Not authorized to change core safe mode"),
         });
     }
-    if new_name.len() > 32 {
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_safe_mode(safe_mode)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_domain(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(new_domain): Json<String>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
         return Err(Error {
-            kind: ErrorKind::BadRequest,
-            source: eyre!("Name too long"),
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change core domain"),
         });
     }
-    if new_name.is_empty() {
+    // an empty domain removes it
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_domain(Some(new_domain))
+        .await?;
+    Ok(())
+}
+
+pub async fn change_soft_delete(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(soft_delete): Json<bool>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
         return Err(Error {
-            kind: ErrorKind::BadRequest,
-            source: eyre!("Name cannot be empty"),
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change soft delete"),
         });
     }
     state
         .global_settings
         .lock()
         .await
-        .set_core_name(new_name)
+        .set_soft_delete(soft_delete)
         .await?;
     Ok(())
 }
 
-pub async fn change_core_safe_mode(
+pub async fn change_trash_retention_days(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
-    Json(safe_mode): Json<bool>,
+    Json(days): Json<u32>,
 ) -> Result<(), Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change trash retention"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_trash_retention_days(days)
+        .await?;
+    Ok(())
+}
 
+pub async fn change_instance_stop_timeout(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(seconds): Json<u32>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     if !requester.is_owner {
         return Err(Error {
             kind: ErrorKind::PermissionDenied,
-            source: eyre!("Not authorized to change core safe mode"),
+            source: eyre!("Not authorized to change instance stop timeout"),
         });
     }
     state
         .global_settings
         .lock()
         .await
-        .set_safe_mode(safe_mode)
+        .set_instance_stop_timeout(seconds)
         .await?;
     Ok(())
 }
 
-pub async fn change_domain(
+pub async fn change_max_concurrent_macros(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
-    Json(new_domain): Json<String>,
+    Json(max): Json<u32>,
 ) -> Result<(), Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     if !requester.is_owner {
         return Err(Error {
             kind: ErrorKind::PermissionDenied,
-            source: eyre!("Not authorized to change core domain"),
+            source: eyre!("Not authorized to change max concurrent macros"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_max_concurrent_macros(max)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_macro_limit_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(policy): Json<MacroLimitPolicy>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change macro limit policy"),
         });
     }
-    if new_domain.len() > 253 {
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_macro_limit_policy(policy)
+        .await?;
+    Ok(())
+}
+
+/// Applied the next time the core starts
+pub async fn change_orphan_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(policy): Json<OrphanPolicy>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
         return Err(Error {
-            kind: ErrorKind::BadRequest,
-            source: eyre!("Domain too long"),
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change orphan policy"),
         });
     }
     state
         .global_settings
         .lock()
         .await
-        .set_domain(if new_domain.is_empty() {
-            None
-        } else {
-            Some(new_domain)
-        })
+        .set_orphan_policy(policy)
         .await?;
     Ok(())
 }
 
+pub async fn change_macro_history_size(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(size): Json<u32>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change macro history size"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_macro_history_size(size)
+        .await?;
+    state
+        .macro_executor
+        .set_exit_status_retention(size as usize);
+    Ok(())
+}
+
+pub async fn change_macro_max_heap_mb(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(max_heap_mb): Json<u32>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change macro heap limit"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_macro_max_heap_mb(max_heap_mb)
+        .await?;
+    state.macro_executor.set_max_heap_mb(max_heap_mb as u64);
+    Ok(())
+}
+
+pub async fn change_upload_rules(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(rules): Json<Vec<UploadRule>>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change upload rules"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_upload_rules(rules)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_unrestricted_macro_imports(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(unrestricted): Json<bool>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change macro import restrictions"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_unrestricted_macro_imports(unrestricted)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_max_write_size(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(bytes): Json<u64>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change max write size"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_max_write_size(bytes)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_zip_workers(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(workers): Json<u32>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change zip workers"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_zip_workers(workers)
+        .await?;
+    Ok(())
+}
+
+/// Takes effect after the core restarts
+/// Takes effect after the core restarts, the CORS layer is only built at startup
+pub async fn change_cors(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(cors): Json<CorsSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change CORS settings"),
+        });
+    }
+    state.global_settings.lock().await.set_cors(cors).await?;
+    Ok(())
+}
+
+/// `null` disables update checks, a new interval takes effect after the current wait
+pub async fn change_update_check_interval(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(hours): Json<Option<u32>>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the update check interval"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_update_check_interval_hours(hours)
+        .await?;
+    Ok(())
+}
+
+/// `null` lets instances use any port, instances outside a new range keep their ports
+pub async fn change_port_range(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(range): Json<Option<PortRange>>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the port range"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_port_range(range)
+        .await?;
+    state.port_manager.lock().await.set_range(range);
+    Ok(())
+}
+
+/// Takes effect after the core restarts, `null` goes back to the default directory
+///
+/// Existing instances aren't moved, so they have to be moved over while the core is stopped
+pub async fn change_instances_path(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(path): Json<Option<PathBuf>>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the instances path"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_instances_path(path)
+        .await?;
+    Ok(())
+}
+
+/// The certificate is swapped right away if TLS is already enabled,
+/// enabling or disabling TLS takes effect after the core restarts
+pub async fn change_tls(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(tls): Json<Option<TlsSettings>>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change TLS settings"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_tls(tls, state.tls_config.as_ref())
+        .await?;
+    Ok(())
+}
+
+pub async fn change_instance_permission_template(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(template): Json<InstancePermissionTemplate>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the instance permission template"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_instance_permission_template(template)
+        .await?;
+    Ok(())
+}
+
+/// Every global setting, unlike `GET /global_settings` only the owner can read them
+pub async fn get_settings(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<GlobalSettingsData>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to read global settings"),
+        });
+    }
+    Ok(Json(state.global_settings.lock().await.as_ref().clone()))
+}
+
+/// Applies a partial update of the global settings, nothing is changed if any field is invalid
+///
+/// Settings that take effect after a restart through their own endpoints still do
+pub async fn update_settings(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(update): Json<GlobalSettingsUpdate>,
+) -> Result<Json<GlobalSettingsData>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change global settings"),
+        });
+    }
+    let fields = update.fields();
+    let mut global_settings = state.global_settings.lock().await;
+    let old_settings = global_settings
+        .update(update, state.tls_config.as_ref())
+        .await?;
+    let new_settings = global_settings.as_ref().clone();
+    if new_settings.port_range != old_settings.port_range {
+        state
+            .port_manager
+            .lock()
+            .await
+            .set_range(new_settings.port_range);
+    }
+    state
+        .macro_executor
+        .set_exit_status_retention(new_settings.macro_history_size as usize);
+    state
+        .macro_executor
+        .set_max_heap_mb(new_settings.macro_max_heap_mb as u64);
+    drop(global_settings);
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    if new_settings.core_name != old_settings.core_name {
+        state.event_broadcaster.send(Event {
+            event_inner: EventInner::CoreEvent(CoreEvent {
+                core_event_inner: CoreEventInner::CoreNameChanged {
+                    name: new_settings.core_name.clone(),
+                },
+            }),
+            snowflake: Snowflake::default(),
+            details: format!("Core renamed to {}", new_settings.core_name),
+            caused_by: caused_by.clone(),
+        });
+    }
+    if !fields.is_empty() {
+        state.event_broadcaster.send(Event {
+            details: format!("Global settings changed: {}", fields.join(", ")),
+            event_inner: EventInner::CoreEvent(CoreEvent {
+                core_event_inner: CoreEventInner::SettingsChanged { fields },
+            }),
+            snowflake: Snowflake::default(),
+            caused_by,
+        });
+    }
+    Ok(Json(new_settings))
+}
+
 pub fn get_global_settings_routes(state: AppState) -> Router {
     Router::new()
         .route("/global_settings", get(get_core_settings))
+        .route("/settings", get(get_settings).put(update_settings))
         .route("/global_settings/name", put(change_core_name))
+        .route("/settings/core_name", put(change_core_name_setting))
         .route("/global_settings/safe_mode", put(change_core_safe_mode))
         .route("/global_settings/domain", put(change_domain))
+        .route("/global_settings/soft_delete", put(change_soft_delete))
+        .route(
+            "/global_settings/trash_retention_days",
+            put(change_trash_retention_days),
+        )
+        .route(
+            "/global_settings/instance_stop_timeout",
+            put(change_instance_stop_timeout),
+        )
+        .route(
+            "/global_settings/max_concurrent_macros",
+            put(change_max_concurrent_macros),
+        )
+        .route(
+            "/global_settings/macro_limit_policy",
+            put(change_macro_limit_policy),
+        )
+        .route("/global_settings/orphan_policy", put(change_orphan_policy))
+        .route(
+            "/global_settings/macro_history_size",
+            put(change_macro_history_size),
+        )
+        .route(
+            "/global_settings/macro_max_heap_mb",
+            put(change_macro_max_heap_mb),
+        )
+        .route("/global_settings/upload_rules", put(change_upload_rules))
+        .route(
+            "/global_settings/unrestricted_macro_imports",
+            put(change_unrestricted_macro_imports),
+        )
+        .route(
+            "/global_settings/max_write_size",
+            put(change_max_write_size),
+        )
+        .route("/global_settings/zip_workers", put(change_zip_workers))
+        .route("/global_settings/cors", put(change_cors))
+        .route(
+            "/global_settings/update_check_interval",
+            put(change_update_check_interval),
+        )
+        .route("/global_settings/port_range", put(change_port_range))
+        .route(
+            "/global_settings/instances_path",
+            put(change_instances_path),
+        )
+        .route("/global_settings/tls", put(change_tls))
+        .route(
+            "/global_settings/instance_permission_template",
+            put(change_instance_permission_template),
+        )
         .with_state(state)
 }
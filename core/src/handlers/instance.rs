@@ -1,29 +1,252 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
 use axum::routing::{delete, get, post};
 use axum::Router;
-use axum::{extract::Path, Json};
+use axum::{
+    extract::{Path, Query},
+    Json,
+};
 use axum_auth::AuthBearer;
 
 use color_eyre::eyre::{eyre, Context};
+use headers::HeaderMap;
+use lazy_static::lazy_static;
 use serde::Deserialize;
-use tracing::error;
+use tracing::{error, warn};
 
-use crate::auth::user::UserAction;
+use crate::auth::permission::InstancePermissionTemplate;
+use crate::auth::user::{User, UserAction};
+use crate::auth::user_id::UserId;
 use crate::error::{Error, ErrorKind};
-use crate::events::{CausedBy, Event, ProgressionEndValue, ProgressionStartValue};
+use crate::events::{
+    CausedBy, Event, ProgressionEndValue, ProgressionEventID, ProgressionStartValue,
+};
 
 use crate::implementations::generic;
 use crate::traits::t_configurable::GameType;
 
-use crate::implementations::minecraft::MinecraftInstance;
-use crate::prelude::{path_to_instances, GameInstance};
+use crate::implementations::minecraft::env::path_to_dotenv;
+use crate::implementations::minecraft::modpack::Modpack;
+use crate::implementations::minecraft::{CreationPlan, MinecraftInstance, SetupConfig};
+use crate::prelude::{path_to_instances, path_to_tmp, GameInstance};
 use crate::traits::t_configurable::manifest::SetupValue;
 use crate::traits::{t_configurable::TConfigurable, t_server::TServer, InstanceInfo, TInstance};
 
 use crate::types::{DotLodestoneConfig, InstanceUuid};
+use crate::util::download_file;
 use crate::{implementations::minecraft, traits::t_server::State, AppState};
 
 use super::instance_setup_configs::HandlerGameType;
 
+/// How long a repeated creation request with the same `Idempotency-Key` returns the same instance
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(60 * 60);
+
+lazy_static! {
+    // instances created by recent requests with an `Idempotency-Key`, keyed by who sent them
+    static ref IDEMPOTENCY_KEYS: Mutex<HashMap<(UserId, String), (Instant, InstanceUuid)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Holds an `Idempotency-Key` for a creation request, released if the request fails
+/// before the instance is created so a retry can try again
+struct IdempotencyClaim {
+    key: Option<(UserId, String)>,
+}
+
+impl IdempotencyClaim {
+    /// `Err` with the instance created by an earlier request with the same key
+    fn claim(
+        headers: &HeaderMap,
+        uid: &UserId,
+        instance_uuid: &InstanceUuid,
+    ) -> Result<Self, InstanceUuid> {
+        let key = match headers
+            .get("Idempotency-Key")
+            .and_then(|key| key.to_str().ok())
+        {
+            Some(key) if !key.is_empty() => (uid.clone(), key.to_string()),
+            _ => return Ok(Self { key: None }),
+        };
+        let mut keys = IDEMPOTENCY_KEYS.lock().unwrap();
+        keys.retain(|_, (claimed_at, _)| claimed_at.elapsed() < IDEMPOTENCY_KEY_TTL);
+        if let Some((_, existing)) = keys.get(&key) {
+            return Err(existing.clone());
+        }
+        keys.insert(key.clone(), (Instant::now(), instance_uuid.clone()));
+        Ok(Self { key: Some(key) })
+    }
+
+    /// Keeps the key for `IDEMPOTENCY_KEY_TTL`
+    fn commit(mut self) {
+        self.key = None;
+    }
+}
+
+impl Drop for IdempotencyClaim {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            IDEMPOTENCY_KEYS.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreationQuery {
+    /// JSON encoded `InstancePermissionTemplate` used instead of the configured one, requires managing permissions
+    permission_template: Option<String>,
+}
+
+/// The template of the request if it has one, which requires managing permissions
+/// as it can grant the creator and other users any instance action
+async fn resolve_permission_template(
+    state: &AppState,
+    requester: &User,
+    query: &CreationQuery,
+) -> Result<InstancePermissionTemplate, Error> {
+    let template = match &query.permission_template {
+        Some(template) => template,
+        None => {
+            let global_settings = state.global_settings.lock().await;
+            return Ok(global_settings.instance_permission_template());
+        }
+    };
+    requester.try_action(&UserAction::ManagePermission)?;
+    let template: InstancePermissionTemplate =
+        serde_json::from_str(template).map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid permission template : {e}"),
+        })?;
+    template.validate()?;
+    Ok(template)
+}
+
+/// Grants the creator and the users of the template their actions on a new instance
+async fn grant_instance_permissions(
+    state: &AppState,
+    creator: &UserId,
+    template: &InstancePermissionTemplate,
+    instance_uuid: &InstanceUuid,
+) {
+    let grants = std::iter::once((creator, template.creator_actions())).chain(
+        template
+            .users
+            .iter()
+            .map(|(uid, kinds)| (uid, kinds.clone())),
+    );
+    let mut users_manager = state.users_manager.write().await;
+    for (uid, kinds) in grants {
+        let mut permissions = match users_manager.get_user(uid) {
+            Some(user) => user.permissions,
+            None => {
+                warn!("User {} of the permission template doesn't exist", uid);
+                continue;
+            }
+        };
+        for kind in kinds {
+            permissions.grant_instance_action(kind, instance_uuid);
+        }
+        // ignore errors since we don't care if the permissions update fails
+        let _ = users_manager
+            .update_permissions(uid, permissions, CausedBy::System)
+            .await
+            .map_err(|e| {
+                error!("Failed to update permissions: {:?}", e);
+                e
+            });
+    }
+}
+
+/// A uuid whose 8 character prefix, used in the instance's directory name, isn't taken
+fn new_instance_uuid(state: &AppState) -> InstanceUuid {
+    let mut instance_uuid = InstanceUuid::default();
+
+    for entry in state.instances.iter() {
+        if let Some(uuid) = entry.key().as_ref().get(0..8) {
+            if uuid == &instance_uuid.no_prefix()[0..8] {
+                instance_uuid = InstanceUuid::default();
+            }
+        }
+    }
+
+    instance_uuid
+}
+
+/// Sets up a Minecraft instance in the background with `setup`, reporting under a progression event
+///
+/// The instance is registered and the template's permissions granted if it succeeds,
+/// otherwise `setup_path` is removed and `port` released
+#[allow(clippy::too_many_arguments)]
+fn spawn_minecraft_setup<F, Fut>(
+    state: AppState,
+    requester: User,
+    permission_template: InstancePermissionTemplate,
+    instance_uuid: InstanceUuid,
+    title: String,
+    total: f64,
+    setup_path: PathBuf,
+    port: u32,
+    setup: F,
+) where
+    F: FnOnce(ProgressionEventID) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<MinecraftInstance, Error>> + Send + 'static,
+{
+    tokio::task::spawn(async move {
+        let event_broadcaster = state.event_broadcaster.clone();
+        let (progression_start_event, event_id) = Event::new_progression_event_start(
+            title,
+            Some(total),
+            Some(ProgressionStartValue::InstanceCreation {
+                instance_uuid: instance_uuid.clone(),
+            }),
+            CausedBy::User {
+                user_id: requester.uid.clone(),
+                user_name: requester.username.clone(),
+            },
+        );
+        event_broadcaster.send(progression_start_event);
+        let minecraft_instance = match setup(event_id.clone()).await {
+            Ok(v) => {
+                event_broadcaster.send(Event::new_progression_event_end(
+                    event_id,
+                    true,
+                    Some("Instance created successfully"),
+                    Some(ProgressionEndValue::InstanceCreation(
+                        v.get_instance_info().await,
+                    )),
+                ));
+                v
+            }
+            Err(e) => {
+                event_broadcaster.send(Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(&format!("Instance creation failed: {e}")),
+                    None,
+                ));
+                state.port_manager.lock().await.deallocate(port);
+                if setup_path.exists() {
+                    if let Err(e) = crate::util::fs::remove_dir_all(setup_path).await {
+                        error!(
+                            "Failed to remove directory after instance creation failed: {}",
+                            e
+                        );
+                    }
+                }
+                return;
+            }
+        };
+        grant_instance_permissions(&state, &requester.uid, &permission_template, &instance_uuid)
+            .await;
+        state
+            .instances
+            .insert(instance_uuid, minecraft_instance.into());
+    });
+}
+
 pub async fn get_instance_list(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -58,31 +281,35 @@ pub async fn get_instance_info(
     Ok(Json(instance.get_instance_info().await))
 }
 
+/// A retried request with the same `Idempotency-Key` header returns the instance already being created
 pub async fn create_minecraft_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
     Path(game_type): Path<HandlerGameType>,
+    Query(query): Query<CreationQuery>,
+    headers: HeaderMap,
     Json(manifest_value): Json<SetupValue>,
 ) -> Result<Json<InstanceUuid>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     requester.try_action(&UserAction::CreateInstance)?;
-    let mut perm = requester.permissions;
-
-    let mut instance_uuid = InstanceUuid::default();
+    let permission_template = resolve_permission_template(&state, &requester, &query).await?;
 
-    for entry in state.instances.iter() {
-        if let Some(uuid) = entry.key().as_ref().get(0..8) {
-            if uuid == &instance_uuid.no_prefix()[0..8] {
-                instance_uuid = InstanceUuid::default();
-            }
-        }
-    }
-
-    let instance_uuid = instance_uuid;
+    let instance_uuid = new_instance_uuid(&state);
+    let idempotency = match IdempotencyClaim::claim(&headers, &requester.uid, &instance_uuid) {
+        Ok(claim) => claim,
+        Err(existing) => return Ok(Json(existing)),
+    };
 
     let flavour = game_type.try_into()?;
 
     let setup_config = MinecraftInstance::construct_setup_config(manifest_value, flavour).await?;
+    state
+        .port_manager
+        .lock()
+        .await
+        .check_in_range(setup_config.port)?;
+
+    let plan = MinecraftInstance::plan_creation(&setup_config).await?;
 
     let setup_path = path_to_instances().join(format!(
         "{}-{}",
@@ -90,97 +317,244 @@ pub async fn create_minecraft_instance(
         &instance_uuid.no_prefix()[0..8]
     ));
 
-    tokio::fs::create_dir_all(&setup_path)
-        .await
-        .context("Failed to create instance directory")?;
+    // the jar may be a modded installer that downloads more, which the buffer should cover
+    crate::util::check_disk_space(
+        path_to_instances(),
+        plan.required_disk_space.unwrap_or_default(),
+    )?;
+
+    {
+        let mut port_manager = state.port_manager.lock().await;
+        if port_manager.port_status(setup_config.port).is_allocated {
+            return Err(Error {
+                kind: ErrorKind::Conflict,
+                source: eyre!("Port {} is already allocated", setup_config.port),
+            });
+        }
+        port_manager.add_port(setup_config.port);
+    }
 
-    let dot_lodestone_config = DotLodestoneConfig::new(instance_uuid.clone(), game_type.into());
+    spawn_minecraft_setup(
+        state.clone(),
+        requester,
+        permission_template,
+        instance_uuid.clone(),
+        format!("Setting up Minecraft server {}", setup_config.name),
+        10.0,
+        setup_path.clone(),
+        setup_config.port,
+        {
+            let instance_uuid = instance_uuid.clone();
+            move |event_id| async move {
+                tokio::fs::create_dir_all(&setup_path)
+                    .await
+                    .context("Failed to create instance directory")?;
+
+                let dot_lodestone_config = DotLodestoneConfig::new(instance_uuid, game_type.into());
+
+                // write dot lodestone config
+
+                crate::util::fs::write_atomic(
+                    setup_path.join(".lodestone_config"),
+                    serde_json::to_string_pretty(&dot_lodestone_config).unwrap(),
+                )
+                .await?;
+
+                minecraft::MinecraftInstance::new(
+                    setup_config,
+                    plan,
+                    dot_lodestone_config,
+                    setup_path,
+                    &event_id,
+                    state.event_broadcaster.clone(),
+                    state.macro_executor.clone(),
+                )
+                .await
+            }
+        },
+    );
+    idempotency.commit();
+    Ok(Json(instance_uuid))
+}
 
-    // write dot lodestone config
+pub async fn preview_minecraft_instance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Path(game_type): Path<HandlerGameType>,
+    Json(manifest_value): Json<SetupValue>,
+) -> Result<Json<CreationPlan>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::CreateInstance)?;
 
-    tokio::fs::write(
-        setup_path.join(".lodestone_config"),
-        serde_json::to_string_pretty(&dot_lodestone_config).unwrap(),
+    let setup_config =
+        MinecraftInstance::construct_setup_config(manifest_value, game_type.try_into()?).await?;
+
+    MinecraftInstance::plan_creation(&setup_config)
+        .await
+        .map(Json)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModpackSetupConfig {
+    url: String,
+    name: String,
+}
+
+/// Downloads the pack at `url` and sets up an instance from it in `setup_path`
+///
+/// The caller is responsible for removing `setup_path` if this fails
+async fn setup_modpack_instance(
+    state: &AppState,
+    url: &str,
+    name: String,
+    port: u32,
+    instance_uuid: &InstanceUuid,
+    setup_path: &std::path::Path,
+    event_id: &ProgressionEventID,
+) -> Result<MinecraftInstance, Error> {
+    state
+        .event_broadcaster
+        .send(Event::new_progression_event_update(
+            event_id,
+            "Downloading modpack",
+            1.0,
+        ));
+    let path_to_pack = download_file(
+        url,
+        path_to_tmp(),
+        Some(&format!("modpack-{}.zip", instance_uuid.no_prefix())),
+        &|_| {},
+        true,
     )
     .await
-    .context("Failed to write .lodestone_config file")?;
+    .context("Failed to download modpack")?;
 
-    tokio::task::spawn({
-        let uuid = instance_uuid.clone();
-        let instance_name = setup_config.name.clone();
-        let event_broadcaster = state.event_broadcaster.clone();
-        let caused_by = CausedBy::User {
-            user_id: requester.uid.clone(),
-            user_name: requester.username.clone(),
+    let result = async {
+        let modpack = tokio::task::spawn_blocking({
+            let path_to_pack = path_to_pack.clone();
+            move || Modpack::read(&path_to_pack)
+        })
+        .await
+        .context("Failed to read modpack")??;
+
+        let setup_config = SetupConfig {
+            name,
+            version: modpack.version.clone(),
+            flavour: modpack.flavour.clone(),
+            port,
+            cmd_args: Vec::new(),
+            description: Some(format!("Created from the {} modpack", modpack.name)),
+            min_ram: Some(1024),
+            max_ram: Some(2048),
+            auto_start: Some(false),
+            restart_on_crash: Some(false),
+            backup_period: None,
         };
-        async move {
-            let (progression_start_event, event_id) = Event::new_progression_event_start(
-                format!("Setting up Minecraft server {instance_name}"),
-                Some(10.0),
-                Some(ProgressionStartValue::InstanceCreation {
-                    instance_uuid: uuid.clone(),
-                }),
-                caused_by,
-            );
-            event_broadcaster.send(progression_start_event);
-            let minecraft_instance = match minecraft::MinecraftInstance::new(
-                setup_config.clone(),
-                dot_lodestone_config,
-                setup_path.clone(),
-                &event_id,
-                state.event_broadcaster.clone(),
-                state.macro_executor.clone(),
-            )
+        let plan = MinecraftInstance::plan_creation(&setup_config).await?;
+        crate::util::check_disk_space(
+            path_to_instances(),
+            plan.required_disk_space.unwrap_or_default(),
+        )?;
+
+        tokio::fs::create_dir_all(setup_path)
             .await
-            {
-                Ok(v) => {
-                    event_broadcaster.send(Event::new_progression_event_end(
-                        event_id,
-                        true,
-                        Some("Instance created successfully"),
-                        Some(ProgressionEndValue::InstanceCreation(
-                            v.get_instance_info().await,
-                        )),
-                    ));
-                    v
-                }
-                Err(e) => {
-                    event_broadcaster.send(Event::new_progression_event_end(
-                        event_id,
-                        false,
-                        Some(&format!("Instance creation failed: {e}")),
-                        None,
-                    ));
-                    crate::util::fs::remove_dir_all(setup_path)
-                        .await
-                        .context("Failed to remove directory after instance creation failed")
-                        .unwrap();
-                    return;
-                }
-            };
-            let mut port_manager = state.port_manager.lock().await;
-            port_manager.add_port(setup_config.port);
-            perm.can_start_instance.insert(uuid.clone());
-            perm.can_stop_instance.insert(uuid.clone());
-            perm.can_view_instance.insert(uuid.clone());
-            perm.can_read_instance_file.insert(uuid.clone());
-            perm.can_write_instance_file.insert(uuid.clone());
-            // ignore errors since we don't care if the permissions update fails
-            let _ = state
-                .users_manager
-                .write()
-                .await
-                .update_permissions(&requester.uid, perm, CausedBy::System)
-                .await
-                .map_err(|e| {
-                    error!("Failed to update permissions: {:?}", e);
-                    e
-                });
-            state
-                .instances
-                .insert(uuid.clone(), minecraft_instance.into());
+            .context("Failed to create instance directory")?;
+        let dot_lodestone_config =
+            DotLodestoneConfig::new(instance_uuid.clone(), GameType::MinecraftJava);
+        crate::util::fs::write_atomic(
+            setup_path.join(".lodestone_config"),
+            serde_json::to_string_pretty(&dot_lodestone_config).unwrap(),
+        )
+        .await?;
+
+        state
+            .event_broadcaster
+            .send(Event::new_progression_event_update(
+                event_id,
+                format!("Installing {} files from modpack", modpack.files.len()),
+                1.0,
+            ));
+        modpack.install(&path_to_pack, setup_path).await?;
+
+        MinecraftInstance::new(
+            setup_config,
+            plan,
+            dot_lodestone_config,
+            setup_path.to_owned(),
+            event_id,
+            state.event_broadcaster.clone(),
+            state.macro_executor.clone(),
+        )
+        .await
+    }
+    .await;
+
+    if let Err(e) = crate::util::fs::remove_file(&path_to_pack).await {
+        error!("Failed to remove downloaded modpack: {}", e);
+    }
+    result
+}
+
+/// Creates a Minecraft instance from a Modrinth or CurseForge pack archive at `url`
+///
+/// A retried request with the same `Idempotency-Key` header returns the instance already being created
+pub async fn create_instance_from_modpack(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<CreationQuery>,
+    headers: HeaderMap,
+    Json(ModpackSetupConfig { url, name }): Json<ModpackSetupConfig>,
+) -> Result<Json<InstanceUuid>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::CreateInstance)?;
+    let permission_template = resolve_permission_template(&state, &requester, &query).await?;
+
+    match url::Url::parse(&url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+        _ => {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid modpack URL {}", url),
+            })
         }
-    });
+    }
+
+    let instance_uuid = new_instance_uuid(&state);
+    let idempotency = match IdempotencyClaim::claim(&headers, &requester.uid, &instance_uuid) {
+        Ok(claim) => claim,
+        Err(existing) => return Ok(Json(existing)),
+    };
+
+    let setup_path =
+        path_to_instances().join(format!("{}-{}", name, &instance_uuid.no_prefix()[0..8]));
+    let port = state.port_manager.lock().await.allocate(25565)?;
+
+    spawn_minecraft_setup(
+        state.clone(),
+        requester,
+        permission_template,
+        instance_uuid.clone(),
+        format!("Setting up Minecraft server {name} from modpack"),
+        12.0,
+        setup_path.clone(),
+        port,
+        {
+            let instance_uuid = instance_uuid.clone();
+            move |event_id| async move {
+                setup_modpack_instance(
+                    &state,
+                    &url,
+                    name,
+                    port,
+                    &instance_uuid,
+                    &setup_path,
+                    &event_id,
+                )
+                .await
+            }
+        },
+    );
+    idempotency.commit();
     Ok(Json(instance_uuid))
 }
 
@@ -222,12 +596,11 @@ pub async fn create_generic_instance(
 
     // write dot lodestone config
 
-    tokio::fs::write(
+    crate::util::fs::write_atomic(
         setup_path.join(".lodestone_config"),
         serde_json::to_string_pretty(&dot_lodestone_config).unwrap(),
     )
-    .await
-    .context("Failed to write .lodestone_config file")?;
+    .await?;
 
     let instance = generic::GenericInstance::new(
         setup_config.url,
@@ -298,6 +671,8 @@ pub async fn delete_instance(
                 i.destruct().await;
             };
             let res = crate::util::fs::remove_dir_all(instance_path).await;
+            // kept in the stores, outside of the instance directory
+            let _ = tokio::fs::remove_file(path_to_dotenv(&uuid)).await;
             match &res {
                 Ok(_) => event_broadcaster.send(Event::new_progression_event_end(
                     event_id,
@@ -335,7 +710,15 @@ pub fn get_instance_routes(state: AppState) -> Router {
             "/instance/create/:game_type",
             post(create_minecraft_instance),
         )
+        .route(
+            "/instance/create/:game_type/preview",
+            post(preview_minecraft_instance),
+        )
         .route("/instance/create_generic", post(create_generic_instance))
+        .route(
+            "/instance/create_from_modpack",
+            post(create_instance_from_modpack),
+        )
         .route("/instance/:uuid", delete(delete_instance))
         .route("/instance/:uuid/info", get(get_instance_info))
         .with_state(state)
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use axum::{
     extract::Path,
     routing::{get, put},
@@ -9,6 +11,15 @@ use color_eyre::eyre::eyre;
 use crate::{
     auth::user::UserAction,
     error::{Error, ErrorKind},
+    implementations::minecraft::{
+        config::{MinecraftInstanceConfig, MinecraftInstanceConfigUpdate},
+        console_log::ConsoleLogRotation,
+        env::{DotEnvEntry, InstanceEnv},
+        idle_shutdown::{IdleShutdown, IdleStatus},
+        jvm_flags::JvmFlagsPreset,
+        startup_progress::StartupMilestone,
+    },
+    prelude::GameInstance,
     traits::t_configurable::{
         manifest::{ConfigurableManifest, ConfigurableValue},
         TConfigurable,
@@ -57,6 +68,14 @@ pub async fn set_instance_setting(
         kind: ErrorKind::NotFound,
         source: eyre!("Instance not found"),
     })?;
+    // the server port of a Minecraft instance, held to the range reserved for instances
+    if section_id == "server_properties_section" && setting_id == "server-port" {
+        state
+            .port_manager
+            .lock()
+            .await
+            .check_in_range(value.try_as_unsigned_integer()?)?;
+    }
 
     instance
         .update_configurable(&section_id, &setting_id, value)
@@ -124,6 +143,335 @@ pub async fn change_version(
     Ok(Json(()))
 }
 
+pub async fn set_jvm_flags(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(jvm_flags): Json<JvmFlagsPreset>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => instance.set_jvm_flags(jvm_flags).await?,
+        GameInstance::GenericInstance(_) => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("JVM flags are only supported for Minecraft instances"),
+            })
+        }
+    }
+    Ok(Json(()))
+}
+
+pub async fn set_idle_shutdown(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(idle_shutdown): Json<Option<IdleShutdown>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => {
+            instance.set_idle_shutdown(idle_shutdown).await?
+        }
+        GameInstance::GenericInstance(_) => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Idle shutdown is only supported for Minecraft instances"),
+            })
+        }
+    }
+    Ok(Json(()))
+}
+
+/// Time left until an empty server is stopped, for the dashboard to count down
+pub async fn get_idle_status(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<IdleStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => Ok(Json(instance.idle_status().await)),
+        GameInstance::GenericInstance(_) => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Idle shutdown is only supported for Minecraft instances"),
+        }),
+    }
+}
+
+pub async fn set_console_log_rotation(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(console_log): Json<Option<ConsoleLogRotation>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => {
+            instance.set_console_log_rotation(console_log).await?
+        }
+        GameInstance::GenericInstance(_) => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Console logs are only supported for Minecraft instances"),
+            })
+        }
+    }
+    Ok(Json(()))
+}
+
+/// Takes effect the next time the instance starts
+pub async fn set_cpu_affinity(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(cores): Json<Vec<usize>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => instance.set_cpu_affinity(cores).await?,
+        GameInstance::GenericInstance(_) => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("CPU affinity is only supported for Minecraft instances"),
+            })
+        }
+    }
+    Ok(Json(()))
+}
+
+/// `null` goes back to the defaults of the instance's flavour, takes effect the next time it starts
+pub async fn set_startup_milestones(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(milestones): Json<Option<Vec<StartupMilestone>>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => {
+            instance.set_startup_milestones(milestones).await?
+        }
+        GameInstance::GenericInstance(_) => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Startup progress is only supported for Minecraft instances"),
+            })
+        }
+    }
+    Ok(Json(()))
+}
+
+pub async fn set_instance_env(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(env): Json<InstanceEnv>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => instance.set_env(env).await?,
+        GameInstance::GenericInstance(_) => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Process environment is only supported for Minecraft instances"),
+            })
+        }
+    }
+    Ok(Json(()))
+}
+
+/// Variables of the instance's `.env` file, secret looking values are left out
+pub async fn get_instance_dotenv(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<DotEnvEntry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => Ok(Json(instance.dotenv_entries().await?)),
+        GameInstance::GenericInstance(_) => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Process environment is only supported for Minecraft instances"),
+        }),
+    }
+}
+
+/// Replaces the instance's `.env` file, a `null` value keeps the current one
+pub async fn set_instance_dotenv(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(vars): Json<BTreeMap<String, Option<String>>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => instance.set_dotenv(vars).await?,
+        GameInstance::GenericInstance(_) => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Process environment is only supported for Minecraft instances"),
+            })
+        }
+    }
+    Ok(Json(()))
+}
+
+pub async fn get_instance_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<MinecraftInstanceConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => Ok(Json(instance.instance_config().await)),
+        GameInstance::GenericInstance(_) => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Typed config is only supported for Minecraft instances"),
+        }),
+    }
+}
+
+pub async fn update_instance_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(update): Json<MinecraftInstanceConfigUpdate>,
+) -> Result<Json<MinecraftInstanceConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => {
+            let old_port = instance.port().await;
+            let new_port = update.port.filter(|port| *port != old_port);
+            // reserved before the update, so no other instance can take it meanwhile
+            if let Some(port) = new_port {
+                let mut port_manager = state.port_manager.lock().await;
+                port_manager.check_in_range(port)?;
+                if port_manager.port_status(port).is_allocated {
+                    return Err(Error {
+                        kind: ErrorKind::Conflict,
+                        source: eyre!("Port {} is already allocated to another instance", port),
+                    });
+                }
+                port_manager.add_port(port);
+            }
+            let result = instance.update_instance_config(update).await;
+            if let Some(port) = new_port {
+                let mut port_manager = state.port_manager.lock().await;
+                match result {
+                    Ok(()) => port_manager.deallocate(old_port),
+                    Err(_) => port_manager.deallocate(port),
+                }
+            }
+            result?;
+            Ok(Json(instance.instance_config().await))
+        }
+        GameInstance::GenericInstance(_) => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Typed config is only supported for Minecraft instances"),
+        }),
+    }
+}
+
 pub fn get_instance_config_routes(state: AppState) -> Router {
     Router::new()
         .route(
@@ -131,11 +479,38 @@ pub fn get_instance_config_routes(state: AppState) -> Router {
             get(get_instance_configurable_manifest),
         )
         .route("/instance/:uuid/version/:new_version", put(change_version))
+        .route(
+            "/instance/:uuid/config",
+            get(get_instance_config).put(update_instance_config),
+        )
         .route("/instance/:uuid/settings", get(get_instance_settings))
         .route(
             "/instance/:uuid/settings/:section_id/:setting_id",
             put(set_instance_setting),
         )
+        .route("/instance/:uuid/settings/jvm_flags", put(set_jvm_flags))
+        .route(
+            "/instance/:uuid/settings/idle_shutdown",
+            put(set_idle_shutdown),
+        )
+        .route("/instance/:uuid/idle", get(get_idle_status))
+        .route(
+            "/instance/:uuid/settings/console_log",
+            put(set_console_log_rotation),
+        )
+        .route(
+            "/instance/:uuid/settings/cpu_affinity",
+            put(set_cpu_affinity),
+        )
+        .route(
+            "/instance/:uuid/settings/startup_milestones",
+            put(set_startup_milestones),
+        )
+        .route("/instance/:uuid/settings/env", put(set_instance_env))
+        .route(
+            "/instance/:uuid/env",
+            get(get_instance_dotenv).put(set_instance_dotenv),
+        )
         .route("/instance/:uuid/name", put(set_instance_name))
         .route("/instance/:uuid/description", put(set_instance_description))
         .with_state(state)
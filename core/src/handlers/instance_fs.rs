@@ -1,33 +1,40 @@
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
 
 use axum::{
-    body::Bytes,
-    extract::{DefaultBodyLimit, Multipart, Path},
-    routing::{delete, get, put},
+    extract::{BodyStream, DefaultBodyLimit, Multipart, Path, Query},
+    response::Response,
+    routing::{delete, get, post, put},
     Json, Router,
 };
 use axum_auth::AuthBearer;
 use color_eyre::eyre::{eyre, Context};
 use fs_extra::TransitProcess;
 use headers::HeaderMap;
+use lazy_static::lazy_static;
 use reqwest::header::CONTENT_LENGTH;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
 use tracing::error;
 use ts_rs::TS;
 use walkdir::WalkDir;
 
 use crate::{
     auth::user::UserAction,
-    error::{Error, ErrorKind},
+    error::{Error, ErrorBody, ErrorKind},
     events::{new_fs_event, CausedBy, Event, FSOperation, FSTarget, ProgressionEndValue},
-    prelude::path_to_tmp,
+    implementations::minecraft::idle_shutdown::IdleShutdownHold,
+    prelude::{path_to_tmp, GameInstance},
     traits::t_configurable::TConfigurable,
     types::InstanceUuid,
+    upload_filter::{check_upload, UploadHead},
     util::{
         format_byte, format_byte_download, list_dir, rand_alphanumeric, resolve_path_conflict,
-        scoped_join_win_safe, unzip_file_async, zip_files, zip_files_async, UnzipOption,
+        scoped_join_win_safe, unzip_file_async, zip_files_async, zip_files_parallel_async,
+        zip_files_relative_to, UnzipOption,
     },
     AppState,
 };
@@ -65,7 +72,10 @@ fn is_path_protected(path: impl AsRef<std::path::Path>) -> bool {
 
 use super::{
     global_fs::{DownloadableFile, FileEntry},
-    util::decode_base64,
+    util::{
+        decode_base64, prepare_upload_dir, read_body_limited, read_text_file, ReadQuery,
+        UploadQuery,
+    },
 };
 
 async fn list_instance_files(
@@ -84,6 +94,7 @@ async fn list_instance_files(
     let root = instance.path().await;
     drop(instance);
     let path = scoped_join_win_safe(&root, relative_path)?;
+    let canonical_root = fs::canonicalize(&root).unwrap_or_else(|_| root.clone());
 
     let ret: Vec<FileEntry> = list_dir(&path, None)
         .await?
@@ -96,6 +107,13 @@ async fn list_instance_files(
                 .ok()
                 .and_then(|p| p.to_str())
                 .map(|s| s.to_owned())?;
+            // don't reveal where links pointing outside of the instance lead,
+            // broken and cyclic links are treated the same way
+            if r.symlink_target.is_some()
+                && !fs::canonicalize(p).map_or(false, |target| target.starts_with(&canonical_root))
+            {
+                r.symlink_target = None;
+            }
             Some(r)
         })
         .collect();
@@ -114,8 +132,9 @@ async fn list_instance_files(
 async fn read_instance_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    Query(ReadQuery { raw }): Query<ReadQuery>,
     AuthBearer(token): AuthBearer,
-) -> Result<String, Error> {
+) -> Result<Response, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
@@ -127,9 +146,7 @@ async fn read_instance_file(
     drop(instance);
     let path = scoped_join_win_safe(root, relative_path)?;
 
-    let ret = tokio::fs::read_to_string(&path)
-        .await
-        .context("Failed to read file")?;
+    let ret = read_text_file(&path, raw).await?;
     let caused_by = CausedBy::User {
         user_id: requester.uid,
         user_name: requester.username,
@@ -146,7 +163,7 @@ async fn write_instance_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
     AuthBearer(token): AuthBearer,
-    body: Bytes,
+    body: BodyStream,
 ) -> Result<Json<()>, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
@@ -165,6 +182,9 @@ async fn write_instance_file(
             source: eyre!("You don't have permission to write to this file"),
         });
     }
+    let max_write_size = state.global_settings.lock().await.max_write_size();
+    let body = read_body_limited(body, max_write_size).await?;
+    let _lock = state.path_locks.lock(&path).await;
     let mut file = tokio::fs::File::create(&path)
         .await
         .context("Failed to create file")?;
@@ -214,6 +234,74 @@ async fn make_instance_directory(
     Ok(Json(()))
 }
 
+#[cfg(unix)]
+async fn create_symlink(target: &std::path::Path, link: &std::path::Path) -> std::io::Result<()> {
+    tokio::fs::symlink(target, link).await
+}
+
+#[cfg(windows)]
+async fn create_symlink(target: &std::path::Path, link: &std::path::Path) -> std::io::Result<()> {
+    if target.is_dir() {
+        tokio::fs::symlink_dir(target, link).await
+    } else {
+        tokio::fs::symlink_file(target, link).await
+    }
+}
+
+/// Creates a symlink at `base64_relative_path` pointing to `base64_relative_target`,
+/// both relative to the instance root, so the link can't point outside of the instance
+async fn create_instance_symlink(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, base64_relative_path, base64_relative_target)): Path<(
+        InstanceUuid,
+        String,
+        String,
+    )>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let relative_path = decode_base64(&base64_relative_path)?;
+    let relative_target = decode_base64(&base64_relative_target)?;
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    drop(instance);
+    let path = scoped_join_win_safe(&root, relative_path)?;
+    let target = scoped_join_win_safe(&root, relative_target)?;
+
+    if path.symlink_metadata().is_ok() {
+        return Err(Error {
+            kind: ErrorKind::Conflict,
+            source: eyre!("{} already exists", path.display()),
+        });
+    }
+    if !target.exists() {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Link target {} does not exist", target.display()),
+        });
+    }
+
+    let _lock = state.path_locks.lock(&path).await;
+    create_symlink(&target, &path)
+        .await
+        .context(format!("Failed to create symlink {}", path.display()))?;
+
+    let caused_by = CausedBy::User {
+        user_id: requester.uid,
+        user_name: requester.username,
+    };
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Create,
+        FSTarget::File(path),
+        caused_by,
+    ));
+    Ok(Json(()))
+}
+
 #[derive(Deserialize, TS)]
 #[ts(export)]
 struct CopyInstanceFileRequest {
@@ -362,38 +450,222 @@ async fn copy_instance_files(
     Ok(Json(()))
 }
 
-async fn move_instance_file(
+/// Upper bound on the entries visited when looking for recently modified files,
+/// so huge instances don't stall the request
+const RECENT_FILES_MAX_WALK: usize = 100_000;
+const RECENT_FILES_MAX_LIMIT: usize = 500;
+const RECENT_FILES_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// Region files that are rewritten constantly while the server runs
+const WORLD_REGION_EXTENSIONS: [&str; 2] = ["mca", "mcr"];
+
+lazy_static! {
+    // files sorted by modification time, newest first, keyed by instance and `include_world`
+    static ref RECENT_FILES_CACHE: Mutex<HashMap<(InstanceUuid, bool), (Instant, Vec<PathBuf>)>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Deserialize)]
+struct RecentFilesQuery {
+    #[serde(default = "default_recent_files_limit")]
+    limit: usize,
+    #[serde(default)]
+    include_world: bool,
+}
+
+fn default_recent_files_limit() -> usize {
+    50
+}
+
+fn walk_recent_files(root: &std::path::Path, include_world: bool) -> Vec<PathBuf> {
+    let mut files: Vec<(SystemTime, PathBuf)> = WalkDir::new(root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .take(RECENT_FILES_MAX_WALK)
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| {
+            include_world
+                || !entry
+                    .path()
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .map_or(false, |ext| WORLD_REGION_EXTENSIONS.contains(&ext))
+        })
+        .filter_map(|entry| {
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            Some((modified, entry.into_path()))
+        })
+        .collect();
+    files.sort_by(|a, b| b.0.cmp(&a.0));
+    files.truncate(RECENT_FILES_MAX_LIMIT);
+    files.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Lists the most recently modified files of the instance, newest first
+async fn get_recent_instance_files(
     axum::extract::State(state): axum::extract::State<AppState>,
-    Path((uuid, base64_relative_path_source, base64_relative_path_dest)): Path<(
-        InstanceUuid,
-        String,
-        String,
-    )>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(RecentFilesQuery {
+        limit,
+        include_world,
+    }): Query<RecentFilesQuery>,
     AuthBearer(token): AuthBearer,
-) -> Result<Json<()>, Error> {
-    let relative_path_source = decode_base64(&base64_relative_path_source)?;
-    let relative_path_dest = decode_base64(&base64_relative_path_dest)?;
+) -> Result<Json<Vec<FileEntry>>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
     let instance = state.instances.get(&uuid).ok_or_else(|| Error {
         kind: ErrorKind::NotFound,
         source: eyre!("Instance not found"),
     })?;
     let root = instance.path().await;
     drop(instance);
-    let path_source = scoped_join_win_safe(&root, relative_path_source)?;
-    let path_dest = scoped_join_win_safe(&root, relative_path_dest)?;
+
+    let key = (uuid.clone(), include_world);
+    let cached = RECENT_FILES_CACHE
+        .lock()
+        .await
+        .get(&key)
+        .filter(|(walked_at, _)| walked_at.elapsed() < RECENT_FILES_CACHE_TTL)
+        .map(|(_, files)| files.clone());
+    let files = match cached {
+        Some(files) => files,
+        None => {
+            let files = tokio::task::spawn_blocking({
+                let root = root.clone();
+                move || walk_recent_files(&root, include_world)
+            })
+            .await
+            .context("Failed to walk instance directory")?;
+            RECENT_FILES_CACHE
+                .lock()
+                .await
+                .insert(key, (Instant::now(), files.clone()));
+            files
+        }
+    };
+
+    let ret = files
+        .iter()
+        .filter(|p| p.is_file())
+        .take(limit.min(RECENT_FILES_MAX_LIMIT))
+        .filter_map(|p| {
+            let mut r: FileEntry = p.as_path().into();
+            r.path = p
+                .strip_prefix(&root)
+                .ok()
+                .and_then(|p| p.to_str())
+                .map(|s| s.to_owned())?;
+            Some(r)
+        })
+        .collect();
+    Ok(Json(ret))
+}
+
+#[derive(Deserialize)]
+struct MoveQuery {
+    /// Resolve the destination relative to the directory containing the source,
+    /// instead of the instance root
+    #[serde(default)]
+    relative: bool,
+}
+
+/// Resolve the destination of a move inside the instance root
+///
+/// In relative mode, `dest` is relative to the directory containing `path_source`,
+/// e.g. `new_name.txt` renames in place and `../old` moves up a directory.
+/// Destinations that would leave the instance root are rejected
+fn resolve_move_dest(
+    root: &std::path::Path,
+    path_source: &std::path::Path,
+    dest: &std::path::Path,
+    relative: bool,
+) -> Result<PathBuf, Error> {
+    if !relative {
+        return scoped_join_win_safe(root, dest);
+    }
+    let source_dir = path_source
+        .parent()
+        .and_then(|parent| parent.strip_prefix(root).ok())
+        .ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Source is not inside the instance"),
+        })?;
+    let mut resolved = source_dir.to_path_buf();
+    for component in dest.components() {
+        match component {
+            std::path::Component::Normal(name) => resolved.push(name),
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                if !resolved.pop() {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Destination is outside the instance"),
+                    });
+                }
+            }
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Relative destination must not be an absolute path"),
+                });
+            }
+        }
+    }
+    scoped_join_win_safe(root, resolved)
+}
+
+/// Fails if `relative_path` climbs above the instance root with `..`
+///
+/// `scoped_join_win_safe` would clamp such a path to the root, which puts uploads
+/// somewhere the user didn't ask for
+fn reject_escaping_path(relative_path: &str) -> Result<(), Error> {
+    let mut depth = 0_usize;
+    for component in std::path::Path::new(relative_path).components() {
+        match component {
+            std::path::Component::Normal(_) => depth += 1,
+            std::path::Component::ParentDir => {
+                depth = depth.checked_sub(1).ok_or_else(|| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Path {} is outside of the instance", relative_path),
+                })?
+            }
+            // a leading `/` is relative to the instance root
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => depth = 0,
+            std::path::Component::CurDir => {}
+        }
+    }
+    Ok(())
+}
+
+/// Held while zipping an instance's files, which is how they are backed up
+fn hold_idle_shutdown(instance: &GameInstance) -> Option<IdleShutdownHold> {
+    match instance {
+        GameInstance::MinecraftInstance(instance) => Some(instance.hold_idle_shutdown()),
+        GameInstance::GenericInstance(_) => None,
+    }
+}
+
+/// Moves `relative_path_source` to `relative_path_dest` inside `root`,
+/// renaming the destination if it's taken. Returns the source and where it ended up
+async fn move_in_instance(
+    state: &AppState,
+    root: &std::path::Path,
+    relative_path_source: &std::path::Path,
+    relative_path_dest: &std::path::Path,
+    relative: bool,
+    can_write_protected: bool,
+) -> Result<(PathBuf, PathBuf), Error> {
+    let path_source = scoped_join_win_safe(root, relative_path_source)?;
+    let path_dest = resolve_move_dest(root, &path_source, relative_path_dest, relative)?;
 
     let relative_path_source = path_source
-        .strip_prefix(&root)
+        .strip_prefix(root)
         .context("Error stripping prefix")?;
     let relative_path_dest = path_dest
-        .strip_prefix(&root)
+        .strip_prefix(root)
         .context("Error stripping prefix")?;
 
-    if !requester.can_perform_action(&UserAction::WriteInstanceFile(uuid.clone()))
-        && (is_path_protected(&path_source) || is_path_protected(&path_dest))
-    {
+    if !can_write_protected && (is_path_protected(&path_source) || is_path_protected(&path_dest)) {
         return Err(Error {
             kind: ErrorKind::PermissionDenied,
             source: eyre!("You don't have permission to write to this file"),
@@ -408,15 +680,54 @@ async fn move_instance_file(
         });
     }
 
-    let path_dest = resolve_path_conflict(path_dest.to_owned(), None);
+    // lock the requested destination before resolving conflicts,
+    // so that concurrent moves to the same destination don't pick the same name
+    let _locks = state
+        .path_locks
+        .lock_all(&[path_source.as_path(), path_dest.as_path()])
+        .await;
+    let resolved_dest = resolve_path_conflict(path_dest.to_owned(), None);
 
-    tokio::fs::rename(&path_source, &path_dest)
+    tokio::fs::rename(&path_source, &resolved_dest)
         .await
         .context(format!(
             "Error moving file from {} to {}",
             relative_path_source.display(),
             relative_path_dest.display()
         ))?;
+    Ok((path_source, resolved_dest))
+}
+
+async fn move_instance_file(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, base64_relative_path_source, base64_relative_path_dest)): Path<(
+        InstanceUuid,
+        String,
+        String,
+    )>,
+    Query(MoveQuery { relative }): Query<MoveQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let relative_path_source = decode_base64(&base64_relative_path_source)?;
+    let relative_path_dest = decode_base64(&base64_relative_path_dest)?;
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    drop(instance);
+
+    let (path_source, _) = move_in_instance(
+        &state,
+        &root,
+        relative_path_source.as_ref(),
+        relative_path_dest.as_ref(),
+        relative,
+        requester.can_perform_action(&UserAction::WriteInstanceFile(uuid.clone())),
+    )
+    .await?;
 
     let caused_by = CausedBy::User {
         user_id: requester.uid,
@@ -434,6 +745,101 @@ async fn move_instance_file(
     Ok(Json(()))
 }
 
+#[derive(Deserialize, TS)]
+#[ts(export)]
+struct MoveManyItem {
+    /// Base64 encoded path relative to the instance root
+    src: String,
+    /// Base64 encoded path, relative to the instance root or to the source's directory
+    /// if `relative` is set
+    dest: String,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+struct MoveManyResult {
+    src: String,
+    /// Where the file ended up relative to the instance root, which differs from the requested
+    /// destination if that was taken. `None` if the move failed
+    dest: Option<String>,
+    error: Option<ErrorBody>,
+}
+
+/// Moves each item independently, a failed move doesn't stop the others
+async fn move_many_instance_files(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(MoveQuery { relative }): Query<MoveQuery>,
+    AuthBearer(token): AuthBearer,
+    Json(items): Json<Vec<MoveManyItem>>,
+) -> Result<Json<Vec<MoveManyResult>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    drop(instance);
+    let can_write_protected =
+        requester.can_perform_action(&UserAction::WriteInstanceFile(uuid.clone()));
+
+    let mut results = Vec::with_capacity(items.len());
+    let mut sources = Vec::new();
+    for MoveManyItem { src, dest } in items {
+        let moved = async {
+            let relative_path_source = decode_base64(&src)?;
+            let relative_path_dest = decode_base64(&dest)?;
+            move_in_instance(
+                &state,
+                &root,
+                relative_path_source.as_ref(),
+                relative_path_dest.as_ref(),
+                relative,
+                can_write_protected,
+            )
+            .await
+        }
+        .await;
+        results.push(match moved {
+            Ok((path_source, resolved_dest)) => {
+                sources.push(path_source);
+                MoveManyResult {
+                    src,
+                    dest: resolved_dest
+                        .strip_prefix(&root)
+                        .ok()
+                        .map(|dest| dest.to_string_lossy().into_owned()),
+                    error: None,
+                }
+            }
+            Err(e) => MoveManyResult {
+                src,
+                dest: None,
+                error: Some(e.body()),
+            },
+        });
+    }
+
+    if !sources.is_empty() {
+        let caused_by = CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        };
+        let mut event = new_fs_event(
+            FSOperation::MoveMany {
+                sources: sources.clone(),
+            },
+            FSTarget::Directory(root),
+            caused_by,
+        );
+        event.details = format!("Moved {} of {} files", sources.len(), results.len());
+        state.event_broadcaster.send(event);
+    }
+
+    Ok(Json(results))
+}
+
 async fn remove_instance_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
@@ -583,6 +989,7 @@ async fn get_instance_file_url(
         source: eyre!("Instance not found"),
     })?;
     let root = instance.path().await;
+    let _idle_shutdown_hold = hold_idle_shutdown(&instance);
     drop(instance);
     let path = scoped_join_win_safe(&root, &relative_path)?;
 
@@ -614,8 +1021,14 @@ async fn get_instance_file_url(
             })?);
             temp_file_path.set_extension("zip");
             let files = Vec::from([path.clone()]);
-            zip_files(&files, temp_file_path.clone(), true).context("Failed to zip file")?;
-            Ok(DownloadableFile::ZippedFile((temp_file_path, temp_dir)))
+            let zip_workers = state.global_settings.lock().await.zip_workers() as usize;
+            zip_files_parallel_async(&files, temp_file_path.clone(), true, zip_workers)
+                .await
+                .context("Failed to zip file")?;
+            Ok(DownloadableFile::ZippedFile((
+                temp_file_path,
+                std::sync::Arc::new(temp_dir),
+            )))
         }
         .await;
         if let Err(e) = res {
@@ -650,14 +1063,114 @@ async fn get_instance_file_url(
     Ok(key)
 }
 
+#[derive(Deserialize)]
+struct DownloadSelectedRequest {
+    /// Relative to the instance directory, none of them can leave it
+    relative_paths: Vec<String>,
+}
+
+/// Zips the selected files and directories, keeping their paths relative to the instance,
+/// and returns a key to download the zip with
+async fn download_selected_instance_files(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<DownloadSelectedRequest>,
+) -> Result<String, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    if request.relative_paths.is_empty() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("No files selected"),
+        });
+    }
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let root = instance.path().await;
+    let name = instance.name().await;
+    let _idle_shutdown_hold = hold_idle_shutdown(&instance);
+    drop(instance);
+
+    let mut paths = Vec::with_capacity(request.relative_paths.len());
+    for relative_path in &request.relative_paths {
+        reject_escaping_path(relative_path)?;
+        let path = scoped_join_win_safe(&root, relative_path)?;
+        if !path.exists() {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("{} does not exist", relative_path),
+            });
+        }
+        paths.push(path);
+    }
+
+    let (start_event, event_id) = Event::new_progression_event_start(
+        format!("Zipping {} files for download", paths.len()),
+        None,
+        None,
+        CausedBy::User {
+            user_id: requester.uid.clone(),
+            user_name: requester.username.clone(),
+        },
+    );
+    state.event_broadcaster.send(start_event);
+    let res: Result<(PathBuf, tempfile::TempDir), Error> = async {
+        let temp_dir =
+            tempfile::tempdir_in(path_to_tmp()).context("Failed to create temporary file")?;
+        let zip_path = temp_dir
+            .path()
+            .join(format!("{}.zip", sanitize_filename::sanitize(&name)));
+        let zip_path = tokio::task::spawn_blocking({
+            let root = root.clone();
+            move || zip_files_relative_to(&paths, Some(&root), zip_path, true)
+        })
+        .await
+        .context("Failed to spawn blocking task")??;
+        Ok((zip_path, temp_dir))
+    }
+    .await;
+    state
+        .event_broadcaster
+        .send(Event::new_progression_event_end(
+            event_id,
+            res.is_ok(),
+            Some(match &res {
+                Ok(_) => "Zipping complete".to_string(),
+                Err(e) => e.to_string(),
+            }),
+            None,
+        ));
+    let (zip_path, temp_dir) = res?;
+
+    let key = rand_alphanumeric(32);
+    state.download_urls.lock().await.insert(
+        key.clone(),
+        DownloadableFile::ZippedFile((zip_path.clone(), std::sync::Arc::new(temp_dir))),
+    );
+    state.event_broadcaster.send(new_fs_event(
+        FSOperation::Download,
+        FSTarget::File(zip_path),
+        CausedBy::User {
+            user_id: requester.uid,
+            user_name: requester.username,
+        },
+    ));
+    Ok(key)
+}
+
 async fn upload_instance_file(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, base64_relative_path)): Path<(InstanceUuid, String)>,
+    Query(query): Query<UploadQuery>,
     headers: HeaderMap,
     AuthBearer(token): AuthBearer,
     mut multipart: Multipart,
-) -> Result<Json<()>, Error> {
+) -> Result<Json<Vec<String>>, Error> {
     let relative_path = decode_base64(&base64_relative_path)?;
+    reject_escaping_path(&relative_path)?;
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
     let caused_by = CausedBy::User {
@@ -671,7 +1184,8 @@ async fn upload_instance_file(
     let root = instance.path().await;
     drop(instance);
     let path_to_dir = scoped_join_win_safe(&root, relative_path)?;
-    crate::util::fs::create_dir_all(&path_to_dir).await?;
+    prepare_upload_dir(&path_to_dir, &query).await?;
+    let upload_rules = state.global_settings.lock().await.upload_rules();
 
     let total = headers
         .get(CONTENT_LENGTH)
@@ -680,6 +1194,10 @@ async fn upload_instance_file(
     let (progression_start_event, event_id) =
         Event::new_progression_event_start("Uploading files", total, None, caused_by.clone());
     state.event_broadcaster.send(progression_start_event);
+    let cancellation = state
+        .upload_cancellations
+        .register(event_id.clone(), requester.uid.clone());
+    let mut stored_paths = Vec::new();
     while let Ok(Some(mut field)) = multipart.next_field().await {
         let name = field.file_name().ok_or_else(|| Error {
             kind: ErrorKind::BadRequest,
@@ -697,13 +1215,37 @@ async fn upload_instance_file(
         let path = resolve_path_conflict(path, None);
 
         let mut file = crate::util::fs::create(&path).await?;
+        let mut upload_head = UploadHead::new();
+        let mut rejected = None;
 
         let threshold = total.unwrap_or(500000.0) / 100.0;
 
         let mut elapsed_bytes = 0_u64;
         let mut last_progression = 0_u64;
 
-        while let Some(chunk) = match field.chunk().await {
+        while let Some(chunk) = match tokio::select! {
+            chunk = field.chunk() => chunk,
+            _ = cancellation.cancelled() => {
+                drop(file);
+                tokio::fs::remove_file(&path).await.ok();
+                state
+                    .event_broadcaster
+                    .send(Event::new_progression_event_end(
+                        event_id,
+                        false,
+                        Some("Cancelled"),
+                        Some(ProgressionEndValue::FSOperationCompleted {
+                            instance_uuid: uuid.clone(),
+                            success: false,
+                            message: format!("Upload of {name} cancelled"),
+                        }),
+                    ));
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Upload cancelled"),
+                });
+            }
+        } {
             Ok(v) => v,
             Err(e) => {
                 tokio::fs::remove_file(&path).await.ok();
@@ -759,13 +1301,47 @@ async fn upload_instance_file(
                                 message: format!("Failed to upload file {name}, {e}"),
                             }),
                         ));
-                    return Err::<Json<()>, std::io::Error>(e)
+                    return Err::<Json<Vec<String>>, std::io::Error>(e)
                         .context("Failed to write chunk")
                         .map_err(Error::from);
                 }
             };
+            if let Some(head) = upload_head.feed(&chunk) {
+                if let Err(e) = check_upload(&upload_rules, &path, head) {
+                    rejected = Some(e);
+                    break;
+                }
+            }
+        }
+        let rejected = rejected.or_else(|| {
+            upload_head
+                .finish()
+                .and_then(|head| check_upload(&upload_rules, &path, head).err())
+        });
+        if let Some(e) = rejected {
+            drop(file);
+            tokio::fs::remove_file(&path).await.ok();
+            state
+                .event_broadcaster
+                .send(Event::new_progression_event_end(
+                    event_id,
+                    false,
+                    Some(&e.to_string()),
+                    Some(ProgressionEndValue::FSOperationCompleted {
+                        instance_uuid: uuid.clone(),
+                        success: false,
+                        message: format!("Failed to upload file {name}, {e}"),
+                    }),
+                ));
+            return Err(e);
         }
 
+        stored_paths.push(
+            path.strip_prefix(&root)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .into_owned(),
+        );
         state.event_broadcaster.send(new_fs_event(
             FSOperation::Upload,
             FSTarget::File(path),
@@ -784,7 +1360,7 @@ async fn upload_instance_file(
                 message: "File(s) uploaded".to_string(),
             }),
         ));
-    Ok(Json(()))
+    Ok(Json(stored_paths))
 }
 
 pub async fn unzip_instance_file(
@@ -874,6 +1450,7 @@ async fn zip_instance_files(
         source: eyre!("Instance not found"),
     })?;
     let root = instance.path().await;
+    let idle_shutdown_hold = hold_idle_shutdown(&instance);
     drop(instance);
     let ZipRequest {
         mut target_relative_paths,
@@ -898,10 +1475,11 @@ async fn zip_instance_files(
     let event_broadcaster = state.event_broadcaster.clone();
 
     tokio::spawn(async move {
+        let _idle_shutdown_hold = idle_shutdown_hold;
         let aggregate_name = {
             let combined_file_name = target_relative_paths
                 .iter()
-                .map(|p| p.file_name().unwrap().to_string_lossy())
+                .map(|p| p.file_name().unwrap_or_default().to_string_lossy())
                 .collect::<Vec<_>>()
                 .join(", ");
             if combined_file_name.len() < 100 {
@@ -971,7 +1549,16 @@ pub fn get_instance_fs_routes(state: AppState) -> Router {
             "/instance/:uuid/fs/:base64_relative_path/mkdir",
             put(make_instance_directory),
         )
+        .route(
+            "/instance/:uuid/fs/:base64_relative_path/symlink/:base64_relative_target",
+            put(create_instance_symlink),
+        )
         .route("/instance/:uuid/fs/cpr", put(copy_instance_files))
+        .route("/instance/:uuid/fs/recent", get(get_recent_instance_files))
+        .route(
+            "/instance/:uuid/fs/move_many",
+            post(move_many_instance_files),
+        )
         .route(
             "/instance/:uuid/fs/:base64_relative_path/move/:base64_relative_path_dest",
             put(move_instance_file),
@@ -1002,5 +1589,60 @@ pub fn get_instance_fs_routes(state: AppState) -> Router {
             put(unzip_instance_file),
         )
         .route("/instance/:uuid/fs/zip", put(zip_instance_files))
+        .route(
+            "/instance/:uuid/files/download",
+            post(download_selected_instance_files),
+        )
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_move_dest() {
+        let temp_dir = tempdir::TempDir::new("test_resolve_move_dest").unwrap();
+        let root = temp_dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("world/region")).unwrap();
+        let source = root.join("world/region/r.0.0.mca");
+
+        // absolute destinations are relative to the instance root
+        assert_eq!(
+            resolve_move_dest(&root, &source, "backup/r.0.0.mca".as_ref(), false).unwrap(),
+            root.join("backup/r.0.0.mca")
+        );
+        assert_eq!(
+            resolve_move_dest(&root, &source, "/backup".as_ref(), false).unwrap(),
+            root.join("backup")
+        );
+        // and can't escape it
+        assert!(
+            resolve_move_dest(&root, &source, "../../etc".as_ref(), false)
+                .unwrap()
+                .starts_with(&root)
+        );
+
+        // relative destinations are relative to the source's directory
+        assert_eq!(
+            resolve_move_dest(&root, &source, "renamed.mca".as_ref(), true).unwrap(),
+            root.join("world/region/renamed.mca")
+        );
+        assert_eq!(
+            resolve_move_dest(&root, &source, "../../r.0.0.mca".as_ref(), true).unwrap(),
+            root.join("r.0.0.mca")
+        );
+        assert!(resolve_move_dest(&root, &source, "../../../r.0.0.mca".as_ref(), true).is_err());
+        assert!(resolve_move_dest(&root, &source, "/r.0.0.mca".as_ref(), true).is_err());
+    }
+
+    #[test]
+    fn test_reject_escaping_path() {
+        assert!(reject_escaping_path("world/region").is_ok());
+        assert!(reject_escaping_path("/world/../plugins/./").is_ok());
+        assert!(reject_escaping_path("").is_ok());
+        assert!(reject_escaping_path("..").is_err());
+        assert!(reject_escaping_path("world/../../etc").is_err());
+        assert!(reject_escaping_path("/../etc").is_err());
+    }
+}
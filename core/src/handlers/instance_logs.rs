@@ -0,0 +1,315 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path as StdPath, PathBuf};
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::StreamBody,
+    extract::{Path, Query},
+    http::header,
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use fancy_regex::Regex;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use ts_rs::TS;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    AppState,
+};
+
+const LOGS_DIR: &str = "logs";
+const DEFAULT_MAX_RESULTS: usize = 100;
+const MAX_RESULTS: usize = 1000;
+/// A search stops once it ran this long or read this many bytes, decompressed,
+/// so a huge log directory can't keep a blocking thread busy
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_SCANNED_BYTES: u64 = 1024 * 1024 * 1024;
+/// Matching lines longer than this are cut
+const MAX_LINE_LENGTH: usize = 4096;
+
+#[derive(Deserialize)]
+pub struct LogSearchQuery {
+    q: String,
+    /// Treat `q` as a regular expression instead of plain text
+    #[serde(default)]
+    regex: bool,
+    #[serde(default)]
+    case_insensitive: bool,
+    max_results: Option<usize>,
+}
+
+/// A line of the newline delimited JSON returned by a log search
+#[derive(Serialize, Debug, PartialEq, TS)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum LogSearchItem {
+    Match {
+        /// Relative to the instance, e.g. `logs/latest.log`
+        file: String,
+        /// Starting at 1
+        line_number: usize,
+        line: String,
+    },
+    /// Sent last if the search stopped before scanning every log
+    Truncated { reason: String },
+}
+
+enum Matcher {
+    Regex(Regex),
+    Text(String),
+    TextCaseInsensitive(String),
+}
+
+impl Matcher {
+    fn new(query: &LogSearchQuery) -> Result<Self, Error> {
+        if query.q.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Search query can't be empty"),
+            });
+        }
+        if query.regex {
+            let pattern = if query.case_insensitive {
+                format!("(?i){}", query.q)
+            } else {
+                query.q.clone()
+            };
+            return Regex::new(&pattern).map(Matcher::Regex).map_err(|e| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid regex {} : {}", query.q, e),
+            });
+        }
+        Ok(if query.case_insensitive {
+            Matcher::TextCaseInsensitive(query.q.to_lowercase())
+        } else {
+            Matcher::Text(query.q.clone())
+        })
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        match self {
+            // patterns that backtrack too much don't match rather than failing the search
+            Matcher::Regex(regex) => regex.is_match(line).unwrap_or(false),
+            Matcher::Text(text) => line.contains(text.as_str()),
+            Matcher::TextCaseInsensitive(text) => line.to_lowercase().contains(text.as_str()),
+        }
+    }
+}
+
+/// `logs/*.log` and the `logs/*.log.gz` they are rotated to, newest first
+fn list_log_files(path_to_logs: &StdPath) -> Vec<PathBuf> {
+    let mut files: Vec<_> = std::fs::read_dir(path_to_logs)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !(name.ends_with(".log") || name.ends_with(".log.gz")) {
+                return None;
+            }
+            let metadata = entry.metadata().ok()?;
+            metadata
+                .is_file()
+                .then(|| (metadata.modified().ok(), entry.path()))
+        })
+        .collect();
+    files.sort_by(|a, b| b.0.cmp(&a.0));
+    files.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Sends the matching lines of the instance's logs, stops early if the receiver is dropped
+fn search_logs(
+    path_to_instance: &StdPath,
+    matcher: &Matcher,
+    max_results: usize,
+    sender: &mpsc::Sender<LogSearchItem>,
+) {
+    let started = Instant::now();
+    let mut scanned_bytes = 0_u64;
+    let mut results = 0;
+    let truncate = |reason: &str| {
+        let _ = sender.blocking_send(LogSearchItem::Truncated {
+            reason: reason.to_string(),
+        });
+    };
+    for path in list_log_files(&path_to_instance.join(LOGS_DIR)) {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let reader: Box<dyn Read> = if path.extension().map_or(false, |e| e == "gz") {
+            Box::new(flate2::read::GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        let mut reader = BufReader::new(reader);
+        let relative_path = path
+            .strip_prefix(path_to_instance)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned();
+        let mut buf = Vec::new();
+        let mut line_number = 0;
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) => break,
+                Ok(read) => scanned_bytes += read as u64,
+                // e.g. a truncated archive, what was read so far was still searched
+                Err(_) => break,
+            }
+            line_number += 1;
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if matcher.is_match(line) {
+                let mut line = line.to_string();
+                if line.len() > MAX_LINE_LENGTH {
+                    let mut end = MAX_LINE_LENGTH;
+                    while !line.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    line.truncate(end);
+                }
+                let item = LogSearchItem::Match {
+                    file: relative_path.clone(),
+                    line_number,
+                    line,
+                };
+                if sender.blocking_send(item).is_err() {
+                    return;
+                }
+                results += 1;
+                if results >= max_results {
+                    truncate("Reached the maximum number of results");
+                    return;
+                }
+            }
+            if scanned_bytes > MAX_SCANNED_BYTES {
+                truncate("Reached the maximum size of logs searched");
+                return;
+            }
+            if started.elapsed() > SEARCH_TIMEOUT {
+                truncate("Search timed out");
+                return;
+            }
+        }
+    }
+}
+
+/// Streams matching lines as newline delimited `LogSearchItem`s, newest logs first
+pub async fn search_instance_logs(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<LogSearchQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<impl IntoResponse, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    let matcher = Matcher::new(&query)?;
+    let max_results = query
+        .max_results
+        .unwrap_or(DEFAULT_MAX_RESULTS)
+        .clamp(1, MAX_RESULTS);
+    let path_to_instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+
+    let (sender, receiver) = mpsc::channel(64);
+    tokio::task::spawn_blocking(move || {
+        search_logs(&path_to_instance, &matcher, max_results, &sender)
+    });
+    let body = StreamBody::new(
+        ReceiverStream::new(receiver)
+            .map(|item| serde_json::to_string(&item).map(|json| json + "\n")),
+    );
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body))
+}
+
+pub fn get_instance_logs_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/logs/search", get(search_instance_logs))
+        .with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn search(root: &StdPath, q: &str, regex: bool, case_insensitive: bool) -> Vec<LogSearchItem> {
+        let matcher = Matcher::new(&LogSearchQuery {
+            q: q.to_string(),
+            regex,
+            case_insensitive,
+            max_results: None,
+        })
+        .unwrap();
+        let (sender, mut receiver) = mpsc::channel(64);
+        search_logs(root, &matcher, 2, &sender);
+        drop(sender);
+        let mut items = Vec::new();
+        while let Ok(item) = receiver.try_recv() {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn test_search_logs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join(LOGS_DIR)).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(b"[00:00:00] Starting\n[00:00:01] ERROR old failure\n")
+            .unwrap();
+        std::fs::write(
+            root.join(LOGS_DIR).join("2024-01-01-1.log.gz"),
+            encoder.finish().unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            search(root, "error", false, true),
+            vec![LogSearchItem::Match {
+                file: PathBuf::from(LOGS_DIR)
+                    .join("2024-01-01-1.log.gz")
+                    .to_string_lossy()
+                    .into_owned(),
+                line_number: 2,
+                line: "[00:00:01] ERROR old failure".to_string(),
+            }]
+        );
+        assert!(search(root, "error", false, false).is_empty());
+        assert_eq!(search(root, r"^\[00:00:0\d\]", true, false).len(), 3);
+        assert!(matches!(
+            search(root, r"^\[00:00:0\d\]", true, false).last(),
+            Some(LogSearchItem::Truncated { .. })
+        ));
+        assert!(Matcher::new(&LogSearchQuery {
+            q: "(".to_string(),
+            regex: true,
+            case_insensitive: false,
+            max_results: None,
+        })
+        .is_err());
+    }
+}
@@ -1,17 +1,19 @@
 use axum::{
     extract::Path,
-    routing::{get, put},
+    routing::{get, post, put},
     Json, Router,
 };
 
 use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
+use serde::Deserialize;
 
 use crate::{
     auth::user::UserAction,
     error::{Error, ErrorKind},
     events::CausedBy,
-    macro_executor::MacroPID,
+    macro_daemon::DaemonStatus,
+    macro_executor::{MacroDiagnostic, MacroPID},
     traits::t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry},
     types::InstanceUuid,
     AppState,
@@ -78,6 +80,7 @@ pub async fn run_macro(
         .run_macro(
             &macro_name,
             args,
+            None,
             CausedBy::User {
                 user_id: requester.uid,
                 user_name: requester.username,
@@ -87,6 +90,50 @@ pub async fn run_macro(
     Ok(Json(()))
 }
 
+#[derive(Deserialize)]
+pub struct RunMacroRequest {
+    name: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Typed parameters the macro reads with `getMacroArgs()`, such as `{ "target_ram": 4096 }`
+    #[serde(default)]
+    structured_args: Option<serde_json::Value>,
+}
+
+pub async fn run_macro_by_name(
+    Path(uuid): Path<InstanceUuid>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(RunMacroRequest {
+        name,
+        args,
+        structured_args,
+    }): Json<RunMacroRequest>,
+) -> Result<Json<MacroPID>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    let task = instance
+        .run_macro(
+            &name,
+            args,
+            structured_args,
+            CausedBy::User {
+                user_id: requester.uid,
+                user_name: requester.username,
+            },
+        )
+        .await?;
+    Ok(Json(task.pid))
+}
+
 pub async fn kill_macro(
     Path((uuid, pid)): Path<(InstanceUuid, MacroPID)>,
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -102,15 +149,106 @@ pub async fn kill_macro(
     Ok(Json(()))
 }
 
+#[derive(Deserialize)]
+pub struct ValidateMacroRequest {
+    /// Macro to check, a folder is checked from its `index.ts`
+    pub name: Option<String>,
+    /// Unsaved contents, replacing those of the macro's main module if `name` is given
+    pub source: Option<String>,
+}
+
+/// Checks a macro's syntax and local imports without running it, no diagnostics means it will load
+///
+/// Validation is syntax-only: types aren't checked, as macros are only transpiled and
+/// the core has no TypeScript checker. Type errors don't stop a macro from running either
+pub async fn validate_macro(
+    Path(uuid): Path<InstanceUuid>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<ValidateMacroRequest>,
+) -> Result<Json<Vec<MacroDiagnostic>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())))?;
+    let instance = state.instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let diagnostics = instance
+        .validate_macro(request.name.as_deref(), request.source)
+        .await?;
+    Ok(Json(diagnostics))
+}
+
+/// Emergency stop for every running macro across all instances
+pub async fn terminate_all_macros(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<MacroPID>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessMacro(None))?;
+    Ok(Json(state.macro_executor.terminate_all()))
+}
+
+/// Starts supervising a macro as a daemon, the body is the macro's arguments
+pub async fn start_daemon(
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(args): Json<Vec<String>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())))?;
+    state.daemon_registry.start(uuid, name, args).await?;
+    Ok(Json(()))
+}
+
+pub async fn stop_daemon(
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())))?;
+    state.daemon_registry.stop(&uuid, &name).await?;
+    Ok(Json(()))
+}
+
+pub async fn get_daemon_status(
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<DaemonStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())))?;
+    Ok(Json(state.daemon_registry.status(&uuid, &name)?))
+}
+
+pub async fn get_daemon_list(
+    Path(uuid): Path<InstanceUuid>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<DaemonStatus>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessMacro(Some(uuid.clone())))?;
+    Ok(Json(state.daemon_registry.list(&uuid)))
+}
+
 pub fn get_instance_macro_routes(state: AppState) -> Router {
     Router::new()
+        .route("/instance/:uuid/macro/run", post(run_macro_by_name))
         .route("/instance/:uuid/macro/run/:macro_name", put(run_macro))
         .route("/instance/:uuid/macro/kill/:pid", put(kill_macro))
+        .route("/instance/:uuid/macro/validate", post(validate_macro))
+        .route("/macro/terminate_all", post(terminate_all_macros))
         .route("/instance/:uuid/macro/list", get(get_instance_macro_list))
         .route("/instance/:uuid/task/list", get(get_instance_task_list))
         .route(
             "/instance/:uuid/history/list",
             get(get_instance_history_list),
         )
+        .route("/instance/:uuid/daemon/list", get(get_daemon_list))
+        .route("/instance/:uuid/daemon/:name", get(get_daemon_status))
+        .route("/instance/:uuid/daemon/:name/start", put(start_daemon))
+        .route("/instance/:uuid/daemon/:name/stop", put(stop_daemon))
         .with_state(state)
 }
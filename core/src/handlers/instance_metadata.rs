@@ -0,0 +1,88 @@
+use axum::{
+    extract::Path,
+    routing::{get, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde_json::Value;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    instance_metadata::{
+        get_metadata, remove_metadata, set_metadata, InstanceMetadata, COMMAND_ALIASES_METADATA_KEY,
+    },
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    AppState,
+};
+
+async fn instance_path(state: &AppState, uuid: &InstanceUuid) -> Result<std::path::PathBuf, Error> {
+    Ok(state
+        .instances
+        .get(uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await)
+}
+
+pub async fn get_instance_metadata(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<InstanceMetadata>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let path = instance_path(&state, &uuid).await?;
+    get_metadata(&path).await.map(Json)
+}
+
+pub async fn set_instance_metadata(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, key)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+    Json(value): Json<Value>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    // aliases are validated by their own endpoint before they can be run
+    if key == COMMAND_ALIASES_METADATA_KEY {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "{} is reserved, use /instance/{}/aliases instead",
+                COMMAND_ALIASES_METADATA_KEY,
+                uuid
+            ),
+        });
+    }
+    let path = instance_path(&state, &uuid).await?;
+    set_metadata(&state.path_locks, &path, key, value).await?;
+    Ok(Json(()))
+}
+
+pub async fn delete_instance_metadata(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, key)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = instance_path(&state, &uuid).await?;
+    remove_metadata(&state.path_locks, &path, &key).await?;
+    Ok(Json(()))
+}
+
+pub fn get_instance_metadata_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/metadata", get(get_instance_metadata))
+        .route(
+            "/instance/:uuid/metadata/:key",
+            put(set_instance_metadata).delete(delete_instance_metadata),
+        )
+        .with_state(state)
+}
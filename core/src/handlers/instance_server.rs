@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use axum::{
     extract::Path,
     routing::{get, post, put},
@@ -8,12 +10,19 @@ use axum::Json;
 use axum_auth::AuthBearer;
 
 use color_eyre::eyre::eyre;
+use fancy_regex::Regex;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use ts_rs::TS;
 
 use crate::{
     auth::user::UserAction,
     error::{Error, ErrorKind},
-    events::CausedBy,
+    events::{CausedBy, Event, EventInner, InstanceEventInner},
+    implementations::minecraft::crash_report::CrashReportEntry,
+    instance_metadata::{get_command_aliases, set_command_aliases, CommandAlias},
+    prelude::GameInstance,
     types::InstanceUuid,
 };
 
@@ -129,7 +138,7 @@ pub async fn send_command(
     Json(command): Json<String>,
 ) -> Result<Json<()>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
-    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    requester.try_console_command(&uuid, &command)?;
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
@@ -146,6 +155,218 @@ pub async fn send_command(
         .map(|_| Json(()))
 }
 
+pub async fn get_aliases(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<CommandAlias>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let path = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    Ok(Json(get_command_aliases(&path).await?))
+}
+
+/// Replaces every alias of the instance, the commands are only checked against
+/// the console permissions of whoever runs them
+pub async fn set_aliases(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(aliases): Json<Vec<CommandAlias>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    set_command_aliases(&state.path_locks, &path, aliases).await?;
+    Ok(Json(()))
+}
+
+/// Sends the alias's commands in order. Every command is checked against the requester's
+/// console permissions before the first is sent, so an alias can't run a command they couldn't
+pub async fn run_alias(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    let alias = get_command_aliases(&instance.path().await)
+        .await?
+        .into_iter()
+        .find(|alias| alias.name == name)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Alias {} not found", name),
+        })?;
+    for command in &alias.commands {
+        requester.try_console_command(&uuid, command)?;
+    }
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    for command in &alias.commands {
+        if let Err(e) = instance.send_command(command, caused_by.clone()).await {
+            return Err(Error {
+                kind: e.kind,
+                source: e
+                    .source
+                    .wrap_err(format!("Alias {} stopped at command \"{}\"", name, command)),
+            });
+        }
+    }
+    Ok(Json(()))
+}
+
+const DEFAULT_CAPTURE_MS: u64 = 1000;
+const MAX_CAPTURE_MS: u64 = 30_000;
+/// Bounds the response when a command floods the console
+const MAX_CAPTURED_LINES: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct CapturedCommand {
+    command: String,
+    /// `DEFAULT_CAPTURE_MS` if omitted, capped at `MAX_CAPTURE_MS`
+    capture_ms: Option<u64>,
+    /// Stops the capture early once an output line matches
+    pattern: Option<String>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct CommandOutput {
+    pub output: Vec<String>,
+    /// Whether the output is the RCON response rather than captured console lines
+    pub via_rcon: bool,
+    /// Whether the capture ended on a line matching the pattern
+    pub matched: bool,
+}
+
+/// Sends a command and returns its output.
+///
+/// Minecraft instances with an RCON connection answer with the RCON response,
+/// other instances return the console lines printed within the capture window.
+pub async fn send_command_with_output(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<CapturedCommand>,
+) -> Result<Json<CommandOutput>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_console_command(&uuid, &request.command)?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let pattern = request
+        .pattern
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid pattern: {}", e),
+        })?;
+    let capture = Duration::from_millis(
+        request
+            .capture_ms
+            .unwrap_or(DEFAULT_CAPTURE_MS)
+            .min(MAX_CAPTURE_MS),
+    );
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+
+    // `stop` goes through stdin so the instance sees the state transition
+    if let GameInstance::MinecraftInstance(minecraft) = &instance {
+        if request.command != "stop" && minecraft.get_rcon().lock().await.is_some() {
+            let response = minecraft.send_rcon(&request.command).await?;
+            let matched = pattern.as_ref().map_or(false, |pattern| {
+                pattern.is_match(&response).unwrap_or(false)
+            });
+            return Ok(Json(CommandOutput {
+                output: response.lines().map(str::to_string).collect(),
+                via_rcon: true,
+                matched,
+            }));
+        }
+    }
+
+    // subscribe before sending so no output is missed
+    let mut event_receiver = state.event_broadcaster.subscribe();
+    instance.send_command(&request.command, caused_by).await?;
+    let (output, matched) =
+        capture_output(&mut event_receiver, &uuid, capture, pattern.as_ref()).await;
+    Ok(Json(CommandOutput {
+        output,
+        via_rcon: false,
+        matched,
+    }))
+}
+
+/// Collects the instance's console lines until `capture` elapses, a line matches `pattern`
+/// or `MAX_CAPTURED_LINES` are collected, returning the lines and whether one matched
+async fn capture_output(
+    event_receiver: &mut Receiver<Event>,
+    uuid: &InstanceUuid,
+    capture: Duration,
+    pattern: Option<&Regex>,
+) -> (Vec<String>, bool) {
+    let mut output = Vec::new();
+    let mut matched = false;
+    let deadline = tokio::time::Instant::now() + capture;
+    while output.len() < MAX_CAPTURED_LINES {
+        let event = match tokio::time::timeout_at(deadline, event_receiver.recv()).await {
+            Ok(Ok(event)) => event,
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) | Err(_) => break,
+        };
+        let line = match event.event_inner {
+            EventInner::InstanceEvent(instance_event) if instance_event.instance_uuid == *uuid => {
+                match instance_event.instance_event_inner {
+                    InstanceEventInner::InstanceOutput { message } => message,
+                    _ => continue,
+                }
+            }
+            _ => continue,
+        };
+        matched = pattern.map_or(false, |pattern| pattern.is_match(&line).unwrap_or(false));
+        output.push(line);
+        if matched {
+            break;
+        }
+    }
+    (output, matched)
+}
+
 pub async fn get_instance_state(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -171,13 +392,199 @@ pub async fn get_instance_state(
     )))
 }
 
+pub async fn get_crash_reports(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<CrashReportEntry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => Ok(Json(instance.crash_reports().await?)),
+        GameInstance::GenericInstance(_) => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Crash reports are only supported for Minecraft instances"),
+        }),
+    }
+}
+
+pub async fn get_crash_report(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, file_name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<String, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => instance.read_crash_report(&file_name).await,
+        GameInstance::GenericInstance(_) => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Crash reports are only supported for Minecraft instances"),
+        }),
+    }
+}
+
+/// Accepting the EULA is part of starting the instance, so it takes the same permission
+pub async fn accept_eula(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::StartInstance(uuid.clone()))?;
+    let instance = state
+        .instances
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .clone();
+    match instance {
+        GameInstance::MinecraftInstance(instance) => {
+            instance.accept_eula(&state.path_locks, &requester).await?
+        }
+        GameInstance::GenericInstance(_) => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Only Minecraft instances have an EULA"),
+            })
+        }
+    }
+    Ok(Json(()))
+}
+
 pub fn get_instance_server_routes(state: AppState) -> Router {
     Router::new()
         .route("/instance/:uuid/start", put(start_instance))
         .route("/instance/:uuid/stop", put(stop_instance))
-        .route("/instance/:uuid/restart", put(restart_instance))
+        .route(
+            "/instance/:uuid/restart",
+            put(restart_instance).post(restart_instance),
+        )
         .route("/instance/:uuid/kill", put(kill_instance))
+        .route("/instance/:uuid/accept_eula", post(accept_eula))
         .route("/instance/:uuid/console", post(send_command))
+        .route("/instance/:uuid/command", post(send_command_with_output))
+        .route("/instance/:uuid/aliases", get(get_aliases).put(set_aliases))
+        .route("/instance/:uuid/aliases/:name/run", post(run_alias))
         .route("/instance/:uuid/state", get(get_instance_state))
+        .route("/instance/:uuid/crash_reports", get(get_crash_reports))
+        .route(
+            "/instance/:uuid/crash_reports/:file_name",
+            get(get_crash_report),
+        )
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_broadcaster::EventBroadcaster;
+
+    fn send_lines(event_broadcaster: &EventBroadcaster, uuid: &InstanceUuid, lines: &[&str]) {
+        for line in lines {
+            event_broadcaster.send(Event::new_instance_output(
+                uuid.clone(),
+                "test".to_string(),
+                line.to_string(),
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture_output_window() {
+        let (event_broadcaster, _) = EventBroadcaster::new(16);
+        let uuid = InstanceUuid::from("instance".to_string());
+        let other_uuid = InstanceUuid::from("other".to_string());
+        let mut event_receiver = event_broadcaster.subscribe();
+        send_lines(&event_broadcaster, &uuid, &["first"]);
+        send_lines(&event_broadcaster, &other_uuid, &["not ours"]);
+        send_lines(&event_broadcaster, &uuid, &["second"]);
+
+        let started = tokio::time::Instant::now();
+        let (output, matched) =
+            capture_output(&mut event_receiver, &uuid, Duration::from_millis(50), None).await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(output, vec!["first", "second"]);
+        assert!(!matched);
+    }
+
+    #[tokio::test]
+    async fn test_capture_output_pattern() {
+        let (event_broadcaster, _) = EventBroadcaster::new(16);
+        let uuid = InstanceUuid::from("instance".to_string());
+        let mut event_receiver = event_broadcaster.subscribe();
+        send_lines(
+            &event_broadcaster,
+            &uuid,
+            &[
+                "Unknown command",
+                "There are 0 of a max of 20 players online:",
+                "after the match",
+            ],
+        );
+
+        let pattern = Regex::new(r"There are \d+ of a max").unwrap();
+        let started = tokio::time::Instant::now();
+        let (output, matched) = capture_output(
+            &mut event_receiver,
+            &uuid,
+            Duration::from_secs(30),
+            Some(&pattern),
+        )
+        .await;
+        // the capture ends on the match rather than at the end of the window
+        assert!(started.elapsed() < Duration::from_secs(30));
+        assert_eq!(
+            output,
+            vec![
+                "Unknown command",
+                "There are 0 of a max of 20 players online:"
+            ]
+        );
+        assert!(matched);
+    }
+
+    #[tokio::test]
+    async fn test_capture_output_line_cap() {
+        let (event_broadcaster, _) = EventBroadcaster::new(MAX_CAPTURED_LINES * 2);
+        let uuid = InstanceUuid::from("instance".to_string());
+        let mut event_receiver = event_broadcaster.subscribe();
+        let lines: Vec<String> = (0..MAX_CAPTURED_LINES + 10)
+            .map(|i| format!("line {i}"))
+            .collect();
+        send_lines(
+            &event_broadcaster,
+            &uuid,
+            &lines.iter().map(String::as_str).collect::<Vec<_>>(),
+        );
+
+        let started = tokio::time::Instant::now();
+        let (output, matched) =
+            capture_output(&mut event_receiver, &uuid, Duration::from_secs(30), None).await;
+        assert!(started.elapsed() < Duration::from_secs(30));
+        assert_eq!(output.len(), MAX_CAPTURED_LINES);
+        assert_eq!(
+            output.last().unwrap(),
+            &format!("line {}", MAX_CAPTURED_LINES - 1)
+        );
+        assert!(!matched);
+    }
+}
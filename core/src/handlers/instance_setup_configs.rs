@@ -2,6 +2,7 @@ use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::implementations::generic;
 use crate::implementations::minecraft;
+use crate::implementations::minecraft::versions::{get_setup_versions, SetupVersions};
 use crate::minecraft::FlavourKind;
 use crate::traits::t_configurable::manifest::SetupManifest;
 use crate::traits::t_configurable::GameType;
@@ -67,6 +68,8 @@ pub async fn get_available_games() -> Json<Vec<HandlerGameType>> {
     ])
 }
 
+/// The same manifest `construct_setup_config` validates against,
+/// so forms rendered from it accept exactly what instance creation does
 pub async fn get_setup_manifest(
     Path(game_type): Path<HandlerGameType>,
 ) -> Result<Json<SetupManifest>, Error> {
@@ -75,6 +78,12 @@ pub async fn get_setup_manifest(
         .map(Json)
 }
 
+pub async fn get_setup_versions_for_game(
+    Path(game_type): Path<HandlerGameType>,
+) -> Result<Json<SetupVersions>, Error> {
+    get_setup_versions(game_type.try_into()?).await.map(Json)
+}
+
 #[derive(Deserialize)]
 pub struct GenericSetupManifestBody {
     pub url: String,
@@ -93,6 +102,14 @@ pub fn get_instance_setup_config_routes(appstate: AppState) -> Router {
     Router::new()
         .route("/games", get(get_available_games))
         .route("/setup_manifest/:game_type", get(get_setup_manifest))
+        .route(
+            "/instance/setup/:game_type/manifest",
+            get(get_setup_manifest),
+        )
+        .route(
+            "/instance/setup/:game_type/versions",
+            get(get_setup_versions_for_game),
+        )
         .route("/generic_setup_manifest", put(get_generic_setup_manifest))
         .with_state(appstate)
 }
@@ -10,7 +10,9 @@ pub mod global_settings;
 pub mod instance;
 pub mod instance_config;
 pub mod instance_fs;
+pub mod instance_logs;
 pub mod instance_macro;
+pub mod instance_metadata;
 pub mod instance_players;
 pub mod instance_server;
 pub mod instance_setup_configs;
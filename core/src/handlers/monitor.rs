@@ -1,20 +1,24 @@
 use std::{collections::HashMap, sync::Arc};
 
 use axum::{
-    extract::{ws::WebSocket, Path, WebSocketUpgrade},
+    extract::{ws::WebSocket, Path, Query, WebSocketUpgrade},
     response::Response,
     routing::get,
-    Router,
+    Json, Router,
 };
+use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
 use futures::{SinkExt, StreamExt};
 use ringbuffer::{AllocRingBuffer, RingBufferExt};
+use serde::Deserialize;
 use tokio::sync::Mutex;
 use tracing::error;
 
 use crate::{
-    error::Error,
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
     prelude::GameInstance,
+    stats_history::StatsSample,
     traits::{t_server::MonitorReport, t_server::TServer},
     types::InstanceUuid,
     AppState,
@@ -29,7 +33,7 @@ pub async fn monitor(
         .instances
         .get(&uuid)
         .ok_or_else(|| Error {
-            kind: crate::error::ErrorKind::NotFound,
+            kind: ErrorKind::NotFound,
             source: eyre!("Instance not found"),
         })?
         .to_owned();
@@ -81,8 +85,38 @@ async fn monitor_ws(
     }
 }
 
+#[derive(Deserialize)]
+pub struct StatsHistoryQuery {
+    /// Unix timestamp in seconds, the whole history is returned if omitted
+    since: Option<i64>,
+}
+
+pub async fn get_stats_history(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<StatsHistoryQuery>,
+) -> Result<Json<Vec<StatsSample>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    if !state.instances.contains_key(&uuid) {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        });
+    }
+    Ok(Json(
+        state
+            .stats_history
+            .lock()
+            .await
+            .get_since(&uuid, query.since),
+    ))
+}
+
 pub fn get_monitor_routes(state: AppState) -> Router {
     Router::new()
         .route("/monitor/:uuid", get(monitor))
+        .route("/instance/:uuid/stats/history", get(get_stats_history))
         .with_state(state)
 }
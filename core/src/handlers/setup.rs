@@ -1,5 +1,10 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
 use axum::{extract::Path, Json, Router};
 use color_eyre::eyre::eyre;
+use lazy_static::lazy_static;
+use tokio::sync::Mutex;
 
 use crate::{
     auth::{permission::UserPermission, user::User},
@@ -10,21 +15,51 @@ use crate::{
 
 use super::users::LoginReply;
 
+/// Failed setup attempts allowed within `SETUP_ATTEMPT_WINDOW` before further attempts are rejected
+const MAX_FAILED_SETUP_ATTEMPTS: usize = 5;
+const SETUP_ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // the setup key is shared by everyone, so attempts are limited globally rather than per client
+    static ref FAILED_SETUP_ATTEMPTS: Mutex<VecDeque<Instant>> = Mutex::new(VecDeque::new());
+}
+
 #[derive(serde::Deserialize)]
 pub struct OwnerSetup {
     username: String,
     password: String,
 }
 
-pub async fn setup_owner(
-    axum::extract::State(state): axum::extract::State<AppState>,
-    Path(key): Path<String>,
-    Json(owner_setup): Json<OwnerSetup>,
-) -> Result<Json<LoginReply>, Error> {
+#[derive(serde::Deserialize)]
+pub struct SetupRequest {
+    key: String,
+    #[serde(flatten)]
+    owner_setup: OwnerSetup,
+}
+
+/// Creates the owner account if `key` is the first time setup key, which can only be used once
+async fn complete_setup(
+    state: &AppState,
+    key: &str,
+    owner_setup: OwnerSetup,
+) -> Result<LoginReply, Error> {
+    let mut failed_attempts = FAILED_SETUP_ATTEMPTS.lock().await;
+    while failed_attempts
+        .front()
+        .map_or(false, |attempt| attempt.elapsed() > SETUP_ATTEMPT_WINDOW)
+    {
+        failed_attempts.pop_front();
+    }
+    if failed_attempts.len() >= MAX_FAILED_SETUP_ATTEMPTS {
+        return Err(Error {
+            kind: ErrorKind::TooManyRequests,
+            source: eyre!("Too many failed setup attempts, try again later."),
+        });
+    }
+
     let mut setup_key_lock = state.first_time_setup_key.lock().await;
     match setup_key_lock.clone() {
         Some(k) if k == key => {
-            setup_key_lock.take();
             let owner = User::new(
                 owner_setup.username,
                 &owner_setup.password,
@@ -38,24 +73,45 @@ pub async fn setup_owner(
                 .await
                 .add_user(owner.clone(), CausedBy::System)
                 .await?;
-            Ok(Json(LoginReply {
+            // only cleared once the owner exists, so a failure doesn't lock the core out of setup
+            setup_key_lock.take();
+            Ok(LoginReply {
                 token: owner.create_jwt()?,
                 user: owner.into(),
-            }))
+            })
         }
         None => Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("Setup key already used."),
-        }),
-        Some(_) => Err(Error {
-            kind: ErrorKind::PermissionDenied,
-            source: eyre!("Invalid setup key."),
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Setup is already complete."),
         }),
+        Some(_) => {
+            failed_attempts.push_back(Instant::now());
+            Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Invalid setup key."),
+            })
+        }
     }
 }
 
+pub async fn setup_owner(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(key): Path<String>,
+    Json(owner_setup): Json<OwnerSetup>,
+) -> Result<Json<LoginReply>, Error> {
+    complete_setup(&state, &key, owner_setup).await.map(Json)
+}
+
+pub async fn setup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Json(SetupRequest { key, owner_setup }): Json<SetupRequest>,
+) -> Result<Json<LoginReply>, Error> {
+    complete_setup(&state, &key, owner_setup).await.map(Json)
+}
+
 pub fn get_setup_route(state: AppState) -> Router {
     Router::new()
+        .route("/setup", axum::routing::post(setup))
         .route("/setup/:key", axum::routing::post(setup_owner))
         .with_state(state)
 }
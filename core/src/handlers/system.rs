@@ -1,66 +1,198 @@
-use axum::{routing::get, Json, Router};
+use std::str::FromStr;
+
+use axum::{
+    extract::Path,
+    routing::{get, post, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
 use serde::{Deserialize, Serialize};
-use sysinfo::{CpuExt, CpuRefreshKind, DiskExt, SystemExt};
+use sysinfo::{CpuExt, CpuRefreshKind, DiskExt, System, SystemExt};
 
-use tokio::time::sleep;
+use tokio::{sync::Mutex, time::sleep};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+use ts_rs::TS;
 
-use crate::AppState;
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    orphans::{find_orphans, kill_orphan, known_instances, OrphanProcess},
+    port_manager::PortAllocations,
+    AppState,
+};
 
 // Since MemInfo is not serializable, we need to create a new struct that is serializable.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
 pub struct MemInfo {
+    #[ts(type = "number")]
     total: u64,
+    #[ts(type = "number")]
     free: u64,
 }
 
-pub async fn get_ram(axum::extract::State(state): axum::extract::State<AppState>) -> Json<MemInfo> {
-    let mut sys = state.system.lock().await;
+fn read_ram(sys: &mut System) -> MemInfo {
     sys.refresh_memory();
-    Json(MemInfo {
+    MemInfo {
         total: sys.total_memory(),
         free: sys.available_memory(),
-    })
+    }
+}
+
+pub async fn get_ram(axum::extract::State(state): axum::extract::State<AppState>) -> Json<MemInfo> {
+    Json(read_ram(&mut *state.system.lock().await))
 }
 
 // Since DiskInfo is not serializable, we need to create a new struct that is serializable.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
 pub struct DiskInfo {
+    #[ts(type = "number")]
     total: u64,
+    #[ts(type = "number")]
     free: u64,
 }
 
-pub async fn get_disk(
-    axum::extract::State(state): axum::extract::State<AppState>,
-) -> Json<DiskInfo> {
-    let mut sys = state.system.lock().await;
+fn read_disk(sys: &mut System) -> DiskInfo {
     sys.refresh_disks_list();
     let disks = sys.disks();
-    Json(DiskInfo {
+    DiskInfo {
         total: disks.iter().fold(0, |acc, v| acc + v.total_space()),
         free: disks.iter().fold(0, |acc, v| acc + v.available_space()),
-    })
+    }
+}
+
+pub async fn get_disk(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Json<DiskInfo> {
+    Json(read_disk(&mut *state.system.lock().await))
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
 pub struct CPUInfo {
+    #[ts(type = "number")]
     pub cpu_speed: u64,
     pub cpu_load: f32,
 }
 
-pub async fn get_cpu_info(
-    axum::extract::State(state): axum::extract::State<AppState>,
-) -> Json<CPUInfo> {
-    let mut sys = state.system.lock().await;
+/// Takes 100ms, as the load is measured between two refreshes
+async fn read_cpu(sys: &mut System) -> CPUInfo {
     sys.refresh_cpu_specifics(CpuRefreshKind::everything());
     sleep(tokio::time::Duration::from_millis(100)).await;
     sys.refresh_cpu();
-    Json(CPUInfo {
+    CPUInfo {
         cpu_speed: {
             sys.cpus().iter().fold(0, |acc, v| acc + v.frequency()) / sys.cpus().len() as u64
         },
         cpu_load: sys.cpus().iter().fold(0.0, |acc, v| acc + v.cpu_usage())
             / sys.cpus().len() as f32,
-    })
+    }
+}
+
+pub async fn get_cpu_info(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Json<CPUInfo> {
+    Json(read_cpu(&mut *state.system.lock().await).await)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct SystemInfo {
+    pub cpu: CPUInfo,
+    pub ram: MemInfo,
+    pub disk: DiskInfo,
+}
+
+pub async fn read_system_info(system: &Mutex<System>) -> SystemInfo {
+    let mut sys = system.lock().await;
+    SystemInfo {
+        cpu: read_cpu(&mut sys).await,
+        ram: read_ram(&mut sys),
+        disk: read_disk(&mut sys),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LogLevel {
+    level: String,
+}
+
+/// Changes the level of the core's logs until the next restart
+pub async fn set_log_level(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(LogLevel { level }): Json<LogLevel>,
+) -> Result<Json<()>, Error> {
+    state
+        .users_manager
+        .read()
+        .await
+        .try_auth_or_err(&token)?
+        .try_action(&UserAction::ManageSystem)?;
+    let level = LevelFilter::from_str(&level).map_err(|_| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!(
+            "Invalid log level {}, expected one of trace, debug, info, warn, error or off",
+            level
+        ),
+    })?;
+    state
+        .log_filter
+        .reload(EnvFilter::new(format!("lodestone_core={}", level)))
+        .map_err(|e| eyre!("Failed to change log level: {}", e))?;
+    tracing::info!("Log level changed to {}", level);
+    Ok(Json(()))
+}
+
+/// The port range reserved for instances and the ports they are on
+pub async fn get_ports(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PortAllocations>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(state.port_manager.lock().await.allocations()))
+}
+
+/// Server processes left running by instances the core isn't running
+pub async fn get_orphans(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<OrphanProcess>>, Error> {
+    state
+        .users_manager
+        .read()
+        .await
+        .try_auth_or_err(&token)?
+        .try_action(&UserAction::ManageSystem)?;
+    let instances = known_instances(&state.instances).await;
+    Ok(Json(find_orphans(
+        &mut *state.system.lock().await,
+        &instances,
+    )))
+}
+
+/// Only orphaned server processes can be killed, any other pid is refused
+pub async fn kill_orphan_process(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(pid): Path<u32>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<OrphanProcess>, Error> {
+    state
+        .users_manager
+        .read()
+        .await
+        .try_auth_or_err(&token)?
+        .try_action(&UserAction::ManageSystem)?;
+    let instances = known_instances(&state.instances).await;
+    let orphan = kill_orphan(&mut *state.system.lock().await, &instances, pid)?;
+    tracing::info!(
+        "Killed process {} left running by instance {}",
+        orphan.pid,
+        orphan.instance_name
+    );
+    Ok(Json(orphan))
 }
 
 pub fn get_system_routes(state: AppState) -> Router {
@@ -68,5 +200,9 @@ pub fn get_system_routes(state: AppState) -> Router {
         .route("/system/ram", get(get_ram))
         .route("/system/disk", get(get_disk))
         .route("/system/cpu", get(get_cpu_info))
+        .route("/system/ports", get(get_ports))
+        .route("/system/log_level", put(set_log_level))
+        .route("/system/orphans", get(get_orphans))
+        .route("/system/orphans/:pid/kill", post(kill_orphan_process))
         .with_state(state)
 }
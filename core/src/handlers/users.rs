@@ -1,12 +1,15 @@
+use std::collections::HashMap;
+
 use crate::{
     auth::{
         jwt_token::JwtToken,
         permission::UserPermission,
-        user::{PublicUser, User, UserAction},
+        user::{PublicUser, User, UserAction, UserActionKind},
         user_id::UserId,
     },
     error::{Error, ErrorKind},
     events::CausedBy,
+    types::InstanceUuid,
     AppState,
 };
 
@@ -127,6 +130,36 @@ pub async fn update_permissions(
     Ok(Json(()))
 }
 
+pub async fn set_read_only(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uid): Path<UserId>,
+    AuthBearer(token): AuthBearer,
+    Json(is_read_only): Json<bool>,
+) -> Result<Json<()>, Error> {
+    let mut users_manager = state.users_manager.write().await;
+
+    let requester = users_manager.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ManagePermission)?;
+    let target = users_manager.get_user(&uid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("User not found"),
+    })?;
+    if target.is_admin && !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can change an admin's role"),
+        });
+    }
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    users_manager
+        .set_read_only(uid, is_read_only, caused_by)
+        .await?;
+    Ok(Json(()))
+}
+
 pub async fn get_self_info(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
@@ -141,6 +174,28 @@ pub async fn get_self_info(
     ))
 }
 
+#[derive(Deserialize)]
+pub struct CanPerformQuery {
+    actions: Vec<UserActionKind>,
+    /// Required for instance specific actions
+    instance_uuid: Option<InstanceUuid>,
+}
+
+/// Reports which of the actions the requester may perform, without performing any
+pub async fn can_perform_actions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(query): Json<CanPerformQuery>,
+) -> Result<Json<HashMap<UserActionKind, bool>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let mut allowed = HashMap::new();
+    for kind in query.actions {
+        let action = UserAction::from_kind(kind, query.instance_uuid.clone())?;
+        allowed.insert(kind, requester.can_perform_action(&action));
+    }
+    Ok(Json(allowed))
+}
+
 pub async fn get_user_info(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uid): Path<UserId>,
@@ -296,7 +351,9 @@ pub fn get_user_routes(state: AppState) -> Router {
         .route("/user/:uid", get(get_user_info))
         .route("/user/:uid", delete(delete_user))
         .route("/user/:uid/update_perm", put(update_permissions))
+        .route("/user/:uid/read_only", put(set_read_only))
         .route("/user/info", get(get_self_info))
+        .route("/auth/can", post(can_perform_actions))
         .route("/user/:uid/rename", put(rename_user))
         .route("/user/:uid/password", put(change_password))
         .route("/user/login", post(login))
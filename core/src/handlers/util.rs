@@ -1,6 +1,26 @@
-use color_eyre::eyre::Context;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
-use crate::error::Error;
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::BodyStream,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use color_eyre::eyre::{eyre, Context};
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+
+use crate::{
+    auth::{user::User, user_id::UserId},
+    error::{Error, ErrorKind},
+    events::ProgressionEventID,
+};
 
 pub fn parse_bearer_token(token: &str) -> Option<String> {
     let mut split = token.split_ascii_whitespace();
@@ -23,3 +43,224 @@ pub fn decode_base64(input: &str) -> Result<String, Error> {
     )
     .context("Invalid UTF-8")?)
 }
+
+/// Collects a request body, failing with `ErrorKind::PayloadTooLarge` once it exceeds `limit` bytes
+/// instead of buffering the rest of it
+pub async fn read_body_limited(mut body: BodyStream, limit: u64) -> Result<Bytes, Error> {
+    let mut ret = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("Failed to read request body")?;
+        if (ret.len() + chunk.len()) as u64 > limit {
+            return Err(Error {
+                kind: ErrorKind::PayloadTooLarge,
+                source: eyre!("Request body is larger than the limit of {} bytes", limit),
+            });
+        }
+        ret.extend_from_slice(&chunk);
+    }
+    Ok(ret.into())
+}
+
+/// Gzipped files that decompress to more than this are cut off, so a small upload can't be used
+/// to keep the core decompressing
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+const DECOMPRESS_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize, Default)]
+pub struct ReadQuery {
+    /// Return `.gz` files as stored instead of decompressing them
+    #[serde(default)]
+    pub raw: bool,
+}
+
+/// Streams a text file, decompressing it first if it ends in `.gz` unless `raw` is set,
+/// such as the `logs/*.log.gz` Minecraft rotates its logs to. `raw` `.gz` files are sent as stored.
+///
+/// The response has started by the time a file decompresses past `MAX_DECOMPRESSED_SIZE`,
+/// so the body is cut off with an error instead
+pub async fn read_text_file(path: &Path, raw: bool) -> Result<Response, Error> {
+    let is_gzip = path
+        .extension()
+        .map_or(false, |extension| extension == "gz");
+    if raw || !is_gzip {
+        let file = tokio::fs::File::open(path)
+            .await
+            .context("Failed to read file")?;
+        let content_type = if is_gzip {
+            "application/gzip"
+        } else {
+            "text/plain; charset=utf-8"
+        };
+        return Ok((
+            [(header::CONTENT_TYPE, content_type)],
+            StreamBody::new(ReaderStream::new(file)),
+        )
+            .into_response());
+    }
+    // opened before responding, so a missing file is still an error response
+    let file = std::fs::File::open(path).context("Failed to read file")?;
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut decoder = flate2::read::GzDecoder::new(file);
+        let mut decompressed = 0;
+        loop {
+            let mut chunk = vec![0; DECOMPRESS_CHUNK_SIZE];
+            let item = match decoder.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(n) if decompressed + n as u64 > MAX_DECOMPRESSED_SIZE => {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!(
+                            "File decompresses to more than the limit of {} bytes",
+                            MAX_DECOMPRESSED_SIZE
+                        ),
+                    ))
+                }
+                Ok(n) => {
+                    decompressed += n as u64;
+                    chunk.truncate(n);
+                    Ok(Bytes::from(chunk))
+                }
+                Err(e) => Err(e),
+            };
+            let is_err = item.is_err();
+            // the client went away
+            if sender.blocking_send(item).is_err() || is_err {
+                return;
+            }
+        }
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        StreamBody::new(ReceiverStream::new(receiver)),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, Default)]
+pub struct UploadQuery {
+    /// Fail with `NotFound` if the destination directory doesn't exist, instead of creating it
+    #[serde(default)]
+    pub require_existing_dir: bool,
+}
+
+/// Creates the directory files are uploaded to, unless the query requires it to exist already
+pub async fn prepare_upload_dir(path_to_dir: &Path, query: &UploadQuery) -> Result<(), Error> {
+    if path_to_dir.is_dir() {
+        return Ok(());
+    }
+    if query.require_existing_dir {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Directory {} doesn't exist", path_to_dir.display()),
+        });
+    }
+    crate::util::fs::create_dir_all(path_to_dir).await
+}
+
+/// Uploads in progress keyed by their progression event id, along with who started them
+#[derive(Clone, Default)]
+pub struct UploadCancellations(
+    Arc<Mutex<HashMap<ProgressionEventID, (UserId, CancellationToken)>>>,
+);
+
+impl UploadCancellations {
+    /// The upload can be cancelled until the returned guard is dropped
+    pub fn register(&self, event_id: ProgressionEventID, uid: UserId) -> UploadCancellation {
+        let token = CancellationToken::new();
+        self.0
+            .lock()
+            .unwrap()
+            .insert(event_id.clone(), (uid, token.clone()));
+        UploadCancellation {
+            cancellations: self.clone(),
+            event_id,
+            token,
+        }
+    }
+
+    /// Only the user who started the upload and the owner can cancel it
+    pub fn cancel(&self, event_id: &ProgressionEventID, requester: &User) -> Result<(), Error> {
+        let cancellations = self.0.lock().unwrap();
+        let (uid, token) = cancellations.get(event_id).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("No upload in progress with this id"),
+        })?;
+        if *uid != requester.uid && !requester.is_owner {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Not authorized to cancel this upload"),
+            });
+        }
+        token.cancel();
+        Ok(())
+    }
+}
+
+pub struct UploadCancellation {
+    cancellations: UploadCancellations,
+    event_id: ProgressionEventID,
+    token: CancellationToken,
+}
+
+impl UploadCancellation {
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+}
+
+impl Drop for UploadCancellation {
+    fn drop(&mut self) {
+        self.cancellations.0.lock().unwrap().remove(&self.event_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use axum::body::HttpBody;
+
+    use super::*;
+
+    async fn read_body(response: Response) -> Vec<u8> {
+        let mut body = response.into_body();
+        let mut ret = Vec::new();
+        while let Some(chunk) = body.data().await {
+            ret.extend_from_slice(&chunk.unwrap());
+        }
+        ret
+    }
+
+    #[tokio::test]
+    async fn test_read_text_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("2024-01-01-1.log.gz");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"[00:00:00] hello").unwrap();
+        let compressed = encoder.finish().unwrap();
+        std::fs::write(&path, &compressed).unwrap();
+
+        assert_eq!(
+            read_body(read_text_file(&path, false).await.unwrap()).await,
+            b"[00:00:00] hello"
+        );
+        // sent as stored
+        assert_eq!(
+            read_body(read_text_file(&path, true).await.unwrap()).await,
+            compressed
+        );
+
+        let plain = temp_dir.path().join("latest.log");
+        std::fs::write(&plain, "plain").unwrap();
+        assert_eq!(
+            read_body(read_text_file(&plain, false).await.unwrap()).await,
+            b"plain"
+        );
+        assert!(
+            read_text_file(&temp_dir.path().join("missing.log.gz"), false)
+                .await
+                .is_err()
+        );
+    }
+}
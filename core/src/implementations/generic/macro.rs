@@ -67,6 +67,7 @@ impl TMacro for GenericInstance {
         &self,
         _name: &str,
         _args: Vec<String>,
+        _structured_args: Option<serde_json::Value>,
         _caused_by: CausedBy,
     ) -> Result<TaskEntry, Error> {
         Err(Error {
@@ -12,7 +12,10 @@ use crate::{
     error::Error,
     event_broadcaster::EventBroadcaster,
     events::CausedBy,
-    macro_executor::{self, MacroExecutor, MacroPID, SpawnResult, WorkerOptionGenerator},
+    macro_executor::{
+        self, MacroExecutor, MacroPID, SpawnOptions, SpawnResult, WorkerOptionGenerator,
+    },
+    prelude::path_to_tmp,
     traits::{
         t_configurable::{
             manifest::{SetupManifest, SetupValue},
@@ -110,17 +113,13 @@ impl GenericInstance {
                 "Failed to write bootstrap to {}",
                 &path_to_bootstrap.display()
             ))?;
-        tokio::fs::write(
+        crate::util::fs::write_atomic(
             &path_to_config,
             serde_json::to_string_pretty(&dot_lodestone_config).context(
                 "Failed to serialize config to string. This is a bug, please report it.",
             )?,
         )
-        .await
-        .context(format!(
-            "Failed to write config to {}",
-            &path_to_config.display()
-        ))?;
+        .await?;
 
         let procedure_bridge = bridge::procedure_call::ProcedureBridge::new();
 
@@ -131,11 +130,13 @@ impl GenericInstance {
         } = core_macro_executor
             .spawn(
                 path_to_bootstrap,
-                Vec::new(),
-                CausedBy::System,
+                path.clone(),
                 Box::new(GenericMainWorkerGenerator::new(procedure_bridge.clone())),
-                None,
-                Some(dot_lodestone_config.uuid().clone()),
+                SpawnOptions {
+                    caused_by: CausedBy::System,
+                    instance_uuid: Some(dot_lodestone_config.uuid().clone()),
+                    ..Default::default()
+                },
             )
             .await?;
         detach_future.await;
@@ -174,11 +175,13 @@ impl GenericInstance {
         } = core_macro_executor
             .spawn(
                 path_to_instance.join("run.ts"),
-                Vec::new(),
-                CausedBy::System,
+                path_to_instance.clone(),
                 Box::new(GenericMainWorkerGenerator::new(procedure_bridge.clone())),
-                None,
-                Some(dot_lodestone_config.uuid().clone()),
+                SpawnOptions {
+                    caused_by: CausedBy::System,
+                    instance_uuid: Some(dot_lodestone_config.uuid().clone()),
+                    ..Default::default()
+                },
             )
             .await?;
 
@@ -232,13 +235,14 @@ impl GenericInstance {
         } = macro_executor
             .spawn(
                 temp_file_path,
-                Vec::new(),
-                CausedBy::System,
+                path_to_tmp().clone(),
                 Box::new(InitWorkerGenerator {
                     bridge: procedure_bridge.clone(),
                 }),
-                None,
-                None,
+                SpawnOptions {
+                    caused_by: CausedBy::System,
+                    ..Default::default()
+                },
             )
             .await?;
 
@@ -288,6 +292,8 @@ impl TInstance for GenericInstance {
             player_count: self.get_player_count().await.ok(),
             max_player_count: self.get_max_player_count().await.ok(),
             player_list: self.get_player_list().await.ok(),
+            detected_version: None,
+            version_mismatch: false,
         }
     }
 }
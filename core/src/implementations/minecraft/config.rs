@@ -0,0 +1,302 @@
+use std::sync::atomic;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::traits::t_configurable::TConfigurable;
+use crate::util::deserialize_some;
+
+use super::configurable::{CmdArgSetting, ServerPropertySetting};
+use super::console_log::ConsoleLogRotation;
+use super::cpu_affinity::validate_cpu_affinity;
+use super::idle_shutdown::IdleShutdown;
+use super::jvm_flags::JvmFlagsPreset;
+use super::startup_progress::{validate_startup_milestones, StartupMilestone};
+use super::{Flavour, MinecraftInstance, RestoreConfig};
+
+/// The user facing settings of a Minecraft instance
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct MinecraftInstanceConfig {
+    pub name: String,
+    pub description: String,
+    pub version: String,
+    pub flavour: Flavour,
+    pub port: u32,
+    pub min_ram: u32,
+    pub max_ram: u32,
+    pub cmd_args: Vec<String>,
+    pub jvm_flags: JvmFlagsPreset,
+    pub auto_start: bool,
+    pub restart_on_crash: bool,
+    pub backup_period: Option<u32>,
+    pub idle_shutdown: Option<IdleShutdown>,
+    pub console_log: Option<ConsoleLogRotation>,
+    pub cpu_affinity: Vec<usize>,
+    pub startup_milestones: Option<Vec<StartupMilestone>>,
+}
+
+impl From<&RestoreConfig> for MinecraftInstanceConfig {
+    fn from(config: &RestoreConfig) -> Self {
+        Self {
+            name: config.name.clone(),
+            description: config.description.clone(),
+            version: config.version.clone(),
+            flavour: config.flavour.clone(),
+            port: config.port,
+            min_ram: config.min_ram,
+            max_ram: config.max_ram,
+            cmd_args: config.cmd_args.clone(),
+            jvm_flags: config.jvm_flags.clone(),
+            auto_start: config.auto_start,
+            restart_on_crash: config.restart_on_crash,
+            backup_period: config.backup_period,
+            idle_shutdown: config.idle_shutdown.clone(),
+            console_log: config.console_log.clone(),
+            cpu_affinity: config.cpu_affinity.clone(),
+            startup_milestones: config.startup_milestones.clone(),
+        }
+    }
+}
+
+/// A partial update of `MinecraftInstanceConfig`, fields left out are unchanged
+#[derive(Debug, Clone, Default, Deserialize, TS)]
+#[ts(export)]
+pub struct MinecraftInstanceConfigUpdate {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub port: Option<u32>,
+    #[serde(default)]
+    pub min_ram: Option<u32>,
+    #[serde(default)]
+    pub max_ram: Option<u32>,
+    #[serde(default)]
+    pub cmd_args: Option<Vec<String>>,
+    #[serde(default)]
+    pub jvm_flags: Option<JvmFlagsPreset>,
+    #[serde(default)]
+    pub auto_start: Option<bool>,
+    #[serde(default)]
+    pub restart_on_crash: Option<bool>,
+    /// `null` disables backups
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[ts(optional)]
+    pub backup_period: Option<Option<u32>>,
+    /// `null` disables idle shutdown
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[ts(optional)]
+    pub idle_shutdown: Option<Option<IdleShutdown>>,
+    /// `null` stops writing the console log
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[ts(optional)]
+    pub console_log: Option<Option<ConsoleLogRotation>>,
+    /// An empty list unpins the instance
+    #[serde(default)]
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// `null` goes back to the defaults of the flavour
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[ts(optional)]
+    pub startup_milestones: Option<Option<Vec<StartupMilestone>>>,
+}
+
+impl MinecraftInstanceConfigUpdate {
+    fn apply(self, config: &mut RestoreConfig) {
+        if let Some(name) = self.name {
+            config.name = name;
+        }
+        if let Some(description) = self.description {
+            config.description = description;
+        }
+        if let Some(version) = self.version {
+            config.version = version;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(min_ram) = self.min_ram {
+            config.min_ram = min_ram;
+        }
+        if let Some(max_ram) = self.max_ram {
+            config.max_ram = max_ram;
+        }
+        if let Some(cmd_args) = self.cmd_args {
+            config.cmd_args = cmd_args;
+        }
+        if let Some(jvm_flags) = self.jvm_flags {
+            config.jvm_flags = jvm_flags;
+        }
+        if let Some(auto_start) = self.auto_start {
+            config.auto_start = auto_start;
+        }
+        if let Some(restart_on_crash) = self.restart_on_crash {
+            config.restart_on_crash = restart_on_crash;
+        }
+        if let Some(backup_period) = self.backup_period {
+            config.backup_period = backup_period;
+        }
+        if let Some(idle_shutdown) = self.idle_shutdown {
+            config.idle_shutdown = idle_shutdown;
+        }
+        if let Some(console_log) = self.console_log {
+            config.console_log = console_log;
+        }
+        if let Some(cpu_affinity) = self.cpu_affinity {
+            config.cpu_affinity = cpu_affinity;
+        }
+        if let Some(startup_milestones) = self.startup_milestones {
+            config.startup_milestones = startup_milestones;
+        }
+    }
+}
+
+fn bad_request(message: &str) -> Error {
+    Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!(message.to_string()),
+    }
+}
+
+/// Shared with `set_name`, so renaming through a config update follows the same rules
+pub(super) fn validate_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() {
+        return Err(bad_request("Name cannot be empty"));
+    }
+    if name.len() > 100 {
+        return Err(bad_request("Name cannot be longer than 100 characters"));
+    }
+    Ok(())
+}
+
+/// Checks the settings users can change, the same way setting them one at a time does
+pub(super) fn validate_config(config: &RestoreConfig) -> Result<(), Error> {
+    validate_name(&config.name)?;
+    if config.port == 0 || config.port > u16::MAX as u32 {
+        return Err(bad_request("Port must be between 1 and 65535"));
+    }
+    if config.min_ram == 0 || config.max_ram == 0 {
+        return Err(bad_request("RAM must be at least 1 MB"));
+    }
+    if config.min_ram > config.max_ram {
+        return Err(bad_request("Minimum RAM cannot be more than maximum RAM"));
+    }
+    if config.backup_period == Some(0) {
+        return Err(bad_request("Backup period must be at least a minute"));
+    }
+    if let Some(IdleShutdown { minutes: 0 }) = config.idle_shutdown {
+        return Err(bad_request(
+            "Idle shutdown period must be at least a minute",
+        ));
+    }
+    if let Some(console_log) = &config.console_log {
+        console_log.validate()?;
+    }
+    validate_cpu_affinity(&config.cpu_affinity)?;
+    if let Some(startup_milestones) = &config.startup_milestones {
+        validate_startup_milestones(startup_milestones)?;
+    }
+    config.jvm_flags.validate()
+}
+
+impl MinecraftInstance {
+    pub async fn instance_config(&self) -> MinecraftInstanceConfig {
+        (&*self.config.lock().await).into()
+    }
+
+    /// Validates the whole update before applying any of it
+    ///
+    /// Changing the version requires the server to be stopped, as the server jar is replaced
+    pub async fn update_instance_config(
+        &self,
+        update: MinecraftInstanceConfigUpdate,
+    ) -> Result<(), Error> {
+        // checked up front, so an invalid update doesn't leave a new server jar behind
+        let mut new_config = self.config.lock().await.clone();
+        update.clone().apply(&mut new_config);
+        validate_config(&new_config)?;
+
+        // downloads the new jar, which is too slow to do while holding the config lock
+        if let Some(version) = update.version.clone() {
+            self.change_version(version).await?;
+        }
+
+        {
+            // applied to the config as it is now, so concurrent updates don't undo each other
+            let mut config = self.config.lock().await;
+            let mut new_config = config.clone();
+            update.apply(&mut new_config);
+            validate_config(&new_config)?;
+
+            // the configurable manifest is the source of truth for these,
+            // so it has to be updated or the change would be overwritten by the next sync
+            let mut manifest = self.configurable_manifest.lock().await;
+            manifest.set_setting(
+                ServerPropertySetting::get_section_id(),
+                ServerPropertySetting::ServerPort(new_config.port as u16).into(),
+            )?;
+            manifest.set_setting(
+                CmdArgSetting::get_section_id(),
+                CmdArgSetting::MinRam(new_config.min_ram).into(),
+            )?;
+            manifest.set_setting(
+                CmdArgSetting::get_section_id(),
+                CmdArgSetting::MaxRam(new_config.max_ram).into(),
+            )?;
+            manifest.set_setting(
+                CmdArgSetting::get_section_id(),
+                CmdArgSetting::Args(new_config.cmd_args.clone()).into(),
+            )?;
+            self.auto_start
+                .store(new_config.auto_start, atomic::Ordering::Relaxed);
+            self.restart_on_crash
+                .store(new_config.restart_on_crash, atomic::Ordering::Relaxed);
+            *config = new_config;
+        }
+        self.write_config_to_file().await?;
+        self.write_properties_to_file().await
+    }
+}
+
+#[test]
+fn test_config_update() {
+    let mut config = RestoreConfig {
+        name: "test".to_string(),
+        version: "1.20.1".to_string(),
+        flavour: Flavour::Vanilla,
+        description: String::new(),
+        cmd_args: Vec::new(),
+        java_cmd: None,
+        port: 25565,
+        min_ram: 1024,
+        max_ram: 2048,
+        auto_start: false,
+        restart_on_crash: false,
+        backup_period: Some(30),
+        jre_major_version: 17,
+        has_started: false,
+        jvm_flags: JvmFlagsPreset::None,
+        idle_shutdown: None,
+        env: Default::default(),
+        console_log: None,
+        cpu_affinity: Vec::new(),
+        startup_milestones: None,
+    };
+    let update: MinecraftInstanceConfigUpdate =
+        serde_json::from_str(r#"{"max_ram": 4096, "backup_period": null}"#).unwrap();
+    update.apply(&mut config);
+    assert_eq!(config.max_ram, 4096);
+    assert_eq!(config.min_ram, 1024);
+    assert_eq!(config.backup_period, None);
+    assert!(validate_config(&config).is_ok());
+
+    let update: MinecraftInstanceConfigUpdate =
+        serde_json::from_str(r#"{"min_ram": 8192}"#).unwrap();
+    update.apply(&mut config);
+    assert!(validate_config(&config).is_err());
+}
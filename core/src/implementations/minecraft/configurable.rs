@@ -13,9 +13,11 @@ use crate::traits::t_configurable::{Game, TConfigurable};
 use crate::traits::t_server::State;
 
 use crate::types::InstanceUuid;
-use crate::util::download_file;
 
-use super::util::{get_fabric_jar_url, get_paper_jar_url, get_vanilla_jar_url};
+use super::config::validate_name;
+use super::util::{
+    download_verified_jar, get_fabric_jar_url, get_paper_jar_url, get_vanilla_jar_url,
+};
 use super::MinecraftInstance;
 
 #[async_trait]
@@ -61,18 +63,7 @@ impl TConfigurable for MinecraftInstance {
     }
 
     async fn set_name(&self, name: String) -> Result<(), Error> {
-        if name.is_empty() {
-            return Err(Error {
-                kind: ErrorKind::BadRequest,
-                source: eyre!("Name cannot be empty"),
-            });
-        }
-        if name.len() > 100 {
-            return Err(Error {
-                kind: ErrorKind::BadRequest,
-                source: eyre!("Name cannot be longer than 100 characters"),
-            });
-        }
+        validate_name(&name)?;
         self.config.lock().await.name = name;
         self.write_config_to_file().await?;
         Ok(())
@@ -119,7 +110,7 @@ impl TConfigurable for MinecraftInstance {
         if version == self.config.lock().await.version {
             return Ok(());
         }
-        let (url, _) = match self.config.lock().await.flavour {
+        let (url, _, checksum) = match self.config.lock().await.flavour {
             super::Flavour::Vanilla => get_vanilla_jar_url(&version).await.ok_or_else(|| {
                 let error_msg =
                     format!("Cannot get the vanilla jar version for version {}", version);
@@ -130,6 +121,7 @@ impl TConfigurable for MinecraftInstance {
             })?,
             super::Flavour::Fabric { .. } => get_fabric_jar_url(&version, &None, &None)
                 .await
+                .map(|(url, flavour)| (url, flavour, None))
                 .ok_or_else(|| {
                     let error_msg =
                         format!("Cannot get the fabric jar version for version {}", version);
@@ -158,12 +150,13 @@ impl TConfigurable for MinecraftInstance {
         };
         let lodestone_tmp = path_to_tmp().clone();
         let temp_dir = tempfile::tempdir_in(lodestone_tmp).context("Failed to create temp dir")?;
-        download_file(
+        download_verified_jar(
             &url,
             temp_dir.path(),
-            Some("server.jar"),
+            "server.jar",
+            checksum.as_ref(),
             &Box::new(|_| {}),
-            true,
+            &|| {},
         )
         .await?;
         let jar_path = temp_dir.path().join("server.jar");
@@ -0,0 +1,251 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::error;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::events::{Event, EventInner, InstanceEventInner};
+use crate::traits::t_server::State;
+
+use super::MinecraftInstance;
+
+/// Kept apart from `logs/`, which belongs to the server
+const CONSOLE_LOG_DIR: &str = "lodestone_logs";
+const CONSOLE_LOG_NAME: &str = "console";
+
+/// Archives beyond this are never kept, so a typo can't fill the instance directory
+const MAX_KEPT_ARCHIVES: u32 = 100;
+
+/// Writes the console output of the instance to `lodestone_logs/console.log`,
+/// rolling it over to `console.1.log` once it grows past `max_size_mb`
+///
+/// Older archives are shifted to `console.2.log` and so on, only the newest `keep` are kept
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct ConsoleLogRotation {
+    pub max_size_mb: u32,
+    pub keep: u32,
+    /// Gzip archives, naming them `console.<n>.log.gz`
+    #[serde(default)]
+    pub compress: bool,
+}
+
+impl ConsoleLogRotation {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.max_size_mb == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Console log size limit must be at least 1 MB"),
+            });
+        }
+        if self.keep > MAX_KEPT_ARCHIVES {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "At most {} console log archives can be kept",
+                    MAX_KEPT_ARCHIVES
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    fn max_size(&self) -> u64 {
+        self.max_size_mb as u64 * 1024 * 1024
+    }
+}
+
+fn archive_path(dir: &Path, index: u32, compressed: bool) -> PathBuf {
+    if compressed {
+        dir.join(format!("{CONSOLE_LOG_NAME}.{index}.log.gz"))
+    } else {
+        dir.join(format!("{CONSOLE_LOG_NAME}.{index}.log"))
+    }
+}
+
+/// Moves `console.log` to `console.1.log`, shifting older archives up and dropping those past `keep`
+async fn rotate(dir: &Path, rotation: &ConsoleLogRotation) -> Result<(), Error> {
+    let active = dir.join(format!("{CONSOLE_LOG_NAME}.log"));
+    // archives may have been written with or without compression
+    for compressed in [false, true] {
+        for index in (1..=MAX_KEPT_ARCHIVES).rev() {
+            let path = archive_path(dir, index, compressed);
+            if !path.exists() {
+                continue;
+            }
+            if index >= rotation.keep {
+                tokio::fs::remove_file(&path)
+                    .await
+                    .context(format!("Failed to remove {}", path.display()))?;
+            } else {
+                tokio::fs::rename(&path, archive_path(dir, index + 1, compressed))
+                    .await
+                    .context(format!("Failed to rotate {}", path.display()))?;
+            }
+        }
+    }
+    if rotation.keep == 0 {
+        return tokio::fs::remove_file(&active)
+            .await
+            .context("Failed to remove console log")
+            .map_err(Error::from);
+    }
+    if !rotation.compress {
+        return tokio::fs::rename(&active, archive_path(dir, 1, false))
+            .await
+            .context("Failed to rotate console log")
+            .map_err(Error::from);
+    }
+    let archive = archive_path(dir, 1, true);
+    tokio::task::spawn_blocking({
+        let active = active.clone();
+        move || -> Result<(), Error> {
+            let mut input = std::fs::File::open(&active).context("Failed to open console log")?;
+            let output =
+                std::fs::File::create(&archive).context("Failed to create console log archive")?;
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+            std::io::copy(&mut input, &mut encoder).context("Failed to compress console log")?;
+            encoder.finish().context("Failed to compress console log")?;
+            Ok(())
+        }
+    })
+    .await
+    .context("Failed to join blocking task")??;
+    tokio::fs::remove_file(&active)
+        .await
+        .context("Failed to remove console log")?;
+    Ok(())
+}
+
+async fn open_active(dir: &Path) -> Result<(tokio::fs::File, u64), Error> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .context("Failed to create console log directory")?;
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(format!("{CONSOLE_LOG_NAME}.log")))
+        .await
+        .context("Failed to open console log")?;
+    let size = file
+        .metadata()
+        .await
+        .context("Failed to read console log size")?
+        .len();
+    Ok((file, size))
+}
+
+impl MinecraftInstance {
+    pub async fn set_console_log_rotation(
+        &self,
+        console_log: Option<ConsoleLogRotation>,
+    ) -> Result<(), Error> {
+        if let Some(console_log) = &console_log {
+            console_log.validate()?;
+        }
+        self.config.lock().await.console_log = console_log;
+        self.write_config_to_file().await
+    }
+
+    /// Appends the console output of one run of the server to the console log, if enabled
+    ///
+    /// `event_receiver` should be subscribed before the server process is spawned,
+    /// so no output is missed
+    pub(super) async fn console_log_task(self, mut event_receiver: Receiver<Event>) {
+        let rotation = match self.config.lock().await.console_log.clone() {
+            Some(rotation) => rotation,
+            None => return,
+        };
+        let name = self.config.lock().await.name.clone();
+        let dir = self.path_to_instance.join(CONSOLE_LOG_DIR);
+        let (mut file, mut size) = match open_active(&dir).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                error!("[{}] Failed to open console log: {}", name, e);
+                return;
+            }
+        };
+        loop {
+            let event = match event_receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            let line = match event.event_inner {
+                EventInner::InstanceEvent(instance_event)
+                    if instance_event.instance_uuid == self.uuid =>
+                {
+                    match instance_event.instance_event_inner {
+                        InstanceEventInner::InstanceOutput { message } => message,
+                        InstanceEventInner::StateTransition {
+                            to: State::Stopped | State::Error,
+                            ..
+                        } => return,
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+            if size > 0 && size + line.len() as u64 + 1 > rotation.max_size() {
+                drop(file);
+                if let Err(e) = rotate(&dir, &rotation).await {
+                    error!("[{}] Failed to rotate console log: {}", name, e);
+                }
+                (file, size) = match open_active(&dir).await {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        error!("[{}] Failed to open console log: {}", name, e);
+                        return;
+                    }
+                };
+            }
+            if let Err(e) = file.write_all(format!("{line}\n").as_bytes()).await {
+                error!("[{}] Failed to write console log: {}", name, e);
+                return;
+            }
+            size += line.len() as u64 + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let rotation = ConsoleLogRotation {
+            max_size_mb: 1,
+            keep: 2,
+            compress: false,
+        };
+        for run in 0..3 {
+            tokio::fs::write(dir.path().join("console.log"), format!("run {run}"))
+                .await
+                .unwrap();
+            rotate(dir.path(), &rotation).await.unwrap();
+        }
+        let read = |index| std::fs::read_to_string(archive_path(dir.path(), index, false)).ok();
+        assert_eq!(read(1).as_deref(), Some("run 2"));
+        assert_eq!(read(2).as_deref(), Some("run 1"));
+        assert_eq!(read(3), None);
+        assert!(!dir.path().join("console.log").exists());
+
+        let rotation = ConsoleLogRotation {
+            compress: true,
+            ..rotation
+        };
+        tokio::fs::write(dir.path().join("console.log"), "run 3")
+            .await
+            .unwrap();
+        rotate(dir.path(), &rotation).await.unwrap();
+        assert!(archive_path(dir.path(), 1, true).exists());
+        assert_eq!(read(2).as_deref(), Some("run 2"));
+        assert_eq!(read(3), None);
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+
+use color_eyre::eyre::eyre;
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::error::{Error, ErrorKind};
+
+use super::MinecraftInstance;
+
+/// Cores on the host, including those the core itself isn't allowed to run on
+fn core_count() -> usize {
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: sysconf has no preconditions
+        let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+        if count > 0 {
+            return count as usize;
+        }
+    }
+    std::thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+}
+
+pub fn validate_cpu_affinity(cores: &[usize]) -> Result<(), Error> {
+    let core_count = core_count();
+    let mut seen = HashSet::new();
+    for core in cores {
+        if *core >= core_count {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Core {} doesn't exist, this host has {} cores",
+                    core,
+                    core_count
+                ),
+            });
+        }
+        if !seen.insert(core) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Core {} is listed more than once", core),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Pins the process spawned by `command` to `cores`, an empty list leaves it unpinned
+///
+/// The affinity is set between fork and exec, so every thread of the JVM inherits it
+pub fn apply_cpu_affinity(command: &mut Command, cores: &[usize], instance_name: &str) {
+    if cores.is_empty() {
+        return;
+    }
+    // cores can go offline between setting the affinity and starting the instance
+    if let Err(e) = validate_cpu_affinity(cores) {
+        warn!("[{}] Not pinning to CPU cores: {}", instance_name, e);
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: cpu_set_t is a plain bitmask, zeroed is a valid empty set
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for core in cores {
+            // SAFETY: validate_cpu_affinity ensured the core exists, CPU_SET ignores out of range cores
+            unsafe { libc::CPU_SET(*core, &mut set) };
+        }
+        // SAFETY: the closure only makes a syscall, which is safe to do between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = command;
+        warn!(
+            "[{}] CPU affinity is only supported on Linux, the instance isn't pinned",
+            instance_name
+        );
+    }
+}
+
+impl MinecraftInstance {
+    /// Takes effect the next time the instance starts
+    pub async fn set_cpu_affinity(&self, cores: Vec<usize>) -> Result<(), Error> {
+        validate_cpu_affinity(&cores)?;
+        self.config.lock().await.cpu_affinity = cores;
+        self.write_config_to_file().await
+    }
+}
@@ -0,0 +1,165 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::types::Snowflake;
+use crate::util::list_dir;
+
+use super::MinecraftInstance;
+
+const CRASH_REPORT_DIR: &str = "crash-reports";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct CrashReportEntry {
+    pub file_name: String,
+    /// Unix timestamp in seconds
+    pub modified: i64,
+    /// `None` if the report couldn't be read or doesn't follow the usual format
+    pub summary: Option<String>,
+}
+
+/// Extracts the description and the exception from a crash report,
+/// e.g. `Exception in server tick loop: java.lang.NullPointerException: ...`
+fn parse_crash_summary(content: &str) -> Option<String> {
+    let mut lines = content.lines().map(str::trim);
+    let description = lines.find_map(|line| line.strip_prefix("Description: "))?;
+    match lines.find(|line| !line.is_empty()) {
+        Some(exception) => Some(format!("{}: {}", description, exception)),
+        None => Some(description.to_string()),
+    }
+}
+
+fn modified_unix_secs(modified: SystemTime) -> i64 {
+    modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+impl MinecraftInstance {
+    fn path_to_crash_report(&self, file_name: &str) -> Result<std::path::PathBuf, Error> {
+        // only plain file names, so other files in the instance can't be read
+        if file_name.is_empty()
+            || Path::new(file_name).file_name() != Some(std::ffi::OsStr::new(file_name))
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid crash report name {}", file_name),
+            });
+        }
+        Ok(self.path_to_instance.join(CRASH_REPORT_DIR).join(file_name))
+    }
+
+    /// Lists the crash reports of the instance, newest first
+    pub async fn crash_reports(&self) -> Result<Vec<CrashReportEntry>, Error> {
+        let path_to_crash_reports = self.path_to_instance.join(CRASH_REPORT_DIR);
+        if !path_to_crash_reports.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut reports = Vec::new();
+        for path in list_dir(&path_to_crash_reports, Some(false)).await? {
+            if path.extension().unwrap_or_default() != "txt" {
+                continue;
+            }
+            let modified = tokio::fs::metadata(&path)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .map_or(0, modified_unix_secs);
+            let summary = tokio::fs::read_to_string(&path)
+                .await
+                .ok()
+                .and_then(|content| parse_crash_summary(&content));
+            reports.push(CrashReportEntry {
+                file_name: path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+                modified,
+                summary,
+            });
+        }
+        reports.sort_by(|a, b| b.modified.cmp(&a.modified));
+        Ok(reports)
+    }
+
+    pub async fn read_crash_report(&self, file_name: &str) -> Result<String, Error> {
+        let path = self.path_to_crash_report(file_name)?;
+        if !path.is_file() {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Crash report {} not found", file_name),
+            });
+        }
+        Ok(tokio::fs::read_to_string(&path)
+            .await
+            .context(format!("Failed to read crash report {}", file_name))?)
+    }
+
+    /// Emits a `CrashReport` event for the newest crash report written since `started_at`,
+    /// called when the server process exits with a nonzero status
+    pub(super) async fn collect_crash_report(&self, started_at: SystemTime) {
+        let name = self.config.lock().await.name.clone();
+        let reports = match self.crash_reports().await {
+            Ok(reports) => reports,
+            Err(e) => {
+                error!("[{}] Failed to list crash reports: {}", name, e);
+                return;
+            }
+        };
+        let report = match reports
+            .into_iter()
+            .find(|report| report.modified >= modified_unix_secs(started_at))
+        {
+            Some(report) => report,
+            None => return,
+        };
+        let summary = report
+            .summary
+            .unwrap_or_else(|| "Unknown crash".to_string());
+        info!("[{}] Server crashed: {}", name, summary);
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_name: name,
+                instance_uuid: self.uuid.clone(),
+                instance_event_inner: InstanceEventInner::CrashReport {
+                    summary,
+                    path: format!("{}/{}", CRASH_REPORT_DIR, report.file_name),
+                },
+            }),
+            snowflake: Snowflake::default(),
+            details: "Server crashed".to_string(),
+            caused_by: CausedBy::System,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_crash_summary() {
+        let report = "---- Minecraft Crash Report ----\n\
+            // Shall we play a game?\n\
+            \n\
+            Time: 2023-03-01 12:00:00\n\
+            Description: Exception in server tick loop\n\
+            \n\
+            java.lang.NullPointerException: Cannot invoke \"Object.hashCode()\"\n\
+            \tat net.minecraft.server.MinecraftServer.tick(MinecraftServer.java:1)\n";
+        assert_eq!(
+            parse_crash_summary(report).as_deref(),
+            Some(
+                "Exception in server tick loop: java.lang.NullPointerException: Cannot invoke \"Object.hashCode()\""
+            )
+        );
+        assert_eq!(parse_crash_summary("not a crash report"), None);
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::prelude::path_to_stores;
+use crate::secret::SecretString;
+use crate::types::InstanceUuid;
+use crate::util::fs::write_atomic;
+
+use super::MinecraftInstance;
+
+/// Where the `.env` file of an instance was kept before it moved to the stores
+const LEGACY_DOTENV_FILE: &str = ".env";
+
+/// Names of variables whose values are left out when the `.env` file is read through the API,
+/// and encrypted at rest if there is a master key
+const SECRET_KEY_PARTS: &[&str] = &[
+    "SECRET",
+    "TOKEN",
+    "PASSWORD",
+    "PASSWD",
+    "KEY",
+    "AUTH",
+    "CREDENTIAL",
+    "PRIVATE",
+];
+
+/// Variables passed through from the core's environment under `EnvPolicy::Minimal`
+const PASSTHROUGH_VARS: &[&str] = &[
+    "PATH",
+    "JAVA_HOME",
+    "HOME",
+    "USER",
+    "LANG",
+    "LANGUAGE",
+    "LC_ALL",
+    "LC_CTYPE",
+    "TZ",
+    "TMPDIR",
+    "TEMP",
+    "TMP",
+    // needed by the JVM on Windows
+    "SYSTEMROOT",
+    "SYSTEMDRIVE",
+    "WINDIR",
+    "USERPROFILE",
+    "APPDATA",
+    "LOCALAPPDATA",
+    "COMSPEC",
+    "PATHEXT",
+];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default, TS)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvPolicy {
+    /// Only a curated set of variables like `PATH` and the locale,
+    /// so secrets passed to the core don't leak into server or mod code
+    #[default]
+    Minimal,
+    /// The core's full environment, for servers that depend on it
+    Inherit,
+}
+
+/// Environment of the server process
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, TS)]
+#[ts(export)]
+pub struct InstanceEnv {
+    #[serde(default)]
+    pub policy: EnvPolicy,
+    /// Set on top of the variables allowed by `policy`, encrypted at rest as they may hold API keys
+    #[serde(default)]
+    #[ts(type = "Record<string, string>")]
+    pub vars: BTreeMap<String, SecretString>,
+}
+
+impl InstanceEnv {
+    pub fn validate(&self) -> Result<(), Error> {
+        for (key, value) in &self.vars {
+            if key.is_empty()
+                || key.contains('=')
+                || key.contains('\0')
+                || value.expose().contains('\0')
+            {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Invalid environment variable {}", key),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// `dotenv` is set on top of the variables allowed by `policy`, `vars` take precedence over it
+    pub fn apply(&self, command: &mut Command, dotenv: &BTreeMap<String, String>) {
+        if self.policy == EnvPolicy::Minimal {
+            command.env_clear();
+            for (key, value) in std::env::vars_os() {
+                let is_passthrough = key.to_str().map_or(false, |key| {
+                    PASSTHROUGH_VARS
+                        .iter()
+                        .any(|var| var.eq_ignore_ascii_case(key))
+                });
+                if is_passthrough {
+                    command.env(key, value);
+                }
+            }
+        }
+        command.envs(dotenv);
+        command.envs(self.vars.iter().map(|(key, value)| (key, value.expose())));
+    }
+
+    pub fn needs_encryption(&self) -> bool {
+        self.vars.values().any(SecretString::needs_encryption)
+    }
+}
+
+/// A variable of the `.env` file as read through the API
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct DotEnvEntry {
+    pub key: String,
+    /// `None` if the name looks like it holds a secret
+    pub value: Option<String>,
+}
+
+pub fn validate_env_key(key: &str) -> Result<(), Error> {
+    let mut chars = key.chars();
+    let is_valid = chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_valid {
+        Ok(())
+    } else {
+        Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Invalid variable name {}, names are letters, digits and underscores and don't start with a digit",
+                key
+            ),
+        })
+    }
+}
+
+fn looks_secret(key: &str) -> bool {
+    let key = key.to_ascii_uppercase();
+    SECRET_KEY_PARTS.iter().any(|part| key.contains(part))
+}
+
+/// Parses `KEY=value` lines, skipping blank lines and `#` comments.
+///
+/// Lines may start with `export`, values may be single quoted (taken as is)
+/// or double quoted (with `\n`, `\"` and `\\` escapes), unquoted values end at ` #`
+pub fn parse_dotenv(contents: &str) -> Result<BTreeMap<String, String>, Error> {
+    let mut vars = BTreeMap::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        let invalid = |reason: &str| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid .env line {} : {}", index + 1, reason),
+        };
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid("expected KEY=value"))?;
+        let key = key.trim();
+        validate_env_key(key).map_err(|e| invalid(&e.source.to_string()))?;
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            quoted
+                .strip_suffix('\'')
+                .ok_or_else(|| invalid("unterminated single quote"))?
+                .to_string()
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let quoted = quoted
+                .strip_suffix('"')
+                .ok_or_else(|| invalid("unterminated double quote"))?;
+            let mut unescaped = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    unescaped.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('n') => unescaped.push('\n'),
+                    Some(escaped @ ('"' | '\\')) => unescaped.push(escaped),
+                    Some(other) => {
+                        unescaped.push('\\');
+                        unescaped.push(other);
+                    }
+                    None => return Err(invalid("trailing backslash")),
+                }
+            }
+            unescaped
+        } else {
+            match value.find(" #") {
+                Some(comment) => value[..comment].trim_end().to_string(),
+                None => value.to_string(),
+            }
+        };
+        if value.contains('\0') {
+            return Err(invalid("values can't contain a null character"));
+        }
+        vars.insert(key.to_string(), value);
+    }
+    Ok(vars)
+}
+
+/// Double quotes every value, so `parse_dotenv` reads back exactly what was written
+fn serialize_dotenv(vars: &BTreeMap<String, String>) -> String {
+    vars.iter()
+        .map(|(key, value)| {
+            let escaped = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{key}=\"{escaped}\"\n")
+        })
+        .collect()
+}
+
+/// Per-instance environment file, edited through `/instance/:uuid/env`
+///
+/// Kept in the stores rather than the instance directory,
+/// so the file API can't read or download the secrets in it
+pub fn path_to_dotenv(uuid: &InstanceUuid) -> PathBuf {
+    path_to_stores()
+        .join("dotenv")
+        .join(format!("{}.env", uuid))
+}
+
+impl MinecraftInstance {
+    /// Variables of the instance's `.env` file, empty if there is none
+    pub async fn read_dotenv(&self) -> Result<BTreeMap<String, String>, Error> {
+        let contents = match tokio::fs::read_to_string(path_to_dotenv(&self.uuid)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return self.migrate_legacy_dotenv().await
+            }
+            Err(e) => return Err(eyre!("Failed to read .env : {}", e).into()),
+        };
+        let mut vars = parse_dotenv(&contents)?;
+        for (key, value) in vars.iter_mut() {
+            if looks_secret(key) {
+                *value = SecretString::from_stored(std::mem::take(value))?
+                    .expose()
+                    .to_string();
+            }
+        }
+        Ok(vars)
+    }
+
+    /// Moves a `.env` file left in the instance directory to the stores
+    async fn migrate_legacy_dotenv(&self) -> Result<BTreeMap<String, String>, Error> {
+        let legacy_path = self.path_to_instance.join(LEGACY_DOTENV_FILE);
+        let vars = match tokio::fs::read_to_string(&legacy_path).await {
+            Ok(contents) => parse_dotenv(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(eyre!("Failed to read .env : {}", e).into()),
+        };
+        self.write_dotenv(&vars).await?;
+        tokio::fs::remove_file(&legacy_path)
+            .await
+            .context("Failed to remove the .env file from the instance directory")?;
+        Ok(vars)
+    }
+
+    async fn write_dotenv(&self, vars: &BTreeMap<String, String>) -> Result<(), Error> {
+        let mut stored = BTreeMap::new();
+        for (key, value) in vars {
+            let value = if looks_secret(key) {
+                SecretString::new(value.clone()).to_stored()?
+            } else {
+                value.clone()
+            };
+            stored.insert(key.clone(), value);
+        }
+        let path = path_to_dotenv(&self.uuid);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .context("Failed to create the .env directory")?;
+        }
+        write_atomic(path, serialize_dotenv(&stored)).await
+    }
+
+    /// The `.env` file with the values of secret looking variables left out
+    pub async fn dotenv_entries(&self) -> Result<Vec<DotEnvEntry>, Error> {
+        Ok(self
+            .read_dotenv()
+            .await?
+            .into_iter()
+            .map(|(key, value)| DotEnvEntry {
+                value: (!looks_secret(&key)).then_some(value),
+                key,
+            })
+            .collect())
+    }
+
+    /// Replaces the `.env` file, a `None` value keeps the variable's current value
+    /// so masked secrets can be sent back unchanged. Comments in the file aren't kept.
+    ///
+    /// Takes effect the next time the server starts
+    pub async fn set_dotenv(&self, vars: BTreeMap<String, Option<String>>) -> Result<(), Error> {
+        let current = self.read_dotenv().await.unwrap_or_default();
+        let mut new_vars = BTreeMap::new();
+        for (key, value) in vars {
+            validate_env_key(&key)?;
+            let value = match value {
+                Some(value) => value,
+                None => current.get(&key).cloned().ok_or_else(|| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("{} has no current value to keep", key),
+                })?,
+            };
+            if value.contains('\0') {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Value of {} contains a null character", key),
+                });
+            }
+            new_vars.insert(key, value);
+        }
+        self.write_dotenv(&new_vars).await
+    }
+
+    /// Takes effect the next time the server starts
+    pub async fn set_env(&self, env: InstanceEnv) -> Result<(), Error> {
+        env.validate()?;
+        self.config.lock().await.env = env;
+        self.write_config_to_file().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dotenv() {
+        let vars = parse_dotenv(
+            "# comment\n\nexport MODE=survival # trailing\nMOTD=\"Hello \\\"world\\\"\\nbye\"\nRAW='a \\n b'\nEMPTY=\n",
+        )
+        .unwrap();
+        assert_eq!(vars["MODE"], "survival");
+        assert_eq!(vars["MOTD"], "Hello \"world\"\nbye");
+        assert_eq!(vars["RAW"], "a \\n b");
+        assert_eq!(vars["EMPTY"], "");
+        assert_eq!(parse_dotenv(&serialize_dotenv(&vars)).unwrap(), vars);
+
+        assert!(parse_dotenv("NO_EQUALS").is_err());
+        assert!(parse_dotenv("1ABC=x").is_err());
+        assert!(parse_dotenv("KEY=\"unterminated").is_err());
+        assert!(looks_secret("discord_bot_token"));
+        assert!(!looks_secret("MODE"));
+    }
+}
@@ -0,0 +1,91 @@
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::auth::user::User;
+use crate::auth::user_id::UserId;
+use crate::error::{Error, ErrorKind};
+use crate::instance_metadata::set_metadata;
+use crate::path_lock::PathLocks;
+use crate::util::fs::write_atomic;
+
+use super::MinecraftInstance;
+
+const EULA_FILE: &str = "eula.txt";
+/// Instance metadata key recording who accepted the EULA, see `EulaAcceptance`
+pub const EULA_METADATA_KEY: &str = "eula_accepted";
+
+/// Written to new instances, which can't start until the EULA is accepted
+pub const NOT_ACCEPTED_EULA: &str =
+    "#generated by Lodestone, see https://aka.ms/MinecraftEULA\neula=false\n";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct EulaAcceptance {
+    pub user_id: UserId,
+    pub user_name: String,
+    /// Unix timestamp in seconds
+    pub time: i64,
+}
+
+/// Whether `eula.txt` has `eula=true`, the way the server itself reads it
+fn is_eula_accepted(content: &str) -> bool {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .any(|(key, value)| key.trim() == "eula" && value.trim().eq_ignore_ascii_case("true"))
+}
+
+impl MinecraftInstance {
+    /// Fails with `ErrorKind::EulaNotAccepted` unless the EULA was accepted,
+    /// either through `accept_eula` or by editing `eula.txt`
+    pub async fn check_eula(&self) -> Result<(), Error> {
+        let content = tokio::fs::read_to_string(self.path_to_instance.join(EULA_FILE))
+            .await
+            .unwrap_or_default();
+        if is_eula_accepted(&content) {
+            Ok(())
+        } else {
+            Err(Error {
+                kind: ErrorKind::EulaNotAccepted,
+                source: eyre!(
+                    "The Minecraft EULA (https://aka.ms/MinecraftEULA) must be accepted before the server can start"
+                ),
+            })
+        }
+    }
+
+    pub async fn accept_eula(&self, path_locks: &PathLocks, user: &User) -> Result<(), Error> {
+        write_atomic(
+            self.path_to_instance.join(EULA_FILE),
+            format!(
+                "#accepted through Lodestone by {}, see https://aka.ms/MinecraftEULA\neula=true\n",
+                user.username
+            ),
+        )
+        .await?;
+        let acceptance = EulaAcceptance {
+            user_id: user.uid.clone(),
+            user_name: user.username.clone(),
+            time: chrono::Utc::now().timestamp(),
+        };
+        set_metadata(
+            path_locks,
+            &self.path_to_instance,
+            EULA_METADATA_KEY.to_string(),
+            serde_json::to_value(acceptance).context("Failed to serialize EULA acceptance")?,
+        )
+        .await
+    }
+}
+
+#[test]
+fn test_is_eula_accepted() {
+    assert!(is_eula_accepted("#generated by Lodestone\neula=true"));
+    assert!(is_eula_accepted("eula = TRUE\r\n"));
+    assert!(!is_eula_accepted(NOT_ACCEPTED_EULA));
+    assert!(!is_eula_accepted("#eula=true\neula=false"));
+    assert!(!is_eula_accepted(""));
+}
@@ -0,0 +1,198 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::time::Instant;
+use tracing::{error, info};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::traits::t_server::{State, TServer};
+use crate::types::Snowflake;
+
+use super::MinecraftInstance;
+
+/// How often the idle watcher picks up changes to the setting while the server is running
+const IDLE_SHUTDOWN_RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Stop the server once no players have been online for `minutes`
+///
+/// The server isn't started again on demand, a player connecting afterwards has to wait for
+/// someone to start it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct IdleShutdown {
+    pub minutes: u32,
+}
+
+/// Where the idle shutdown countdown is at
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct IdleStatus {
+    /// `None` if idle shutdown is off
+    pub idle_minutes: Option<u32>,
+    pub player_count: u32,
+    /// `None` unless the server is running with nobody online and idle shutdown is on
+    pub seconds_until_shutdown: Option<u64>,
+}
+
+/// Keeps the server from being stopped for being idle until dropped
+pub struct IdleShutdownHold(Arc<AtomicUsize>);
+
+impl Drop for IdleShutdownHold {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl MinecraftInstance {
+    /// Held while the instance's files are being archived, stopping the server
+    /// would have it save the world halfway through the backup
+    pub fn hold_idle_shutdown(&self) -> IdleShutdownHold {
+        self.idle_shutdown_holds.fetch_add(1, Ordering::SeqCst);
+        IdleShutdownHold(self.idle_shutdown_holds.clone())
+    }
+
+    pub async fn set_idle_shutdown(
+        &self,
+        idle_shutdown: Option<IdleShutdown>,
+    ) -> Result<(), Error> {
+        if let Some(IdleShutdown { minutes: 0 }) = idle_shutdown {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Idle shutdown period must be at least a minute"),
+            });
+        }
+        self.config.lock().await.idle_shutdown = idle_shutdown;
+        self.write_config_to_file().await
+    }
+
+    pub async fn idle_status(&self) -> IdleStatus {
+        let idle_minutes = self
+            .config
+            .lock()
+            .await
+            .idle_shutdown
+            .as_ref()
+            .map(|idle_shutdown| idle_shutdown.minutes);
+        let idle_since = *self.idle_since.lock().await;
+        let seconds_until_shutdown = idle_since.zip(idle_minutes).map(|(since, minutes)| {
+            (since + Duration::from_secs(minutes as u64 * 60))
+                .saturating_duration_since(Instant::now())
+                .as_secs()
+        });
+        IdleStatus {
+            idle_minutes,
+            player_count: self.players_manager.lock().await.count(),
+            seconds_until_shutdown,
+        }
+    }
+
+    /// Watches player changes for one run of the server, and stops it once it has been empty
+    /// for the configured idle period
+    ///
+    /// `event_receiver` should be subscribed before the server process is spawned,
+    /// so the transition to running is not missed
+    pub(super) async fn idle_shutdown_task(self, event_receiver: Receiver<Event>) {
+        self.watch_idle(event_receiver).await;
+        *self.idle_since.lock().await = None;
+    }
+
+    /// Reacts to player changes only, the player list is never polled
+    async fn watch_idle(&self, mut event_receiver: Receiver<Event>) {
+        // only counts while the server is running
+        let mut idle_since: Option<Instant> = None;
+        let mut player_count = 0;
+        loop {
+            *self.idle_since.lock().await = idle_since;
+            let idle_minutes = self
+                .config
+                .lock()
+                .await
+                .idle_shutdown
+                .as_ref()
+                .map(|idle_shutdown| idle_shutdown.minutes);
+            let deadline = idle_since
+                .zip(idle_minutes)
+                .map(|(since, minutes)| since + Duration::from_secs(minutes as u64 * 60));
+            let recheck = Instant::now() + IDLE_SHUTDOWN_RECHECK_INTERVAL;
+            let wake_at = deadline.map_or(recheck, |deadline| deadline.min(recheck));
+            tokio::select! {
+                _ = tokio::time::sleep_until(wake_at) => {
+                    let (deadline, idle_minutes) = match (deadline, idle_minutes) {
+                        (Some(deadline), Some(idle_minutes)) => (deadline, idle_minutes),
+                        _ => continue,
+                    };
+                    if Instant::now() < deadline || self.state().await != State::Running {
+                        continue;
+                    }
+                    let name = self.config.lock().await.name.clone();
+                    if self.idle_shutdown_holds.load(Ordering::SeqCst) > 0 {
+                        // the idle period starts over rather than stopping the server halfway through the backup
+                        info!("[{}] Backup in progress, not stopping idle server yet", name);
+                        idle_since = Some(Instant::now());
+                        continue;
+                    }
+                    info!("[{}] No players online for {} minutes, stopping", name, idle_minutes);
+                    self.event_broadcaster.send(Event {
+                        event_inner: EventInner::InstanceEvent(InstanceEvent {
+                            instance_name: name.clone(),
+                            instance_uuid: self.uuid.clone(),
+                            instance_event_inner: InstanceEventInner::IdleShutdown { idle_minutes },
+                        }),
+                        snowflake: Snowflake::default(),
+                        details: "Stopping server as it has been idle".to_string(),
+                        caused_by: CausedBy::System,
+                    });
+                    if let Err(e) = self.stop(CausedBy::System, false).await {
+                        error!("[{}] Failed to stop idle server: {}", name, e);
+                    }
+                    return;
+                }
+                result = event_receiver.recv() => {
+                    let event = match result {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return,
+                    };
+                    let instance_event = match event.event_inner {
+                        EventInner::InstanceEvent(instance_event)
+                            if instance_event.instance_uuid == self.uuid =>
+                        {
+                            instance_event
+                        }
+                        _ => continue,
+                    };
+                    match instance_event.instance_event_inner {
+                        InstanceEventInner::StateTransition {
+                            to: State::Running, ..
+                        } => {
+                            // players could have joined before the transition was received
+                            player_count = self.players_manager.lock().await.count() as usize;
+                            if player_count == 0 {
+                                idle_since = Some(Instant::now());
+                            }
+                        }
+                        InstanceEventInner::StateTransition {
+                            to: State::Stopping | State::Stopped | State::Error,
+                            ..
+                        } => return,
+                        InstanceEventInner::PlayerChange { player_list, .. } => {
+                            player_count = player_list.len();
+                            if player_count > 0 {
+                                idle_since = None;
+                            } else if idle_since.is_none() && self.state().await == State::Running {
+                                idle_since = Some(Instant::now());
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
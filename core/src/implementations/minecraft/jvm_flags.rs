@@ -0,0 +1,111 @@
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+/// Heap size in MB above which Aikar recommends the large heap variant of the flags
+const AIKAR_LARGE_HEAP_THRESHOLD: u32 = 12 * 1024;
+
+/// JVM flags passed to the server in addition to the heap size and `cmd_args`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, TS)]
+#[ts(export)]
+#[serde(tag = "preset", content = "flags", rename_all = "snake_case")]
+pub enum JvmFlagsPreset {
+    #[default]
+    None,
+    /// Aikar's G1GC tuning flags, see https://docs.papermc.io/paper/aikars-flags
+    Aikar,
+    Custom(Vec<String>),
+}
+
+impl JvmFlagsPreset {
+    /// Heap size is controlled by `min_ram` and `max_ram`,
+    /// so flags setting it would conflict with them
+    pub fn validate(&self) -> Result<(), Error> {
+        if let JvmFlagsPreset::Custom(flags) = self {
+            if let Some(flag) = flags.iter().find(|flag| {
+                let flag = flag.trim();
+                flag.starts_with("-Xmx")
+                    || flag.starts_with("-Xms")
+                    || flag.starts_with("-XX:MaxHeapSize")
+                    || flag.starts_with("-XX:InitialHeapSize")
+            }) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "Flag {} conflicts with the instance's min_ram and max_ram settings",
+                        flag
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flags(&self, max_ram: u32) -> Vec<String> {
+        match self {
+            JvmFlagsPreset::None => Vec::new(),
+            JvmFlagsPreset::Aikar => aikar_flags(max_ram),
+            JvmFlagsPreset::Custom(flags) => flags
+                .iter()
+                .map(|flag| flag.trim().to_string())
+                .filter(|flag| !flag.is_empty())
+                .collect(),
+        }
+    }
+}
+
+fn aikar_flags(max_ram: u32) -> Vec<String> {
+    let large_heap = max_ram >= AIKAR_LARGE_HEAP_THRESHOLD;
+    let (new_size, max_new_size, region_size, reserve, initiating_occupancy) = if large_heap {
+        (40, 50, "16M", 15, 20)
+    } else {
+        (30, 40, "8M", 20, 15)
+    };
+    vec![
+        "-XX:+UseG1GC".to_string(),
+        "-XX:+ParallelRefProcEnabled".to_string(),
+        "-XX:MaxGCPauseMillis=200".to_string(),
+        "-XX:+UnlockExperimentalVMOptions".to_string(),
+        "-XX:+DisableExplicitGC".to_string(),
+        "-XX:+AlwaysPreTouch".to_string(),
+        format!("-XX:G1NewSizePercent={}", new_size),
+        format!("-XX:G1MaxNewSizePercent={}", max_new_size),
+        format!("-XX:G1HeapRegionSize={}", region_size),
+        format!("-XX:G1ReservePercent={}", reserve),
+        "-XX:G1HeapWastePercent=5".to_string(),
+        "-XX:G1MixedGCCountTarget=4".to_string(),
+        format!(
+            "-XX:InitiatingHeapOccupancyPercent={}",
+            initiating_occupancy
+        ),
+        "-XX:G1MixedGCLiveThresholdPercent=90".to_string(),
+        "-XX:G1RSetUpdatingPauseTimePercent=5".to_string(),
+        "-XX:SurvivorRatio=32".to_string(),
+        "-XX:+PerfDisableSharedMem".to_string(),
+        "-XX:MaxTenuringThreshold=1".to_string(),
+        "-Dusing.aikars.flags=https://mcflags.emc.gs".to_string(),
+        "-Daikars.new.flags=true".to_string(),
+    ]
+}
+
+#[test]
+fn test_jvm_flags_preset() {
+    assert!(JvmFlagsPreset::None.flags(4096).is_empty());
+    assert!(JvmFlagsPreset::Aikar
+        .flags(4096)
+        .contains(&"-XX:G1HeapRegionSize=8M".to_string()));
+    assert!(JvmFlagsPreset::Aikar
+        .flags(16384)
+        .contains(&"-XX:G1HeapRegionSize=16M".to_string()));
+    assert!(JvmFlagsPreset::Custom(vec!["-XX:+UseZGC".to_string()])
+        .validate()
+        .is_ok());
+    assert!(JvmFlagsPreset::Custom(vec!["-Xmx8G".to_string()])
+        .validate()
+        .is_err());
+    assert!(JvmFlagsPreset::Custom(vec![" -Xms1G".to_string()])
+        .validate()
+        .is_err());
+}
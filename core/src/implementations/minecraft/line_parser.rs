@@ -73,3 +73,38 @@ pub fn parse_server_started(system_msg: &str) -> bool {
     }
     RE.is_match(system_msg).unwrap()
 }
+
+/// The version in the `Starting minecraft server version X` line every flavour logs on startup
+pub fn parse_server_version(system_msg: &str) -> Option<String> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"Starting minecraft server version (\S+)").unwrap();
+    }
+    RE.captures(system_msg)
+        .ok()?
+        .map(|caps| caps.get(1).unwrap().as_str().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_server_version;
+
+    #[test]
+    fn test_parse_server_version() {
+        assert_eq!(
+            parse_server_version(
+                "[12:00:00] [Server thread/INFO]: Starting minecraft server version 1.20.1"
+            ),
+            Some("1.20.1".to_string())
+        );
+        assert_eq!(
+            parse_server_version("[12:00:00 INFO]: Starting minecraft server version 23w31a\r"),
+            Some("23w31a".to_string())
+        );
+        assert_eq!(
+            parse_server_version(
+                "[12:00:00] [Server thread/INFO]: Starting Minecraft server on *:25565"
+            ),
+            None
+        );
+    }
+}
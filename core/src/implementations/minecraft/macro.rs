@@ -1,38 +1,27 @@
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path};
 
 use async_trait::async_trait;
 use color_eyre::eyre::{eyre, Context};
 
 use crate::{
-    error::Error,
+    error::{Error, ErrorKind},
     events::CausedBy,
-    macro_executor::{DefaultWorkerOptionGenerator, MacroPID, SpawnResult},
+    macro_executor::{
+        resolve_macro_invocation, validate_macro, DefaultWorkerOptionGenerator, MacroDiagnostic,
+        MacroPID, SpawnOptions, SpawnResult,
+    },
     traits::t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry},
 };
 
 use super::MinecraftInstance;
 
-pub fn resolve_macro_invocation(path_to_macro: &Path, macro_name: &str) -> Option<PathBuf> {
-    let ts_macro = path_to_macro.join(macro_name).with_extension("ts");
-    let js_macro = path_to_macro.join(macro_name).with_extension("js");
-
-    let macro_folder = path_to_macro.join(macro_name);
-
-    if ts_macro.is_file() {
-        return Some(ts_macro);
-    } else if js_macro.is_file() {
-        return Some(js_macro);
-    } else if macro_folder.is_dir() {
-        // check if index.ts exists
-        let index_ts = macro_folder.join("index.ts");
-        let index_js = macro_folder.join("index.js");
-        if index_ts.exists() {
-            return Some(index_ts);
-        } else if index_js.exists() {
-            return Some(index_js);
-        }
-    }
-    None
+/// A macro name must be a single path component, so it can't resolve outside the macro directory
+fn is_valid_macro_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
 }
 
 #[async_trait]
@@ -75,7 +64,7 @@ impl TMacro for MinecraftInstance {
     async fn get_task_list(&self) -> Result<Vec<TaskEntry>, Error> {
         let mut ret = Vec::new();
         for (pid, task_entry) in self.pid_to_task_entry.lock().await.iter() {
-            if self.macro_executor.get_macro_status(*pid).await.is_none() {
+            if self.macro_executor.is_running(*pid) {
                 ret.push(task_entry.clone());
             }
         }
@@ -85,14 +74,22 @@ impl TMacro for MinecraftInstance {
 
     async fn get_history_list(&self) -> Result<Vec<HistoryEntry>, Error> {
         let mut ret = Vec::new();
-        for (pid, task_entry) in self.pid_to_task_entry.lock().await.iter() {
+        let mut pid_to_task_entry = self.pid_to_task_entry.lock().await;
+        // tasks whose exit status the executor no longer keeps are forgotten too
+        let mut forgotten = Vec::new();
+        for (pid, task_entry) in pid_to_task_entry.iter() {
             if let Some(exit_status) = self.macro_executor.get_macro_status(*pid).await {
                 ret.push(HistoryEntry {
                     task: task_entry.clone(),
                     exit_status,
                 });
+            } else if !self.macro_executor.is_running(*pid) {
+                forgotten.push(*pid);
             }
         }
+        for pid in forgotten {
+            pid_to_task_entry.shift_remove(&pid);
+        }
         ret.sort_by(|a, b| b.exit_status.time().cmp(&a.exit_status.time()));
         Ok(ret)
     }
@@ -111,20 +108,35 @@ impl TMacro for MinecraftInstance {
         &self,
         name: &str,
         args: Vec<String>,
+        structured_args: Option<serde_json::Value>,
         caused_by: CausedBy,
     ) -> Result<TaskEntry, Error> {
-        let path_to_macro = resolve_macro_invocation(&self.path_to_macros, name)
-            .ok_or_else(|| eyre!("Failed to resolve macro invocation for {}", name))?;
+        if !is_valid_macro_name(name) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid macro name {}", name),
+            });
+        }
+        let path_to_macro =
+            resolve_macro_invocation(&self.path_to_macros, name).ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Failed to resolve macro invocation for {}", name),
+            })?;
 
         let SpawnResult { macro_pid: pid, .. } = self
             .macro_executor
             .spawn(
                 path_to_macro,
-                args,
-                caused_by,
+                self.path_to_instance.clone(),
                 Box::new(DefaultWorkerOptionGenerator),
-                None,
-                Some(self.uuid.clone()),
+                SpawnOptions {
+                    args,
+                    structured_args,
+                    caused_by,
+                    instance_uuid: Some(self.uuid.clone()),
+                    max_heap_mb: Some(self.macro_executor.max_heap_mb()),
+                    ..Default::default()
+                },
             )
             .await?;
         let entry = TaskEntry {
@@ -148,4 +160,34 @@ impl TMacro for MinecraftInstance {
         self.macro_executor.abort_macro(pid)?;
         Ok(())
     }
+
+    async fn validate_macro(
+        &self,
+        name: Option<&str>,
+        source: Option<String>,
+    ) -> Result<Vec<MacroDiagnostic>, Error> {
+        let entry = match name {
+            Some(name) => {
+                if !is_valid_macro_name(name) {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Invalid macro name {}", name),
+                    });
+                }
+                resolve_macro_invocation(&self.path_to_macros, name).ok_or_else(|| Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!("Failed to resolve macro invocation for {}", name),
+                })?
+            }
+            // relative imports of an unsaved macro resolve from the macro directory
+            None if source.is_some() => self.path_to_macros.join("untitled.ts"),
+            None => {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Either a macro name or its source is required"),
+                })
+            }
+        };
+        Ok(validate_macro(&self.path_to_macros, &entry, source).await)
+    }
 }
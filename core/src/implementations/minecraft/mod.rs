@@ -1,24 +1,35 @@
+pub mod config;
 pub mod configurable;
+pub mod console_log;
+pub mod cpu_affinity;
+pub mod crash_report;
+pub mod env;
+pub mod eula;
 pub mod fabric;
 mod forge;
+pub mod idle_shutdown;
+pub mod jvm_flags;
 mod line_parser;
 pub mod r#macro;
+pub mod modpack;
 mod paper;
 pub mod player;
 mod players_manager;
 pub mod resource;
 pub mod server;
+pub mod startup_progress;
 pub mod util;
 mod vanilla;
 pub mod versions;
 
+use async_trait::async_trait;
 use color_eyre::eyre::{eyre, Context, ContextCompat};
 use enum_kinds::EnumKind;
 use indexmap::IndexMap;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::process::Stdio;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::Arc;
 use sysinfo::SystemExt;
 use tokio::io::AsyncWriteExt;
@@ -56,11 +67,20 @@ use crate::util::{
 };
 
 use self::configurable::{CmdArgSetting, ServerPropertySetting};
+use self::console_log::ConsoleLogRotation;
 use self::fabric::get_fabric_minecraft_versions;
 use self::forge::get_forge_minecraft_versions;
+use self::env::InstanceEnv;
+use self::eula::NOT_ACCEPTED_EULA;
+use self::idle_shutdown::IdleShutdown;
+use self::jvm_flags::JvmFlagsPreset;
 use self::paper::get_paper_minecraft_versions;
 use self::players_manager::PlayersManager;
-use self::util::{get_jre_url, get_server_jar_url, read_properties_from_path};
+use self::startup_progress::StartupMilestone;
+use self::util::{
+    download_verified_jar, get_content_length, get_jre_url, get_server_jar_url,
+    read_properties_from_path, JarChecksum,
+};
 use self::vanilla::get_vanilla_minecraft_versions;
 
 #[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
@@ -77,9 +97,10 @@ pub struct PaperBuildVersion(i64);
 pub struct ForgeBuildVersion(String);
 
 /// A parameter for constructor of `MinecraftInstance`
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, EnumKind)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, EnumKind, TS)]
+#[ts(export)]
 #[serde(rename_all = "snake_case")]
-#[enum_kind(FlavourKind, derive(Serialize, Deserialize, TS))]
+#[enum_kind(FlavourKind, derive(Serialize, Deserialize, TS, Hash))]
 pub enum Flavour {
     Vanilla,
     Fabric {
@@ -152,6 +173,30 @@ pub struct SetupConfig {
     pub restart_on_crash: Option<bool>,
     pub backup_period: Option<u32>,
 }
+
+/// What setting up a `MinecraftInstance` from a `SetupConfig` will do
+///
+/// Resolving a plan only queries remote APIs, it does not create any files or allocate any ports
+#[derive(Clone, Debug, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct CreationPlan {
+    pub name: String,
+    pub version: String,
+    /// The flavour with its build versions resolved
+    pub flavour: Flavour,
+    pub port: u32,
+    pub jar_url: String,
+    pub jar_name: String,
+    /// `None` if the jar's source doesn't publish one, the jar is then not verified
+    pub jar_checksum: Option<JarChecksum>,
+    pub jre_url: String,
+    pub jre_major_version: u64,
+    /// Whether the required JRE is already present and will not be downloaded
+    pub jre_installed: bool,
+    /// Total size in bytes of the files that will be downloaded, if the remote reported it
+    pub required_disk_space: Option<u64>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RestoreConfig {
     pub name: String,
@@ -168,6 +213,21 @@ pub struct RestoreConfig {
     pub backup_period: Option<u32>,
     pub jre_major_version: u64,
     pub has_started: bool,
+    #[serde(default)]
+    pub jvm_flags: JvmFlagsPreset,
+    #[serde(default)]
+    pub idle_shutdown: Option<IdleShutdown>,
+    #[serde(default)]
+    pub env: InstanceEnv,
+    /// `None` if the console output isn't written to a log
+    #[serde(default)]
+    pub console_log: Option<ConsoleLogRotation>,
+    /// Cores the server process is pinned to, empty if it isn't pinned
+    #[serde(default)]
+    pub cpu_affinity: Vec<usize>,
+    /// Output lines startup progress is read from, `None` uses the defaults of the flavour
+    #[serde(default)]
+    pub startup_milestones: Option<Vec<StartupMilestone>>,
 }
 
 #[derive(Clone)]
@@ -200,6 +260,12 @@ pub struct MinecraftInstance {
     rcon_conn: Arc<Mutex<Option<rcon::Connection<tokio::net::TcpStream>>>>,
     macro_name_to_last_run: Arc<Mutex<HashMap<String, i64>>>,
     pid_to_task_entry: Arc<Mutex<IndexMap<MacroPID, TaskEntry>>>,
+    /// When the running server last became empty, `None` while players are online
+    idle_since: Arc<Mutex<Option<tokio::time::Instant>>>,
+    /// Archives of the instance's files in progress, the server isn't stopped for being idle meanwhile
+    idle_shutdown_holds: Arc<AtomicUsize>,
+    /// The version the running server reported, `None` until detected
+    detected_version: Arc<Mutex<Option<String>>>,
 }
 
 #[tokio::test]
@@ -385,6 +451,57 @@ impl MinecraftInstance {
         })
     }
 
+    pub async fn plan_creation(config: &SetupConfig) -> Result<CreationPlan, Error> {
+        let (jre_url, jre_major_version) = get_jre_url(config.version.as_str())
+            .await
+            .context("Could not get JRE URL")?;
+        let jre_installed = path_to_binaries()
+            .join("java")
+            .join(format!("jre{}", jre_major_version))
+            .exists();
+
+        let flavour_name = config.flavour.to_string();
+        let (jar_url, flavour, jar_checksum) =
+            get_server_jar_url(config.version.as_str(), &config.flavour)
+                .await
+                .ok_or_else({
+                    || {
+                        eyre!(
+                            "Could not find a {} server.jar for version {}",
+                            flavour_name,
+                            config.version
+                        )
+                    }
+                })?;
+        let jar_name = match flavour {
+            Flavour::Forge { .. } => "forge-installer.jar",
+            _ => "server.jar",
+        };
+
+        let jar_size = get_content_length(&jar_url).await;
+        let required_disk_space = if jre_installed {
+            jar_size
+        } else {
+            jar_size
+                .zip(get_content_length(&jre_url).await)
+                .map(|(jar, jre)| jar + jre)
+        };
+
+        Ok(CreationPlan {
+            name: config.name.clone(),
+            version: config.version.clone(),
+            flavour,
+            port: config.port,
+            jar_url,
+            jar_name: jar_name.to_string(),
+            jar_checksum,
+            jre_url,
+            jre_major_version,
+            jre_installed,
+            required_disk_space,
+        })
+    }
+
     fn init_configurable_manifest(
         restore_config: &RestoreConfig,
         java_cmd: String,
@@ -428,8 +545,10 @@ impl MinecraftInstance {
         ConfigurableManifest::new(false, false, setting_sections)
     }
 
+    /// The EULA has to be accepted through `accept_eula` before the instance starts
     pub async fn new(
         config: SetupConfig,
+        plan: CreationPlan,
         dot_lodestone_config: DotLodestoneConfig,
         path_to_instance: PathBuf,
         progression_event_id: &ProgressionEventID,
@@ -455,7 +574,7 @@ impl MinecraftInstance {
             .and(tokio::fs::create_dir_all(&path_to_resources.join("mods")).await)
             .and(tokio::fs::create_dir_all(&path_to_resources.join("worlds")).await)
             .and(tokio::fs::create_dir_all(&path_to_resources.join("defaults")).await)
-            .and(tokio::fs::write(&path_to_eula, "#generated by Lodestone\neula=true").await)
+            .and(tokio::fs::write(&path_to_eula, NOT_ACCEPTED_EULA).await)
             .and(
                 tokio::fs::write(&path_to_properties, format!("server-port={}", config.port)).await,
             )
@@ -466,9 +585,15 @@ impl MinecraftInstance {
             })?;
 
         // Step 2: Download JRE
-        let (url, jre_major_version) = get_jre_url(config.version.as_str())
-            .await
-            .context("Could not get JRE URL")?;
+        let CreationPlan {
+            jre_url: url,
+            jre_major_version,
+            jar_url,
+            jar_name,
+            jar_checksum,
+            flavour,
+            ..
+        } = plan;
         if !path_to_runtimes
             .join("java")
             .join(format!("jre{}", jre_major_version))
@@ -535,27 +660,14 @@ impl MinecraftInstance {
         }
 
         // Step 3: Download server.jar
-        let flavour_name = config.flavour.to_string();
-        let (jar_url, flavour) = get_server_jar_url(config.version.as_str(), &config.flavour)
-            .await
-            .ok_or_else({
-                || {
-                    eyre!(
-                        "Could not find a {} server.jar for version {}",
-                        flavour_name,
-                        config.version
-                    )
-                }
-            })?;
-        let jar_name = match flavour {
-            Flavour::Forge { .. } => "forge-installer.jar",
-            _ => "server.jar",
-        };
+        let flavour_name = flavour.to_string();
+        let jar_name = jar_name.as_str();
 
-        download_file(
+        download_verified_jar(
             jar_url.as_str(),
             &path_to_instance,
-            Some(jar_name),
+            jar_name,
+            jar_checksum.as_ref(),
             {
                 let event_broadcaster = event_broadcaster.clone();
                 &move |dl| {
@@ -584,7 +696,13 @@ impl MinecraftInstance {
                     }
                 }
             },
-            true,
+            &|| {
+                event_broadcaster.send(Event::new_progression_event_update(
+                    progression_event_id,
+                    format!("3/4: Verifying {}", jar_name),
+                    0.0,
+                ));
+            },
         )
         .await?;
         let jre = path_to_runtimes
@@ -604,8 +722,10 @@ impl MinecraftInstance {
                 1.0,
             ));
 
+            let mut forge_installer_command = Command::new(&jre);
+            InstanceEnv::default().apply(&mut forge_installer_command, &BTreeMap::new());
             if !dont_spawn_terminal(
-                Command::new(&jre)
+                forge_installer_command
                     .arg("-jar")
                     .arg(&path_to_instance.join("forge-installer.jar"))
                     .arg("--installServer")
@@ -655,19 +775,21 @@ impl MinecraftInstance {
             jre_major_version,
             has_started: false,
             java_cmd: Some(jre.to_string_lossy().to_string()),
+            jvm_flags: JvmFlagsPreset::default(),
+            idle_shutdown: None,
+            env: InstanceEnv::default(),
+            console_log: None,
+            cpu_affinity: Vec::new(),
+            startup_milestones: None,
         };
         // create config file
-        tokio::fs::write(
+        crate::util::fs::write_atomic(
             &path_to_config,
             to_string_pretty(&restore_config).context(
                 "Failed to serialize config to string. This is a bug, please report it.",
             )?,
         )
-        .await
-        .context(format!(
-            "Failed to write config file at {}",
-            &path_to_config.display()
-        ))?;
+        .await?;
         MinecraftInstance::restore(
             path_to_instance,
             dot_lodestone_config,
@@ -747,26 +869,28 @@ impl MinecraftInstance {
             configurable_manifest,
             macro_name_to_last_run: Arc::new(Mutex::new(HashMap::new())),
             pid_to_task_entry: Arc::new(Mutex::new(IndexMap::new())),
+            idle_since: Arc::new(Mutex::new(None)),
+            idle_shutdown_holds: Arc::new(AtomicUsize::new(0)),
+            detected_version: Arc::new(Mutex::new(None)),
         };
         instance
             .read_properties()
             .await
             .context("Failed to read properties")?;
+        // secrets stored before a master key was set are encrypted on first load
+        if instance.config.lock().await.env.needs_encryption() {
+            instance.write_config_to_file().await?;
+        }
         Ok(instance)
     }
 
     async fn write_config_to_file(&self) -> Result<(), Error> {
-        tokio::fs::write(
+        crate::util::fs::write_atomic(
             &self.path_to_config,
             to_string_pretty(&*self.config.lock().await)
                 .context("Failed to serialize config to string, this is a bug, please report it")?,
         )
         .await
-        .context(format!(
-            "Failed to write config to file at {}",
-            &self.path_to_config.display()
-        ))?;
-        Ok(())
     }
 
     async fn read_properties(&self) -> Result<(), Error> {
@@ -890,6 +1014,18 @@ impl MinecraftInstance {
         );
     }
 
+    /// Takes effect the next time the server starts
+    pub async fn set_jvm_flags(&self, jvm_flags: JvmFlagsPreset) -> Result<(), Error> {
+        jvm_flags.validate()?;
+        self.config.lock().await.jvm_flags = jvm_flags;
+        self.write_config_to_file().await
+    }
+
+    /// The pid of the running server process
+    pub async fn pid(&self) -> Option<u32> {
+        self.process.lock().await.as_ref().and_then(|p| p.id())
+    }
+
     pub fn get_rcon(&self) -> Arc<Mutex<Option<rcon::Connection<tokio::net::TcpStream>>>> {
         self.rcon_conn.clone()
     }
@@ -910,4 +1046,9 @@ impl MinecraftInstance {
     }
 }
 
-impl TInstance for MinecraftInstance {}
+#[async_trait]
+impl TInstance for MinecraftInstance {
+    async fn detected_version(&self) -> Option<String> {
+        self.detected_version.lock().await.clone()
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::Deserialize;
+
+use crate::error::{Error, ErrorKind};
+use crate::util::scoped_join_win_safe;
+
+use super::util::{download_verified_jar, JarChecksum};
+use super::{FabricLoaderVersion, Flavour, ForgeBuildVersion};
+
+/// Directories of a pack archive that are copied into the instance as is
+const MODRINTH_OVERRIDE_DIRS: [&str; 2] = ["overrides", "server-overrides"];
+
+/// Files the core manages itself, so the pack's copy is not applied.
+/// A pack can't accept the EULA on the user's behalf, modpack instances
+/// go through the same accept flow as any other instance
+const SKIPPED_OVERRIDES: [&str; 2] = ["server.properties", "eula.txt"];
+
+/// Hosts a Modrinth pack may download its files from, a pack can't make the core fetch from anywhere else
+const MODRINTH_DOWNLOAD_HOSTS: [&str; 3] = [
+    "cdn.modrinth.com",
+    "github.com",
+    "raw.githubusercontent.com",
+];
+
+/// A file the pack downloads into the instance
+#[derive(Debug, Clone)]
+pub struct ModpackFile {
+    /// Relative to the instance
+    pub path: String,
+    pub url: String,
+    /// The download is removed and the install fails if it doesn't match
+    pub checksum: JarChecksum,
+}
+
+/// What a modpack archive declares, read from its manifest
+#[derive(Debug, Clone)]
+pub struct Modpack {
+    pub name: String,
+    pub version: String,
+    pub flavour: Flavour,
+    pub files: Vec<ModpackFile>,
+    override_dirs: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModrinthIndex {
+    name: String,
+    files: Vec<ModrinthFile>,
+    dependencies: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ModrinthFile {
+    path: String,
+    #[serde(default)]
+    hashes: HashMap<String, String>,
+    downloads: Vec<String>,
+    env: Option<ModrinthEnv>,
+}
+
+#[derive(Deserialize)]
+struct ModrinthEnv {
+    server: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CurseForgeManifest {
+    name: String,
+    minecraft: CurseForgeMinecraft,
+    #[serde(default)]
+    files: Vec<serde_json::Value>,
+    overrides: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CurseForgeMinecraft {
+    version: String,
+    #[serde(default)]
+    mod_loaders: Vec<CurseForgeModLoader>,
+}
+
+#[derive(Deserialize)]
+struct CurseForgeModLoader {
+    id: String,
+    #[serde(default)]
+    primary: bool,
+}
+
+fn bad_manifest(message: String) -> Error {
+    Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Invalid modpack: {}", message),
+    }
+}
+
+/// The first download of `file` from an allowed host, with the strongest hash the pack declares for it
+fn modrinth_file(file: ModrinthFile) -> Result<ModpackFile, Error> {
+    let checksum = match (file.hashes.get("sha512"), file.hashes.get("sha1")) {
+        (Some(sha512), _) => JarChecksum::Sha512(sha512.to_lowercase()),
+        (None, Some(sha1)) => JarChecksum::Sha1(sha1.to_lowercase()),
+        (None, None) => return Err(bad_manifest(format!("no hash for {}", file.path))),
+    };
+    let url = file
+        .downloads
+        .into_iter()
+        .find(|download| {
+            url::Url::parse(download).map_or(false, |url| {
+                url.scheme() == "https"
+                    && url
+                        .host_str()
+                        .map_or(false, |host| MODRINTH_DOWNLOAD_HOSTS.contains(&host))
+            })
+        })
+        .ok_or_else(|| {
+            bad_manifest(format!(
+                "no download for {} from {}",
+                file.path,
+                MODRINTH_DOWNLOAD_HOSTS.join(", ")
+            ))
+        })?;
+    Ok(ModpackFile {
+        path: file.path,
+        url,
+        checksum,
+    })
+}
+
+fn modrinth_flavour(
+    version: &str,
+    dependencies: &HashMap<String, String>,
+) -> Result<Flavour, Error> {
+    if let Some(loader_version) = dependencies.get("fabric-loader") {
+        Ok(Flavour::Fabric {
+            loader_version: Some(FabricLoaderVersion(loader_version.clone())),
+            installer_version: None,
+        })
+    } else if let Some(forge_version) = dependencies.get("forge") {
+        Ok(Flavour::Forge {
+            build_version: Some(ForgeBuildVersion(format!("{}-{}", version, forge_version))),
+        })
+    } else if let Some((loader, _)) = dependencies
+        .iter()
+        .find(|(dependency, _)| dependency.as_str() != "minecraft")
+    {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Modpacks using {} are not supported", loader),
+        })
+    } else {
+        Ok(Flavour::Vanilla)
+    }
+}
+
+fn curseforge_flavour(
+    version: &str,
+    mod_loaders: &[CurseForgeModLoader],
+) -> Result<Flavour, Error> {
+    let loader = match mod_loaders
+        .iter()
+        .find(|loader| loader.primary)
+        .or_else(|| mod_loaders.first())
+    {
+        Some(loader) => loader,
+        None => return Ok(Flavour::Vanilla),
+    };
+    match loader.id.split_once('-') {
+        Some(("forge", build)) => Ok(Flavour::Forge {
+            build_version: Some(ForgeBuildVersion(format!("{}-{}", version, build))),
+        }),
+        Some(("fabric", loader_version)) => Ok(Flavour::Fabric {
+            loader_version: Some(FabricLoaderVersion(loader_version.to_string())),
+            installer_version: None,
+        }),
+        _ => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Modpacks using {} are not supported", loader.id),
+        }),
+    }
+}
+
+fn read_zip_entry<R: std::io::Read + std::io::Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    name: &str,
+) -> Option<Result<String, Error>> {
+    let mut entry = archive.by_name(name).ok()?;
+    let mut content = String::new();
+    Some(
+        entry
+            .read_to_string(&mut content)
+            .context(format!("Failed to read {} from modpack", name))
+            .map(|_| content)
+            .map_err(Error::from),
+    )
+}
+
+impl Modpack {
+    /// Reads a Modrinth (`modrinth.index.json`) or CurseForge (`manifest.json`) pack archive
+    pub fn read(path_to_pack: &Path) -> Result<Self, Error> {
+        let file = std::fs::File::open(path_to_pack).context("Failed to open modpack")?;
+        let mut archive = zip::ZipArchive::new(file)
+            .map_err(|e| bad_manifest(format!("not a zip archive ({})", e)))?;
+
+        if let Some(index) = read_zip_entry(&mut archive, "modrinth.index.json") {
+            let index: ModrinthIndex = serde_json::from_str(&index?)
+                .map_err(|e| bad_manifest(format!("malformed modrinth.index.json ({})", e)))?;
+            let version = index
+                .dependencies
+                .get("minecraft")
+                .cloned()
+                .ok_or_else(|| bad_manifest("no Minecraft version declared".to_string()))?;
+            let flavour = modrinth_flavour(&version, &index.dependencies)?;
+            let files = index
+                .files
+                .into_iter()
+                .filter(|file| {
+                    file.env
+                        .as_ref()
+                        .map_or(true, |env| env.server != "unsupported")
+                })
+                .map(modrinth_file)
+                .collect::<Result<Vec<_>, Error>>()?;
+            return Ok(Self {
+                name: index.name,
+                version,
+                flavour,
+                files,
+                override_dirs: MODRINTH_OVERRIDE_DIRS
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+            });
+        }
+
+        if let Some(manifest) = read_zip_entry(&mut archive, "manifest.json") {
+            let manifest: CurseForgeManifest = serde_json::from_str(&manifest?)
+                .map_err(|e| bad_manifest(format!("malformed manifest.json ({})", e)))?;
+            if !manifest.files.is_empty() {
+                return Err(Error {
+                    kind: ErrorKind::UnsupportedOperation,
+                    source: eyre!(
+                        "CurseForge packs download their mods through the CurseForge API, which is not supported. Use the pack's server files instead"
+                    ),
+                });
+            }
+            let flavour =
+                curseforge_flavour(&manifest.minecraft.version, &manifest.minecraft.mod_loaders)?;
+            return Ok(Self {
+                name: manifest.name,
+                version: manifest.minecraft.version,
+                flavour,
+                files: Vec::new(),
+                override_dirs: vec![manifest
+                    .overrides
+                    .unwrap_or_else(|| "overrides".to_string())],
+            });
+        }
+
+        Err(bad_manifest(
+            "no modrinth.index.json or manifest.json found".to_string(),
+        ))
+    }
+
+    /// Copies the pack's overrides into `path_to_instance`, skipping files the core manages
+    fn extract_overrides(&self, path_to_pack: &Path, path_to_instance: &Path) -> Result<(), Error> {
+        let file = std::fs::File::open(path_to_pack).context("Failed to open modpack")?;
+        let mut archive = zip::ZipArchive::new(file).context("Failed to read modpack")?;
+        for i in 0..archive.len() {
+            let mut entry = archive
+                .by_index(i)
+                .context("Failed to read modpack entry")?;
+            // rejects absolute paths and `..`
+            let entry_path = match entry.enclosed_name() {
+                Some(path) => path.to_owned(),
+                None => continue,
+            };
+            let relative_path = match self
+                .override_dirs
+                .iter()
+                .find_map(|dir| entry_path.strip_prefix(dir).ok())
+            {
+                Some(path) if !path.as_os_str().is_empty() => path.to_owned(),
+                _ => continue,
+            };
+            if SKIPPED_OVERRIDES
+                .iter()
+                .any(|skipped| relative_path == Path::new(skipped))
+            {
+                continue;
+            }
+            let dest = scoped_join_win_safe(path_to_instance, &relative_path)?;
+            if entry.is_dir() {
+                std::fs::create_dir_all(&dest)
+                    .context(format!("Failed to create {}", dest.display()))?;
+                continue;
+            }
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)
+                    .context(format!("Failed to create {}", parent.display()))?;
+            }
+            let mut out = std::fs::File::create(&dest)
+                .context(format!("Failed to create {}", dest.display()))?;
+            std::io::copy(&mut entry, &mut out)
+                .context(format!("Failed to extract {}", relative_path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Lays out the pack's files and overrides in `path_to_instance`
+    pub async fn install(&self, path_to_pack: &Path, path_to_instance: &Path) -> Result<(), Error> {
+        tokio::task::spawn_blocking({
+            let modpack = self.clone();
+            let path_to_pack = path_to_pack.to_owned();
+            let path_to_instance = path_to_instance.to_owned();
+            move || modpack.extract_overrides(&path_to_pack, &path_to_instance)
+        })
+        .await
+        .context("Failed to extract modpack overrides")??;
+
+        for file in &self.files {
+            let dest: PathBuf = scoped_join_win_safe(path_to_instance, &file.path)?;
+            let (parent, file_name) = match (dest.parent(), dest.file_name()) {
+                (Some(parent), Some(file_name)) => (parent, file_name.to_string_lossy()),
+                _ => return Err(bad_manifest(format!("invalid file path {}", file.path))),
+            };
+            download_verified_jar(
+                &file.url,
+                parent,
+                file_name.as_ref(),
+                Some(&file.checksum),
+                &|_| {},
+                &|| {},
+            )
+            .await
+            .context(format!("Failed to download {}", file.path))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{modrinth_file, JarChecksum, ModrinthFile};
+
+    fn file(downloads: &[&str], hashes: &[(&str, &str)]) -> ModrinthFile {
+        ModrinthFile {
+            path: "mods/example.jar".to_string(),
+            hashes: hashes
+                .iter()
+                .map(|(algorithm, hash)| (algorithm.to_string(), hash.to_string()))
+                .collect::<HashMap<_, _>>(),
+            downloads: downloads.iter().map(|url| url.to_string()).collect(),
+            env: None,
+        }
+    }
+
+    #[test]
+    fn test_modrinth_file() {
+        let picked = modrinth_file(file(
+            &[
+                "https://evil.example/example.jar",
+                "http://cdn.modrinth.com/example.jar",
+                "https://cdn.modrinth.com/data/example.jar",
+            ],
+            &[("sha1", "AB"), ("sha512", "CD")],
+        ))
+        .unwrap();
+        assert_eq!(picked.url, "https://cdn.modrinth.com/data/example.jar");
+        assert_eq!(picked.checksum, JarChecksum::Sha512("cd".to_string()));
+
+        let sha1_only = modrinth_file(file(
+            &["https://github.com/example/releases/example.jar"],
+            &[("sha1", "ab")],
+        ))
+        .unwrap();
+        assert_eq!(sha1_only.checksum, JarChecksum::Sha1("ab".to_string()));
+
+        // no allowed host
+        assert!(modrinth_file(file(
+            &["https://cdn.modrinth.com.evil.example/example.jar"],
+            &[("sha1", "ab")]
+        ))
+        .is_err());
+        // no hash
+        assert!(modrinth_file(file(&["https://cdn.modrinth.com/data/example.jar"], &[])).is_err());
+    }
+}
@@ -69,4 +69,13 @@ impl TPlayerManagement for MinecraftInstance {
     async fn get_player_list(&self) -> Result<HashSet<Player>, Error> {
         Ok(self.players_manager.lock().await.clone().into())
     }
+
+    /// Written to server.properties, a running server picks it up on its next start
+    async fn set_max_player_count(&self, max_player_count: u32) -> Result<(), Error> {
+        self.configurable_manifest.lock().await.set_setting(
+            ServerPropertySetting::get_section_id(),
+            ServerPropertySetting::MaxPlayers(max_player_count).into(),
+        )?;
+        self.write_properties_to_file().await
+    }
 }
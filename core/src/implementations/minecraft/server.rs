@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 use std::process::Stdio;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use color_eyre::eyre::{eyre, Context};
 use sysinfo::{Pid, PidExt, ProcessExt, SystemExt};
@@ -11,11 +11,14 @@ use crate::error::{Error, ErrorKind};
 use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
 use crate::implementations::minecraft::line_parser::{
     parse_player_joined, parse_player_left, parse_player_msg, parse_server_started,
-    parse_system_msg, PlayerMessage,
+    parse_server_version, parse_system_msg, PlayerMessage,
 };
 use crate::implementations::minecraft::player::MinecraftPlayer;
-use crate::implementations::minecraft::util::name_to_uuid;
-use crate::macro_executor::{DefaultWorkerOptionGenerator, SpawnResult};
+use crate::implementations::minecraft::util::{name_to_uuid, read_jar_version};
+use crate::macro_executor::{
+    resolve_macro_invocation, DefaultWorkerOptionGenerator, SpawnOptions, SpawnResult,
+};
+use crate::orphans;
 use crate::traits::t_configurable::TConfigurable;
 use crate::traits::t_macro::TaskEntry;
 use crate::traits::t_server::{MonitorReport, State, StateAction, TServer};
@@ -23,22 +26,29 @@ use crate::traits::t_server::{MonitorReport, State, StateAction, TServer};
 use crate::types::Snowflake;
 use crate::util::{dont_spawn_terminal, list_dir};
 
-use super::r#macro::resolve_macro_invocation;
+use super::cpu_affinity::apply_cpu_affinity;
+use super::startup_progress::{default_startup_milestones, StartupProgress};
 use super::{Flavour, ForgeBuildVersion, MinecraftInstance};
 use tracing::{error, info, warn};
 
 #[async_trait::async_trait]
 impl TServer for MinecraftInstance {
     async fn start(&self, cause_by: CausedBy, block: bool) -> Result<(), Error> {
+        self.check_eula().await?;
+        // read before the state changes, so a malformed file doesn't leave the instance starting
+        let dotenv = self.read_dotenv().await?;
         let config = self.config.lock().await.clone();
         self.state.lock().await.try_transition(
             StateAction::UserStart,
-            Some(&|state| {
+            Some(&|from, state| {
                 self.event_broadcaster.send(Event {
                     event_inner: EventInner::InstanceEvent(InstanceEvent {
                         instance_name: config.name.clone(),
                         instance_uuid: self.uuid.clone(),
-                        instance_event_inner: InstanceEventInner::StateTransition { to: state },
+                        instance_event_inner: InstanceEventInner::StateTransition {
+                            from: Some(from),
+                            to: state,
+                        },
                     }),
                     snowflake: Snowflake::default(),
                     details: "Starting server".to_string(),
@@ -60,11 +70,14 @@ impl TServer for MinecraftInstance {
                 .macro_executor
                 .spawn(
                     prelaunch,
-                    Vec::new(),
-                    CausedBy::System,
+                    self.path_to_instance.clone(),
                     Box::new(DefaultWorkerOptionGenerator),
-                    None,
-                    Some(self.uuid.clone()),
+                    SpawnOptions {
+                        caused_by: CausedBy::System,
+                        instance_uuid: Some(self.uuid.clone()),
+                        max_heap_mb: Some(self.macro_executor.max_heap_mb()),
+                        ..Default::default()
+                    },
                 )
                 .await;
 
@@ -113,9 +126,16 @@ impl TServer for MinecraftInstance {
         };
 
         let mut server_start_command = Command::new(&jre);
+        config.env.apply(&mut server_start_command, &dotenv);
+        apply_cpu_affinity(
+            &mut server_start_command,
+            &config.cpu_affinity,
+            &config.name,
+        );
         let server_start_command = server_start_command
             .arg(format!("-Xmx{}M", config.max_ram))
             .arg(format!("-Xms{}M", config.min_ram))
+            .args(config.jvm_flags.flags(config.max_ram))
             .args(
                 &config
                     .cmd_args
@@ -203,6 +223,20 @@ impl TServer for MinecraftInstance {
             .arg("nogui")
             .current_dir(&self.path_to_instance);
 
+        // a first guess until the server logs its version, forge doesn't run server.jar directly
+        let jar_version = match config.flavour {
+            Flavour::Forge { .. } => None,
+            _ => {
+                let jar = self.path_to_instance.join("server.jar");
+                tokio::task::spawn_blocking(move || read_jar_version(&jar))
+                    .await
+                    .ok()
+                    .flatten()
+            }
+        };
+        *self.detected_version.lock().await = jar_version;
+
+        let started_at = SystemTime::now();
         match dont_spawn_terminal(server_start_command)
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
@@ -232,15 +266,37 @@ impl TServer for MinecraftInstance {
                     );
                     eyre!("Failed to take stderr during startup")
                 })?;
+                if let Some(pid) = proc.id() {
+                    orphans::write_pidfile(&self.path_to_instance, pid).await;
+                }
                 *self.process.lock().await = Some(proc);
+                tokio::task::spawn(
+                    self.clone()
+                        .idle_shutdown_task(self.event_broadcaster.subscribe()),
+                );
+                tokio::task::spawn(
+                    self.clone()
+                        .console_log_task(self.event_broadcaster.subscribe()),
+                );
                 tokio::task::spawn({
                     let mut __self = self.clone();
                     let event_broadcaster = __self.event_broadcaster.clone();
                     let uuid = __self.uuid.clone();
                     let name = config.name.clone();
                     let players_manager = __self.players_manager.clone();
+                    let startup_milestones = config
+                        .startup_milestones
+                        .clone()
+                        .unwrap_or_else(|| default_startup_milestones(&config.flavour));
                     async move {
                         let mut did_start = false;
+                        let mut startup_progress = Some(StartupProgress::start(
+                            &event_broadcaster,
+                            uuid.clone(),
+                            &name,
+                            startup_milestones,
+                            cause_by.clone(),
+                        ));
 
                         let mut stdout_reader = BufReader::new(stdout);
                         let mut stderr_reader = BufReader::new(stderr);
@@ -295,15 +351,30 @@ impl TServer for MinecraftInstance {
                                         snowflake: Snowflake::default(),
                                         caused_by: CausedBy::System,
                                     });
+                                    if let Some(update) = startup_progress
+                                        .as_mut()
+                                        .and_then(|progress| progress.on_line(&line))
+                                    {
+                                        event_broadcaster.send(update);
+                                    }
 
+                                    if !did_start {
+                                        if let Some(version) = parse_server_version(&line) {
+                                            __self.detected_version.lock().await.replace(version);
+                                        }
+                                    }
                                     if parse_server_started(&line) && !did_start {
                                         did_start = true;
+                                        if let Some(progress) = startup_progress.take() {
+                                            event_broadcaster
+                                                .send(progress.finish(true, "Server started"));
+                                        }
                                         __self.state
                                             .lock()
                                             .await
                                             .try_transition(
                                                 StateAction::InstanceStart,
-                                                Some(&|state| {
+                                                Some(&|from, state| {
                                                     event_broadcaster.send(Event {
                                                 event_inner: EventInner::InstanceEvent(
                                                     InstanceEvent {
@@ -311,6 +382,7 @@ impl TServer for MinecraftInstance {
                                                         instance_uuid: __self.uuid.clone(),
                                                         instance_event_inner:
                                                             InstanceEventInner::StateTransition {
+                                                                from: Some(from),
                                                                 to: state,
                                                             },
                                                     },
@@ -440,18 +512,27 @@ impl TServer for MinecraftInstance {
                             }
                         }
                         info!("Instance {} process shutdown", name);
+                        if let Some(progress) = startup_progress.take() {
+                            event_broadcaster.send(
+                                progress
+                                    .finish(false, "Server stopped before it finished starting"),
+                            );
+                        }
                         __self.state
                             .lock()
                             .await
                             .try_transition(
                                 StateAction::InstanceStop,
-                                Some(&|state| {
+                                Some(&|from, state| {
                                     event_broadcaster.send(Event {
                                         event_inner: EventInner::InstanceEvent(InstanceEvent {
                                             instance_name: config.name.clone(),
                                             instance_uuid: __self.uuid.clone(),
                                             instance_event_inner:
-                                                InstanceEventInner::StateTransition { to: state },
+                                                InstanceEventInner::StateTransition {
+                                                    from: Some(from),
+                                                    to: state,
+                                                },
                                         }),
                                         snowflake: Snowflake::default(),
                                         details: "Instance stopping as server process exited"
@@ -463,6 +544,19 @@ impl TServer for MinecraftInstance {
                             .unwrap();
                         __self.players_manager.lock().await.clear(name);
                         __self.rcon_conn.lock().await.take();
+                        let process = __self.process.lock().await.take();
+                        if let Some(mut process) = process {
+                            match process.wait().await {
+                                Ok(status) if !status.success() => {
+                                    __self.collect_crash_report(started_at).await
+                                }
+                                Ok(_) => {}
+                                Err(e) => {
+                                    error!("[{}] Failed to get exit status: {}", config.name, e)
+                                }
+                            }
+                            orphans::remove_pidfile(&__self.path_to_instance).await;
+                        }
                     }
                 });
                 self.config.lock().await.has_started = true;
@@ -474,7 +568,7 @@ impl TServer for MinecraftInstance {
                     while let Ok(event) = rx.recv().await {
                         if let EventInner::InstanceEvent(InstanceEvent {
                             instance_uuid: event_instance_uuid,
-                            instance_event_inner: InstanceEventInner::StateTransition { to },
+                            instance_event_inner: InstanceEventInner::StateTransition { to, .. },
                             ..
                         }) = event.event_inner
                         {
@@ -502,12 +596,13 @@ impl TServer for MinecraftInstance {
                     .await
                     .try_transition(
                         StateAction::InstanceStop,
-                        Some(&|state| {
+                        Some(&|from, state| {
                             self.event_broadcaster.send(Event {
                                 event_inner: EventInner::InstanceEvent(InstanceEvent {
                                     instance_name: config.name.clone(),
                                     instance_uuid: self.uuid.clone(),
                                     instance_event_inner: InstanceEventInner::StateTransition {
+                                        from: Some(from),
                                         to: state,
                                     },
                                 }),
@@ -528,12 +623,15 @@ impl TServer for MinecraftInstance {
 
         self.state.lock().await.try_transition(
             StateAction::UserStop,
-            Some(&|state| {
+            Some(&|from, state| {
                 self.event_broadcaster.send(Event {
                     event_inner: EventInner::InstanceEvent(InstanceEvent {
                         instance_name: config.name.clone(),
                         instance_uuid: self.uuid.clone(),
-                        instance_event_inner: InstanceEventInner::StateTransition { to: state },
+                        instance_event_inner: InstanceEventInner::StateTransition {
+                            from: Some(from),
+                            to: state,
+                        },
                     }),
                     snowflake: Snowflake::default(),
                     details: "Stopping server".to_string(),
@@ -566,7 +664,7 @@ impl TServer for MinecraftInstance {
             while let Ok(event) = rx.recv().await {
                 if let EventInner::InstanceEvent(InstanceEvent {
                     instance_uuid: event_instance_uuid,
-                    instance_event_inner: InstanceEventInner::StateTransition { to },
+                    instance_event_inner: InstanceEventInner::StateTransition { to, .. },
                     ..
                 }) = event.event_inner
                 {
@@ -581,20 +679,75 @@ impl TServer for MinecraftInstance {
         }
     }
 
+    /// Stops the server if it is running and starts it again once it has stopped
+    ///
+    /// Fails with `ErrorKind::BadRequest` if the server is in the middle of starting or stopping
     async fn restart(&self, caused_by: CausedBy, block: bool) -> Result<(), Error> {
+        let state = self.state().await;
+        if !matches!(state, State::Running | State::Stopped) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Cannot restart an instance that is {}",
+                    state.to_string().to_lowercase()
+                ),
+            });
+        }
+        let restart = {
+            let __self = self.clone();
+            async move {
+                let name = __self.config.lock().await.name.clone();
+                let steps = if state == State::Running { 2.0 } else { 1.0 };
+                let (start_event, event_id) = Event::new_progression_event_start(
+                    format!("Restarting {}", name),
+                    Some(steps),
+                    None,
+                    caused_by.clone(),
+                );
+                __self.event_broadcaster.send(start_event);
+                let res = async {
+                    if state == State::Running {
+                        __self
+                            .event_broadcaster
+                            .send(Event::new_progression_event_update(
+                                &event_id,
+                                "Stopping server",
+                                1.0,
+                            ));
+                        __self.stop(caused_by.clone(), true).await?;
+                    }
+                    __self
+                        .event_broadcaster
+                        .send(Event::new_progression_event_update(
+                            &event_id,
+                            "Starting server",
+                            1.0,
+                        ));
+                    __self.start(caused_by, true).await
+                }
+                .await;
+                __self
+                    .event_broadcaster
+                    .send(Event::new_progression_event_end(
+                        event_id,
+                        res.is_ok(),
+                        Some(match &res {
+                            Ok(_) => "Server restarted".to_string(),
+                            Err(e) => format!("Failed to restart server: {}", e),
+                        }),
+                        None,
+                    ));
+                res
+            }
+        };
         if block {
-            self.stop(caused_by.clone(), block).await?;
-            self.start(caused_by, block).await
+            restart.await
         } else {
-            self.state
-                .lock()
-                .await
-                .try_new_state(StateAction::UserStop, None)?;
-
-            let mut __self = self.clone();
+            let name = self.config.lock().await.name.clone();
             tokio::task::spawn(async move {
-                __self.stop(caused_by.clone(), true).await.unwrap();
-                __self.start(caused_by, block).await.unwrap()
+                if let Err(e) = restart.await {
+                    error!("[{}] Failed to restart instance: {}", name, e);
+                }
             });
             Ok(())
         }
@@ -622,11 +775,12 @@ impl TServer for MinecraftInstance {
                 "[{}] Process not available, assuming instance is stopped",
                 config.name.clone()
             );
-            *self.state.lock().await = State::Stopped;
+            let from = std::mem::replace(&mut *self.state.lock().await, State::Stopped);
             self.event_broadcaster
                 .send(Event::new_instance_state_transition(
                     self.uuid.clone(),
                     config.name.clone(),
+                    Some(from),
                     State::Stopped,
                 ));
             Err(eyre!("Process not available, assuming instance is stopped"))?;
@@ -648,12 +802,13 @@ impl TServer for MinecraftInstance {
                     if command == "stop" {
                         self.state.lock().await.try_new_state(
                             StateAction::UserStop,
-                            Some(&|state| {
+                            Some(&|from, state| {
                                 self.event_broadcaster.send(Event {
                                     event_inner: EventInner::InstanceEvent(InstanceEvent {
                                         instance_name: config.name.clone(),
                                         instance_uuid: self.uuid.clone(),
                                         instance_event_inner: InstanceEventInner::StateTransition {
+                                            from: Some(from),
                                             to: state,
                                         },
                                     }),
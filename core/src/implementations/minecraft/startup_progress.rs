@@ -0,0 +1,247 @@
+use color_eyre::eyre::eyre;
+use fancy_regex::Regex;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, ProgressionEventID, ProgressionStartValue};
+use crate::types::InstanceUuid;
+
+use super::{Flavour, MinecraftInstance};
+
+/// A line of the server's output that shows how far it got while starting
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, TS)]
+#[ts(export)]
+pub struct StartupMilestone {
+    /// Regex matched against every line of output
+    pub pattern: String,
+    /// Progress from 0 to 100 once a line matches
+    pub progress: f64,
+    /// If set, the first capture group of `pattern` is read as a percentage
+    /// and mapped from `progress` to `progress_end`, as in "Preparing spawn area: 45%"
+    #[serde(default)]
+    pub progress_end: Option<f64>,
+}
+
+impl StartupMilestone {
+    fn new(pattern: &str, progress: f64, progress_end: Option<f64>) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            progress,
+            progress_end,
+        }
+    }
+
+    fn is_valid_progress(progress: f64) -> bool {
+        (0.0..=100.0).contains(&progress)
+    }
+}
+
+/// Shared by every flavour, the server is done once it prints `Done (...)!`
+fn vanilla_startup_milestones() -> Vec<StartupMilestone> {
+    vec![
+        StartupMilestone::new(r"Starting minecraft server version", 5.0, None),
+        StartupMilestone::new(r"Loading properties", 10.0, None),
+        StartupMilestone::new(r"Preparing level", 30.0, None),
+        StartupMilestone::new(r"Preparing start region", 40.0, None),
+        StartupMilestone::new(r"Preparing spawn area: (\d+)%", 40.0, Some(95.0)),
+    ]
+}
+
+/// Used when an instance doesn't set its own milestones
+///
+/// Modded servers spend most of their startup loading mods, so those milestones come first
+pub fn default_startup_milestones(flavour: &Flavour) -> Vec<StartupMilestone> {
+    let mut milestones = match flavour {
+        Flavour::Forge { .. } => vec![
+            StartupMilestone::new(r"ModLauncher running", 2.0, None),
+            StartupMilestone::new(r"Loading \d+ mods|Found mod file", 10.0, None),
+            StartupMilestone::new(r"Forge mod loading", 25.0, None),
+        ],
+        Flavour::Fabric { .. } => vec![StartupMilestone::new(r"Loading \d+ mods", 2.0, None)],
+        Flavour::Paper { .. } | Flavour::Spigot => {
+            vec![StartupMilestone::new(r"Loading libraries", 2.0, None)]
+        }
+        Flavour::Vanilla => Vec::new(),
+    };
+    // the vanilla milestones a modded server passes before its mods finish loading are skipped,
+    // as progress never goes backwards
+    milestones.extend(vanilla_startup_milestones());
+    milestones
+}
+
+pub fn validate_startup_milestones(milestones: &[StartupMilestone]) -> Result<(), Error> {
+    for milestone in milestones {
+        if let Err(e) = Regex::new(&milestone.pattern) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Invalid startup milestone pattern {}: {}",
+                    milestone.pattern,
+                    e
+                ),
+            });
+        }
+        if !StartupMilestone::is_valid_progress(milestone.progress)
+            || !milestone
+                .progress_end
+                .map_or(true, StartupMilestone::is_valid_progress)
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Progress of startup milestone {} must be between 0 and 100",
+                    milestone.pattern
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reports how far a starting server got as a progression event keyed to the instance
+pub struct StartupProgress {
+    event_id: ProgressionEventID,
+    milestones: Vec<(Regex, StartupMilestone)>,
+    progress: f64,
+}
+
+impl StartupProgress {
+    /// Sends the start of the progression, invalid patterns are skipped
+    pub fn start(
+        event_broadcaster: &EventBroadcaster,
+        instance_uuid: InstanceUuid,
+        instance_name: &str,
+        milestones: Vec<StartupMilestone>,
+        caused_by: CausedBy,
+    ) -> Self {
+        let (start_event, event_id) = Event::new_progression_event_start(
+            format!("Starting {instance_name}"),
+            Some(100.0),
+            Some(ProgressionStartValue::InstanceStart { instance_uuid }),
+            caused_by,
+        );
+        event_broadcaster.send(start_event);
+        Self {
+            event_id,
+            milestones: milestones
+                .into_iter()
+                .filter_map(|milestone| Some((Regex::new(&milestone.pattern).ok()?, milestone)))
+                .collect(),
+            progress: 0.0,
+        }
+    }
+
+    /// Progress reached by `line`, if it matches a milestone
+    fn progress_of(&self, line: &str) -> Option<f64> {
+        self.milestones.iter().find_map(|(re, milestone)| {
+            let captures = re.captures(line).ok()??;
+            Some(match milestone.progress_end {
+                Some(progress_end) => {
+                    let percent = captures
+                        .get(1)
+                        .and_then(|percent| percent.as_str().parse::<f64>().ok())
+                        .unwrap_or(0.0)
+                        .clamp(0.0, 100.0);
+                    milestone.progress + (progress_end - milestone.progress) * percent / 100.0
+                }
+                None => milestone.progress,
+            })
+        })
+    }
+
+    /// An update if `line` moved the progress forward
+    ///
+    /// Updates carry the difference to the last one, progress never goes backwards
+    pub fn on_line(&mut self, line: &str) -> Option<Event> {
+        let progress = self.progress_of(line)?;
+        if progress <= self.progress {
+            return None;
+        }
+        let delta = progress - self.progress;
+        self.progress = progress;
+        Some(Event::new_progression_event_update(
+            &self.event_id,
+            line.trim(),
+            delta,
+        ))
+    }
+
+    pub fn finish(self, success: bool, message: &str) -> Event {
+        Event::new_progression_event_end(self.event_id, success, Some(message), None)
+    }
+}
+
+impl MinecraftInstance {
+    /// `None` goes back to the defaults of the instance's flavour, takes effect the next time it starts
+    pub async fn set_startup_milestones(
+        &self,
+        milestones: Option<Vec<StartupMilestone>>,
+    ) -> Result<(), Error> {
+        if let Some(milestones) = &milestones {
+            validate_startup_milestones(milestones)?;
+        }
+        self.config.lock().await.startup_milestones = milestones;
+        self.write_config_to_file().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventInner, ProgressionEventInner};
+
+    fn progress_of(event: Option<Event>) -> Option<f64> {
+        match event?.event_inner {
+            EventInner::ProgressionEvent(event) => match event.progression_event_inner() {
+                ProgressionEventInner::ProgressionUpdate { progress, .. } => Some(*progress),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_startup_progress() {
+        let (event_broadcaster, _rx) = EventBroadcaster::new(10);
+        let milestones = default_startup_milestones(&Flavour::Vanilla);
+        assert!(validate_startup_milestones(&milestones).is_ok());
+        let mut progress = StartupProgress::start(
+            &event_broadcaster,
+            InstanceUuid::default(),
+            "test",
+            milestones,
+            CausedBy::System,
+        );
+        assert_eq!(
+            progress_of(progress.on_line(
+                "[12:00:00] [Server thread/INFO]: Starting minecraft server version 1.20.1"
+            )),
+            Some(5.0)
+        );
+        assert_eq!(
+            progress_of(
+                progress.on_line("[12:00:01] [Server thread/INFO]: Preparing level \"world\"")
+            ),
+            Some(25.0)
+        );
+        // 40 + 55 * 0.5, less the 30 already reported
+        assert_eq!(
+            progress_of(
+                progress.on_line("[12:00:02] [Worker-Main-1/INFO]: Preparing spawn area: 50%")
+            ),
+            Some(37.5)
+        );
+        // going backwards is ignored
+        assert!(progress
+            .on_line("[12:00:03] [Server thread/INFO]: Loading properties")
+            .is_none());
+        assert!(progress
+            .on_line("[12:00:03] [Server thread/INFO]: hello")
+            .is_none());
+
+        assert!(validate_startup_milestones(&[StartupMilestone::new("(", 10.0, None)]).is_err());
+        assert!(validate_startup_milestones(&[StartupMilestone::new("a", 110.0, None)]).is_err());
+    }
+}
@@ -1,13 +1,23 @@
 use color_eyre::eyre::{eyre, Context, ContextCompat};
 use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
-use std::{collections::BTreeMap, path::Path, str::FromStr};
+use sha1::Digest;
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use tokio::io::AsyncBufReadExt;
+use tracing::warn;
+use ts_rs::TS;
 
 use super::{
     FabricInstallerVersion, FabricLoaderVersion, Flavour, ForgeBuildVersion, PaperBuildVersion,
 };
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
+use crate::util::{download_file, DownloadProgress};
 
 pub async fn read_properties_from_path(
     path_to_properties: &Path,
@@ -50,21 +60,123 @@ pub async fn read_properties_from_path(
     Ok(ret)
 }
 
-// Returns the jar url and the updated flavour with version information
-pub async fn get_server_jar_url(version: &str, flavour: &Flavour) -> Option<(String, Flavour)> {
+/// The hash a server jar or modpack file is published with, in lowercase hex
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, TS)]
+#[ts(export)]
+#[serde(tag = "algorithm", content = "hash", rename_all = "snake_case")]
+pub enum JarChecksum {
+    Sha1(String),
+    Sha256(String),
+    Sha512(String),
+}
+
+impl JarChecksum {
+    fn hash_file(&self, path: &Path) -> std::io::Result<String> {
+        fn hash<D: Digest>(mut file: std::fs::File) -> std::io::Result<String> {
+            let mut hasher = D::new();
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let read = file.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+            }
+            Ok(hex::encode(hasher.finalize()))
+        }
+        let file = std::fs::File::open(path)?;
+        match self {
+            JarChecksum::Sha1(_) => hash::<sha1::Sha1>(file),
+            JarChecksum::Sha256(_) => hash::<sha2::Sha256>(file),
+            JarChecksum::Sha512(_) => hash::<sha2::Sha512>(file),
+        }
+    }
+
+    /// Fails with `ErrorKind::ChecksumMismatch` if the file at `path` doesn't have this hash
+    pub async fn verify(&self, path: &Path) -> Result<(), Error> {
+        let checksum = self.clone();
+        let path_buf = path.to_owned();
+        let actual = tokio::task::spawn_blocking(move || checksum.hash_file(&path_buf))
+            .await
+            .context("Failed to spawn blocking task")?
+            .context(format!("Failed to read {}", path.display()))?;
+        let (algorithm, expected) = match self {
+            JarChecksum::Sha1(expected) => ("SHA-1", expected),
+            JarChecksum::Sha256(expected) => ("SHA-256", expected),
+            JarChecksum::Sha512(expected) => ("SHA-512", expected),
+        };
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(Error {
+                kind: ErrorKind::ChecksumMismatch,
+                source: eyre!(
+                    "{} of {} is {}, expected {}",
+                    algorithm,
+                    path.display(),
+                    actual,
+                    expected
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Downloads a server jar into `path`, downloading it once more if it doesn't match `checksum`
+///
+/// `on_verify` is called before each check, jars published without a checksum aren't verified
+pub async fn download_verified_jar(
+    url: &str,
+    path: &Path,
+    jar_name: &str,
+    checksum: Option<&JarChecksum>,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+    on_verify: &(dyn Fn() + Send + Sync),
+) -> Result<PathBuf, Error> {
+    let mut attempts_left = 2;
+    loop {
+        let jar_path = download_file(url, path, Some(jar_name), on_download, true).await?;
+        let checksum = match checksum {
+            Some(checksum) => checksum,
+            None => return Ok(jar_path),
+        };
+        on_verify();
+        attempts_left -= 1;
+        match checksum.verify(&jar_path).await {
+            Ok(_) => return Ok(jar_path),
+            Err(e) if attempts_left > 0 => {
+                warn!("Downloaded {jar_name} is corrupted, downloading it again: {e}");
+            }
+            Err(e) => {
+                // a corrupted jar is never left behind to be started
+                let _ = tokio::fs::remove_file(&jar_path).await;
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Returns the jar url, the updated flavour with version information
+/// and the checksum of the jar if its source publishes one
+pub async fn get_server_jar_url(
+    version: &str,
+    flavour: &Flavour,
+) -> Option<(String, Flavour, Option<JarChecksum>)> {
     match flavour {
         Flavour::Vanilla => get_vanilla_jar_url(version).await,
+        // Fabric builds the server launcher on request and publishes no checksum for it
         Flavour::Fabric {
             loader_version,
             installer_version,
-        } => get_fabric_jar_url(version, loader_version, installer_version).await,
+        } => get_fabric_jar_url(version, loader_version, installer_version)
+            .await
+            .map(|(url, flavour)| (url, flavour, None)),
         Flavour::Paper { build_version } => get_paper_jar_url(version, build_version).await,
         Flavour::Spigot => todo!(),
         Flavour::Forge { build_version } => get_forge_jar_url(version, build_version).await.ok(),
     }
 }
 
-pub async fn get_vanilla_jar_url(version: &str) -> Option<(String, Flavour)> {
+pub async fn get_vanilla_jar_url(version: &str) -> Option<(String, Flavour, Option<JarChecksum>)> {
     let client = reqwest::Client::new();
     let response_text = client
         .get("https://launchermeta.mojang.com/mc/game/version_manifest.json")
@@ -101,6 +213,9 @@ pub async fn get_vanilla_jar_url(version: &str) -> Option<(String, Flavour)> {
             .to_string()
             .replace('\"', ""),
         Flavour::Vanilla,
+        response["downloads"]["server"]["sha1"]
+            .as_str()
+            .map(|sha1| JarChecksum::Sha1(sha1.to_string())),
     ))
 }
 
@@ -260,7 +375,7 @@ pub async fn get_fabric_jar_url(
 pub async fn get_paper_jar_url(
     version: &str,
     paper_build_version: &Option<PaperBuildVersion>,
-) -> Option<(String, Flavour)> {
+) -> Option<(String, Flavour, Option<JarChecksum>)> {
     let client = reqwest::Client::new();
 
     let builds_text = client
@@ -312,13 +427,19 @@ pub async fn get_paper_jar_url(
         Flavour::Paper {
             build_version: Some(PaperBuildVersion(build_version)),
         },
+        build
+            .get("downloads")?
+            .get("application")?
+            .get("sha256")
+            .and_then(|sha256| sha256.as_str())
+            .map(|sha256| JarChecksum::Sha256(sha256.to_string())),
     ))
 }
 
 pub async fn get_forge_jar_url(
     version: &str,
     forge_build_version: &Option<ForgeBuildVersion>,
-) -> Result<(String, Flavour), Error> {
+) -> Result<(String, Flavour, Option<JarChecksum>), Error> {
     let client = reqwest::Client::new();
 
     let response: BTreeMap<String, Vec<String>> = serde_json::from_str(
@@ -344,14 +465,26 @@ pub async fn get_forge_jar_url(
             .context("Failed to get forge versions, no builds found")?
     };
 
+    let url = format!(
+        "https://maven.minecraftforge.net/net/minecraftforge/forge/{}/forge-{}-installer.jar",
+        build, build
+    );
+    // published next to the jar by maven, some old builds lack it
+    let checksum = match client.get(format!("{url}.sha1")).send().await {
+        Ok(response) if response.status().is_success() => response
+            .text()
+            .await
+            .ok()
+            .map(|sha1| JarChecksum::Sha1(sha1.trim().to_string())),
+        _ => None,
+    };
+
     Ok((
-        format!(
-            "https://maven.minecraftforge.net/net/minecraftforge/forge/{}/forge-{}-installer.jar",
-            build, build
-        ),
+        url,
         Flavour::Forge {
             build_version: Some(ForgeBuildVersion(build.to_string())),
         },
+        checksum,
     ))
 }
 
@@ -423,6 +556,24 @@ pub async fn get_jre_url(version: &str) -> Option<(String, u64)> {
     ))
 }
 
+/// Returns the size of the file at `url` as reported by the server, without downloading it
+pub async fn get_content_length(url: &str) -> Option<u64> {
+    reqwest::Client::new()
+        .head(url)
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?
+        // the body of a HEAD response is empty, so read the header instead of `content_length()`
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
 pub async fn name_to_uuid(name: impl AsRef<str>) -> Option<String> {
     // GET https://api.mojang.com/users/profiles/minecraft/<username>
     let client = reqwest::Client::new();
@@ -440,19 +591,34 @@ pub async fn name_to_uuid(name: impl AsRef<str>) -> Option<String> {
     Some(res["id"].as_str()?.to_owned())
 }
 
+/// The version id in the server jar's `version.json`, which vanilla jars have since 1.14.
+///
+/// Blocking, the jar is read synchronously
+pub fn read_jar_version(jar: &Path) -> Option<String> {
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(jar).ok()?).ok()?;
+    let mut contents = String::new();
+    archive
+        .by_name("version.json")
+        .ok()?
+        .read_to_string(&mut contents)
+        .ok()?;
+    let version: Value = serde_json::from_str(&contents).ok()?;
+    Some(version["id"].as_str()?.to_owned())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::minecraft::{
-        util::{get_forge_jar_url, get_server_jar_url},
+        util::{get_forge_jar_url, get_server_jar_url, JarChecksum},
         FabricInstallerVersion, FabricLoaderVersion, Flavour, ForgeBuildVersion, PaperBuildVersion,
     };
     use tokio;
 
     #[tokio::test]
     async fn test_get_vanilla_jar_url() {
-        assert_eq!(super::get_vanilla_jar_url("1.18.2").await, Some(("https://piston-data.mojang.com/v1/objects/c8f83c5655308435b3dcf03c06d9fe8740a77469/server.jar".to_string(), Flavour::Vanilla)));
-        assert_eq!(super::get_vanilla_jar_url("21w44a").await, Some(("https://piston-data.mojang.com/v1/objects/ae583fd57a8c07f2d6fbadce1ce1e1379bf4b32d/server.jar".to_string(), Flavour::Vanilla)));
-        assert_eq!(super::get_vanilla_jar_url("1.8.4").await, Some(("https://launcher.mojang.com/v1/objects/dd4b5eba1c79500390e0b0f45162fa70d38f8a3d/server.jar".to_string(), Flavour::Vanilla)));
+        assert_eq!(super::get_vanilla_jar_url("1.18.2").await, Some(("https://piston-data.mojang.com/v1/objects/c8f83c5655308435b3dcf03c06d9fe8740a77469/server.jar".to_string(), Flavour::Vanilla, Some(JarChecksum::Sha1("c8f83c5655308435b3dcf03c06d9fe8740a77469".to_string())))));
+        assert_eq!(super::get_vanilla_jar_url("21w44a").await, Some(("https://piston-data.mojang.com/v1/objects/ae583fd57a8c07f2d6fbadce1ce1e1379bf4b32d/server.jar".to_string(), Flavour::Vanilla, Some(JarChecksum::Sha1("ae583fd57a8c07f2d6fbadce1ce1e1379bf4b32d".to_string())))));
+        assert_eq!(super::get_vanilla_jar_url("1.8.4").await, Some(("https://launcher.mojang.com/v1/objects/dd4b5eba1c79500390e0b0f45162fa70d38f8a3d/server.jar".to_string(), Flavour::Vanilla, Some(JarChecksum::Sha1("dd4b5eba1c79500390e0b0f45162fa70d38f8a3d".to_string())))));
 
         assert_eq!(super::get_vanilla_jar_url("1.8.4asdasd").await, None);
     }
@@ -496,19 +662,24 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_paper_jar_url() {
-        assert_eq!(super::get_paper_jar_url("1.19.3", &Some(PaperBuildVersion(308))).await, Some((
+        assert_eq!(super::get_paper_jar_url("1.19.3", &Some(PaperBuildVersion(308))).await.map(|(url, flavour, _)| (url, flavour)), Some((
             "https://api.papermc.io/v2/projects/paper/versions/1.19.3/builds/308/downloads/paper-1.19.3-308.jar".to_string(),
             Flavour::Paper { build_version: Some(PaperBuildVersion(308)) }
         )));
-        assert_eq!(super::get_paper_jar_url("1.13-pre7", &Some(PaperBuildVersion(1))).await, Some((
+        assert_eq!(super::get_paper_jar_url("1.13-pre7", &Some(PaperBuildVersion(1))).await.map(|(url, flavour, _)| (url, flavour)), Some((
             "https://api.papermc.io/v2/projects/paper/versions/1.13-pre7/builds/1/downloads/paper-1.13-pre7-1.jar".to_string(),
             Flavour::Paper { build_version: Some(PaperBuildVersion(1)) }
         )));
-        assert_eq!(super::get_paper_jar_url("1.19", &None).await, Some((
+        assert_eq!(super::get_paper_jar_url("1.19", &None).await.map(|(url, flavour, _)| (url, flavour)), Some((
             "https://api.papermc.io/v2/projects/paper/versions/1.19/builds/81/downloads/paper-1.19-81.jar".to_string(),
             Flavour::Paper { build_version: Some(PaperBuildVersion(81)) }
         )));
 
+        assert!(matches!(
+            super::get_paper_jar_url("1.19.3", &Some(PaperBuildVersion(308))).await,
+            Some((_, _, Some(JarChecksum::Sha256(_))))
+        ));
+
         assert_eq!(super::get_paper_jar_url("1.19.3bruh", &None).await, None);
     }
 
@@ -520,14 +691,18 @@ mod tests {
     #[tokio::test]
     async fn test_get_server_jar_url() {
         assert_eq!(
-            get_server_jar_url("1.7.10", &Flavour::Forge { build_version: None }).await,
+            get_server_jar_url("1.7.10", &Flavour::Forge { build_version: None })
+                .await
+                .map(|(url, flavour, _)| (url, flavour)),
             Some((
                 "https://maven.minecraftforge.net/net/minecraftforge/forge/1.7.10-10.13.4.1614-1.7.10/forge-1.7.10-10.13.4.1614-1.7.10-installer.jar".to_string(),
                 Flavour::Forge { build_version: Some(ForgeBuildVersion("1.7.10-10.13.4.1614-1.7.10".to_string())) }
             ))
         );
         assert_eq!(
-            get_server_jar_url("1.7.10_pre4", &Flavour::Forge { build_version: None }).await,
+            get_server_jar_url("1.7.10_pre4", &Flavour::Forge { build_version: None })
+                .await
+                .map(|(url, flavour, _)| (url, flavour)),
             Some((
                 "https://maven.minecraftforge.net/net/minecraftforge/forge/1.7.10_pre4-10.12.2.1149-prerelease/forge-1.7.10_pre4-10.12.2.1149-prerelease-installer.jar".to_string(),
                 Flavour::Forge { build_version: Some(ForgeBuildVersion("1.7.10_pre4-10.12.2.1149-prerelease".to_string())) }
@@ -544,4 +719,72 @@ mod tests {
             None
         );
     }
+
+    #[tokio::test]
+    async fn test_verify_jar_checksum() {
+        use crate::error::ErrorKind;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("server.jar");
+        std::fs::write(&path, "hello world").unwrap();
+
+        JarChecksum::Sha1("2aae6c35c94fcfb415dbe95f408b9ce91ee846ed".to_string())
+            .verify(&path)
+            .await
+            .unwrap();
+        JarChecksum::Sha256(
+            "B94D27B9934D3E08A52E52D7DA7DABFAC484EFE37A5380EE9088F7ACE2EFCDE9".to_string(),
+        )
+        .verify(&path)
+        .await
+        .unwrap();
+        assert!(matches!(
+            JarChecksum::Sha1("0000000000000000000000000000000000000000".to_string())
+                .verify(&path)
+                .await,
+            Err(crate::Error {
+                kind: ErrorKind::ChecksumMismatch,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_read_jar_version() {
+        use std::io::Write;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let write_jar = |name: &str, entry: &str, contents: &str| {
+            let path = temp_dir.path().join(name);
+            let mut writer = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+            writer
+                .start_file(entry, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+            writer.finish().unwrap();
+            path
+        };
+
+        let jar = write_jar(
+            "vanilla.jar",
+            "version.json",
+            r#"{"id": "1.20.1", "name": "1.20.1", "world_version": 3465}"#,
+        );
+        assert_eq!(super::read_jar_version(&jar), Some("1.20.1".to_string()));
+
+        // jars older than 1.14 have no version.json
+        let jar = write_jar("old.jar", "net/minecraft/server/MinecraftServer.class", "");
+        assert_eq!(super::read_jar_version(&jar), None);
+
+        let jar = write_jar("broken.jar", "version.json", "not json");
+        assert_eq!(super::read_jar_version(&jar), None);
+
+        let not_a_jar = temp_dir.path().join("server.jar");
+        std::fs::write(&not_a_jar, "hello world").unwrap();
+        assert_eq!(super::read_jar_version(&not_a_jar), None);
+        assert_eq!(
+            super::read_jar_version(&temp_dir.path().join("missing.jar")),
+            None
+        );
+    }
 }
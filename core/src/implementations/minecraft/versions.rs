@@ -1,11 +1,23 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
 use color_eyre::eyre::{eyre, Context};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tokio::sync::Mutex;
+use tracing::warn;
 use ts_rs::TS;
 
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
+
+use super::fabric::get_fabric_loader_versions;
+use super::FlavourKind;
+
+/// How long fetched versions are served before asking upstream again
+const SETUP_VERSIONS_TTL: Duration = Duration::from_secs(60 * 60);
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
 #[ts(export)]
 pub struct MinecraftVersions {
     pub old_alpha: Vec<String>,
@@ -184,6 +196,91 @@ pub async fn get_forge_versions() -> Result<MinecraftVersions, Error> {
     group_minecraft_versions(&versions).await
 }
 
+/// Versions installable for a flavour
+#[derive(Serialize, Debug, Clone, TS)]
+#[ts(export)]
+pub struct SetupVersions {
+    pub versions: MinecraftVersions,
+    /// Loader versions for flavours that have them, newest first
+    pub loader_versions: Vec<String>,
+    /// Unix timestamp of when this was fetched from upstream
+    pub fetched_at: i64,
+    /// Upstream could not be reached and this is an older copy
+    pub stale: bool,
+}
+
+struct CachedSetupVersions {
+    setup_versions: SetupVersions,
+    fetched: Instant,
+}
+
+lazy_static! {
+    static ref SETUP_VERSIONS_CACHE: Mutex<HashMap<FlavourKind, CachedSetupVersions>> =
+        Mutex::new(HashMap::new());
+}
+
+async fn fetch_setup_versions(flavour: FlavourKind) -> Result<SetupVersions, Error> {
+    let (versions, loader_versions) = match flavour {
+        FlavourKind::Vanilla => (get_vanilla_versions().await?, Vec::new()),
+        FlavourKind::Fabric => (
+            get_fabric_versions().await?,
+            get_fabric_loader_versions().await?,
+        ),
+        FlavourKind::Paper => (get_paper_versions().await?, Vec::new()),
+        FlavourKind::Forge => (get_forge_versions().await?, Vec::new()),
+        FlavourKind::Spigot => {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Spigot is not supported"),
+            })
+        }
+    };
+    Ok(SetupVersions {
+        versions,
+        loader_versions,
+        fetched_at: chrono::Utc::now().timestamp(),
+        stale: false,
+    })
+}
+
+/// Versions installable for `flavour`, cached for `SETUP_VERSIONS_TTL`
+///
+/// If upstream can't be reached, the last fetched copy is returned marked as stale
+pub async fn get_setup_versions(flavour: FlavourKind) -> Result<SetupVersions, Error> {
+    // held while fetching, so concurrent requests wait for one fetch instead of each making their own
+    let mut cache = SETUP_VERSIONS_CACHE.lock().await;
+    if let Some(cached) = cache.get(&flavour) {
+        if cached.fetched.elapsed() < SETUP_VERSIONS_TTL {
+            return Ok(cached.setup_versions.clone());
+        }
+    }
+    match fetch_setup_versions(flavour).await {
+        Ok(setup_versions) => {
+            cache.insert(
+                flavour,
+                CachedSetupVersions {
+                    setup_versions: setup_versions.clone(),
+                    fetched: Instant::now(),
+                },
+            );
+            Ok(setup_versions)
+        }
+        Err(e) => match cache.get(&flavour) {
+            Some(cached) => {
+                warn!(
+                    "Failed to fetch {:?} versions, serving stale copy: {}",
+                    flavour, e
+                );
+                Ok(SetupVersions {
+                    stale: true,
+                    ..cached.setup_versions.clone()
+                })
+            }
+            None => Err(e),
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,273 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, ErrorKind},
+    path_lock::PathLocks,
+    util::fs::write_atomic,
+};
+
+/// Arbitrary values attached to an instance by macros and the UI, kept out of the instance config
+const METADATA_FILE_NAME: &str = "metadata.json";
+
+const MAX_KEY_LENGTH: usize = 128;
+/// Serialized size limits in bytes, metadata is meant for small things like notes and flags
+const MAX_VALUE_SIZE: usize = 16 * 1024;
+const MAX_TOTAL_SIZE: usize = 256 * 1024;
+
+pub type InstanceMetadata = HashMap<String, Value>;
+
+fn path_to_metadata(path_to_instance: &Path) -> PathBuf {
+    path_to_instance.join(METADATA_FILE_NAME)
+}
+
+async fn read(path_to_metadata: &Path) -> Result<InstanceMetadata, Error> {
+    if !path_to_metadata.exists() {
+        return Ok(InstanceMetadata::new());
+    }
+    let content = tokio::fs::read(path_to_metadata)
+        .await
+        .context("Failed to read instance metadata")?;
+    Ok(serde_json::from_slice(&content).context("Failed to parse instance metadata")?)
+}
+
+async fn write(path_to_metadata: &Path, metadata: &InstanceMetadata) -> Result<(), Error> {
+    let content =
+        serde_json::to_string_pretty(metadata).context("Failed to serialize instance metadata")?;
+    if content.len() > MAX_TOTAL_SIZE {
+        return Err(Error {
+            kind: ErrorKind::PayloadTooLarge,
+            source: eyre!(
+                "Instance metadata can't be larger than {} bytes",
+                MAX_TOTAL_SIZE
+            ),
+        });
+    }
+    write_atomic(path_to_metadata, content).await
+}
+
+pub async fn get_metadata(path_to_instance: &Path) -> Result<InstanceMetadata, Error> {
+    read(&path_to_metadata(path_to_instance)).await
+}
+
+pub async fn set_metadata(
+    path_locks: &PathLocks,
+    path_to_instance: &Path,
+    key: String,
+    value: Value,
+) -> Result<(), Error> {
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Metadata keys must be between 1 and {} bytes long",
+                MAX_KEY_LENGTH
+            ),
+        });
+    }
+    let value_size = serde_json::to_vec(&value)
+        .context("Failed to serialize metadata value")?
+        .len();
+    if value_size > MAX_VALUE_SIZE {
+        return Err(Error {
+            kind: ErrorKind::PayloadTooLarge,
+            source: eyre!(
+                "Metadata values can't be larger than {} bytes",
+                MAX_VALUE_SIZE
+            ),
+        });
+    }
+    let path = path_to_metadata(path_to_instance);
+    let _lock = path_locks.lock(&path).await;
+    let mut metadata = read(&path).await?;
+    metadata.insert(key, value);
+    write(&path, &metadata).await
+}
+
+pub async fn remove_metadata(
+    path_locks: &PathLocks,
+    path_to_instance: &Path,
+    key: &str,
+) -> Result<(), Error> {
+    let path = path_to_metadata(path_to_instance);
+    let _lock = path_locks.lock(&path).await;
+    let mut metadata = read(&path).await?;
+    if metadata.remove(key).is_none() {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Metadata key {} not found", key),
+        });
+    }
+    write(&path, &metadata).await
+}
+
+/// Instance metadata key holding the instance's `CommandAlias`es
+pub const COMMAND_ALIASES_METADATA_KEY: &str = "command_aliases";
+
+const MAX_COMMAND_ALIASES: usize = 64;
+const MAX_ALIAS_COMMANDS: usize = 32;
+const MAX_ALIAS_NAME_LENGTH: usize = 64;
+
+/// A named sequence of console commands, run in order as one action
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct CommandAlias {
+    /// Letters, digits, `-` and `_`, as it is part of the URL that runs the alias
+    pub name: String,
+    pub commands: Vec<String>,
+}
+
+impl CommandAlias {
+    pub fn validate(&self) -> Result<(), Error> {
+        let is_valid_name = !self.name.is_empty()
+            && self.name.len() <= MAX_ALIAS_NAME_LENGTH
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_valid_name {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Invalid alias name {}, names are up to {} letters, digits, - and _",
+                    self.name,
+                    MAX_ALIAS_NAME_LENGTH
+                ),
+            });
+        }
+        if self.commands.is_empty() || self.commands.len() > MAX_ALIAS_COMMANDS {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Alias {} must have between 1 and {} commands",
+                    self.name,
+                    MAX_ALIAS_COMMANDS
+                ),
+            });
+        }
+        // a line break would let one command smuggle in another
+        if let Some(command) = self
+            .commands
+            .iter()
+            .find(|command| command.trim().is_empty() || command.contains(['\n', '\r']))
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Invalid command {:?} in alias {}, commands are a single non-empty line",
+                    command,
+                    self.name
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Checks the aliases as a whole, on top of each `CommandAlias::validate`
+fn validate_command_aliases(aliases: &[CommandAlias]) -> Result<(), Error> {
+    if aliases.len() > MAX_COMMAND_ALIASES {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "An instance can't have more than {} aliases",
+                MAX_COMMAND_ALIASES
+            ),
+        });
+    }
+    let mut names = HashSet::new();
+    for alias in aliases {
+        alias.validate()?;
+        if !names.insert(alias.name.as_str()) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Alias {} is defined more than once", alias.name),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Validated again on read, as the metadata file can be edited through the instance's files
+pub async fn get_command_aliases(path_to_instance: &Path) -> Result<Vec<CommandAlias>, Error> {
+    match get_metadata(path_to_instance)
+        .await?
+        .remove(COMMAND_ALIASES_METADATA_KEY)
+    {
+        Some(aliases) => {
+            let aliases: Vec<CommandAlias> =
+                serde_json::from_value(aliases).context("Failed to parse command aliases")?;
+            validate_command_aliases(&aliases)?;
+            Ok(aliases)
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Replaces every alias of the instance
+pub async fn set_command_aliases(
+    path_locks: &PathLocks,
+    path_to_instance: &Path,
+    aliases: Vec<CommandAlias>,
+) -> Result<(), Error> {
+    validate_command_aliases(&aliases)?;
+    set_metadata(
+        path_locks,
+        path_to_instance,
+        COMMAND_ALIASES_METADATA_KEY.to_string(),
+        serde_json::to_value(aliases).context("Failed to serialize command aliases")?,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alias(name: &str, commands: &[&str]) -> CommandAlias {
+        CommandAlias {
+            name: name.to_string(),
+            commands: commands.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_validate_command_aliases() {
+        assert!(
+            validate_command_aliases(&[alias("restart-warn", &["say hi", "save-all"])]).is_ok()
+        );
+        assert!(validate_command_aliases(&[alias("bad name", &["say hi"])]).is_err());
+        assert!(validate_command_aliases(&[alias("empty", &[])]).is_err());
+        assert!(validate_command_aliases(&[alias("smuggle", &["say hi\nop x"])]).is_err());
+        assert!(
+            validate_command_aliases(&[alias("twice", &["list"]), alias("twice", &["list"])])
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stored_aliases_are_validated_on_read() {
+        let temp_dir = tempdir::TempDir::new("instance_metadata").unwrap();
+        let path_locks = PathLocks::default();
+        set_command_aliases(&path_locks, temp_dir.path(), vec![alias("hi", &["say hi"])])
+            .await
+            .unwrap();
+        assert_eq!(get_command_aliases(temp_dir.path()).await.unwrap().len(), 1);
+
+        // written around `set_command_aliases`, as an edit of metadata.json would
+        let mut metadata = get_metadata(temp_dir.path()).await.unwrap();
+        metadata.insert(
+            COMMAND_ALIASES_METADATA_KEY.to_string(),
+            serde_json::to_value(vec![alias("hi", &["say hi\nop x"])]).unwrap(),
+        );
+        write(&path_to_metadata(temp_dir.path()), &metadata)
+            .await
+            .unwrap();
+        assert!(get_command_aliases(temp_dir.path()).await.is_err());
+    }
+}
@@ -3,8 +3,8 @@
 use crate::event_broadcaster::EventBroadcaster;
 use crate::migration::migrate;
 use crate::prelude::{
-    init_app_state, init_paths, lodestone_path, path_to_global_settings, path_to_stores,
-    path_to_tmp, path_to_users, VERSION,
+    default_path_to_instances, init_app_state, init_paths, lodestone_path, path_to_global_settings,
+    path_to_stores, path_to_tmp, path_to_users, VERSION,
 };
 use crate::traits::t_configurable::GameType;
 use crate::traits::t_server::State;
@@ -16,8 +16,9 @@ use crate::{
         gateway::get_gateway_routes, global_fs::get_global_fs_routes,
         global_settings::get_global_settings_routes, instance::*,
         instance_config::get_instance_config_routes, instance_fs::get_instance_fs_routes,
-        instance_macro::get_instance_macro_routes, instance_players::get_instance_players_routes,
-        instance_server::get_instance_server_routes,
+        instance_logs::get_instance_logs_routes, instance_macro::get_instance_macro_routes,
+        instance_metadata::get_instance_metadata_routes,
+        instance_players::get_instance_players_routes, instance_server::get_instance_server_routes,
         instance_setup_configs::get_instance_setup_config_routes, monitor::get_monitor_routes,
         setup::get_setup_route, system::get_system_routes, users::get_user_routes,
     },
@@ -30,20 +31,21 @@ use axum::Router;
 use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
 use color_eyre::eyre::Context;
-use color_eyre::Report;
+use cors::CorsSettings;
 use dashmap::DashMap;
 use error::Error;
 use events::{CausedBy, Event};
 use futures::Future;
 use global_settings::GlobalSettings;
 use implementations::{generic, minecraft};
+use macro_daemon::DaemonRegistry;
 use macro_executor::MacroExecutor;
+use path_lock::PathLocks;
 use port_manager::PortManager;
 use prelude::GameInstance;
-use reqwest::{header, Method};
 use ringbuffer::{AllocRingBuffer, RingBufferWrite};
+use stats_history::StatsHistory;
 
-use semver::Version;
 use sqlx::{sqlite::SqliteConnectOptions, Pool};
 use std::{
     collections::{HashMap, HashSet},
@@ -58,19 +60,19 @@ use tokio::{
     select,
     sync::{broadcast::error::RecvError, Mutex, RwLock},
 };
-use tower_http::{
-    cors::{Any, CorsLayer},
-    trace::TraceLayer,
-};
+use tokio_util::sync::CancellationToken;
+use tower_http::trace::TraceLayer;
 use tracing::{debug, error, info, warn};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, EnvFilter};
 use traits::{t_configurable::TConfigurable, t_server::MonitorReport, t_server::TServer};
 use types::{DotLodestoneConfig, InstanceUuid};
+use update_check::UpdateChecker;
 use uuid::Uuid;
 use fs3::FileExt;
 
 pub mod auth;
+mod cors;
 pub mod db;
 mod deno_ops;
 pub mod error;
@@ -79,16 +81,28 @@ mod events;
 pub mod global_settings;
 mod handlers;
 pub mod implementations;
+mod instance_metadata;
+pub mod macro_daemon;
 pub mod macro_executor;
 mod migration;
+mod orphans;
 mod output_types;
+mod path_lock;
 mod port_manager;
 pub mod prelude;
+mod resumable_upload;
+mod secret;
+mod stats_history;
 pub mod tauri_export;
+mod tls;
 mod traits;
+mod trash;
 pub mod types;
+mod update_check;
+mod upload_filter;
 pub mod util;
 use handlers::global_fs::DownloadableFile;
+use handlers::util::UploadCancellations;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -97,6 +111,7 @@ pub struct AppState {
     events_buffer: Arc<Mutex<AllocRingBuffer<Event>>>,
     console_out_buffer: Arc<Mutex<HashMap<InstanceUuid, AllocRingBuffer<Event>>>>,
     monitor_buffer: Arc<Mutex<HashMap<InstanceUuid, AllocRingBuffer<MonitorReport>>>>,
+    stats_history: Arc<Mutex<StatsHistory>>,
     event_broadcaster: EventBroadcaster,
     uuid: String,
     up_since: i64,
@@ -106,7 +121,16 @@ pub struct AppState {
     first_time_setup_key: Arc<Mutex<Option<String>>>,
     download_urls: Arc<Mutex<HashMap<String, DownloadableFile>>>,
     macro_executor: MacroExecutor,
+    daemon_registry: DaemonRegistry,
     sqlite_pool: sqlx::SqlitePool,
+    path_locks: PathLocks,
+    log_filter: LogFilterHandle,
+    /// Nothing was started automatically on boot
+    recovery_mode: bool,
+    update_checker: UpdateChecker,
+    /// `None` if the API is served over plain HTTP
+    tls_config: Option<RustlsConfig>,
+    upload_cancellations: UploadCancellations,
 }
 
 impl AppState {
@@ -123,6 +147,24 @@ impl AppState {
     }
 }
 
+/// Instances in the default directory aren't restored once the instances path is changed,
+/// so they are pointed out instead of silently disappearing
+fn warn_about_left_behind_instances(default_path_to_instances: &Path, path_to_instances: &Path) {
+    let entries = match default_path_to_instances.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        if entry.path().join(".lodestone_config").is_file() {
+            warn!(
+                "Instance {} is outside of the instances path and won't be loaded. Stop the core and move it to {} to keep using it",
+                entry.path().display(),
+                path_to_instances.display()
+            );
+        }
+    }
+}
+
 async fn restore_instances(
     instances_path: &Path,
     event_broadcaster: EventBroadcaster,
@@ -207,7 +249,11 @@ async fn restore_instances(
     Ok(ret)
 }
 
-fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
+/// Handle to the filter shared by all log outputs, see `setup_tracing`
+pub type LogFilterHandle =
+    tracing_subscriber::reload::Handle<EnvFilter, tracing_subscriber::Registry>;
+
+fn setup_tracing() -> (tracing_appender::non_blocking::WorkerGuard, LogFilterHandle) {
     let file_appender =
         tracing_appender::rolling::hourly(lodestone_path().join("log"), "lodestone_core.log");
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
@@ -242,11 +288,14 @@ fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
             .with_ansi(false)
             .with_writer(non_blocking);
 
+        let (filter, filter_handle) =
+            tracing_subscriber::reload::Layer::new(EnvFilter::from("lodestone_core=debug"));
         tracing_subscriber::registry()
+            .with(filter)
             .with(fmt_layer_stdout)
             .with(fmt_layer_file)
-            .with(EnvFilter::from("lodestone_core=debug"))
             .init();
+        (_guard, filter_handle)
     }
 
     #[cfg(not(debug_assertions))]
@@ -277,17 +326,19 @@ fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
             // Don't display the event's target (module path)
             .with_target(true)
             .with_ansi(false)
-            .with_writer(non_blocking)
-            .with_filter(EnvFilter::from("lodestone_core=debug"));
+            .with_writer(non_blocking);
 
+        // the file gets everything the reloadable filter lets through, stdout is kept at info
+        let (filter, filter_handle) =
+            tracing_subscriber::reload::Layer::new(EnvFilter::from("lodestone_core=debug"));
         tracing_subscriber::registry()
             // .with(ErrorLayer::default())
+            .with(filter)
             .with(fmt_layer_stdout)
             .with(fmt_layer_file)
             .init();
+        (_guard, filter_handle)
     }
-
-    _guard
 }
 
 fn output_sys_info() {
@@ -311,56 +362,6 @@ fn output_sys_info() {
     );
 }
 
-async fn check_for_core_update() {
-    #[derive(serde::Deserialize)]
-    pub struct Release {
-        pub tag_name: String,
-    }
-    pub async fn get_latest_release() -> Result<Version, Report> {
-        let release_url =
-            "https://api.github.com/repos/Lodestone-Team/lodestone_core/releases/latest";
-        let client = reqwest::Client::new();
-
-        let response = client
-            .get(release_url)
-            .header("User-Agent", "lodestone_cli")
-            .send()
-            .await?;
-        response.error_for_status_ref()?;
-
-        let release: Release = response.json().await?;
-        // tag_name is prefixed with a v, so we need to remove it to get the version
-        let tag_name = release.tag_name.trim_start_matches('v');
-        let latest_version = Version::parse(tag_name)?;
-        Ok(latest_version)
-    }
-
-    let latest_version = match get_latest_release().await {
-        Ok(v) => v,
-        Err(e) => {
-            error!("Failed to get latest release: {}", e);
-            return;
-        }
-    };
-
-    if latest_version.pre.is_empty() {
-        // we don't want to update to a pre-release
-        let current_version = VERSION.with(|v| v.clone());
-        if current_version < latest_version {
-            info!(
-                "A new version of lodestone_core is available: {}",
-                latest_version
-            );
-            info!(
-                "Read how to update here: {url}",
-                url = "https://github.com/Lodestone-Team/lodestone/wiki/Updating"
-            );
-        } else {
-            info!("lodestone_core is up to date");
-        }
-    }
-}
-
 #[derive(Debug, Parser)]
 pub struct Args {
     #[arg(long, default_value = "false")]
@@ -369,6 +370,68 @@ pub struct Args {
     pub is_desktop: bool,
     #[arg(short, long)]
     pub lodestone_path: Option<PathBuf>,
+    /// Don't auto start instances or resume macro daemons, for recovering from a crash loop.
+    /// Also enabled by setting `LODESTONE_RECOVERY_MODE`.
+    /// Unrelated to the `safe_mode` global setting, which restricts what users can be granted
+    #[arg(long, default_value = "false")]
+    pub recovery_mode: bool,
+}
+
+/// Env var enabling recovery mode, see `Args::recovery_mode`
+const RECOVERY_MODE_ENV: &str = "LODESTONE_RECOVERY_MODE";
+
+/// Resolves when the process receives SIGTERM, never on platforms without it
+async fn terminate_signal() {
+    #[cfg(unix)]
+    {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+                return;
+            }
+            Err(e) => error!("Failed to listen for SIGTERM : {}", e),
+        }
+    }
+    std::future::pending::<()>().await
+}
+
+/// Stops an instance for shutdown, killing it if it doesn't stop within `timeout`
+async fn shutdown_instance(instance: &GameInstance, timeout: Duration) {
+    match instance.state().await {
+        State::Starting => {
+            info!(
+                "Killing instance that is starting : {}",
+                instance.uuid().await
+            );
+            if let Err(e) = instance.kill(CausedBy::System).await {
+                error!(
+                    "Failed to stop instance {} : {}. Instance may need manual cleanup",
+                    instance.uuid().await,
+                    e
+                );
+            }
+        }
+        State::Running => {
+            match tokio::time::timeout(timeout, instance.stop(CausedBy::System, true)).await {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => error!("Failed to stop instance {} : {}", instance.uuid().await, e),
+                Err(_) => warn!(
+                    "Instance {} did not stop within {} seconds",
+                    instance.uuid().await,
+                    timeout.as_secs()
+                ),
+            }
+            info!("Killing instance {}", instance.uuid().await);
+            if let Err(e) = instance.kill(CausedBy::System).await {
+                error!(
+                    "Failed to kill instance {} : {}. Instance may need manual cleanup",
+                    instance.uuid().await,
+                    e
+                );
+            }
+        }
+        State::Error | State::Stopped | State::Stopping => {}
+    }
 }
 
 pub async fn run(
@@ -400,7 +463,26 @@ pub async fn run(
     init_paths(lodestone_path.clone());
     info!("Lodestone path: {}", lodestone_path.display());
     std::env::set_current_dir(&lodestone_path).unwrap();
-    let guard = setup_tracing();
+    let (guard, log_filter) = setup_tracing();
+    if let Ok(passphrase) = std::env::var(secret::MASTER_PASSPHRASE_ENV) {
+        // so it isn't passed on to child processes
+        std::env::remove_var(secret::MASTER_PASSPHRASE_ENV);
+        // starting without it would write the secrets back in plaintext
+        secret::init_master_key(&passphrase, &lodestone_path)
+            .map_err(|e| {
+                error!(
+                    "Failed to initialize master key: {}, lodestone will now crash...",
+                    e
+                );
+            })
+            .unwrap();
+    }
+    let recovery_mode = args.recovery_mode
+        || std::env::var(RECOVERY_MODE_ENV)
+            .map_or(false, |v| !v.is_empty() && v != "0" && v != "false");
+    if recovery_mode {
+        warn!("Recovery mode is on, instances and macro daemons won't be started automatically");
+    }
     if args.is_desktop {
         info!("Lodestone Core running in Tauri");
     }
@@ -408,7 +490,6 @@ pub async fn run(
         warn!("Lodestone Core is not meant to be run as a standalone program. Please use Lodestone CLI instead.");
         warn!("Download it here: https://github.com/Lodestone-Team/lodestone_cli")
     }
-    check_for_core_update().await;
     output_sys_info();
 
     let lockfile_path = lodestone_path.join("lodestone.lock");
@@ -424,7 +505,12 @@ pub async fn run(
     let _ = migrate(&lodestone_path).map_err(|e| {
         error!("Error while migrating lodestone: {}. Lodestone will still start, but one or more instance may be in an erroneous state", e);
     });
-    let path_to_instances = lodestone_path.join("instances");
+    let path_to_instances = crate::prelude::path_to_instances().clone();
+    let default_path_to_instances = default_path_to_instances(&lodestone_path);
+    if path_to_instances != default_path_to_instances {
+        info!("Instances path: {}", path_to_instances.display());
+        warn_about_left_behind_instances(&default_path_to_instances, &path_to_instances);
+    }
 
     let (tx, _rx) = EventBroadcaster::new(512);
 
@@ -456,7 +542,14 @@ pub async fn run(
     } else {
         None
     };
-    let macro_executor = MacroExecutor::new(tx.clone(), tokio::runtime::Handle::current());
+    macro_executor::set_unrestricted_module_hosts(global_settings.unrestricted_macro_imports());
+    let macro_executor = MacroExecutor::new(tx.clone(), tokio::runtime::Handle::current())
+        .with_concurrency_limit(
+            global_settings.max_concurrent_macros() as usize,
+            global_settings.macro_limit_policy(),
+        )
+        .with_exit_status_retention(global_settings.macro_history_size() as usize)
+        .with_max_heap_mb(global_settings.macro_max_heap_mb() as u64);
     let instances = restore_instances(&path_to_instances, tx.clone(), macro_executor.clone())
         .await
         .map_err(|e| {
@@ -467,25 +560,41 @@ pub async fn run(
         })
         .unwrap();
 
+    let tls_config = match global_settings.tls().load().await {
+        Ok(config) => Some(config),
+        Err(e) => {
+            warn!("Invalid TLS config : {e}, using HTTP");
+            None
+        }
+    };
+
     let mut allocated_ports = HashSet::new();
     for instance_entry in instances.iter() {
         allocated_ports.insert(instance_entry.value().port().await);
     }
+    let instances = Arc::new(instances);
+    let daemon_registry =
+        DaemonRegistry::new(instances.clone(), macro_executor.clone(), tx.clone());
     let shared_state = AppState {
-        instances: Arc::new(instances),
+        instances,
         users_manager: Arc::new(RwLock::new(users_manager)),
         events_buffer: Arc::new(Mutex::new(AllocRingBuffer::with_capacity(512))),
         console_out_buffer: Arc::new(Mutex::new(HashMap::new())),
         monitor_buffer: Arc::new(Mutex::new(HashMap::new())),
+        stats_history: Arc::new(Mutex::new(StatsHistory::new())),
         event_broadcaster: tx.clone(),
         uuid: Uuid::new_v4().to_string(),
         up_since: chrono::Utc::now().timestamp(),
-        port_manager: Arc::new(Mutex::new(PortManager::new(allocated_ports))),
+        port_manager: Arc::new(Mutex::new(PortManager::new(
+            allocated_ports,
+            global_settings.port_range(),
+        ))),
         first_time_setup_key: Arc::new(Mutex::new(first_time_setup_key)),
         system: Arc::new(Mutex::new(sysinfo::System::new_all())),
         download_urls: Arc::new(Mutex::new(HashMap::new())),
         global_settings: Arc::new(Mutex::new(global_settings)),
         macro_executor,
+        daemon_registry,
         sqlite_pool: Pool::connect_with(
             SqliteConnectOptions::from_str(&format!(
                 "sqlite://{}/data.db",
@@ -496,13 +605,28 @@ pub async fn run(
         )
         .await
         .unwrap(),
+        path_locks: PathLocks::new(),
+        log_filter,
+        recovery_mode,
+        update_checker: UpdateChecker::new(tx.clone()),
+        tls_config,
+        upload_cancellations: UploadCancellations::default(),
     };
 
     init_app_state(shared_state.clone());
 
+    // before auto starting, so a kill frees the ports the instances are about to use
+    let orphan_policy = shared_state.global_settings.lock().await.orphan_policy();
+    orphans::reconcile_orphans(
+        &mut *shared_state.system.lock().await,
+        &shared_state.instances,
+        orphan_policy,
+    )
+    .await;
+
     for mut entry in shared_state.instances.iter_mut() {
         let instance = entry.value_mut();
-        if instance.auto_start().await {
+        if instance.auto_start().await && !recovery_mode {
             info!("Auto starting instance {}", instance.name().await);
             if let Err(e) = instance.start(CausedBy::System, false).await {
                 error!(
@@ -514,6 +638,13 @@ pub async fn run(
         }
     }
 
+    // the persisted daemons are kept, so they resume on the next normal boot
+    if !recovery_mode {
+        if let Err(e) = shared_state.daemon_registry.resume().await {
+            error!("Failed to resume macro daemons: {}", e);
+        }
+    }
+
     let event_buffer_task = {
         let event_buffer = shared_state.events_buffer.clone();
         let console_out_buffer = shared_state.console_out_buffer.clone();
@@ -548,7 +679,13 @@ pub async fn run(
         }
     };
 
-    let write_to_db_task = write_event_to_db_task(tx.subscribe(), shared_state.sqlite_pool.clone());
+    // spawned so events emitted while shutting down are still written
+    let db_writer_shutdown = CancellationToken::new();
+    let mut write_to_db_task = tokio::spawn(write_event_to_db_task(
+        tx.subscribe(),
+        shared_state.sqlite_pool.clone(),
+        db_writer_shutdown.clone(),
+    ));
 
     let monitor_report_task = {
         let monitor_buffer = shared_state.monitor_buffer.clone();
@@ -570,28 +707,39 @@ pub async fn run(
         }
     };
 
-    let tls_config_result = RustlsConfig::from_pem_file(
-        lodestone_path.join("tls").join("cert.pem"),
-        lodestone_path.join("tls").join("key.pem"),
-    )
-    .await;
+    let stats_history_task = stats_history::stats_history_task(
+        shared_state.instances.clone(),
+        shared_state.stats_history.clone(),
+    );
+
+    let trash_purge_task = trash::trash_purge_task(shared_state.global_settings.clone());
+
+    let upload_purge_task = resumable_upload::upload_purge_task();
+
+    let update_check_task = update_check::update_check_task(
+        shared_state.update_checker.clone(),
+        shared_state.global_settings.clone(),
+    );
+
+    if let Some(tls_config) = shared_state.tls_config.clone() {
+        tokio::spawn(tls::tls_reload_task(
+            tls_config,
+            shared_state.global_settings.clone(),
+        ));
+    }
     let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
 
     (
         {
             let shared_state = shared_state.clone();
             async move {
-                let cors = CorsLayer::new()
-                    .allow_methods([
-                        Method::GET,
-                        Method::POST,
-                        Method::PATCH,
-                        Method::PUT,
-                        Method::DELETE,
-                        Method::OPTIONS,
-                    ])
-                    .allow_headers([header::ORIGIN, header::CONTENT_TYPE, header::AUTHORIZATION]) // Note I can't find X-Auth-Token but it was in the original rocket version, hope it's fine
-                    .allow_origin(Any);
+                let cors_settings = shared_state.global_settings.lock().await.cors();
+                let cors = cors_settings.to_layer().unwrap_or_else(|e| {
+                    error!("Invalid CORS settings, falling back to the defaults: {}", e);
+                    CorsSettings::default()
+                        .to_layer()
+                        .expect("Default CORS settings are valid")
+                });
 
                 let trace = TraceLayer::new_for_http();
 
@@ -609,7 +757,9 @@ pub async fn run(
                     .merge(get_setup_route(shared_state.clone()))
                     .merge(get_monitor_routes(shared_state.clone()))
                     .merge(get_instance_macro_routes(shared_state.clone()))
+                    .merge(get_instance_metadata_routes(shared_state.clone()))
                     .merge(get_instance_fs_routes(shared_state.clone()))
+                    .merge(get_instance_logs_routes(shared_state.clone()))
                     .merge(get_global_fs_routes(shared_state.clone()))
                     .merge(get_global_settings_routes(shared_state.clone()))
                     .merge(get_gateway_routes(shared_state.clone()))
@@ -632,9 +782,10 @@ pub async fn run(
                 let axum_server_handle = axum_server::Handle::new();
                 tokio::spawn({
                     let axum_server_handle = axum_server_handle.clone();
+                    let tls_config = shared_state.tls_config.clone();
                     async move {
-                        match tls_config_result {
-                            Ok(config) => {
+                        match tls_config {
+                            Some(config) => {
                                 info!("TLS enabled");
                                 info!("Lodestone Core live on {addr}");
                                 info!("Note that Lodestone Core does not host the web dashboard itself. Please visit https://www.lodestone.cc for setup instructions.");
@@ -643,8 +794,7 @@ pub async fn run(
                                     .serve(app.into_make_service())
                                     .await
                             }
-                            Err(e) => {
-                                warn!("Invalid TLS config : {e}, using HTTP");
+                            None => {
                                 info!("Lodestone Core live on {addr}");
                                 info!("Note that Lodestone Core does not host the web dashboard itself. Please visit https://www.lodestone.cc for setup instructions.");
                                 axum_server::bind(addr)
@@ -658,15 +808,32 @@ pub async fn run(
                 });
                 // capture file into the move block
                 let _file = file;
+                let mut write_to_db_task_exited = false;
                 select! {
-                    _ = write_to_db_task => info!("Write to db task exited"),
+                    _ = &mut write_to_db_task => {
+                        info!("Write to db task exited");
+                        write_to_db_task_exited = true;
+                    },
                     _ = event_buffer_task => info!("Event buffer task exited"),
                     _ = monitor_report_task => info!("Monitor report task exited"),
+                    _ = stats_history_task => info!("Stats history task exited"),
+                    _ = trash_purge_task => info!("Trash purge task exited"),
+                    _ = upload_purge_task => info!("Upload purge task exited"),
+                    _ = update_check_task => info!("Update check task exited"),
                     _ = shutdown_rx => info!("Shutdown signal received"),
                     _ = tokio::signal::ctrl_c() => info!("Ctrl+C received"),
+                    _ = terminate_signal() => info!("SIGTERM received"),
                 }
+                let (progression_start_event, event_id) = Event::new_progression_event_start(
+                    "Stopping Lodestone Core",
+                    Some(shared_state.instances.len() as f64 + 1.0),
+                    None,
+                    CausedBy::System,
+                );
+                shared_state.event_broadcaster.send(progression_start_event);
                 info!("Shutting down web server");
-                axum_server_handle.shutdown();
+                // in flight requests are given some time to complete, new ones are refused
+                axum_server_handle.graceful_shutdown(Some(Duration::from_secs(10)));
                 info!("Signalling all instances to stop");
                 // cleanup
                 let mut handles = vec![];
@@ -675,49 +842,60 @@ pub async fn run(
                     error!("Failed to remove tmp dir : {}", e);
                     e
                 });
-                for entry in shared_state.instances.iter() {
-                    let instance = entry.value().clone();
-                    match instance.state().await {
-                        State::Starting => {
-                            let handle = tokio::spawn({
-                                let instance = instance.clone();
-                                async move {
-                                    info!(
-                                        "Killing instance that is starting : {}",
-                                        instance.uuid().await
-                                    );
-                                    if let Err(e) = instance.kill(CausedBy::System).await {
-                                        error!(
-                                        "Failed to stop instance {} : {}. Instance may need manual cleanup",
-                                        instance.uuid().await,
-                                        e
-                                    );
-                                    }
-                                }
-                            });
-                            handles.push(handle);
-                        }
-                        State::Running => {
-                            let handle = tokio::spawn({
-                                let instance = instance.clone();
-                                async move {
-                                    if let Err(e) = instance.stop(CausedBy::System, false).await {
-                                        error!(
-                                        "Failed to stop instance {} : {}. Instance may need manual cleanup",
-                                        instance.uuid().await,
-                                        e
-                                    );
-                                    }
-                                }
-                            });
-                            handles.push(handle);
-                        }
-                        State::Error | State::Stopped | State::Stopping => continue,
-                    }
+                let stop_timeout = Duration::from_secs(
+                    shared_state
+                        .global_settings
+                        .lock()
+                        .await
+                        .instance_stop_timeout() as u64,
+                );
+                let instances: Vec<GameInstance> = shared_state
+                    .instances
+                    .iter()
+                    .map(|entry| entry.value().clone())
+                    .collect();
+                for instance in instances {
+                    let event_broadcaster = shared_state.event_broadcaster.clone();
+                    let event_id = event_id.clone();
+                    handles.push(tokio::spawn(async move {
+                        shutdown_instance(&instance, stop_timeout).await;
+                        event_broadcaster.send(Event::new_progression_event_update(
+                            &event_id,
+                            format!("Stopped {}", instance.name().await),
+                            1.0,
+                        ));
+                    }));
                 }
                 for handle in handles {
                     let _ = handle.await;
                 }
+                info!("Aborting running macros");
+                shared_state
+                    .event_broadcaster
+                    .send(Event::new_progression_event_update(
+                        &event_id,
+                        "Aborting macros",
+                        1.0,
+                    ));
+                shared_state
+                    .macro_executor
+                    .abort_all(Duration::from_secs(5))
+                    .await;
+                shared_state
+                    .event_broadcaster
+                    .send(Event::new_progression_event_end(
+                        event_id,
+                        true,
+                        Some("Lodestone Core stopped"),
+                        None,
+                    ));
+                // the audit log, including the exit statuses of the macros aborted above,
+                // is only persisted once the db writer has caught up
+                info!("Flushing events to db");
+                db_writer_shutdown.cancel();
+                if !write_to_db_task_exited {
+                    let _ = write_to_db_task.await;
+                }
             }
         },
         shared_state,
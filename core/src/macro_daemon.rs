@@ -0,0 +1,398 @@
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{eyre, Context};
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, ErrorKind},
+    event_broadcaster::EventBroadcaster,
+    events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner},
+    macro_executor::{MacroExecutor, MacroPID},
+    prelude::{path_to_stores, GameInstance},
+    traits::{t_configurable::TConfigurable, t_macro::ExitStatus, t_macro::TMacro},
+    types::{InstanceUuid, Snowflake},
+    util::fs::write_atomic,
+};
+
+/// The set of daemons that should be running, so they are resumed when the core restarts
+const DAEMONS_FILE_NAME: &str = "macro_daemons.json";
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A daemon that ran at least this long before crashing is restarted with the initial backoff again
+const HEALTHY_RUN: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum DaemonState {
+    Starting,
+    Running {
+        pid: MacroPID,
+    },
+    /// The macro exited with an error or was killed, it is spawned again after `backoff_secs`
+    Restarting {
+        backoff_secs: u64,
+        reason: String,
+    },
+    /// The daemon was stopped or its macro exited successfully, it is no longer supervised
+    Stopped,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct DaemonStatus {
+    /// Name of the macro, a daemon is identified by it within its instance
+    pub name: String,
+    pub args: Vec<String>,
+    pub state: DaemonState,
+    /// Restarts since the daemon was started
+    pub restart_count: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedDaemon {
+    instance_uuid: InstanceUuid,
+    name: String,
+    args: Vec<String>,
+}
+
+struct DaemonEntry {
+    status: DaemonStatus,
+    /// Cancelled when the daemon is stopped, the supervisor stops touching the registry afterwards
+    cancel: CancellationToken,
+}
+
+type DaemonKey = (InstanceUuid, String);
+
+/// Keeps named macros running, restarting them with a backoff when they crash
+///
+/// Unlike a plain macro run, which gets a fresh `MacroPID` every time,
+/// a daemon is addressed by its instance and macro name across restarts
+#[derive(Clone)]
+pub struct DaemonRegistry {
+    daemons: Arc<DashMap<DaemonKey, DaemonEntry>>,
+    instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+    macro_executor: MacroExecutor,
+    event_broadcaster: EventBroadcaster,
+    persist_lock: Arc<Mutex<()>>,
+}
+
+fn path_to_daemons() -> PathBuf {
+    path_to_stores().join(DAEMONS_FILE_NAME)
+}
+
+fn daemon_not_found(name: &str) -> Error {
+    Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Daemon {} not found", name),
+    }
+}
+
+impl DaemonRegistry {
+    pub fn new(
+        instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+        macro_executor: MacroExecutor,
+        event_broadcaster: EventBroadcaster,
+    ) -> Self {
+        Self {
+            daemons: Arc::new(DashMap::new()),
+            instances,
+            macro_executor,
+            event_broadcaster,
+            persist_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Starts the daemons that were running when the core last shut down,
+    /// dropping those whose instance no longer exists
+    pub async fn resume(&self) -> Result<(), Error> {
+        let path = path_to_daemons();
+        if !path.exists() {
+            return Ok(());
+        }
+        let content = tokio::fs::read(&path)
+            .await
+            .context("Failed to read macro daemons")?;
+        let persisted: Vec<PersistedDaemon> =
+            serde_json::from_slice(&content).context("Failed to parse macro daemons")?;
+        for daemon in persisted {
+            if !self.instances.contains_key(&daemon.instance_uuid) {
+                warn!(
+                    "Not resuming daemon {} as instance {} no longer exists",
+                    daemon.name, daemon.instance_uuid
+                );
+                continue;
+            }
+            info!("Resuming daemon {}", daemon.name);
+            if let Err(e) = self.spawn_supervisor(daemon.instance_uuid, daemon.name, daemon.args) {
+                warn!("Not resuming daemon: {}", e);
+            }
+        }
+        self.persist().await
+    }
+
+    pub async fn start(
+        &self,
+        instance_uuid: InstanceUuid,
+        name: String,
+        args: Vec<String>,
+    ) -> Result<(), Error> {
+        if !self.instances.contains_key(&instance_uuid) {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Instance not found"),
+            });
+        }
+        self.spawn_supervisor(instance_uuid, name, args)?;
+        self.persist().await
+    }
+
+    pub async fn stop(&self, instance_uuid: &InstanceUuid, name: &str) -> Result<(), Error> {
+        let (_, entry) = self
+            .daemons
+            .remove(&(instance_uuid.clone(), name.to_string()))
+            .ok_or_else(|| daemon_not_found(name))?;
+        entry.cancel.cancel();
+        self.persist().await
+    }
+
+    pub fn status(&self, instance_uuid: &InstanceUuid, name: &str) -> Result<DaemonStatus, Error> {
+        self.daemons
+            .get(&(instance_uuid.clone(), name.to_string()))
+            .map(|entry| entry.status.clone())
+            .ok_or_else(|| daemon_not_found(name))
+    }
+
+    pub fn list(&self, instance_uuid: &InstanceUuid) -> Vec<DaemonStatus> {
+        let mut daemons: Vec<DaemonStatus> = self
+            .daemons
+            .iter()
+            .filter(|entry| &entry.key().0 == instance_uuid)
+            .map(|entry| entry.status.clone())
+            .collect();
+        daemons.sort_by(|a, b| a.name.cmp(&b.name));
+        daemons
+    }
+
+    async fn persist(&self) -> Result<(), Error> {
+        let _lock = self.persist_lock.lock().await;
+        let persisted: Vec<PersistedDaemon> = self
+            .daemons
+            .iter()
+            .map(|entry| PersistedDaemon {
+                instance_uuid: entry.key().0.clone(),
+                name: entry.key().1.clone(),
+                args: entry.status.args.clone(),
+            })
+            .collect();
+        let content =
+            serde_json::to_string_pretty(&persisted).context("Failed to serialize daemons")?;
+        write_atomic(path_to_daemons(), content).await
+    }
+
+    /// Fails with `ErrorKind::Conflict` if a daemon with the same name is already registered,
+    /// checked and registered under the same shard lock so concurrent starts can't both spawn
+    fn spawn_supervisor(
+        &self,
+        instance_uuid: InstanceUuid,
+        name: String,
+        args: Vec<String>,
+    ) -> Result<(), Error> {
+        let cancel = CancellationToken::new();
+        match self.daemons.entry((instance_uuid.clone(), name.clone())) {
+            Entry::Occupied(_) => {
+                return Err(Error {
+                    kind: ErrorKind::Conflict,
+                    source: eyre!("Daemon {} is already running", name),
+                })
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(DaemonEntry {
+                    status: DaemonStatus {
+                        name: name.clone(),
+                        args: args.clone(),
+                        state: DaemonState::Starting,
+                        restart_count: 0,
+                    },
+                    cancel: cancel.clone(),
+                });
+            }
+        }
+        tokio::spawn({
+            let registry = self.clone();
+            async move { registry.supervise(instance_uuid, name, args, cancel).await }
+        });
+        Ok(())
+    }
+
+    /// Records the new state and emits a `DaemonStateChanged` event,
+    /// unless the daemon was stopped since this supervisor was spawned
+    async fn transition(
+        &self,
+        instance: &GameInstance,
+        key: &DaemonKey,
+        cancel: &CancellationToken,
+        state: DaemonState,
+        restarted: bool,
+    ) {
+        if state != DaemonState::Stopped {
+            if cancel.is_cancelled() {
+                return;
+            }
+            match self.daemons.get_mut(key) {
+                Some(mut entry) => {
+                    entry.status.state = state.clone();
+                    if restarted {
+                        entry.status.restart_count += 1;
+                    }
+                }
+                None => return,
+            }
+        }
+        self.event_broadcaster.send(Event {
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_name: instance.name().await,
+                instance_uuid: key.0.clone(),
+                instance_event_inner: InstanceEventInner::DaemonStateChanged {
+                    name: key.1.clone(),
+                    state,
+                },
+            }),
+            snowflake: Snowflake::default(),
+            details: format!("Daemon {} changed state", key.1),
+            caused_by: CausedBy::System,
+        });
+    }
+
+    /// Removes the daemon if it's still the one this supervisor was spawned for
+    async fn forget(&self, key: &DaemonKey, cancel: &CancellationToken) {
+        if self
+            .daemons
+            .remove_if(key, |_, entry| {
+                !entry.cancel.is_cancelled() && !cancel.is_cancelled()
+            })
+            .is_some()
+        {
+            if let Err(e) = self.persist().await {
+                error!("Failed to save macro daemons: {}", e);
+            }
+        }
+    }
+
+    async fn supervise(
+        self,
+        instance_uuid: InstanceUuid,
+        name: String,
+        args: Vec<String>,
+        cancel: CancellationToken,
+    ) {
+        let key = (instance_uuid, name);
+        let mut backoff = INITIAL_BACKOFF;
+        let mut restarted = false;
+        loop {
+            let instance = match self.instances.get(&key.0) {
+                Some(instance) => instance.value().clone(),
+                None => {
+                    warn!("Stopping daemon {} as its instance no longer exists", key.1);
+                    self.forget(&key, &cancel).await;
+                    return;
+                }
+            };
+            self.transition(&instance, &key, &cancel, DaemonState::Starting, restarted)
+                .await;
+            let started_at = Instant::now();
+            let reason = match instance
+                .run_macro(&key.1, args.clone(), None, CausedBy::System)
+                .await
+            {
+                Ok(task) => {
+                    self.transition(
+                        &instance,
+                        &key,
+                        &cancel,
+                        DaemonState::Running { pid: task.pid },
+                        false,
+                    )
+                    .await;
+                    let exit_status = tokio::select! {
+                        exit_status = self.macro_executor.wait_for_exit(task.pid) => exit_status,
+                        _ = cancel.cancelled() => {
+                            let _ = self.macro_executor.abort_macro(task.pid);
+                            self.transition(&instance, &key, &cancel, DaemonState::Stopped, false)
+                                .await;
+                            return;
+                        }
+                    };
+                    match exit_status {
+                        Ok(ExitStatus::Success { .. }) => {
+                            info!("Daemon {} exited successfully", key.1);
+                            self.forget(&key, &cancel).await;
+                            self.transition(&instance, &key, &cancel, DaemonState::Stopped, false)
+                                .await;
+                            return;
+                        }
+                        Ok(ExitStatus::Killed { .. }) => "Macro was killed".to_string(),
+                        Ok(ExitStatus::Error { error_msg, .. }) => error_msg,
+                        Err(e) => e.to_string(),
+                    }
+                }
+                // the macro doesn't exist or can't run on this instance, retrying won't help
+                Err(e)
+                    if matches!(
+                        e.kind,
+                        ErrorKind::NotFound
+                            | ErrorKind::BadRequest
+                            | ErrorKind::UnsupportedOperation
+                    ) =>
+                {
+                    error!("Stopping daemon {}: {}", key.1, e);
+                    self.forget(&key, &cancel).await;
+                    self.transition(&instance, &key, &cancel, DaemonState::Stopped, false)
+                        .await;
+                    return;
+                }
+                Err(e) => e.to_string(),
+            };
+            if started_at.elapsed() >= HEALTHY_RUN {
+                backoff = INITIAL_BACKOFF;
+            }
+            warn!(
+                "Daemon {} exited ({}), restarting in {}s",
+                key.1,
+                reason,
+                backoff.as_secs()
+            );
+            self.transition(
+                &instance,
+                &key,
+                &cancel,
+                DaemonState::Restarting {
+                    backoff_secs: backoff.as_secs(),
+                    reason,
+                },
+                false,
+            )
+            .await;
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = cancel.cancelled() => {
+                    self.transition(&instance, &key, &cancel, DaemonState::Stopped, false)
+                        .await;
+                    return;
+                }
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            restarted = true;
+        }
+    }
+}
@@ -1,32 +1,42 @@
 use std::{
+    cell::Cell,
+    collections::{HashSet, VecDeque},
     fmt::{Debug, Display},
-    path::PathBuf,
+    net::IpAddr,
+    path::{Path, PathBuf},
     rc::Rc,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     time::Duration,
 };
 
 use color_eyre::eyre::Context;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use deno_runtime::permissions::Permissions;
 use futures_util::Future;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::{sync::mpsc, task::LocalSet};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::LocalSet,
+};
 use tracing::{debug, error, log::warn};
 use ts_rs::TS;
 
 use crate::{
     deno_ops::{
-        events::register_all_event_ops, instance_control::register_instance_control_ops,
+        events::{register_all_event_ops, MacroDetachedSet, MacroProgressionTable},
+        fs::MacroFs,
+        http::register_http_ops,
+        instance_control::register_instance_control_ops,
         prelude::register_prelude_ops,
     },
     error::{Error, ErrorKind},
     event_broadcaster::EventBroadcaster,
-    events::{CausedBy, EventInner, MacroEvent, MacroEventInner},
+    events::{CausedBy, Event, EventInner, MacroEvent, MacroEventInner},
+    prelude::{try_lodestone_path, try_path_to_instances},
     traits::t_macro::ExitStatus,
     types::InstanceUuid,
 };
@@ -56,6 +66,10 @@ pub trait WorkerOptionGenerator: Send + Sync {
 
 pub struct DefaultWorkerOptionGenerator;
 
+/// Default heap limit of user macros, so a runaway macro is stopped before the host runs out of memory.
+/// The `macro_max_heap_mb` global setting overrides it
+pub const DEFAULT_MACRO_MAX_HEAP_MB: u64 = 1024;
+
 impl WorkerOptionGenerator for DefaultWorkerOptionGenerator {
     fn generate(&self) -> deno_runtime::worker::WorkerOptions {
         deno_runtime::worker::WorkerOptions {
@@ -65,8 +79,194 @@ impl WorkerOptionGenerator for DefaultWorkerOptionGenerator {
     }
 }
 
+/// Fetches and transpiles local and remote modules
+///
+/// Remote fetches that fail with a connection error or a 5xx status are retried
+/// up to `max_retries` times, with an exponential backoff starting at `base_delay`,
+/// until `fetch_deadline` has passed since the first attempt
 pub struct TypescriptModuleLoader {
     http: reqwest::Client,
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub fetch_deadline: Duration,
+    /// Hosts remote modules can be imported from, including their subdomains. `None` allows any host
+    pub allowed_hosts: Option<Vec<String>>,
+    /// Whether importing from hosts that resolve to private or loopback addresses is rejected
+    pub block_private_addresses: bool,
+}
+
+/// Hosts remote modules can be imported from unless imports are unrestricted
+pub const DEFAULT_ALLOWED_MODULE_HOSTS: &[&str] = &[
+    "deno.land",
+    "esm.sh",
+    "cdn.jsdelivr.net",
+    "unpkg.com",
+    "raw.githubusercontent.com",
+];
+
+/// Mirrors `GlobalSettings::unrestricted_macro_imports` for loaders created by worker generators
+static UNRESTRICTED_MODULE_HOSTS: AtomicBool = AtomicBool::new(false);
+
+/// Applies to module loaders created afterwards
+pub fn set_unrestricted_module_hosts(unrestricted: bool) {
+    UNRESTRICTED_MODULE_HOSTS.store(unrestricted, Ordering::Relaxed);
+}
+
+fn is_private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // shared address space, RFC 6598
+                || (first == 100 && (64..128).contains(&second))
+        }
+        IpAddr::V6(ip) => {
+            let first_segment = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // unique local
+                || (first_segment & 0xfe00) == 0xfc00
+                // link local
+                || (first_segment & 0xffc0) == 0xfe80
+                || ip
+                    .to_ipv4_mapped()
+                    .map_or(false, |ip| is_private_address(ip.into()))
+        }
+    }
+}
+
+fn is_host_allowed(host: &str, allowed_hosts: &[String]) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+    allowed_hosts
+        .iter()
+        .any(|allowed| host == *allowed || host.ends_with(&format!(".{allowed}")))
+}
+
+/// The checks that can be done without resolving the host, also applied to redirects
+fn check_module_url(
+    url: &ModuleSpecifier,
+    allowed_hosts: Option<&[String]>,
+    block_private_addresses: bool,
+) -> Result<(), anyhow::Error> {
+    let host = match url.host() {
+        Some(host) => host,
+        None => bail!("Module {url} has no host"),
+    };
+    if let Some(allowed_hosts) = allowed_hosts {
+        if !is_host_allowed(&host.to_string(), allowed_hosts) {
+            bail!(
+                "Importing modules from {host} is not allowed, allowed hosts are {}",
+                allowed_hosts.join(", ")
+            );
+        }
+    }
+    let ip = match host {
+        deno_core::url::Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+        deno_core::url::Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
+        deno_core::url::Host::Domain(_) => None,
+    };
+    if block_private_addresses && ip.map_or(false, is_private_address) {
+        bail!("Importing modules from private address {host} is not allowed");
+    }
+    Ok(())
+}
+
+/// Rejects modules from hosts that aren't allowed before any request is made
+async fn check_module_host(
+    url: &ModuleSpecifier,
+    allowed_hosts: Option<&[String]>,
+    block_private_addresses: bool,
+) -> Result<(), anyhow::Error> {
+    check_module_url(url, allowed_hosts, block_private_addresses)?;
+    if !block_private_addresses {
+        return Ok(());
+    }
+    if let Some(deno_core::url::Host::Domain(domain)) = url.host() {
+        let port = url.port_or_known_default().unwrap_or(443);
+        let resolves_to_private = tokio::net::lookup_host((domain, port))
+            .await?
+            .any(|addr| is_private_address(addr.ip()));
+        if resolves_to_private {
+            bail!(
+                "Importing modules from {domain} is not allowed, it resolves to a private address"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The working directory of a macro must be an existing directory inside the lodestone directory
+/// or the instances directory, which can be elsewhere
+fn check_macro_cwd(cwd: &Path) -> Result<(), Error> {
+    let canonical_cwd = cwd
+        .canonicalize()
+        .ok()
+        .filter(|cwd| cwd.is_dir())
+        .ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Macro working directory {} isn't a directory",
+                cwd.display()
+            ),
+        })?;
+    // unset when the executor is used on its own, such as in tests
+    if let Some(lodestone_path) = try_lodestone_path() {
+        let is_allowed = std::iter::once(lodestone_path)
+            .chain(try_path_to_instances())
+            .any(|allowed| {
+                let allowed = allowed.canonicalize().unwrap_or_else(|_| allowed.clone());
+                canonical_cwd.starts_with(allowed)
+            });
+        if !is_allowed {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!(
+                    "Macro working directory {} is outside the lodestone and instances directories",
+                    cwd.display()
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Name of the directory in-game macros are kept in, inside an instance's macro directory
+const IN_GAME_MACRO_DIR: &str = "in_game";
+
+fn resolve_macro_in_dir(dir: &Path, macro_name: &str) -> Option<PathBuf> {
+    let ts_macro = dir.join(macro_name).with_extension("ts");
+    let js_macro = dir.join(macro_name).with_extension("js");
+
+    let macro_folder = dir.join(macro_name);
+
+    if ts_macro.is_file() {
+        return Some(ts_macro);
+    } else if js_macro.is_file() {
+        return Some(js_macro);
+    } else if macro_folder.is_dir() {
+        // check if index.ts exists
+        let index_ts = macro_folder.join("index.ts");
+        let index_js = macro_folder.join("index.js");
+        if index_ts.exists() {
+            return Some(index_ts);
+        } else if index_js.exists() {
+            return Some(index_js);
+        }
+    }
+    None
+}
+
+/// Resolves a macro name to its main module
+///
+/// `<name>.ts` is preferred over `<name>.js`, then a `<name>/` folder with an `index.ts` or
+/// `index.js`. If none exist, the same lookup is done in the `in_game` directory.
+pub fn resolve_macro_invocation(path_to_macro: &Path, macro_name: &str) -> Option<PathBuf> {
+    resolve_macro_in_dir(path_to_macro, macro_name)
+        .or_else(|| resolve_macro_in_dir(&path_to_macro.join(IN_GAME_MACRO_DIR), macro_name))
 }
 
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash, TS)]
@@ -100,12 +300,98 @@ impl Display for MacroPID {
 
 impl Default for TypescriptModuleLoader {
     fn default() -> Self {
+        let unrestricted = UNRESTRICTED_MODULE_HOSTS.load(Ordering::Relaxed);
+        let allowed_hosts: Option<Vec<String>> = if unrestricted {
+            None
+        } else {
+            Some(
+                DEFAULT_ALLOWED_MODULE_HOSTS
+                    .iter()
+                    .map(|host| host.to_string())
+                    .collect(),
+            )
+        };
+        let http = if unrestricted {
+            reqwest::Client::new()
+        } else {
+            let allowed_hosts = allowed_hosts.clone();
+            reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                    if attempt.previous().len() >= 10 {
+                        attempt.error("Too many redirects")
+                    } else if let Err(e) =
+                        check_module_url(attempt.url(), allowed_hosts.as_deref(), true)
+                    {
+                        attempt.error(e.to_string())
+                    } else {
+                        attempt.follow()
+                    }
+                }))
+                .build()
+                .unwrap_or_else(|_| reqwest::Client::new())
+        };
         Self {
-            http: reqwest::Client::new(),
+            http,
+            max_retries: 3,
+            base_delay: Duration::from_millis(250),
+            fetch_deadline: Duration::from_secs(30),
+            allowed_hosts,
+            block_private_addresses: !unrestricted,
         }
     }
 }
 
+/// Delay before retry number `attempt` (starting at 0), with up to 50% jitter
+fn retry_delay(base_delay: Duration, attempt: u32) -> Duration {
+    use rand::Rng;
+
+    let delay = base_delay.saturating_mul(2u32.saturating_pow(attempt));
+    let jitter = rand::thread_rng().gen_range(0.0, 0.5);
+    delay + delay.mul_f64(jitter)
+}
+
+async fn fetch_module(
+    http: &reqwest::Client,
+    module_specifier: &ModuleSpecifier,
+    max_retries: u32,
+    base_delay: Duration,
+    fetch_deadline: Duration,
+) -> Result<reqwest::Response, anyhow::Error> {
+    let fetch = async {
+        let mut attempt = 0;
+        loop {
+            let error = match http.get(module_specifier.clone()).send().await {
+                Ok(res) if res.status().is_success() => return Ok(res),
+                Ok(res) if res.status().is_server_error() => {
+                    format!("server responded with {}", res.status())
+                }
+                Ok(res) => bail!(
+                    "Failed to fetch module {module_specifier}: server responded with {}",
+                    res.status()
+                ),
+                Err(e) if e.is_connect() || e.is_timeout() => e.to_string(),
+                Err(e) => bail!("Failed to fetch module {module_specifier}: {e}"),
+            };
+            if attempt >= max_retries {
+                bail!(
+                    "Failed to fetch module {module_specifier} after {} attempts: {error}",
+                    attempt + 1
+                );
+            }
+            warn!("Failed to fetch module {module_specifier}, retrying: {error}");
+            tokio::time::sleep(retry_delay(base_delay, attempt)).await;
+            attempt += 1;
+        }
+    };
+    match tokio::time::timeout(fetch_deadline, fetch).await {
+        Ok(res) => res,
+        Err(_) => bail!(
+            "Failed to fetch module {module_specifier}: timed out after {}s",
+            fetch_deadline.as_secs()
+        ),
+    }
+}
+
 impl ModuleLoader for TypescriptModuleLoader {
     fn resolve(
         &self,
@@ -124,6 +410,10 @@ impl ModuleLoader for TypescriptModuleLoader {
     ) -> Pin<Box<ModuleSourceFuture>> {
         let module_specifier = module_specifier.clone();
         let http = self.http.clone();
+        let (max_retries, base_delay, fetch_deadline) =
+            (self.max_retries, self.base_delay, self.fetch_deadline);
+        let allowed_hosts = self.allowed_hosts.clone();
+        let block_private_addresses = self.block_private_addresses;
         async move {
             let (code, module_type, media_type, should_transpile) = match module_specifier
                 .to_file_path()
@@ -155,10 +445,20 @@ impl ModuleLoader for TypescriptModuleLoader {
                 }
                 Err(_) => {
                     if module_specifier.scheme() == "http" || module_specifier.scheme() == "https" {
-                        let http_res = http.get(module_specifier.to_string()).send().await?;
-                        if !http_res.status().is_success() {
-                            bail!("Failed to fetch module: {module_specifier}");
-                        }
+                        check_module_host(
+                            &module_specifier,
+                            allowed_hosts.as_deref(),
+                            block_private_addresses,
+                        )
+                        .await?;
+                        let http_res = fetch_module(
+                            &http,
+                            &module_specifier,
+                            max_retries,
+                            base_delay,
+                            fetch_deadline,
+                        )
+                        .await?;
                         let content_type = http_res
                             .headers()
                             .get("content-type")
@@ -209,15 +509,234 @@ impl ModuleLoader for TypescriptModuleLoader {
     }
 }
 
+/// A problem found in a macro without running it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct MacroDiagnostic {
+    /// File the problem is in, relative to the macro directory
+    pub file: String,
+    /// 1-based, `None` if the problem isn't at a location in the file, like a missing module
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// Class of the error the problem is thrown as when the macro runs, such as `SyntaxError`.
+    /// Never a TypeScript type error, as types aren't checked
+    pub class: String,
+    pub message: String,
+}
+
+fn static_imports(parsed: &deno_ast::ParsedSource) -> Vec<String> {
+    use deno_ast::swc::ast::{ModuleDecl, ModuleItem};
+
+    parsed
+        .module()
+        .body
+        .iter()
+        .filter_map(|item| match item {
+            ModuleItem::ModuleDecl(ModuleDecl::Import(import)) => {
+                Some(import.src.value.to_string())
+            }
+            ModuleItem::ModuleDecl(ModuleDecl::ExportAll(export)) => {
+                Some(export.src.value.to_string())
+            }
+            ModuleItem::ModuleDecl(ModuleDecl::ExportNamed(export)) => {
+                export.src.as_ref().map(|src| src.value.to_string())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parses and transpiles a macro and the local modules it imports, the way the module loader would
+///
+/// Only syntax is checked, as there's no type checker in the runtime, and remote modules aren't
+/// fetched. `source` replaces the contents of `entry`, so unsaved changes can be checked.
+/// Returns no diagnostics if the macro is fine
+pub async fn validate_macro(
+    macro_dir: &Path,
+    entry: &Path,
+    source: Option<String>,
+) -> Vec<MacroDiagnostic> {
+    let relative = |path: &Path| {
+        path.strip_prefix(macro_dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .to_string()
+    };
+    let mut diagnostics = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![(entry.to_path_buf(), source)];
+    while let Some((path, source)) = pending.pop() {
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        let file = relative(&path);
+        let problem = |class: &str, message: String| MacroDiagnostic {
+            file: file.clone(),
+            line: None,
+            column: None,
+            class: class.to_string(),
+            message,
+        };
+        let located = |diagnostic: &deno_ast::Diagnostic| MacroDiagnostic {
+            file: file.clone(),
+            line: Some(diagnostic.display_position.line_number),
+            column: Some(diagnostic.display_position.column_number),
+            class: deno_errors::get_error_class_name(&diagnostic.clone().into()).to_string(),
+            message: diagnostic.message().to_string(),
+        };
+        let media_type = MediaType::from_path(&path);
+        if media_type == MediaType::Json {
+            continue;
+        }
+        if media_type == MediaType::Unknown {
+            diagnostics.push(problem(
+                "TypeError",
+                format!("Unknown extension {:?}", path.extension()),
+            ));
+            continue;
+        }
+        let code = match source {
+            Some(source) => source,
+            None => match tokio::fs::read_to_string(&path).await {
+                Ok(code) => code,
+                Err(e) => {
+                    let message = format!("Failed to read module: {}", e);
+                    diagnostics.push(problem(
+                        deno_errors::get_error_class_name(&e.into()),
+                        message,
+                    ));
+                    continue;
+                }
+            },
+        };
+        let parsed = deno_ast::parse_module(ParseParams {
+            specifier: file.clone(),
+            text_info: SourceTextInfo::from_string(code),
+            media_type,
+            capture_tokens: false,
+            scope_analysis: false,
+            maybe_syntax: None,
+        });
+        let parsed = match parsed {
+            Ok(parsed) => parsed,
+            Err(diagnostic) => {
+                diagnostics.push(located(&diagnostic));
+                continue;
+            }
+        };
+        diagnostics.extend(parsed.diagnostics().iter().map(located));
+        if let Err(e) = parsed.transpile(&Default::default()) {
+            diagnostics.push(problem(
+                deno_errors::get_error_class_name(&e),
+                e.to_string(),
+            ));
+        }
+        let referrer = match ModuleSpecifier::from_file_path(&path) {
+            Ok(referrer) => referrer,
+            Err(_) => continue,
+        };
+        for specifier in static_imports(&parsed) {
+            match resolve_import(&specifier, referrer.as_str()) {
+                Ok(import) => {
+                    if let Ok(import_path) = import.to_file_path() {
+                        pending.push((import_path, None));
+                    }
+                }
+                Err(e) => diagnostics.push(problem(
+                    "TypeError",
+                    format!("Invalid import {}: {}", specifier, e),
+                )),
+            }
+        }
+    }
+    diagnostics
+}
+
+/// What `MacroExecutor::spawn` does when the maximum number of concurrent macros are running
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default, TS)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum MacroLimitPolicy {
+    /// Wait for a running macro to stop
+    #[default]
+    Queue,
+    /// Fail with `ErrorKind::TooManyRequests`
+    Reject,
+}
+
+pub const DEFAULT_MAX_CONCURRENT_MACROS: usize = 32;
+
+/// Exit statuses kept for finished macros before the oldest are forgotten
+pub const DEFAULT_EXIT_STATUS_RETENTION: usize = 1000;
+
+/// Prevents more than one macro with the same key from running at once
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SingletonKey {
+    pub key: String,
+    /// If true, spawning while a macro with the same key is running returns the running macro,
+    /// otherwise it fails with `ErrorKind::Conflict`
+    pub reuse_existing: bool,
+}
+
+/// Frees a singleton key once the macro holding it exits
+struct SingletonGuard {
+    singleton_table: Arc<DashMap<String, MacroPID>>,
+    key: String,
+    pid: MacroPID,
+}
+
+enum SingletonClaim {
+    Claimed(SingletonGuard),
+    /// The key is held by this running macro, which should be reused
+    Running(MacroPID),
+}
+
+impl Drop for SingletonGuard {
+    fn drop(&mut self) {
+        self.singleton_table
+            .remove_if(&self.key, |_, pid| *pid == self.pid);
+    }
+}
+
+/// Keeps a pid in `live_pids` until the macro is done with it,
+/// or until `spawn` gives up on it
+struct LivePidGuard {
+    live_pids: Arc<DashSet<MacroPID>>,
+    pid: MacroPID,
+}
+
+impl Drop for LivePidGuard {
+    fn drop(&mut self) {
+        self.live_pids.remove(&self.pid);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct MacroExecutor {
+    /// running macros only, a macro is removed once it stops
     macro_process_table: Arc<DashMap<MacroPID, deno_core::v8::IsolateHandle>>,
+    /// the most recent `exit_status_retention` macros to stop
     exit_status_table: Arc<DashMap<MacroPID, ExitStatus>>,
+    /// pids in `exit_status_table`, in the order they stopped
+    stopped_pids: Arc<std::sync::Mutex<VecDeque<MacroPID>>>,
+    exit_status_retention: Arc<AtomicUsize>,
+    /// heap limit in MiB callers pass to `spawn` for macros they don't size themselves
+    max_heap_mb: Arc<AtomicU64>,
+    /// pids of macros queued, starting or running, a pid that is neither here
+    /// nor in `exit_status_table` was forgotten or never ran
+    live_pids: Arc<DashSet<MacroPID>>,
     channel_table:
         Arc<DashMap<MacroPID, (mpsc::UnboundedSender<Value>, mpsc::UnboundedSender<Value>)>>,
     event_broadcaster: EventBroadcaster,
     next_process_id: Arc<AtomicUsize>,
     rt: tokio::runtime::Handle,
+    /// one permit per running macro
+    concurrency_limit: Arc<Semaphore>,
+    limit_policy: MacroLimitPolicy,
+    /// singleton key to the macro holding it
+    singleton_table: Arc<DashMap<String, MacroPID>>,
+    progression_table: MacroProgressionTable,
+    detached_pids: MacroDetachedSet,
 }
 
 pub struct SpawnResult {
@@ -226,15 +745,50 @@ pub struct SpawnResult {
     pub exit_future: Pin<Box<dyn Future<Output = Result<ExitStatus, Error>> + Send>>,
 }
 
+/// The optional parameters of `MacroExecutor::spawn`
+pub struct SpawnOptions {
+    pub args: Vec<String>,
+    /// What `getMacroArgs()` returns, for callers passing typed parameters
+    /// instead of or alongside the string `args`
+    pub structured_args: Option<serde_json::Value>,
+    pub caused_by: CausedBy,
+    /// `None` allows everything
+    pub permissions: Option<Permissions>,
+    pub instance_uuid: Option<InstanceUuid>,
+    /// If set and a macro with the same key is still running,
+    /// no new macro is spawned, see `SingletonKey`
+    pub singleton_key: Option<SingletonKey>,
+    /// Caps the macro's V8 heap, a macro reaching it is terminated with `ExitStatus::Error`
+    /// instead of taking the core down. `None` leaves the heap uncapped
+    pub max_heap_mb: Option<u64>,
+}
+
+impl Default for SpawnOptions {
+    fn default() -> Self {
+        Self {
+            args: Vec::new(),
+            structured_args: None,
+            caused_by: CausedBy::Unknown,
+            permissions: None,
+            instance_uuid: None,
+            singleton_key: None,
+            max_heap_mb: None,
+        }
+    }
+}
+
 impl MacroExecutor {
     pub fn new(event_broadcaster: EventBroadcaster, rt: tokio::runtime::Handle) -> MacroExecutor {
         let process_table = Arc::new(DashMap::new());
         let process_id = Arc::new(AtomicUsize::new(0));
         let exit_status_table = Arc::new(DashMap::new());
+        let progression_table: MacroProgressionTable = Arc::new(DashMap::new());
 
-        // spawn a task to listen for exit events and update the exit status table
+        // spawn a task to listen for exit events and close what the macro left open,
+        // the exit status table is filled by the macro thread itself
         tokio::task::spawn({
-            let exit_status_table = exit_status_table.clone();
+            let progression_table = progression_table.clone();
+            let event_broadcaster = event_broadcaster.clone();
             let mut rx = event_broadcaster.subscribe();
             async move {
                 loop {
@@ -245,7 +799,15 @@ impl MacroExecutor {
                             ..
                         }) = event.try_macro_event()
                         {
-                            exit_status_table.insert(*macro_pid, exit_status.clone());
+                            // close the progress bar of a macro that didn't end it itself
+                            if let Some((_, event_id)) = progression_table.remove(macro_pid) {
+                                event_broadcaster.send(Event::new_progression_event_end(
+                                    event_id,
+                                    exit_status.is_success(),
+                                    Some("Macro exited without ending its progress"),
+                                    None,
+                                ));
+                            }
                         }
                     }
                 }
@@ -257,11 +819,63 @@ impl MacroExecutor {
             event_broadcaster,
             channel_table: Arc::new(DashMap::new()),
             exit_status_table,
+            stopped_pids: Arc::new(std::sync::Mutex::new(VecDeque::new())),
+            exit_status_retention: Arc::new(AtomicUsize::new(DEFAULT_EXIT_STATUS_RETENTION)),
+            max_heap_mb: Arc::new(AtomicU64::new(DEFAULT_MACRO_MAX_HEAP_MB)),
+            live_pids: Arc::new(DashSet::new()),
             next_process_id: process_id,
             rt,
+            concurrency_limit: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_MACROS)),
+            limit_policy: MacroLimitPolicy::default(),
+            singleton_table: Arc::new(DashMap::new()),
+            progression_table,
+            detached_pids: Arc::new(DashSet::new()),
         }
     }
 
+    /// Limits how many macros can run at once, should be set before any macro is spawned
+    pub fn with_concurrency_limit(
+        mut self,
+        max_concurrent_macros: usize,
+        limit_policy: MacroLimitPolicy,
+    ) -> Self {
+        self.concurrency_limit = Arc::new(Semaphore::new(max_concurrent_macros.max(1)));
+        self.limit_policy = limit_policy;
+        self
+    }
+
+    /// See `set_exit_status_retention`
+    pub fn with_exit_status_retention(self, retention: usize) -> Self {
+        self.set_exit_status_retention(retention);
+        self
+    }
+
+    /// How many exit statuses of stopped macros are kept, at least one.
+    ///
+    /// Older ones are forgotten as other macros stop, `get_macro_status` then returns `None`
+    /// for them and `wait_for_exit` a `NotFound` error.
+    pub fn set_exit_status_retention(&self, retention: usize) {
+        self.exit_status_retention
+            .store(retention.max(1), Ordering::Relaxed);
+    }
+
+    /// See `set_max_heap_mb`
+    pub fn with_max_heap_mb(self, max_heap_mb: u64) -> Self {
+        self.set_max_heap_mb(max_heap_mb);
+        self
+    }
+
+    /// The heap limit in MiB for macros started from now on, see `max_heap_mb`
+    pub fn set_max_heap_mb(&self, max_heap_mb: u64) {
+        self.max_heap_mb.store(max_heap_mb, Ordering::Relaxed);
+    }
+
+    /// The configured heap limit in MiB, `spawn` callers pass it as `max_heap_mb`
+    /// unless the macro needs a limit of its own
+    pub fn max_heap_mb(&self) -> u64 {
+        self.max_heap_mb.load(Ordering::Relaxed)
+    }
+
     /// For timeout:
     ///
     /// If `None`, the handle will never timeout.
@@ -271,48 +885,126 @@ impl MacroExecutor {
     /// Note that this does not terminate the process, it just stops the handle from waiting for it.
     ///
     /// It is up to the caller to terminate the process if it is still running.
-    #[allow(clippy::too_many_arguments)]
+    ///
+    /// With `MacroLimitPolicy::Queue`, a macro spawned while the limit is reached still returns
+    /// right away, it starts running once another macro stops.
+    ///
+    /// `cwd` is the directory the main module and its imports are resolved from,
+    /// usually the instance's directory. It must be inside the lodestone directory.
+    /// Relative paths given to Deno's file APIs resolve from it too, and `Deno.chdir`
+    /// only moves the macro's own working directory, see `MacroFs`.
     pub async fn spawn(
         &self,
         path_to_main_module: PathBuf,
-        args: Vec<String>,
-        _caused_by: CausedBy,
+        cwd: PathBuf,
         worker_options_generator: Box<dyn WorkerOptionGenerator>,
-        permissions: Option<Permissions>,
-        instance_uuid: Option<InstanceUuid>,
+        options: SpawnOptions,
     ) -> Result<SpawnResult, Error> {
+        let SpawnOptions {
+            args,
+            structured_args,
+            caused_by: _,
+            permissions,
+            instance_uuid,
+            singleton_key,
+            max_heap_mb,
+        } = options;
+        check_macro_cwd(&cwd)?;
         let pid = MacroPID(self.next_process_id.fetch_add(1, Ordering::SeqCst));
+        // dropped on every early return below, or by the macro thread once it exits
+        self.live_pids.insert(pid);
+        let live_pid_guard = LivePidGuard {
+            live_pids: self.live_pids.clone(),
+            pid,
+        };
+        // claimed before waiting on the concurrency limit, so queued duplicates are caught too
+        let singleton_guard = match singleton_key {
+            Some(singleton_key) => match self.claim_singleton_key(singleton_key, pid)? {
+                SingletonClaim::Claimed(guard) => Some(guard),
+                SingletonClaim::Running(existing_pid) => return Ok(self.attach(existing_pid)),
+            },
+            None => None,
+        };
+        // held by the macro thread until it exits. A queued macro waits for its permit on
+        // its own thread, so callers get the pid right away instead of blocking until a slot frees
+        let permit = match self.concurrency_limit.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) if self.limit_policy == MacroLimitPolicy::Reject => {
+                return Err(Error {
+                    kind: ErrorKind::TooManyRequests,
+                    source: eyre!("Too many macros are running, try again later"),
+                });
+            }
+            Err(_) => {
+                self.event_broadcaster.send(
+                    MacroEvent {
+                        macro_pid: pid,
+                        macro_event_inner: MacroEventInner::Queued,
+                        instance_uuid: instance_uuid.clone(),
+                    }
+                    .into(),
+                );
+                None
+            }
+        };
         let exit_future = Box::pin({
-            let __self = self.clone();
-            async move { __self.wait_with_timeout(pid).await }
+            let executor = self.clone();
+            async move { executor.wait_with_timeout(pid).await }
         });
         let detach_future = Box::pin({
-            let __self = self.clone();
+            let executor = self.clone();
             async move {
-                __self.wait_for_detach(pid).await;
+                executor.wait_for_detach(pid).await;
             }
         });
-        let main_module = deno_core::resolve_path(
-            ".",
-            &std::env::current_dir().context("Failed to get current directory")?,
-        )
-        .context("Failed to resolve path")?;
+        let main_module = deno_core::resolve_path(".", &cwd).context("Failed to resolve path")?;
+        let queued = permit.is_none();
+        // subscribed before the thread starts so its started event can't be missed
+        let rx = self.event_broadcaster.subscribe();
         std::thread::spawn({
             let process_table = self.macro_process_table.clone();
+            let executor = self.clone();
             let event_broadcaster = self.event_broadcaster.clone();
+            let progression_table = self.progression_table.clone();
+            let detached_pids = self.detached_pids.clone();
             let rt = self.rt.clone();
+            let concurrency_limit = self.concurrency_limit.clone();
             move || {
+                // the semaphore is never closed
+                let _permit =
+                    permit.or_else(|| rt.block_on(concurrency_limit.acquire_owned()).ok());
+                let _singleton_guard = singleton_guard;
+                let _live_pid_guard = live_pid_guard;
                 let _guard = rt.enter();
                 let local = LocalSet::new();
+                let stopped_sent = Rc::new(Cell::new(false));
                 local.spawn_local({
                     let event_broadcaster = event_broadcaster.clone();
                     let instance_uuid = instance_uuid.clone();
+                    let stopped_sent = stopped_sent.clone();
+                    let executor = executor.clone();
                     async move {
                         let mut worker_option = worker_options_generator.generate();
                         worker_option.get_error_class_fn = Some(&deno_errors::get_error_class_name);
-                        register_prelude_ops(&mut worker_option);
-                        register_all_event_ops(&mut worker_option, event_broadcaster.clone());
-                        register_instance_control_ops(&mut worker_option);
+                        worker_option.fs =
+                            Arc::new(MacroFs::new(worker_option.fs.clone(), cwd.clone()));
+                        register_prelude_ops(&mut worker_option, structured_args);
+                        register_all_event_ops(
+                            &mut worker_option,
+                            event_broadcaster.clone(),
+                            pid,
+                            instance_uuid.clone(),
+                            progression_table,
+                            detached_pids,
+                        );
+                        register_instance_control_ops(&mut worker_option, instance_uuid.clone());
+                        register_http_ops(&mut worker_option);
+                        if let Some(max_heap_mb) = max_heap_mb {
+                            worker_option.create_params = Some(
+                                deno_core::v8::CreateParams::default()
+                                    .heap_limits(0, max_heap_mb as usize * 1024 * 1024),
+                            );
+                        }
 
                         let mut main_worker = deno_runtime::worker::MainWorker::from_options(
                             main_module,
@@ -330,12 +1022,14 @@ impl MacroExecutor {
                                 "deps_inject",
                                 deno_core::FastString::Owned(
                                     format!(
-                                        "const __macro_pid = {}; const __instance_uuid = \"{}\";",
+                                        "const __macro_pid = {}; const __instance_uuid = {}; const __cwd = {};",
                                         pid.0,
                                         instance_uuid
                                             .clone()
-                                            .map(|uuid| uuid.to_string())
-                                            .unwrap_or_else(|| "null".to_string())
+                                            .map(|uuid| format!("\"{}\"", uuid))
+                                            .unwrap_or_else(|| "null".to_string()),
+                                        serde_json::to_string(&cwd.to_string_lossy())
+                                            .unwrap()
                                     )
                                     .into_boxed_str(),
                                 ),
@@ -347,13 +1041,48 @@ impl MacroExecutor {
 
                         process_table.insert(pid, isolate_handle);
 
+                        let heap_limit_hit = Rc::new(Cell::new(false));
+                        if max_heap_mb.is_some() {
+                            let isolate_handle =
+                                main_worker.js_runtime.v8_isolate().thread_safe_handle();
+                            let heap_limit_hit = heap_limit_hit.clone();
+                            main_worker.js_runtime.add_near_heap_limit_callback(
+                                move |current_limit, _initial_limit| {
+                                    heap_limit_hit.set(true);
+                                    isolate_handle.terminate_execution();
+                                    // headroom to unwind the termination, V8 aborts the process otherwise
+                                    current_limit * 2
+                                },
+                            );
+                        }
+
+                        let send_stopped = |exit_status: ExitStatus| {
+                            stopped_sent.set(true);
+                            executor.record_exit(pid, &exit_status);
+                            event_broadcaster.send(
+                                MacroEvent {
+                                    macro_pid: pid,
+                                    macro_event_inner: MacroEventInner::Stopped { exit_status },
+                                    instance_uuid: instance_uuid.clone(),
+                                }
+                                .into(),
+                            );
+                        };
+                        let is_terminated = |e: &anyhow::Error| {
+                            e.to_string() == "Uncaught Error: execution terminated"
+                        };
+
                         let main_module = match deno_core::resolve_path(
                             &path_to_main_module.to_string_lossy(),
-                            &std::env::current_dir().unwrap(),
+                            &cwd,
                         ) {
                             Ok(v) => v,
                             Err(e) => {
                                 error!("Error resolving main module: {}", e);
+                                send_stopped(ExitStatus::Error {
+                                    error_msg: e.to_string(),
+                                    time: chrono::Utc::now().timestamp(),
+                                });
                                 return;
                             }
                         };
@@ -368,86 +1097,54 @@ impl MacroExecutor {
                         );
 
                         if let Err(e) = main_worker.execute_main_module(&main_module).await {
-                            if e.to_string() == "Uncaught Error: execution terminated" {
+                            if is_terminated(&e) && heap_limit_hit.get() {
+                                warn!("Macro {} exceeded its heap limit", pid.0);
+                                send_stopped(ExitStatus::Error {
+                                    error_msg: "heap limit exceeded".to_string(),
+                                    time: chrono::Utc::now().timestamp(),
+                                });
+                            } else if is_terminated(&e) {
                                 warn!("User terminated macro execution");
-                                event_broadcaster.send(
-                                    MacroEvent {
-                                        macro_pid: pid,
-                                        macro_event_inner: MacroEventInner::Stopped {
-                                            exit_status: ExitStatus::Killed {
-                                                time: chrono::Utc::now().timestamp(),
-                                            },
-                                        },
-                                        instance_uuid,
-                                    }
-                                    .into(),
-                                );
+                                send_stopped(ExitStatus::Killed {
+                                    time: chrono::Utc::now().timestamp(),
+                                });
                             } else {
                                 error!("Error executing main module {main_module}: {}", e);
-                                event_broadcaster.send(
-                                    MacroEvent {
-                                        macro_pid: pid,
-                                        macro_event_inner: MacroEventInner::Stopped {
-                                            exit_status: ExitStatus::Error {
-                                                error_msg: e.to_string(),
-                                                time: chrono::Utc::now().timestamp(),
-                                            },
-                                        },
-                                        instance_uuid,
-                                    }
-                                    .into(),
-                                );
+                                send_stopped(ExitStatus::Error {
+                                    error_msg: e.to_string(),
+                                    time: chrono::Utc::now().timestamp(),
+                                });
                             }
                             return;
                         }
 
                         if let Err(e) = main_worker.run_event_loop(false).await {
-                            if e.to_string() == "Uncaught Error: execution terminated" {
+                            if is_terminated(&e) && heap_limit_hit.get() {
+                                warn!("Macro {} exceeded its heap limit", pid.0);
+                                send_stopped(ExitStatus::Error {
+                                    error_msg: "heap limit exceeded".to_string(),
+                                    time: chrono::Utc::now().timestamp(),
+                                });
+                            } else if is_terminated(&e) {
                                 warn!("User terminated macro execution");
-                                event_broadcaster.send(
-                                    MacroEvent {
-                                        macro_pid: pid,
-                                        macro_event_inner: MacroEventInner::Stopped {
-                                            exit_status: ExitStatus::Killed {
-                                                time: chrono::Utc::now().timestamp(),
-                                            },
-                                        },
-                                        instance_uuid: instance_uuid.clone(),
-                                    }
-                                    .into(),
-                                );
+                                send_stopped(ExitStatus::Killed {
+                                    time: chrono::Utc::now().timestamp(),
+                                });
                             } else {
                                 error!("Error running event loops: {}", e);
-                                event_broadcaster.send(
-                                    MacroEvent {
-                                        macro_pid: pid,
-                                        macro_event_inner: MacroEventInner::Stopped {
-                                            exit_status: ExitStatus::Error {
-                                                error_msg: e.to_string(),
-                                                time: chrono::Utc::now().timestamp(),
-                                            },
-                                        },
-                                        instance_uuid: instance_uuid.clone(),
-                                    }
-                                    .into(),
-                                );
+                                send_stopped(ExitStatus::Error {
+                                    error_msg: e.to_string(),
+                                    time: chrono::Utc::now().timestamp(),
+                                });
                             }
+                            return;
                         }
 
                         debug!("Macro event loop exited");
 
-                        event_broadcaster.send(
-                            MacroEvent {
-                                macro_pid: pid,
-                                macro_event_inner: MacroEventInner::Stopped {
-                                    exit_status: ExitStatus::Success {
-                                        time: chrono::Utc::now().timestamp(),
-                                    },
-                                },
-                                instance_uuid,
-                            }
-                            .into(),
-                        );
+                        send_stopped(ExitStatus::Success {
+                            time: chrono::Utc::now().timestamp(),
+                        });
 
                         // If the while loop returns, then all the LocalSpawner
                         // objects have been dropped.
@@ -458,16 +1155,19 @@ impl MacroExecutor {
                 // spawned tasks have returned.
                 rt.block_on(local);
                 debug!("MacroExecutor thread exited");
+                // the macro reports how it stopped itself unless its task panicked
+                if stopped_sent.get() {
+                    return;
+                }
+                let exit_status = ExitStatus::Error {
+                    time: chrono::Utc::now().timestamp(),
+                    error_msg: "Macro executor thread unexpectedly panicked".to_string(),
+                };
+                executor.record_exit(pid, &exit_status);
                 event_broadcaster.send(
                     MacroEvent {
                         macro_pid: pid,
-                        macro_event_inner: MacroEventInner::Stopped {
-                            exit_status: ExitStatus::Error {
-                                time: chrono::Utc::now().timestamp(),
-                                error_msg: "Macro executor thread unexpectedly panicked"
-                                    .to_string(),
-                            },
-                        },
+                        macro_event_inner: MacroEventInner::Stopped { exit_status },
                         instance_uuid: instance_uuid.clone(),
                     }
                     .into(),
@@ -475,11 +1175,17 @@ impl MacroExecutor {
             }
         });
 
+        // a queued macro only starts once a slot frees up, waiting for it would block the caller
+        if queued {
+            return Ok(SpawnResult {
+                macro_pid: pid,
+                detach_future,
+                exit_future,
+            });
+        }
+
         // listen to event broadcaster for macro started event
         // and return the pid
-
-        let rx = self.event_broadcaster.subscribe();
-
         let fut = async move {
             let mut rx = rx;
             loop {
@@ -510,6 +1216,83 @@ impl MacroExecutor {
         })
     }
 
+    /// Called by the macro thread before it reports how the macro stopped,
+    /// so a stopped macro is never seen running and its exit status is already there
+    ///
+    /// Only the exit status is kept, and only for the most recent macros to stop,
+    /// so a core running many macros doesn't accumulate them
+    fn record_exit(&self, pid: MacroPID, exit_status: &ExitStatus) {
+        self.exit_status_table.insert(pid, exit_status.clone());
+        {
+            let mut stopped_pids = self.stopped_pids.lock().unwrap();
+            stopped_pids.push_back(pid);
+            while stopped_pids.len() > self.exit_status_retention.load(Ordering::Relaxed) {
+                if let Some(oldest_pid) = stopped_pids.pop_front() {
+                    self.exit_status_table.remove(&oldest_pid);
+                }
+            }
+        }
+        self.macro_process_table.remove(&pid);
+        self.channel_table.remove(&pid);
+        self.detached_pids.remove(&pid);
+    }
+
+    /// Claims `singleton_key` for `pid`, or returns the pid of the running macro holding it
+    fn claim_singleton_key(
+        &self,
+        singleton_key: SingletonKey,
+        pid: MacroPID,
+    ) -> Result<SingletonClaim, Error> {
+        match self.singleton_table.entry(singleton_key.key.clone()) {
+            dashmap::mapref::entry::Entry::Occupied(mut entry) => {
+                let existing_pid = *entry.get();
+                // the key is freed after the stopped event is sent, so it may not be gone yet
+                if self.exit_status_table.contains_key(&existing_pid) {
+                    entry.insert(pid);
+                } else if singleton_key.reuse_existing {
+                    return Ok(SingletonClaim::Running(existing_pid));
+                } else {
+                    return Err(Error {
+                        kind: ErrorKind::Conflict,
+                        source: eyre!(
+                            "A macro with key {} is already running as {}",
+                            singleton_key.key,
+                            existing_pid
+                        ),
+                    });
+                }
+            }
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                entry.insert(pid);
+            }
+        }
+        Ok(SingletonClaim::Claimed(SingletonGuard {
+            singleton_table: self.singleton_table.clone(),
+            key: singleton_key.key,
+            pid,
+        }))
+    }
+
+    /// A `SpawnResult` for a macro that is already running
+    ///
+    /// The macro may detach or stop before the futures are polled,
+    /// so both check what already happened instead of only waiting for the event
+    fn attach(&self, pid: MacroPID) -> SpawnResult {
+        SpawnResult {
+            macro_pid: pid,
+            exit_future: Box::pin({
+                let executor = self.clone();
+                async move { executor.wait_for_exit(pid).await }
+            }),
+            detach_future: Box::pin({
+                let executor = self.clone();
+                async move {
+                    executor.wait_for_detach(pid).await;
+                }
+            }),
+        }
+    }
+
     /// abort a macro execution
     pub fn abort_macro(&self, pid: MacroPID) -> Result<(), Error> {
         self.macro_process_table
@@ -522,8 +1305,40 @@ impl MacroExecutor {
         Ok(())
     }
 
+    /// terminate every running macro, returning the pids that were signalled
+    ///
+    /// Terminated macros report `ExitStatus::Killed` once they stop.
+    /// A macro may exit on its own while this runs, terminating its isolate is then a no-op.
+    /// Macros still queued for a slot are not affected
+    pub fn terminate_all(&self) -> Vec<MacroPID> {
+        self.macro_process_table
+            .iter()
+            .filter(|entry| !self.exit_status_table.contains_key(entry.key()))
+            .map(|entry| {
+                entry.value().terminate_execution();
+                *entry.key()
+            })
+            .collect()
+    }
+
+    /// abort all running macros, waiting up to `timeout` for them to report their exit status
+    pub async fn abort_all(&self, timeout: Duration) {
+        let running = self.terminate_all();
+        let _ = tokio::time::timeout(timeout, async {
+            while running.iter().any(|pid| self.is_running(*pid)) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await;
+    }
+
+    /// wait for a macro to detach, returning right away if it already has
     pub async fn wait_for_detach(&self, target_macro_pid: MacroPID) {
         let mut rx = self.event_broadcaster.subscribe();
+        // checked after subscribing, so a detach in between isn't missed
+        if self.detached_pids.contains(&target_macro_pid) {
+            return;
+        }
         loop {
             let event = rx.recv().await.unwrap();
             if let EventInner::MacroEvent(MacroEvent {
@@ -561,6 +1376,52 @@ impl MacroExecutor {
         }
     }
 
+    /// wait for a macro to finish, returning right away if it already has
+    ///
+    /// Unlike the `exit_future` of a `SpawnResult`, this can be called after the macro exited.
+    ///
+    /// Errors with `NotFound` if the macro never ran, or stopped so long ago
+    /// that its exit status is no longer kept, see `set_exit_status_retention`
+    pub async fn wait_for_exit(&self, pid: MacroPID) -> Result<ExitStatus, Error> {
+        let mut rx = self.event_broadcaster.subscribe();
+        loop {
+            // polled in case the stop event was sent before we subscribed
+            if let Some(exit_status) = self.exit_status_table.get(&pid) {
+                return Ok(exit_status.clone());
+            }
+            // the exit status is recorded before the pid stops being live
+            if !self.live_pids.contains(&pid) {
+                return Err(Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!("Macro {} isn't running and its exit status isn't kept", pid),
+                });
+            }
+            match tokio::time::timeout(Duration::from_secs(1), rx.recv()).await {
+                Ok(Ok(event)) => {
+                    if let Some(MacroEvent {
+                        macro_pid,
+                        macro_event_inner: MacroEventInner::Stopped { exit_status },
+                        ..
+                    }) = event.try_macro_event()
+                    {
+                        if *macro_pid == pid {
+                            return Ok(exit_status.clone());
+                        }
+                    }
+                }
+                Ok(Err(tokio::sync::broadcast::error::RecvError::Closed)) => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Whether the macro started and hasn't stopped yet, macros queued for a slot aren't running
+    pub fn is_running(&self, pid: MacroPID) -> bool {
+        self.macro_process_table.contains_key(&pid)
+    }
+
     pub async fn get_macro_status(&self, pid: MacroPID) -> Option<ExitStatus> {
         self.exit_status_table.get(&pid).map(|v| v.clone())
     }
@@ -576,8 +1437,7 @@ mod tests {
     use super::{TypescriptModuleLoader, WorkerOptionGenerator};
 
     use crate::event_broadcaster::EventBroadcaster;
-    use crate::events::CausedBy;
-    use crate::macro_executor::SpawnResult;
+    use crate::macro_executor::{SpawnOptions, SpawnResult};
 
     struct BasicMainWorkerGenerator;
 
@@ -613,7 +1473,8 @@ mod tests {
             super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
 
         // create a temp directory
-        let temp_dir = tempdir::TempDir::new("macro_test").unwrap().into_path();
+        let temp_dir_guard = tempdir::TempDir::new("macro_test").unwrap();
+        let temp_dir = temp_dir_guard.path().to_owned();
 
         // create a macro file
 
@@ -635,17 +1496,63 @@ mod tests {
         let SpawnResult { exit_future, .. } = executor
             .spawn(
                 path_to_macro,
-                Vec::new(),
-                CausedBy::Unknown,
+                temp_dir.clone(),
                 Box::new(basic_worker_generator),
-                None,
-                None,
+                SpawnOptions::default(),
             )
             .await
             .unwrap();
         exit_future.await.unwrap();
     }
 
+    #[test]
+    fn test_resolve_macro_invocation() {
+        use super::resolve_macro_invocation;
+
+        let temp_dir_guard = tempdir::TempDir::new("macro_resolve_test").unwrap();
+        let temp_dir = temp_dir_guard.path().to_owned();
+        let in_game = temp_dir.join("in_game");
+        std::fs::create_dir_all(temp_dir.join("ts_folder")).unwrap();
+        std::fs::create_dir_all(temp_dir.join("js_folder")).unwrap();
+        std::fs::create_dir_all(temp_dir.join("empty_folder")).unwrap();
+        std::fs::create_dir_all(&in_game).unwrap();
+        for path in [
+            temp_dir.join("both.ts"),
+            temp_dir.join("both.js"),
+            temp_dir.join("js_only.js"),
+            temp_dir.join("ts_folder").join("index.ts"),
+            temp_dir.join("ts_folder").join("index.js"),
+            temp_dir.join("js_folder").join("index.js"),
+            in_game.join("greet.ts"),
+            in_game.join("both.ts"),
+        ] {
+            std::fs::write(path, "").unwrap();
+        }
+
+        assert_eq!(
+            resolve_macro_invocation(&temp_dir, "both"),
+            Some(temp_dir.join("both.ts"))
+        );
+        assert_eq!(
+            resolve_macro_invocation(&temp_dir, "js_only"),
+            Some(temp_dir.join("js_only.js"))
+        );
+        assert_eq!(
+            resolve_macro_invocation(&temp_dir, "ts_folder"),
+            Some(temp_dir.join("ts_folder").join("index.ts"))
+        );
+        assert_eq!(
+            resolve_macro_invocation(&temp_dir, "js_folder"),
+            Some(temp_dir.join("js_folder").join("index.js"))
+        );
+        assert_eq!(
+            resolve_macro_invocation(&temp_dir, "greet"),
+            Some(in_game.join("greet.ts"))
+        );
+        assert_eq!(resolve_macro_invocation(&temp_dir, "empty_folder"), None);
+        assert_eq!(resolve_macro_invocation(&temp_dir, "missing"), None);
+    }
+
     #[tokio::test]
     async fn test_http_url() {
         tracing_subscriber::fmt::try_init();
@@ -657,7 +1564,8 @@ mod tests {
             super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
 
         // create a temp directory
-        let temp_dir = tempdir::TempDir::new("macro_test").unwrap().into_path();
+        let temp_dir_guard = tempdir::TempDir::new("macro_test").unwrap();
+        let temp_dir = temp_dir_guard.path().to_owned();
 
         // create a macro file
 
@@ -677,16 +1585,574 @@ mod tests {
         let SpawnResult { exit_future, .. } = executor
             .spawn(
                 path_to_macro,
-                Vec::new(),
-                CausedBy::Unknown,
+                temp_dir.clone(),
                 Box::new(basic_worker_generator),
-                None,
-                None,
+                SpawnOptions::default(),
             )
             .await
             .unwrap();
         exit_future.await.unwrap();
     }
+
+    #[tokio::test]
+    async fn singleton_key() {
+        use super::SingletonKey;
+        use crate::error::{Error, ErrorKind};
+
+        let (event_broadcaster, _rx) = EventBroadcaster::new(10);
+        let executor =
+            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
+        let temp_dir_guard = tempdir::TempDir::new("macro_test").unwrap();
+        let temp_dir = temp_dir_guard.path().to_owned();
+        let path_to_macro = temp_dir.join("test.ts");
+        std::fs::write(
+            &path_to_macro,
+            "await new Promise((resolve) => setTimeout(resolve, 1000));",
+        )
+        .unwrap();
+        let spawn = |reuse_existing| {
+            executor.spawn(
+                path_to_macro.clone(),
+                temp_dir.clone(),
+                Box::new(BasicMainWorkerGenerator),
+                SpawnOptions {
+                    singleton_key: Some(SingletonKey {
+                        key: "test".to_string(),
+                        reuse_existing,
+                    }),
+                    ..Default::default()
+                },
+            )
+        };
+
+        let SpawnResult {
+            macro_pid,
+            exit_future,
+            ..
+        } = spawn(false).await.unwrap();
+        assert_eq!(spawn(true).await.unwrap().macro_pid, macro_pid);
+        assert!(matches!(
+            spawn(false).await,
+            Err(Error {
+                kind: ErrorKind::Conflict,
+                ..
+            })
+        ));
+        exit_future.await.unwrap();
+        // the key is freed shortly after the macro stops
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_ne!(spawn(false).await.unwrap().macro_pid, macro_pid);
+    }
+
+    #[tokio::test]
+    async fn singleton_key_attach_after_detach() {
+        use super::SingletonKey;
+
+        let (event_broadcaster, _rx) = EventBroadcaster::new(10);
+        let executor =
+            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
+        let temp_dir = tempdir::TempDir::new("macro_test").unwrap();
+        let path_to_macro = temp_dir.path().join("test.ts");
+        std::fs::write(
+            &path_to_macro,
+            r#"
+            const core = Deno[Deno.internal].core;
+            core.ops.emit_detach(__macro_pid);
+            await new Promise((resolve) => setTimeout(resolve, 1000));
+            "#,
+        )
+        .unwrap();
+        let spawn = || {
+            executor.spawn(
+                path_to_macro.clone(),
+                temp_dir.path().to_path_buf(),
+                Box::new(BasicMainWorkerGenerator),
+                SpawnOptions {
+                    singleton_key: Some(SingletonKey {
+                        key: "test".to_string(),
+                        reuse_existing: true,
+                    }),
+                    ..Default::default()
+                },
+            )
+        };
+
+        let first = spawn().await.unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), first.detach_future)
+            .await
+            .unwrap();
+        // the macro already detached, attaching must not wait for another detach
+        let attached = spawn().await.unwrap();
+        assert_eq!(attached.macro_pid, first.macro_pid);
+        tokio::time::timeout(std::time::Duration::from_secs(1), attached.detach_future)
+            .await
+            .unwrap();
+        first.exit_future.await.unwrap();
+        // nor for a stop that already happened
+        tokio::time::timeout(std::time::Duration::from_secs(1), attached.exit_future)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sleep() {
+        use crate::traits::t_macro::ExitStatus;
+
+        let (event_broadcaster, _rx) = EventBroadcaster::new(10);
+        let executor =
+            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
+        let temp_dir_guard = tempdir::TempDir::new("macro_test").unwrap();
+        let temp_dir = temp_dir_guard.path().to_owned();
+        let path_to_macro = temp_dir.join("test.ts");
+        std::fs::write(
+            &path_to_macro,
+            r#"
+            const core = Deno[Deno.internal].core;
+            for (let i = 0; i < 5; i++) {
+                await core.opAsync("sleep_ms", 100);
+            }
+            "#,
+        )
+        .unwrap();
+
+        let started_at = std::time::Instant::now();
+        let SpawnResult { exit_future, .. } = executor
+            .spawn(
+                path_to_macro,
+                temp_dir.clone(),
+                Box::new(BasicMainWorkerGenerator),
+                SpawnOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            exit_future.await.unwrap(),
+            ExitStatus::Success { .. }
+        ));
+        // the event loop waited on the timers instead of exiting early
+        assert!(started_at.elapsed() >= std::time::Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_timers() {
+        use crate::traits::t_macro::ExitStatus;
+
+        let (event_broadcaster, _rx) = EventBroadcaster::new(10);
+        let executor =
+            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
+        let temp_dir = tempdir::TempDir::new("macro_test").unwrap();
+        let path_to_macro = temp_dir.path().join("test.ts");
+        let prelude = deno_core::ModuleSpecifier::from_file_path(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/deno_ops/prelude/prelude.ts"
+        ))
+        .unwrap();
+        std::fs::write(
+            &path_to_macro,
+            format!(
+                r#"
+                import {{ setTimer, awaitTimer, clearTimer }} from "{prelude}";
+                const forgotten = (id: string) =>
+                    awaitTimer(id).then(() => false, () => true);
+
+                setTimer("fired", 50);
+                if (!(await awaitTimer("fired"))) throw new Error("timer didn't fire");
+                if (!(await forgotten("fired"))) throw new Error("awaited timer kept");
+
+                setTimer("cleared", 60_000);
+                const cleared = awaitTimer("cleared");
+                if (!clearTimer("cleared")) throw new Error("pending timer not cleared");
+                if (await cleared) throw new Error("cleared timer fired");
+
+                setTimer("unawaited", 60_000);
+                clearTimer("unawaited");
+                if (!(await forgotten("unawaited"))) throw new Error("cleared timer kept");
+                // the id is free again
+                setTimer("unawaited", 10);
+                await awaitTimer("unawaited");
+                "#
+            ),
+        )
+        .unwrap();
+
+        let started_at = std::time::Instant::now();
+        let SpawnResult { exit_future, .. } = executor
+            .spawn(
+                path_to_macro,
+                temp_dir.path().to_owned(),
+                Box::new(BasicMainWorkerGenerator),
+                SpawnOptions::default(),
+            )
+            .await
+            .unwrap();
+        match exit_future.await.unwrap() {
+            ExitStatus::Success { .. } => {}
+            exit_status => panic!("Unexpected exit status {:?}", exit_status),
+        }
+        // cleared timers don't keep the macro running
+        assert!(started_at.elapsed() < std::time::Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn test_relative_paths_resolve_from_cwd() {
+        use crate::traits::t_macro::ExitStatus;
+
+        let (event_broadcaster, _rx) = EventBroadcaster::new(10);
+        let executor =
+            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
+        let temp_dir = tempdir::TempDir::new("macro_test").unwrap();
+        let path_to_macro = temp_dir.path().join("test.ts");
+        std::fs::write(temp_dir.path().join("server.properties"), "motd=hi").unwrap();
+        std::fs::create_dir(temp_dir.path().join("world")).unwrap();
+        std::fs::write(temp_dir.path().join("world").join("level.dat"), "level").unwrap();
+        std::fs::write(
+            &path_to_macro,
+            format!(
+                r#"
+                if (Deno.cwd() !== {cwd}) throw new Error(`wrong cwd ${{Deno.cwd()}}`);
+                if (await Deno.readTextFile("./server.properties") !== "motd=hi")
+                    throw new Error("relative read missed the cwd");
+                Deno.writeTextFileSync("written.txt", "written");
+                Deno.chdir("world");
+                if (Deno.readTextFileSync("level.dat") !== "level")
+                    throw new Error("chdir didn't move the cwd");
+                "#,
+                cwd = serde_json::to_string(&temp_dir.path().to_string_lossy()).unwrap()
+            ),
+        )
+        .unwrap();
+        let core_cwd = std::env::current_dir().unwrap();
+
+        let SpawnResult { exit_future, .. } = executor
+            .spawn(
+                path_to_macro,
+                temp_dir.path().to_owned(),
+                Box::new(BasicMainWorkerGenerator),
+                SpawnOptions::default(),
+            )
+            .await
+            .unwrap();
+        match exit_future.await.unwrap() {
+            ExitStatus::Success { .. } => {}
+            exit_status => panic!("Unexpected exit status {:?}", exit_status),
+        }
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join("written.txt")).unwrap(),
+            "written"
+        );
+        // the macro's chdir doesn't touch the core's working directory
+        assert_eq!(std::env::current_dir().unwrap(), core_cwd);
+    }
+
+    #[tokio::test]
+    async fn test_structured_args() {
+        use crate::traits::t_macro::ExitStatus;
+
+        let (event_broadcaster, _rx) = EventBroadcaster::new(10);
+        let executor =
+            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
+        let temp_dir = tempdir::TempDir::new("macro_test").unwrap();
+        let path_to_macro = temp_dir.path().join("test.ts");
+        let prelude = deno_core::ModuleSpecifier::from_file_path(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/deno_ops/prelude/prelude.ts"
+        ))
+        .unwrap();
+        std::fs::write(
+            &path_to_macro,
+            format!(
+                r#"
+                import {{ getMacroArgs }} from "{prelude}";
+                const args = getMacroArgs<{{ target_ram: number; flags: string[] }}>();
+                if (args?.target_ram !== 4096) throw new Error(`wrong args ${{JSON.stringify(args)}}`);
+                if (args.flags.join(",") !== "a,b") throw new Error("wrong flags");
+                if (Deno.args.join(",") !== "plain") throw new Error("wrong string args");
+                "#
+            ),
+        )
+        .unwrap();
+
+        let SpawnResult { exit_future, .. } = executor
+            .spawn(
+                path_to_macro,
+                temp_dir.path().to_owned(),
+                Box::new(BasicMainWorkerGenerator),
+                SpawnOptions {
+                    args: vec!["plain".to_string()],
+                    structured_args: Some(serde_json::json!({
+                        "target_ram": 4096,
+                        "flags": ["a", "b"],
+                    })),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        match exit_future.await.unwrap() {
+            ExitStatus::Success { .. } => {}
+            exit_status => panic!("Unexpected exit status {:?}", exit_status),
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_spawn_returns_immediately() {
+        use super::MacroLimitPolicy;
+        use crate::traits::t_macro::ExitStatus;
+
+        let (event_broadcaster, _rx) = EventBroadcaster::new(10);
+        let executor =
+            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current())
+                .with_concurrency_limit(1, MacroLimitPolicy::Queue);
+        let temp_dir = tempdir::TempDir::new("macro_test").unwrap();
+        let path_to_macro = temp_dir.path().join("test.ts");
+        std::fs::write(
+            &path_to_macro,
+            "await new Promise((resolve) => setTimeout(resolve, 500));",
+        )
+        .unwrap();
+        let spawn = || {
+            executor.spawn(
+                path_to_macro.clone(),
+                temp_dir.path().to_owned(),
+                Box::new(BasicMainWorkerGenerator),
+                SpawnOptions::default(),
+            )
+        };
+
+        let first = spawn().await.unwrap();
+        // the only slot is taken, the second macro is queued instead of holding up the caller
+        let second = tokio::time::timeout(std::time::Duration::from_millis(100), spawn())
+            .await
+            .expect("spawn waited for a free slot")
+            .unwrap();
+        assert!(matches!(
+            first.exit_future.await.unwrap(),
+            ExitStatus::Success { .. }
+        ));
+        assert!(matches!(
+            second.exit_future.await.unwrap(),
+            ExitStatus::Success { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_heap_limit() {
+        use crate::traits::t_macro::ExitStatus;
+
+        let (event_broadcaster, _rx) = EventBroadcaster::new(10);
+        let executor =
+            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
+        let temp_dir_guard = tempdir::TempDir::new("macro_test").unwrap();
+        let temp_dir = temp_dir_guard.path().to_owned();
+        let path_to_macro = temp_dir.join("test.ts");
+        std::fs::write(
+            &path_to_macro,
+            r#"
+            const hog = [];
+            while (true) {
+                hog.push(new Array(1024 * 1024).fill(hog.length));
+            }
+            "#,
+        )
+        .unwrap();
+
+        let SpawnResult { exit_future, .. } = executor
+            .spawn(
+                path_to_macro,
+                temp_dir.clone(),
+                Box::new(BasicMainWorkerGenerator),
+                SpawnOptions {
+                    max_heap_mb: Some(64),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        match exit_future.await.unwrap() {
+            ExitStatus::Error { error_msg, .. } => assert_eq!(error_msg, "heap limit exceeded"),
+            exit_status => panic!("Unexpected exit status {:?}", exit_status),
+        }
+    }
+
+    #[tokio::test]
+    async fn single_terminal_event() {
+        use crate::events::{EventInner, MacroEvent, MacroEventInner};
+        use crate::traits::t_macro::ExitStatus;
+
+        let (event_broadcaster, mut rx) = EventBroadcaster::new(100);
+        let executor =
+            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current());
+        let temp_dir_guard = tempdir::TempDir::new("macro_test").unwrap();
+        let temp_dir = temp_dir_guard.path().to_owned();
+        let path_to_macro = temp_dir.join("test.ts");
+        std::fs::write(&path_to_macro, "console.log('done');").unwrap();
+
+        let SpawnResult {
+            macro_pid,
+            exit_future,
+            ..
+        } = executor
+            .spawn(
+                path_to_macro,
+                temp_dir.clone(),
+                Box::new(BasicMainWorkerGenerator),
+                SpawnOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            exit_future.await.unwrap(),
+            ExitStatus::Success { .. }
+        ));
+        // give the executor thread time to exit, which used to report a second status
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+        let mut terminal_events = 0;
+        while let Ok(event) = rx.try_recv() {
+            if let EventInner::MacroEvent(MacroEvent {
+                macro_pid: pid,
+                macro_event_inner: MacroEventInner::Stopped { .. },
+                ..
+            }) = event.event_inner
+            {
+                if pid == macro_pid {
+                    terminal_events += 1;
+                }
+            }
+        }
+        assert_eq!(terminal_events, 1);
+    }
+
+    #[tokio::test]
+    async fn finished_macros_are_pruned() {
+        use crate::error::{Error, ErrorKind};
+
+        let (event_broadcaster, _rx) = EventBroadcaster::new(100);
+        let executor =
+            super::MacroExecutor::new(event_broadcaster, tokio::runtime::Handle::current())
+                .with_exit_status_retention(5);
+        let temp_dir_guard = tempdir::TempDir::new("macro_test").unwrap();
+        let temp_dir = temp_dir_guard.path().to_owned();
+        let path_to_macro = temp_dir.join("test.ts");
+        std::fs::write(&path_to_macro, "console.log('done');").unwrap();
+
+        let mut pids = Vec::new();
+        for _ in 0..20 {
+            let SpawnResult {
+                macro_pid,
+                exit_future,
+                ..
+            } = executor
+                .spawn(
+                    path_to_macro.clone(),
+                    temp_dir.clone(),
+                    Box::new(BasicMainWorkerGenerator),
+                    SpawnOptions::default(),
+                )
+                .await
+                .unwrap();
+            exit_future.await.unwrap();
+            pids.push(macro_pid);
+        }
+
+        assert!(executor.macro_process_table.is_empty());
+        assert!(executor.channel_table.is_empty());
+        assert_eq!(executor.exit_status_table.len(), 5);
+        // only the most recent ones are kept
+        for pid in &pids[..15] {
+            assert!(executor.get_macro_status(*pid).await.is_none());
+            assert!(!executor.is_running(*pid));
+        }
+        for pid in &pids[15..] {
+            assert!(executor.get_macro_status(*pid).await.is_some());
+        }
+        // a forgotten macro can't be waited on, but that must not hang
+        assert!(matches!(
+            tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                executor.wait_for_exit(pids[0])
+            )
+            .await
+            .unwrap(),
+            Err(Error {
+                kind: ErrorKind::NotFound,
+                ..
+            })
+        ));
+        assert!(executor.wait_for_exit(pids[19]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_validate_macro() {
+        use super::validate_macro;
+
+        let temp_dir_guard = tempdir::TempDir::new("macro_validate_test").unwrap();
+        let temp_dir = temp_dir_guard.path().to_owned();
+        std::fs::create_dir_all(temp_dir.join("folder")).unwrap();
+        std::fs::write(
+            temp_dir.join("folder").join("index.ts"),
+            "import { greet } from \"./greet.ts\";\ngreet();\n",
+        )
+        .unwrap();
+        std::fs::write(
+            temp_dir.join("folder").join("greet.ts"),
+            "export function greet() {\n    console.log(\"hi\"\n}\n",
+        )
+        .unwrap();
+
+        let entry = temp_dir.join("folder").join("index.ts");
+        let diagnostics = validate_macro(&temp_dir, &entry, None).await;
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].file,
+            std::path::Path::new("folder")
+                .join("greet.ts")
+                .to_string_lossy()
+        );
+        assert_eq!(diagnostics[0].line, Some(3));
+        assert_eq!(diagnostics[0].class, "SyntaxError");
+
+        // unsaved changes replace the file on disk
+        let diagnostics = validate_macro(
+            &temp_dir,
+            &entry,
+            Some("const x: number = 1;\nconsole.log(x);\n".to_string()),
+        )
+        .await;
+        assert!(diagnostics.is_empty());
+
+        let diagnostics = validate_macro(
+            &temp_dir,
+            &entry,
+            Some("import \"./missing.ts\";\n".to_string()),
+        )
+        .await;
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].class, "NotFound");
+    }
+
+    #[test]
+    fn test_check_module_url() {
+        use super::check_module_url;
+
+        let allowed_hosts = vec!["deno.land".to_string()];
+        let check = |url: &str, allowed_hosts: Option<&[String]>| {
+            check_module_url(&url.parse().unwrap(), allowed_hosts, true).is_ok()
+        };
+        assert!(check("https://deno.land/std/mod.ts", Some(&allowed_hosts)));
+        assert!(check(
+            "https://cdn.deno.land/std/mod.ts",
+            Some(&allowed_hosts)
+        ));
+        assert!(!check("https://evildeno.land/mod.ts", Some(&allowed_hosts)));
+        assert!(!check("https://example.com/mod.ts", Some(&allowed_hosts)));
+        assert!(check("https://example.com/mod.ts", None));
+        assert!(!check("http://127.0.0.1/mod.ts", None));
+        assert!(!check("http://192.168.1.1/mod.ts", None));
+        assert!(!check("http://[::1]/mod.ts", None));
+        assert!(check("http://1.1.1.1/mod.ts", None));
+    }
 }
 
 mod deno_errors {
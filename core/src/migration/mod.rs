@@ -11,7 +11,7 @@ use tracing::{debug, info};
 use crate::{
     error::Error,
     implementations::minecraft::Flavour,
-    prelude::VERSION,
+    prelude::{resolve_path_to_instances, VERSION},
     traits::t_configurable::GameType,
     types::{InstanceUuid, LodestoneMetadata},
 };
@@ -52,6 +52,11 @@ enum LegacyVersion {
     V043,
 }
 
+/// Instances may be outside the lodestone directory, see `resolve_path_to_instances`
+fn path_to_instances(lodestone_path: &Path) -> PathBuf {
+    resolve_path_to_instances(lodestone_path, &lodestone_path.join("global_settings.json"))
+}
+
 fn determine_legacy_version(lodestone_path: &Path) -> Result<Option<LegacyVersion>, Error> {
     let metadata_path = lodestone_path.join(".lodestone_metadata.json");
     // if the metadata exists, then it's not a legacy version
@@ -59,7 +64,7 @@ fn determine_legacy_version(lodestone_path: &Path) -> Result<Option<LegacyVersio
         Ok(None)
     } else {
         // check if there is at least one instance with a .lodestone_config file
-        let instances_path = lodestone_path.join("instances");
+        let instances_path = path_to_instances(lodestone_path);
         if !instances_path.is_dir() {
             return Ok(None);
         }
@@ -107,11 +112,11 @@ pub fn migrate(lodestone_path: &Path) -> Result<(), Error> {
     match legacy_version {
         Some(LegacyVersion::V042) => {
             info!("Migrating from v0.4.2 to v0.4.3");
-            v042_to_v044::migrate_v042_to_v044(&lodestone_path.join("instances"))?;
+            v042_to_v044::migrate_v042_to_v044(&path_to_instances(lodestone_path))?;
         }
         Some(LegacyVersion::V043) => {
             info!("Migrating from v0.4.3 to v0.4.4");
-            v043_to_v044::migrate_v043_to_v044(&lodestone_path.join("instances"))?;
+            v043_to_v044::migrate_v043_to_v044(&path_to_instances(lodestone_path))?;
         }
         None => {
             info!("No migration needed");
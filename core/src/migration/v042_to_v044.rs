@@ -4,7 +4,7 @@ use color_eyre::eyre::Context;
 use serde_json::{json, Value};
 use tracing::error;
 
-use crate::{error::Error, implementations::minecraft::RestoreConfig};
+use crate::{error::Error, implementations::minecraft::RestoreConfig, util::fs::write_atomic_sync};
 
 use super::RestoreConfigV042;
 
@@ -25,6 +25,12 @@ impl From<RestoreConfigV042> for RestoreConfig {
             jre_major_version: config.jre_major_version,
             has_started: config.has_started,
             java_cmd: None,
+            jvm_flags: Default::default(),
+            idle_shutdown: None,
+            env: Default::default(),
+            console_log: None,
+            cpu_affinity: Vec::new(),
+            startup_milestones: None,
         }
     }
 }
@@ -77,21 +83,14 @@ fn migrate_v042_instance_to_v044(path_to_instance: &Path) -> Result<(), Error> {
     let dot_lodestone_config_new: crate::types::DotLodestoneConfig =
         dot_lodestone_config.clone().into();
     let dot_lodestone_config_new = serde_json::to_string_pretty(&dot_lodestone_config_new).unwrap();
-    std::fs::write(&path_to_dot_lodestone_config, dot_lodestone_config_new).context(format!(
-        "Failed to write config file at {}",
-        &path_to_dot_lodestone_config.display()
-    ))?;
+    write_atomic_sync(&path_to_dot_lodestone_config, dot_lodestone_config_new)?;
 
     let dot_lodestone_minecraft_config: RestoreConfig = dot_lodestone_config.into();
     let dot_lodestone_minecraft_config =
         serde_json::to_string_pretty(&dot_lodestone_minecraft_config).unwrap();
-    std::fs::write(
+    write_atomic_sync(
         &path_to_dot_lodestone_minecraft_config,
         dot_lodestone_minecraft_config,
-    )
-    .context(format!(
-        "Failed to write config file at {}",
-        &path_to_dot_lodestone_minecraft_config.display()
-    ))?;
+    )?;
     Ok(())
 }
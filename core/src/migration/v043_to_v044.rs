@@ -1,4 +1,4 @@
-use crate::{error::Error, types::DotLodestoneConfig};
+use crate::{error::Error, types::DotLodestoneConfig, util::fs::write_atomic_sync};
 use color_eyre::eyre::Context;
 use std::path::Path;
 use tracing::error;
@@ -44,9 +44,6 @@ fn migrate_v043_instance_to_v044(path_to_instance: &Path) -> Result<(), Error> {
     let new_dot_lodestone_config: DotLodestoneConfig = dot_lodestone_config.into();
 
     let string = serde_json::to_string_pretty(&new_dot_lodestone_config).unwrap();
-    std::fs::write(path_to_instance.join(".lodestone_config"), string).context(format!(
-        "Failed to write config file at {}",
-        &path_to_instance.join(".lodestone_config").display()
-    ))?;
+    write_atomic_sync(path_to_instance.join(".lodestone_config"), string)?;
     Ok(())
 }
@@ -0,0 +1,325 @@
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use color_eyre::eyre::eyre;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
+use tracing::{error, info, warn};
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, ErrorKind},
+    prelude::GameInstance,
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+};
+
+/// Holds the pid of the running server process, removed once it exits
+const PIDFILE: &str = ".lodestone_pid";
+
+/// What happens to orphaned server processes found when the core starts
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default, TS)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanPolicy {
+    /// Leave them running and log a warning, they can be killed from `/system/orphans`
+    #[default]
+    Keep,
+    /// Kill them before any instance is auto started
+    Kill,
+}
+
+/// A server process still running from an instance the core isn't running,
+/// usually left behind by a crash of the core
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct OrphanProcess {
+    pub pid: u32,
+    pub instance_uuid: InstanceUuid,
+    pub instance_name: String,
+    pub cmd: Vec<String>,
+    /// Bytes
+    #[ts(type = "number")]
+    pub memory: u64,
+    /// Unix timestamp in seconds
+    #[ts(type = "number")]
+    pub start_time: u64,
+}
+
+/// An instance whose server processes can be orphaned
+pub struct KnownInstance {
+    uuid: InstanceUuid,
+    name: String,
+    path: PathBuf,
+    /// The process the core is running for it, which is never an orphan
+    pid: Option<u32>,
+}
+
+pub async fn write_pidfile(instance_path: &Path, pid: u32) {
+    if let Err(e) = tokio::fs::write(instance_path.join(PIDFILE), pid.to_string()).await {
+        error!(
+            "Failed to write pidfile for {} : {}",
+            instance_path.display(),
+            e
+        );
+    }
+}
+
+pub async fn remove_pidfile(instance_path: &Path) {
+    let _ = tokio::fs::remove_file(instance_path.join(PIDFILE)).await;
+}
+
+/// Only Minecraft instances run a server process of their own
+pub async fn known_instances(
+    instances: &DashMap<InstanceUuid, GameInstance>,
+) -> Vec<KnownInstance> {
+    let mut ret = Vec::new();
+    for entry in instances.iter() {
+        if let GameInstance::MinecraftInstance(instance) = entry.value() {
+            ret.push(KnownInstance {
+                uuid: entry.key().clone(),
+                name: instance.name().await,
+                path: instance.path().await,
+                pid: instance.pid().await,
+            });
+        }
+    }
+    ret
+}
+
+/// The pid recorded in the instance's pidfile, if the process started before the file was written.
+/// A process started afterwards only reused the pid
+fn pidfile_pid(system: &System, instance_path: &Path) -> Option<Pid> {
+    let pidfile = instance_path.join(PIDFILE);
+    let written_at = std::fs::metadata(&pidfile)
+        .ok()?
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    let pid: u32 = std::fs::read_to_string(&pidfile)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    let pid = Pid::from_u32(pid);
+    match system.process(pid) {
+        Some(process) if process.start_time() <= written_at => Some(pid),
+        _ => {
+            // the process is gone, so the pidfile is stale
+            let _ = std::fs::remove_file(&pidfile);
+            None
+        }
+    }
+}
+
+/// Whether the command line is a `java` process running a jar of the instance, as the server does.
+/// Forge 1.17+ passes its jars through an `@..._args.txt` file instead
+///
+/// Any other process in the instance directory, like a shell or `tail -f logs/latest.log`, isn't a server
+fn is_instance_server(cmd: &[String], instance_path: &Path) -> bool {
+    let is_java = cmd.first().map_or(false, |program| {
+        Path::new(program)
+            .file_stem()
+            .map_or(false, |stem| stem == "java" || stem == "javaw")
+    });
+    if !is_java {
+        return false;
+    }
+    let canonical_path = instance_path.canonicalize().ok();
+    cmd.iter().skip(1).any(|arg| {
+        let path = Path::new(arg.trim_start_matches('@'));
+        let in_instance = path.starts_with(instance_path)
+            || canonical_path
+                .as_ref()
+                .map_or(false, |canonical| path.starts_with(canonical));
+        let is_server_file = path.extension().map_or(false, |ext| ext == "jar")
+            || path
+                .file_name()
+                .map_or(false, |name| name.to_string_lossy().ends_with("_args.txt"));
+        in_instance && is_server_file
+    })
+}
+
+/// Server processes of `instances` the core isn't running, found through their pidfiles
+/// and by scanning for `java` processes running a jar of an instance
+pub fn find_orphans(system: &mut System, instances: &[KnownInstance]) -> Vec<OrphanProcess> {
+    system.refresh_processes();
+    let own_pid = std::process::id();
+    let mut orphans: Vec<OrphanProcess> = Vec::new();
+    for instance in instances {
+        if instance.pid.is_some() {
+            continue;
+        }
+        let recorded = pidfile_pid(system, &instance.path);
+        for (pid, process) in system.processes() {
+            if pid.as_u32() == own_pid
+                || orphans.iter().any(|orphan| orphan.pid == pid.as_u32())
+                || (Some(*pid) != recorded && !is_instance_server(process.cmd(), &instance.path))
+            {
+                continue;
+            }
+            orphans.push(OrphanProcess {
+                pid: pid.as_u32(),
+                instance_uuid: instance.uuid.clone(),
+                instance_name: instance.name.clone(),
+                cmd: process.cmd().to_vec(),
+                memory: process.memory(),
+                start_time: process.start_time(),
+            });
+        }
+    }
+    orphans
+}
+
+/// Kills `pid` if it is one of the orphans of `instances`, any other process is refused
+pub fn kill_orphan(
+    system: &mut System,
+    instances: &[KnownInstance],
+    pid: u32,
+) -> Result<OrphanProcess, Error> {
+    let orphan = find_orphans(system, instances)
+        .into_iter()
+        .find(|orphan| orphan.pid == pid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Process {} is not an orphaned server process", pid),
+        })?;
+    match system.process(Pid::from_u32(pid)) {
+        Some(process) if process.kill() => Ok(orphan),
+        _ => Err(eyre!("Failed to kill process {}", pid).into()),
+    }
+}
+
+/// Applies `policy` to the orphans left by the last run, before instances are auto started
+pub async fn reconcile_orphans(
+    system: &mut System,
+    instances: &DashMap<InstanceUuid, GameInstance>,
+    policy: OrphanPolicy,
+) {
+    let instances = known_instances(instances).await;
+    for orphan in find_orphans(system, &instances) {
+        match policy {
+            OrphanPolicy::Keep => warn!(
+                "Process {} of instance {} is still running from before the core started, it may be holding the instance's port",
+                orphan.pid, orphan.instance_name
+            ),
+            OrphanPolicy::Kill => match kill_orphan(system, &instances, orphan.pid) {
+                Ok(_) => info!(
+                    "Killed process {} left running by instance {}",
+                    orphan.pid, orphan.instance_name
+                ),
+                Err(e) => error!(
+                    "Failed to kill process {} left running by instance {} : {}",
+                    orphan.pid, orphan.instance_name, e
+                ),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_is_instance_server() {
+        let instance = Path::new("/srv/lodestone/instances/survival");
+        assert!(is_instance_server(
+            &cmd(&[
+                "/srv/lodestone/bin/java/17/bin/java",
+                "-Xmx2G",
+                "-jar",
+                "/srv/lodestone/instances/survival/server.jar",
+                "nogui"
+            ]),
+            instance
+        ));
+        assert!(is_instance_server(
+            &cmd(&[
+                "java",
+                "@/srv/lodestone/instances/survival/libraries/net/minecraftforge/forge/1.19.2-43.2.0/unix_args.txt",
+                "nogui"
+            ]),
+            instance
+        ));
+        // runs from the instance directory, but isn't the server
+        assert!(!is_instance_server(
+            &cmd(&[
+                "tail",
+                "-f",
+                "/srv/lodestone/instances/survival/logs/latest.log"
+            ]),
+            instance
+        ));
+        assert!(!is_instance_server(
+            &cmd(&["vim", "/srv/lodestone/instances/survival/server.jar"]),
+            instance
+        ));
+        assert!(!is_instance_server(
+            &cmd(&[
+                "java",
+                "-jar",
+                "/srv/lodestone/instances/survival/logs/latest.log"
+            ]),
+            instance
+        ));
+        // another instance's server
+        assert!(!is_instance_server(
+            &cmd(&[
+                "java",
+                "-jar",
+                "/srv/lodestone/instances/creative/server.jar"
+            ]),
+            instance
+        ));
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let temp_dir = tempdir::TempDir::new("test_find_orphans").unwrap();
+        // neither is a java process, only the one in the pidfile counts
+        let mut recorded = std::process::Command::new("sleep")
+            .arg("30")
+            .current_dir(temp_dir.path())
+            .spawn()
+            .unwrap();
+        let mut bystander = std::process::Command::new("sleep")
+            .arg("30")
+            .current_dir(temp_dir.path())
+            .spawn()
+            .unwrap();
+        std::fs::write(temp_dir.path().join(PIDFILE), recorded.id().to_string()).unwrap();
+        let mut instance = KnownInstance {
+            uuid: InstanceUuid::default(),
+            name: "test".to_string(),
+            path: temp_dir.path().to_path_buf(),
+            pid: None,
+        };
+
+        let mut system = System::new();
+        let orphans = find_orphans(&mut system, std::slice::from_ref(&instance));
+        assert_eq!(
+            orphans.iter().map(|orphan| orphan.pid).collect::<Vec<_>>(),
+            vec![recorded.id()]
+        );
+        assert!(kill_orphan(&mut system, std::slice::from_ref(&instance), bystander.id()).is_err());
+
+        // the core is running the instance, so nothing of it is orphaned
+        instance.pid = Some(recorded.id());
+        assert!(find_orphans(&mut system, std::slice::from_ref(&instance)).is_empty());
+
+        recorded.kill().unwrap();
+        bystander.kill().unwrap();
+        let _ = recorded.wait();
+        let _ = bystander.wait();
+    }
+}
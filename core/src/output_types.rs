@@ -1,11 +1,13 @@
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use ts_rs::TS;
 
 use crate::{
     events::{
-        CausedBy, Event, EventInner, EventLevel, InstanceEventInner, MacroEventInner,
-        ProgressionEventInner,
+        CausedBy, Event, EventInner, EventLevel, InstanceEvent, InstanceEventInner,
+        MacroEventInner, ProgressionEventInner,
     },
+    macro_daemon::DaemonState,
     types::Snowflake,
 };
 
@@ -23,12 +25,18 @@ impl From<&Event> for ClientEvent {
     fn from(event: &Event) -> Self {
         let level = match &event.event_inner {
             EventInner::InstanceEvent(i) => match i.instance_event_inner {
-                InstanceEventInner::InstanceError { .. } => EventLevel::Error,
-                InstanceEventInner::InstanceWarning { .. } => EventLevel::Warning,
+                InstanceEventInner::InstanceError { .. }
+                | InstanceEventInner::CrashReport { .. } => EventLevel::Error,
+                InstanceEventInner::InstanceWarning { .. }
+                | InstanceEventInner::DaemonStateChanged {
+                    state: DaemonState::Restarting { .. },
+                    ..
+                } => EventLevel::Warning,
                 _ => EventLevel::Info,
             },
             EventInner::UserEvent(_) => EventLevel::Info,
             EventInner::MacroEvent(m) => match m.macro_event_inner {
+                MacroEventInner::Queued => EventLevel::Info,
                 MacroEventInner::Started => EventLevel::Info,
                 MacroEventInner::Stopped { ref exit_status } => {
                     if exit_status.is_success() {
@@ -38,6 +46,7 @@ impl From<&Event> for ClientEvent {
                     }
                 }
                 MacroEventInner::Detach => EventLevel::Info,
+                MacroEventInner::Notification { ref level, .. } => level.clone(),
             },
             EventInner::ProgressionEvent(p) => match p.progression_event_inner() {
                 ProgressionEventInner::ProgressionStart { .. } => EventLevel::Info,
@@ -51,6 +60,7 @@ impl From<&Event> for ClientEvent {
                 }
             },
             EventInner::FSEvent(_) => EventLevel::Info,
+            EventInner::CoreEvent(_) => EventLevel::Info,
         };
         ClientEvent {
             event_inner: event.event_inner.clone(),
@@ -73,3 +83,120 @@ impl AsRef<ClientEvent> for ClientEvent {
         self
     }
 }
+
+/// How events are encoded on the event websocket, picked with its `encoding` query parameter
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum EventEncoding {
+    /// Every event as a `ClientEvent`
+    #[default]
+    Json,
+    /// Frequent events as a JSON array of a numeric tag and positional fields,
+    /// see `to_compact_json`
+    Compact,
+}
+
+impl EventEncoding {
+    pub fn encode(&self, event: &Event) -> serde_json::Result<String> {
+        match self {
+            EventEncoding::Json => serde_json::to_string(event),
+            EventEncoding::Compact => to_compact_json(event),
+        }
+    }
+}
+
+/// Encodes the frequent events as arrays without field names, `details`, `level` and `caused_by`.
+/// Tags are stable, new ones are only ever added:
+///
+/// - `[0, ClientEvent]` for every other event
+/// - `[1, snowflake, event_id, progress, progress_message]` for `ProgressionUpdate`
+/// - `[2, snowflake, instance_uuid, from, to]` for `StateTransition`
+/// - `[3, snowflake, instance_uuid, player_list, players_joined, players_left]` for `PlayerChange`
+pub fn to_compact_json(event: &Event) -> serde_json::Result<String> {
+    let compact = match &event.event_inner {
+        EventInner::ProgressionEvent(progression_event) => {
+            match progression_event.progression_event_inner() {
+                ProgressionEventInner::ProgressionUpdate {
+                    progress_message,
+                    progress,
+                } => Some(json!([
+                    1,
+                    event.snowflake,
+                    progression_event.event_id(),
+                    progress,
+                    progress_message
+                ])),
+                _ => None,
+            }
+        }
+        EventInner::InstanceEvent(InstanceEvent {
+            instance_uuid,
+            instance_event_inner,
+            ..
+        }) => match instance_event_inner {
+            InstanceEventInner::StateTransition { from, to } => {
+                Some(json!([2, event.snowflake, instance_uuid, from, to]))
+            }
+            InstanceEventInner::PlayerChange {
+                player_list,
+                players_joined,
+                players_left,
+            } => Some(json!([
+                3,
+                event.snowflake,
+                instance_uuid,
+                player_list,
+                players_joined,
+                players_left
+            ])),
+            _ => None,
+        },
+        _ => None,
+    };
+    match compact {
+        Some(compact) => serde_json::to_string(&compact),
+        None => serde_json::to_string(&(0, event)),
+    }
+}
+
+#[test]
+fn test_compact_event_size() {
+    // an upload sends an update per chunk it receives
+    let (start, event_id) = Event::new_progression_event_start(
+        "Uploading world.zip",
+        Some(64.0 * 1024.0 * 1024.0),
+        None,
+        CausedBy::System,
+    );
+    let mut events = vec![start];
+    for _ in 0..1000 {
+        events.push(Event::new_progression_event_update(
+            &event_id,
+            "Uploading world.zip",
+            8192.0,
+        ));
+    }
+    let size = |encoding: EventEncoding| -> usize {
+        events
+            .iter()
+            .map(|event| encoding.encode(event).unwrap().len())
+            .sum()
+    };
+    let json_size = size(EventEncoding::Json);
+    let compact_size = size(EventEncoding::Compact);
+    assert!(
+        compact_size * 2 < json_size,
+        "json: {json_size} bytes, compact: {compact_size} bytes"
+    );
+
+    let update: serde_json::Value =
+        serde_json::from_str(&to_compact_json(&events[1]).unwrap()).unwrap();
+    assert_eq!(update[0], 1);
+    assert_eq!(update[3], 8192.0);
+    assert_eq!(update[4], "Uploading world.zip");
+    let start: serde_json::Value =
+        serde_json::from_str(&to_compact_json(&events[0]).unwrap()).unwrap();
+    assert_eq!(start[0], 0);
+    assert_eq!(start[1]["level"], "Info");
+}
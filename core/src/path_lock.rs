@@ -0,0 +1,129 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use dashmap::DashMap;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// Advisory per-path locks to serialize writes to the same file
+///
+/// Writes to different paths do not contend with each other,
+/// and entries are removed once no one holds or waits on them.
+///
+/// Note that these locks are process-local: they only coordinate writers going through the core,
+/// and do nothing against other processes (or macros using the filesystem directly)
+#[derive(Clone, Default)]
+pub struct PathLocks {
+    locks: Arc<DashMap<PathBuf, Arc<Mutex<()>>>>,
+}
+
+pub struct PathLockGuard {
+    // Option so the guard can be released before trying to clean up the entry
+    guard: Option<OwnedMutexGuard<()>>,
+    path: PathBuf,
+    locks: Arc<DashMap<PathBuf, Arc<Mutex<()>>>>,
+}
+
+impl Drop for PathLockGuard {
+    fn drop(&mut self) {
+        self.guard.take();
+        // the map holds the only reference if no one else holds or waits on the lock
+        // this check is done under the shard lock, so no one can clone the mutex in between
+        self.locks
+            .remove_if(&self.path, |_, mutex| Arc::strong_count(mutex) == 1);
+    }
+}
+
+/// Resolves the path used as the lock key, so different spellings of the same file share a lock
+///
+/// The file may not exist yet (e.g. the destination of a move),
+/// in which case only the parent is resolved
+async fn canonical_key(path: &Path) -> PathBuf {
+    if let Ok(canonical) = tokio::fs::canonicalize(path).await {
+        return canonical;
+    }
+    if let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) {
+        if let Ok(canonical_parent) = tokio::fs::canonicalize(parent).await {
+            return canonical_parent.join(file_name);
+        }
+    }
+    path.to_owned()
+}
+
+impl PathLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits until no one else holds the lock for `path`, and holds it until the guard is dropped
+    pub async fn lock(&self, path: impl AsRef<Path>) -> PathLockGuard {
+        let path = canonical_key(path.as_ref()).await;
+        let mutex = self.locks.entry(path.clone()).or_default().clone();
+        PathLockGuard {
+            guard: Some(mutex.lock_owned().await),
+            path,
+            locks: self.locks.clone(),
+        }
+    }
+
+    /// Locks multiple paths at once
+    ///
+    /// Paths are always locked in the same order,
+    /// so callers locking overlapping sets of paths can't deadlock each other
+    pub async fn lock_all(&self, paths: &[&Path]) -> Vec<PathLockGuard> {
+        let mut keys = Vec::with_capacity(paths.len());
+        for path in paths {
+            keys.push(canonical_key(path).await);
+        }
+        keys.sort();
+        keys.dedup();
+        let mut guards = Vec::with_capacity(keys.len());
+        for key in keys {
+            guards.push(self.lock(key).await);
+        }
+        guards
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_same_path_serializes() {
+        let locks = PathLocks::new();
+        let guard = locks.lock("/tmp/lodestone_path_lock_test").await;
+        let second = tokio::time::timeout(
+            Duration::from_millis(50),
+            locks.lock("/tmp/lodestone_path_lock_test"),
+        )
+        .await;
+        assert!(second.is_err());
+        drop(guard);
+        let second = tokio::time::timeout(
+            Duration::from_millis(50),
+            locks.lock("/tmp/lodestone_path_lock_test"),
+        )
+        .await;
+        assert!(second.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_different_paths_and_cleanup() {
+        let locks = PathLocks::new();
+        let a = locks.lock("/tmp/lodestone_path_lock_test_a").await;
+        let b = tokio::time::timeout(
+            Duration::from_millis(50),
+            locks.lock("/tmp/lodestone_path_lock_test_b"),
+        )
+        .await;
+        assert!(b.is_ok());
+        assert_eq!(locks.locks.len(), 2);
+        drop(a);
+        drop(b);
+        assert!(locks.locks.is_empty());
+    }
+}
@@ -2,11 +2,50 @@ use std::{collections::HashSet, net::SocketAddrV4};
 
 use color_eyre::eyre::{eyre, Context};
 use serde::{Deserialize, Serialize};
+use ts_rs::TS;
 
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
+
+/// Ports instances are confined to, both ends included
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct PortRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl PortRange {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.start == 0 || self.end > u16::MAX as u32 || self.start > self.end {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Invalid port range {}-{}, ports go from 1 to {}",
+                    self.start,
+                    self.end,
+                    u16::MAX
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, port: u32) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, TS)]
+#[ts(export)]
+pub struct PortAllocations {
+    /// `None` if instances can use any port
+    pub range: Option<PortRange>,
+    pub allocated: Vec<u32>,
+}
 
 pub struct PortManager {
     allocated_ports: HashSet<u32>,
+    range: Option<PortRange>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
@@ -16,12 +55,64 @@ pub struct PortStatus {
 }
 
 impl PortManager {
-    pub fn new(allocated_ports: HashSet<u32>) -> PortManager {
-        PortManager { allocated_ports }
+    pub fn new(allocated_ports: HashSet<u32>, range: Option<PortRange>) -> PortManager {
+        PortManager {
+            allocated_ports,
+            range,
+        }
+    }
+
+    /// Instances already on ports outside a new range keep them
+    pub fn set_range(&mut self, range: Option<PortRange>) {
+        self.range = range;
     }
 
-    pub fn allocate(&mut self, start_port: u32) -> u32 {
-        if self.allocated_ports.contains(&start_port) {
+    pub fn allocations(&self) -> PortAllocations {
+        let mut allocated: Vec<u32> = self.allocated_ports.iter().copied().collect();
+        allocated.sort_unstable();
+        PortAllocations {
+            range: self.range,
+            allocated,
+        }
+    }
+
+    /// Fails if `port` is outside the configured range
+    pub fn check_in_range(&self, port: u32) -> Result<(), Error> {
+        match self.range {
+            Some(range) if !range.contains(port) => Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Port {} is outside of the range {}-{} reserved for instances",
+                    port,
+                    range.start,
+                    range.end
+                ),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Allocates the lowest free port in the configured range,
+    /// or the first free port from `start_port` if there is none
+    pub fn allocate(&mut self, start_port: u32) -> Result<u32, Error> {
+        if let Some(range) = self.range {
+            let port = (range.start..=range.end)
+                .find(|port| {
+                    !self.allocated_ports.contains(port)
+                        && port_scanner::local_port_available(*port as u16)
+                })
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::Conflict,
+                    source: eyre!(
+                        "No free port left in the range {}-{}",
+                        range.start,
+                        range.end
+                    ),
+                })?;
+            self.allocated_ports.insert(port);
+            return Ok(port);
+        }
+        Ok(if self.allocated_ports.contains(&start_port) {
             let mut new_port = start_port + 1;
             while self.allocated_ports.contains(&new_port)
                 || !port_scanner::local_port_available(new_port as u16)
@@ -33,7 +124,7 @@ impl PortManager {
         } else {
             self.allocated_ports.insert(start_port);
             start_port
-        }
+        })
     }
 
     pub fn port_status(&self, port: u32) -> PortStatus {
@@ -80,3 +171,29 @@ impl PortManager {
         .unwrap()
     }
 }
+
+#[test]
+fn test_port_range() {
+    assert!(PortRange { start: 0, end: 10 }.validate().is_err());
+    assert!(PortRange { start: 20, end: 10 }.validate().is_err());
+    assert!(PortRange {
+        start: 25565,
+        end: 70000
+    }
+    .validate()
+    .is_err());
+
+    let mut port_manager = PortManager::new(
+        HashSet::from([41000]),
+        Some(PortRange {
+            start: 41000,
+            end: 41002,
+        }),
+    );
+    assert!(port_manager.check_in_range(41001).is_ok());
+    assert!(port_manager.check_in_range(25565).is_err());
+    // the lowest port that isn't allocated, unless something else is listening on it
+    let port = port_manager.allocate(25565).unwrap();
+    assert!((41001..=41002).contains(&port));
+    assert_eq!(port_manager.allocations().allocated[0], 41000);
+}
@@ -1,5 +1,5 @@
 use lazy_static::lazy_static;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use once_cell::sync::OnceCell;
 use semver::{BuildMetadata, Prerelease};
@@ -10,12 +10,22 @@ pub fn lodestone_path() -> &'static PathBuf {
     LODESTONE_PATH.get().unwrap()
 }
 
+/// `None` until `init_paths` is called, which tests usually don't
+pub fn try_lodestone_path() -> Option<&'static PathBuf> {
+    LODESTONE_PATH.get()
+}
+
 static PATH_TO_INSTANCES: OnceCell<PathBuf> = OnceCell::new();
 
 pub fn path_to_instances() -> &'static PathBuf {
     PATH_TO_INSTANCES.get().unwrap()
 }
 
+/// `None` until `init_paths` is called
+pub fn try_path_to_instances() -> Option<&'static PathBuf> {
+    PATH_TO_INSTANCES.get()
+}
+
 static PATH_TO_BINARIES: OnceCell<PathBuf> = OnceCell::new();
 
 pub fn path_to_binaries() -> &'static PathBuf {
@@ -46,6 +56,12 @@ pub fn path_to_tmp() -> &'static PathBuf {
     PATH_TO_TMP.get().unwrap()
 }
 
+static PATH_TO_TRASH: OnceCell<PathBuf> = OnceCell::new();
+
+pub fn path_to_trash() -> &'static PathBuf {
+    PATH_TO_TRASH.get().unwrap()
+}
+
 static APP_STATE: OnceCell<AppState> = OnceCell::new();
 
 pub fn init_app_state(app_state: AppState) {
@@ -56,22 +72,47 @@ pub fn app_state() -> &'static AppState {
     APP_STATE.get().unwrap()
 }
 
+/// Where instances are stored, takes precedence over `instances_path` in the global settings
+pub const INSTANCES_PATH_ENV: &str = "LODESTONE_INSTANCES_PATH";
+
+pub fn default_path_to_instances(lodestone_path: &Path) -> PathBuf {
+    lodestone_path.join("instances")
+}
+
+/// `LODESTONE_INSTANCES_PATH`, then `instances_path` in the global settings, then the default
+///
+/// The global settings are read here as they are only loaded once the paths are initialized
+pub fn resolve_path_to_instances(lodestone_path: &Path, path_to_global_settings: &Path) -> PathBuf {
+    if let Some(path) = std::env::var_os(INSTANCES_PATH_ENV).filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
+    std::fs::read(path_to_global_settings)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<GlobalSettingsData>(&bytes).ok())
+        .and_then(|global_settings| global_settings.instances_path)
+        .unwrap_or_else(|| default_path_to_instances(lodestone_path))
+}
+
 /// Initialize the paths for the lodestone instance.
 /// This function should only be called once.
 ///
 /// Also creates the directories if they don't exist.
 pub fn init_paths(lodestone_path: PathBuf) {
-    let path_to_instances = lodestone_path.join("instances");
     let path_to_binaries = lodestone_path.join("bin");
     let path_to_stores = lodestone_path.join("stores");
     let path_to_global_settings = lodestone_path.join("global_settings.json");
+    let path_to_instances = resolve_path_to_instances(&lodestone_path, &path_to_global_settings);
     let path_to_users = lodestone_path.join("stores").join("users.json");
     let path_to_tmp = lodestone_path.join("tmp");
+    let path_to_trash = lodestone_path.join(".lodestone_trash");
 
-    std::fs::create_dir_all(&path_to_instances).unwrap();
+    if let Err(e) = check_dir_writable(&path_to_instances) {
+        panic!("Instances directory is unusable: {}", e);
+    }
     std::fs::create_dir_all(&path_to_binaries).unwrap();
     std::fs::create_dir_all(&path_to_stores).unwrap();
     std::fs::create_dir_all(&path_to_tmp).unwrap();
+    std::fs::create_dir_all(&path_to_trash).unwrap();
     // std::fs::File::create(&path_to_global_settings).unwrap();
     // std::fs::File::create(&path_to_users).unwrap();
     // std::fs::File::create(&path_to_tmp).unwrap();
@@ -83,6 +124,7 @@ pub fn init_paths(lodestone_path: PathBuf) {
     let _ = PATH_TO_GLOBAL_SETTINGS.set(path_to_global_settings);
     let _ = PATH_TO_USERS.set(path_to_users);
     let _ = PATH_TO_TMP.set(path_to_tmp);
+    let _ = PATH_TO_TRASH.set(path_to_trash);
 }
 
 thread_local! {
@@ -103,7 +145,9 @@ lazy_static! {
 }
 
 use crate::generic::GenericInstance;
+use crate::global_settings::GlobalSettingsData;
 use crate::minecraft::MinecraftInstance;
+use crate::util::check_dir_writable;
 use crate::AppState;
 #[enum_dispatch::enum_dispatch(
     TInstance,
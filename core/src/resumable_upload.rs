@@ -0,0 +1,529 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use color_eyre::eyre::{eyre, Context};
+use futures_util::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::error;
+use ts_rs::TS;
+
+use crate::{
+    auth::{user::User, user_id::UserId},
+    error::{Error, ErrorKind},
+    prelude::path_to_stores,
+    trash::move_path,
+    upload_filter::{check_upload, UploadHead, UploadRule, SNIFF_LEN},
+    util::{check_disk_space, rand_alphanumeric, resolve_path_conflict},
+};
+
+/// Uploads without a chunk or status request for this long are removed
+const UPLOAD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const DATA_FILE: &str = "data";
+const STATE_FILE: &str = "upload.json";
+
+/// Uploads with a chunk being written or being completed, so requests for them can't interleave
+static BUSY_UPLOADS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Kept in the stores rather than the temporary directory, so uploads survive a restart of the core
+fn path_to_uploads() -> PathBuf {
+    path_to_stores().join("uploads")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UploadState {
+    id: String,
+    uid: UserId,
+    /// Directory the file is moved into once complete
+    destination: PathBuf,
+    name: String,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ResumableUploadStatus {
+    pub id: String,
+    pub name: String,
+    /// Bytes received so far, where the next chunk starts
+    #[ts(type = "number")]
+    pub offset: u64,
+    /// Declared when the upload was started
+    #[ts(type = "number")]
+    pub size: u64,
+}
+
+struct BusyGuard(String);
+
+impl BusyGuard {
+    fn acquire(id: &str) -> Result<Self, Error> {
+        if !BUSY_UPLOADS.lock().unwrap().insert(id.to_string()) {
+            return Err(Error {
+                kind: ErrorKind::Conflict,
+                source: eyre!("Another request for upload {} is in progress", id),
+            });
+        }
+        Ok(Self(id.to_string()))
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        BUSY_UPLOADS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// A file uploaded in chunks, each appended at the offset the previous one ended at.
+///
+/// The received bytes and the upload's metadata are kept in `stores/uploads/<id>/`,
+/// so an interrupted upload resumes from its status instead of starting over
+pub struct ResumableUpload {
+    dir: PathBuf,
+    state: UploadState,
+}
+
+impl ResumableUpload {
+    /// `size` is the size of the whole file, chunks past it are rejected
+    pub async fn create(
+        uid: UserId,
+        destination: PathBuf,
+        name: String,
+        size: u64,
+    ) -> Result<Self, Error> {
+        Self::create_in(&path_to_uploads(), uid, destination, name, size).await
+    }
+
+    async fn create_in(
+        uploads_dir: &Path,
+        uid: UserId,
+        destination: PathBuf,
+        name: String,
+        size: u64,
+    ) -> Result<Self, Error> {
+        // the bytes are received in the stores, then moved to the destination
+        check_disk_space(&destination, size)?;
+        let id = rand_alphanumeric(32);
+        let dir = uploads_dir.join(&id);
+        crate::util::fs::create_dir_all(&dir).await?;
+        if let Err(e) = check_disk_space(&dir, size) {
+            remove_upload_dir(&dir).await;
+            return Err(e);
+        }
+        let state = UploadState {
+            id,
+            uid,
+            destination,
+            name,
+            size,
+        };
+        tokio::fs::File::create(dir.join(DATA_FILE))
+            .await
+            .context("Failed to create upload file")?;
+        let upload = Self { dir, state };
+        upload.write_state().await?;
+        Ok(upload)
+    }
+
+    async fn write_state(&self) -> Result<(), Error> {
+        crate::util::fs::write_atomic(
+            self.dir.join(STATE_FILE),
+            serde_json::to_vec(&self.state).context("Failed to serialize upload")?,
+        )
+        .await
+    }
+
+    /// Only the user who started the upload and the owner can continue it,
+    /// and only to the destination it was started for
+    pub async fn open(id: &str, requester: &User, destination: &Path) -> Result<Self, Error> {
+        Self::open_in(&path_to_uploads(), id, requester, destination).await
+    }
+
+    async fn open_in(
+        uploads_dir: &Path,
+        id: &str,
+        requester: &User,
+        destination: &Path,
+    ) -> Result<Self, Error> {
+        let not_found = || Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("No upload with id {}", id),
+        };
+        // ids are generated alphanumeric, anything else could escape the uploads directory
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(not_found());
+        }
+        let dir = uploads_dir.join(id);
+        let state: UploadState = match tokio::fs::read(dir.join(STATE_FILE)).await {
+            Ok(contents) => serde_json::from_slice(&contents).context("Corrupted upload state")?,
+            Err(_) => return Err(not_found()),
+        };
+        if state.uid != requester.uid && !requester.is_owner {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("Not authorized to access this upload"),
+            });
+        }
+        if state.destination != destination {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Upload {} was started for another directory", id),
+            });
+        }
+        Ok(Self { dir, state })
+    }
+
+    async fn offset(&self) -> Result<u64, Error> {
+        Ok(tokio::fs::metadata(self.dir.join(DATA_FILE))
+            .await
+            .context("Failed to read upload file")?
+            .len())
+    }
+
+    /// Also keeps the upload from expiring, so a client can wait before resuming
+    pub async fn status(&self) -> Result<ResumableUploadStatus, Error> {
+        self.write_state().await?;
+        Ok(ResumableUploadStatus {
+            id: self.state.id.clone(),
+            name: self.state.name.clone(),
+            offset: self.offset().await?,
+            size: self.state.size,
+        })
+    }
+
+    /// The first `SNIFF_LEN` bytes received, or all of them if there are fewer
+    async fn read_head(&self) -> Result<Vec<u8>, Error> {
+        let mut head = vec![0; SNIFF_LEN];
+        let mut read = 0;
+        let mut file = tokio::fs::File::open(self.dir.join(DATA_FILE))
+            .await
+            .context("Failed to open upload file")?;
+        while read < SNIFF_LEN {
+            match file
+                .read(&mut head[read..])
+                .await
+                .context("Failed to read upload file")?
+            {
+                0 => break,
+                n => read += n,
+            }
+        }
+        head.truncate(read);
+        Ok(head)
+    }
+
+    /// Appends `body` at `offset`, which must be where the received bytes end.
+    ///
+    /// The upload is checked against `upload_rules` as soon as its first bytes are in,
+    /// and removed if it doesn't pass, so a rejected file isn't received in full.
+    ///
+    /// Bytes received before the body is interrupted are kept, the status has the offset to resume from
+    pub async fn append<S, E>(
+        &self,
+        offset: u64,
+        mut body: S,
+        upload_rules: &[UploadRule],
+    ) -> Result<u64, Error>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let _guard = BusyGuard::acquire(&self.state.id)?;
+        let current = self.offset().await?;
+        if offset != current {
+            return Err(Error {
+                kind: ErrorKind::Conflict,
+                source: eyre!(
+                    "Chunk starts at {} but the upload is at {}",
+                    offset,
+                    current
+                ),
+            });
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(DATA_FILE))
+            .await
+            .context("Failed to open upload file")?;
+        // the head may be split across chunks sent in separate requests
+        let mut head = UploadHead::new();
+        if current < SNIFF_LEN as u64 {
+            head.feed(&self.read_head().await?);
+        }
+        let path = self.state.destination.join(&self.state.name);
+        let mut rejected = false;
+        let mut received = current;
+        let result = async {
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.context("Failed to read chunk")?;
+                received += chunk.len() as u64;
+                if received > self.state.size {
+                    return Err(Error {
+                        kind: ErrorKind::PayloadTooLarge,
+                        source: eyre!(
+                            "Upload is larger than the declared {} bytes",
+                            self.state.size
+                        ),
+                    });
+                }
+                if let Some(head) = head.feed(&chunk) {
+                    if let Err(e) = check_upload(upload_rules, &path, head) {
+                        rejected = true;
+                        return Err(e);
+                    }
+                }
+                file.write_all(&chunk)
+                    .await
+                    .context("Failed to write chunk")?;
+            }
+            Ok::<(), Error>(())
+        }
+        .await;
+        file.flush().await.context("Failed to write chunk")?;
+        if rejected {
+            drop(file);
+            // the upload can't ever pass, so it isn't kept around for a retry
+            remove_upload_dir(&self.dir).await;
+        }
+        result?;
+        Ok(received)
+    }
+
+    /// Moves the file into its destination, returning where it ended up
+    pub async fn complete(self, upload_rules: &[UploadRule]) -> Result<PathBuf, Error> {
+        let _guard = BusyGuard::acquire(&self.state.id)?;
+        let data = self.dir.join(DATA_FILE);
+        let offset = self.offset().await?;
+        if offset != self.state.size {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Upload has {} of the declared {} bytes",
+                    offset,
+                    self.state.size
+                ),
+            });
+        }
+        let path = resolve_path_conflict(self.state.destination.join(&self.state.name), None);
+        // checked again as the rules may have changed since the first chunk
+        if let Err(e) = check_upload(upload_rules, &path, &self.read_head().await?) {
+            // the upload can't ever pass, so it isn't kept around for a retry
+            remove_upload_dir(&self.dir).await;
+            return Err(e);
+        }
+        move_path(data, path.clone()).await?;
+        remove_upload_dir(&self.dir).await;
+        Ok(path)
+    }
+}
+
+async fn remove_upload_dir(dir: &Path) {
+    if let Err(e) = tokio::fs::remove_dir_all(dir).await {
+        error!("Failed to remove upload {} : {}", dir.display(), e);
+    }
+}
+
+/// Removes uploads that weren't touched for `ttl`
+async fn purge_abandoned_uploads(uploads_dir: &Path, ttl: Duration) -> Result<(), Error> {
+    let mut entries = match tokio::fs::read_dir(uploads_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(eyre!("Failed to read uploads directory : {}", e).into()),
+    };
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context("Failed to read uploads directory")?
+    {
+        let dir = entry.path();
+        let id = entry.file_name().to_string_lossy().into_owned();
+        // held until the upload is removed, so a chunk can't start writing to it meanwhile
+        let _guard = match BusyGuard::acquire(&id) {
+            Ok(guard) => guard,
+            Err(_) => continue,
+        };
+        // the latest of the last chunk and the last status request
+        let last_active = [dir.join(DATA_FILE), dir.join(STATE_FILE)]
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok()?.modified().ok())
+            .max();
+        let expired = last_active
+            .and_then(|last_active| last_active.elapsed().ok())
+            .map_or(true, |elapsed| elapsed > ttl);
+        if expired {
+            remove_upload_dir(&dir).await;
+        }
+    }
+    Ok(())
+}
+
+pub async fn upload_purge_task() {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        if let Err(e) = purge_abandoned_uploads(&path_to_uploads(), UPLOAD_TTL).await {
+            error!("Failed to purge abandoned uploads : {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::user::UserPermission;
+    use crate::upload_filter::UploadRule;
+
+    fn body(chunks: &[&'static [u8]]) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Unpin {
+        futures_util::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn user() -> User {
+        User::new(
+            "test".to_string(),
+            "test",
+            false,
+            false,
+            UserPermission::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_reopen() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let user = user();
+        let upload = ResumableUpload::create_in(
+            uploads_dir.path(),
+            user.uid.clone(),
+            destination.path().to_path_buf(),
+            "world.txt".to_string(),
+            11,
+        )
+        .await
+        .unwrap();
+        let id = upload.status().await.unwrap().id;
+        assert_eq!(upload.append(0, body(&[b"hello"]), &[]).await.unwrap(), 5);
+        drop(upload);
+
+        let upload = ResumableUpload::open_in(uploads_dir.path(), &id, &user, destination.path())
+            .await
+            .unwrap();
+        assert_eq!(upload.status().await.unwrap().offset, 5);
+        assert_eq!(upload.append(5, body(&[b" world"]), &[]).await.unwrap(), 11);
+        let path = upload.complete(&[]).await.unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "hello world");
+        assert!(!uploads_dir.path().join(&id).exists());
+    }
+
+    #[tokio::test]
+    async fn test_append_checks() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let upload = ResumableUpload::create_in(
+            uploads_dir.path(),
+            user().uid,
+            destination.path().to_path_buf(),
+            "world.txt".to_string(),
+            8,
+        )
+        .await
+        .unwrap();
+        upload.append(0, body(&[b"abc"]), &[]).await.unwrap();
+
+        // chunks must continue where the received bytes end
+        let conflict = upload.append(1, body(&[b"def"]), &[]).await.unwrap_err();
+        assert!(matches!(conflict.kind, ErrorKind::Conflict));
+        assert_eq!(upload.status().await.unwrap().offset, 3);
+
+        // nor can they go past the declared size
+        let too_large = upload
+            .append(3, body(&[b"def", b"ghi"]), &[])
+            .await
+            .unwrap_err();
+        assert!(matches!(too_large.kind, ErrorKind::PayloadTooLarge));
+        assert!(upload.complete(&[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_by_upload_rules() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let rules = vec![UploadRule {
+            directory: destination.path().to_path_buf(),
+            allow: None,
+            deny: vec!["application/x-executable".to_string()],
+        }];
+        let create = |name: &str| {
+            ResumableUpload::create_in(
+                uploads_dir.path(),
+                user().uid,
+                destination.path().to_path_buf(),
+                name.to_string(),
+                32,
+            )
+        };
+
+        // rejected as soon as the head is in, even split across requests
+        let upload = create("server").await.unwrap();
+        let id = upload.status().await.unwrap().id;
+        upload.append(0, body(&[b"\x7fEL"]), &rules).await.unwrap();
+        let rejected = upload
+            .append(3, body(&[b"F", b"0123456789abcdef"]), &rules)
+            .await
+            .unwrap_err();
+        assert!(matches!(rejected.kind, ErrorKind::BadRequest));
+        assert!(!uploads_dir.path().join(&id).exists());
+
+        // and on completion, for rules added after the first chunk
+        let upload = create("server").await.unwrap();
+        let id = upload.status().await.unwrap().id;
+        upload
+            .append(0, body(&[b"\x7fELF0123456789abcdef0123456789ab"]), &[])
+            .await
+            .unwrap();
+        assert!(upload.complete(&rules).await.is_err());
+        assert!(!uploads_dir.path().join(&id).exists());
+        assert!(!destination.path().join("server").exists());
+    }
+
+    #[tokio::test]
+    async fn test_purge_abandoned_uploads() {
+        let uploads_dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let create = || {
+            ResumableUpload::create_in(
+                uploads_dir.path(),
+                user().uid,
+                destination.path().to_path_buf(),
+                "world.txt".to_string(),
+                8,
+            )
+        };
+        let idle = create().await.unwrap().status().await.unwrap().id;
+        let busy = create().await.unwrap().status().await.unwrap().id;
+
+        purge_abandoned_uploads(uploads_dir.path(), UPLOAD_TTL)
+            .await
+            .unwrap();
+        assert!(uploads_dir.path().join(&idle).exists());
+
+        // an upload with a chunk being written is kept even if it expired
+        let guard = BusyGuard::acquire(&busy).unwrap();
+        purge_abandoned_uploads(uploads_dir.path(), Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(!uploads_dir.path().join(&idle).exists());
+        assert!(uploads_dir.path().join(&busy).exists());
+        drop(guard);
+    }
+}
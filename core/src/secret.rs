@@ -0,0 +1,201 @@
+use std::path::Path;
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
+use color_eyre::eyre::{eyre, Context};
+use once_cell::sync::OnceCell;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::Error;
+
+/// Environment variable holding the passphrase the master key is derived from
+///
+/// It is only read from the environment at startup, not set through first time setup,
+/// as the key has to be derived again on every start to read back the secrets
+pub const MASTER_PASSPHRASE_ENV: &str = "LODESTONE_MASTER_PASSPHRASE";
+
+const SALT_FILE_NAME: &str = ".master_key_salt";
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+/// Stored before plaintext values that would otherwise look encrypted, `enc:` is reserved
+const ESCAPED_PREFIX: &str = "enc:none:";
+const NONCE_LEN: usize = 24;
+
+static MASTER_KEY: OnceCell<[u8; 32]> = OnceCell::new();
+
+/// Derives the master key from `passphrase`, with a salt kept in `lodestone_path`
+///
+/// Without a master key, `SecretString`s are stored in plaintext
+pub fn init_master_key(passphrase: &str, lodestone_path: &Path) -> Result<(), Error> {
+    let path_to_salt = lodestone_path.join(SALT_FILE_NAME);
+    let salt = if path_to_salt.exists() {
+        std::fs::read(&path_to_salt).context("Failed to read master key salt")?
+    } else {
+        let mut salt = vec![0; 16];
+        OsRng.fill_bytes(&mut salt);
+        crate::util::fs::write_atomic_sync(&path_to_salt, &salt)?;
+        salt
+    };
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|e| eyre!("Failed to derive master key: {}", e))?;
+    MASTER_KEY
+        .set(key)
+        .map_err(|_| eyre!("Master key is already initialized"))?;
+    Ok(())
+}
+
+pub fn has_master_key() -> bool {
+    MASTER_KEY.get().is_some()
+}
+
+/// A string that is encrypted with the master key when serialized
+///
+/// Plaintext values are still accepted when deserializing,
+/// so existing configs are encrypted the next time they are written
+#[derive(Clone, Default)]
+pub struct SecretString {
+    value: String,
+    encrypted_at_rest: bool,
+}
+
+impl SecretString {
+    pub fn new(value: String) -> Self {
+        Self {
+            value,
+            encrypted_at_rest: false,
+        }
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    /// Whether the value should be written again to be encrypted
+    pub fn needs_encryption(&self) -> bool {
+        has_master_key() && !self.encrypted_at_rest
+    }
+
+    /// The value as written at rest, encrypted if there is a master key
+    pub fn to_stored(&self) -> Result<String, Error> {
+        match MASTER_KEY.get() {
+            Some(key) => encrypt(key, &self.value),
+            None if self.value.starts_with("enc:") => {
+                Ok(format!("{}{}", ESCAPED_PREFIX, self.value))
+            }
+            None => Ok(self.value.clone()),
+        }
+    }
+
+    /// Reads back a value written by `to_stored`, plaintext is taken as is
+    pub fn from_stored(stored: String) -> Result<Self, Error> {
+        if let Some(value) = stored.strip_prefix(ESCAPED_PREFIX) {
+            return Ok(Self::new(value.to_string()));
+        }
+        match stored.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(encoded) => {
+                let key = MASTER_KEY.get().ok_or_else(|| {
+                    eyre!(
+                        "Secret is encrypted but {} is not set",
+                        MASTER_PASSPHRASE_ENV
+                    )
+                })?;
+                Ok(Self {
+                    value: decrypt(key, encoded)?,
+                    encrypted_at_rest: true,
+                })
+            }
+            None => Ok(Self::new(stored)),
+        }
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        Self::new(value)
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl Eq for SecretString {}
+
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretString(***)")
+    }
+}
+
+fn encrypt(key: &[u8; 32], plaintext: &str) -> Result<String, Error> {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(XNonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| eyre!("Failed to encrypt secret"))?;
+    let mut payload = nonce.to_vec();
+    payload.extend(ciphertext);
+    Ok(format!("{}{}", ENCRYPTED_PREFIX, base64::encode(payload)))
+}
+
+fn decrypt(key: &[u8; 32], encoded: &str) -> Result<String, Error> {
+    let payload = base64::decode(encoded).context("Failed to decode secret")?;
+    if payload.len() < NONCE_LEN {
+        return Err(eyre!("Secret is too short").into());
+    }
+    let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+    let plaintext = XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| eyre!("Failed to decrypt secret, was the master passphrase changed?"))?;
+    Ok(String::from_utf8(plaintext).context("Secret is not valid UTF-8")?)
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_stored()
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::from_stored(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = [7; 32];
+        let encrypted = encrypt(&key, "hunter2").unwrap();
+        assert!(encrypted.starts_with(ENCRYPTED_PREFIX));
+        assert!(!encrypted.contains("hunter2"));
+        let encoded = encrypted.strip_prefix(ENCRYPTED_PREFIX).unwrap();
+        assert_eq!(decrypt(&key, encoded).unwrap(), "hunter2");
+        assert!(decrypt(&[8; 32], encoded).is_err());
+    }
+
+    #[test]
+    fn test_plaintext_with_prefix_round_trips() {
+        // no test sets the master key, so values are stored as plaintext
+        for value in ["enc:v1:not-ciphertext", "enc:none:x", "enc:", "plain"] {
+            let stored = SecretString::new(value.to_string()).to_stored().unwrap();
+            assert_eq!(SecretString::from_stored(stored).unwrap().expose(), value);
+        }
+        assert_eq!(
+            SecretString::new("plain".to_string()).to_stored().unwrap(),
+            "plain"
+        );
+    }
+}
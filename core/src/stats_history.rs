@@ -0,0 +1,186 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use ts_rs::TS;
+
+use crate::{
+    prelude::GameInstance,
+    traits::t_server::{State, TServer},
+    types::InstanceUuid,
+};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// An hour of samples
+const MAX_SAMPLES_PER_INSTANCE: usize = 720;
+/// Upper bound on samples kept across all instances,
+/// each instance gets a smaller share once too many are running
+const MAX_TOTAL_SAMPLES: usize = MAX_SAMPLES_PER_INSTANCE * 16;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct StatsSample {
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+    /// Percentage of total CPU capacity
+    pub cpu_usage: f32,
+    /// Resident set size in bytes
+    pub memory_usage: u64,
+}
+
+struct InstanceStatsHistory {
+    /// Start time of the process the samples belong to
+    start_time: Option<u64>,
+    samples: VecDeque<StatsSample>,
+}
+
+/// CPU and memory history of running instances
+///
+/// Only the current run of an instance is kept, its history is dropped when it stops
+#[derive(Default)]
+pub struct StatsHistory {
+    histories: HashMap<InstanceUuid, InstanceStatsHistory>,
+}
+
+impl StatsHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn capacity_per_instance(&self) -> usize {
+        (MAX_TOTAL_SAMPLES / self.histories.len().max(1)).min(MAX_SAMPLES_PER_INSTANCE)
+    }
+
+    pub fn record(&mut self, uuid: &InstanceUuid, start_time: Option<u64>, sample: StatsSample) {
+        let history = self
+            .histories
+            .entry(uuid.clone())
+            .or_insert_with(|| InstanceStatsHistory {
+                start_time,
+                samples: VecDeque::new(),
+            });
+        // the instance restarted between two samples
+        if history.start_time != start_time {
+            history.start_time = start_time;
+            history.samples.clear();
+        }
+        history.samples.push_back(sample);
+        let capacity = self.capacity_per_instance();
+        for history in self.histories.values_mut() {
+            while history.samples.len() > capacity {
+                history.samples.pop_front();
+            }
+        }
+    }
+
+    pub fn clear(&mut self, uuid: &InstanceUuid) {
+        self.histories.remove(uuid);
+    }
+
+    /// Samples taken at or after `since`, oldest first
+    pub fn get_since(&self, uuid: &InstanceUuid, since: Option<i64>) -> Vec<StatsSample> {
+        self.histories
+            .get(uuid)
+            .map(|history| {
+                history
+                    .samples
+                    .iter()
+                    .filter(|sample| since.map_or(true, |since| sample.timestamp >= since))
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+pub async fn stats_history_task(
+    instances: Arc<DashMap<InstanceUuid, GameInstance>>,
+    stats_history: Arc<Mutex<StatsHistory>>,
+) {
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        // clone out of the map so no shard lock is held across awaits
+        let instances: Vec<(InstanceUuid, GameInstance)> = instances
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        let mut running = Vec::new();
+        for (uuid, instance) in instances {
+            if instance.state().await != State::Running {
+                continue;
+            }
+            let report = instance.monitor().await;
+            running.push((uuid, report));
+        }
+        let timestamp = chrono::Utc::now().timestamp();
+        let mut stats_history = stats_history.lock().await;
+        let stopped: Vec<InstanceUuid> = stats_history
+            .histories
+            .keys()
+            .filter(|uuid| {
+                !running
+                    .iter()
+                    .any(|(running_uuid, _)| running_uuid == *uuid)
+            })
+            .cloned()
+            .collect();
+        for uuid in stopped {
+            stats_history.clear(&uuid);
+        }
+        for (uuid, report) in running {
+            if let (Some(cpu_usage), Some(memory_usage)) = (report.cpu_usage, report.memory_usage) {
+                stats_history.record(
+                    &uuid,
+                    report.start_time,
+                    StatsSample {
+                        timestamp,
+                        cpu_usage,
+                        memory_usage,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: i64) -> StatsSample {
+        StatsSample {
+            timestamp,
+            cpu_usage: 0.0,
+            memory_usage: 0,
+        }
+    }
+
+    #[test]
+    fn test_history_bounds() {
+        let mut history = StatsHistory::new();
+        let uuid = InstanceUuid::default();
+        for i in 0..(MAX_SAMPLES_PER_INSTANCE as i64 + 10) {
+            history.record(&uuid, Some(0), sample(i));
+        }
+        let samples = history.get_since(&uuid, None);
+        assert_eq!(samples.len(), MAX_SAMPLES_PER_INSTANCE);
+        assert_eq!(samples[0].timestamp, 10);
+        assert_eq!(
+            history.get_since(&uuid, Some(100)).len(),
+            MAX_SAMPLES_PER_INSTANCE - 90
+        );
+
+        // a restart starts a new history
+        history.record(&uuid, Some(1), sample(0));
+        assert_eq!(history.get_since(&uuid, None).len(), 1);
+
+        history.clear(&uuid);
+        assert!(history.get_since(&uuid, None).is_empty());
+    }
+}
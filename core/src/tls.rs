@@ -0,0 +1,97 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, ErrorKind},
+    global_settings::GlobalSettings,
+};
+
+/// PEM encoded certificate chain and private key the API is served with over HTTPS
+///
+/// Every endpoint is served over TLS once enabled, including downloads, event streams and WebSockets
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct TlsSettings {
+    #[ts(type = "string")]
+    pub cert_path: PathBuf,
+    #[ts(type = "string")]
+    pub key_path: PathBuf,
+}
+
+impl TlsSettings {
+    /// `tls/cert.pem` and `tls/key.pem` in the lodestone path, used when nothing is configured
+    pub fn default_in(lodestone_path: &Path) -> Self {
+        Self {
+            cert_path: lodestone_path.join("tls").join("cert.pem"),
+            key_path: lodestone_path.join("tls").join("key.pem"),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        for path in [&self.cert_path, &self.key_path] {
+            if !path.is_absolute() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("TLS path {} must be absolute", path.display()),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn load(&self) -> Result<RustlsConfig, Error> {
+        RustlsConfig::from_pem_file(&self.cert_path, &self.key_path)
+            .await
+            .map_err(|e| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid TLS certificate or key : {e}"),
+            })
+    }
+
+    /// Swaps the certificate of a running server, open connections are kept
+    pub async fn reload(&self, config: &RustlsConfig) -> Result<(), Error> {
+        config
+            .reload_from_pem_file(&self.cert_path, &self.key_path)
+            .await
+            .map_err(|e| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid TLS certificate or key : {e}"),
+            })
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+}
+
+/// Reloads the certificate whenever the configured files or paths change,
+/// so renewed certificates (e.g. from Let's Encrypt) are picked up without a restart
+pub async fn tls_reload_task(config: RustlsConfig, global_settings: Arc<Mutex<GlobalSettings>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    let mut last_seen = None;
+    loop {
+        interval.tick().await;
+        let tls = global_settings.lock().await.tls();
+        let seen = Some((tls.modified(), tls.clone()));
+        // the first tick only records what the server was started with
+        if last_seen.is_some() && last_seen != seen {
+            match tls.reload(&config).await {
+                Ok(_) => info!("Reloaded TLS certificate"),
+                // the previous certificate is kept, e.g. while only one file has been renewed
+                Err(e) => error!("Failed to reload TLS certificate : {e}"),
+            }
+        }
+        last_seen = seen;
+    }
+}
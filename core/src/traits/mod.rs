@@ -37,6 +37,10 @@ pub struct InstanceInfo {
     pub player_count: Option<u32>,
     pub max_player_count: Option<u32>,
     pub player_list: Option<HashSet<Player>>,
+    /// The version the server reports running, which differs from `version` if the jar was swapped manually
+    pub detected_version: Option<String>,
+    /// Whether `detected_version` differs from `version`
+    pub version_mismatch: bool,
 }
 use crate::generic::GenericInstance;
 use crate::minecraft::MinecraftInstance;
@@ -47,13 +51,23 @@ use crate::types::InstanceUuid;
 pub trait TInstance:
     TConfigurable + TMacro + TPlayerManagement + TResourceManagement + TServer + Clone
 {
+    /// The version the running server reports, `None` if the implementation can't tell
+    async fn detected_version(&self) -> Option<String> {
+        None
+    }
+
     async fn get_instance_info(&self) -> InstanceInfo {
+        let version = self.version().await;
+        let detected_version = self.detected_version().await;
+        let version_mismatch = detected_version
+            .as_ref()
+            .map_or(false, |detected| *detected != version);
         InstanceInfo {
             uuid: self.uuid().await,
             name: self.name().await,
             game_type: self.game_type().await,
             description: self.description().await,
-            version: self.version().await,
+            version,
             port: self.port().await,
             creation_time: self.creation_time().await,
             path: self.path().await.display().to_string(),
@@ -63,6 +77,8 @@ pub trait TInstance:
             player_count: self.get_player_count().await.ok(),
             max_player_count: self.get_max_player_count().await.ok(),
             player_list: self.get_player_list().await.ok(),
+            detected_version,
+            version_mismatch,
         }
     }
 }
@@ -531,6 +531,14 @@ impl SetupManifest {
                 });
             }
         }
+        // sections left out entirely still need their required settings
+        for (section_id, section) in self.setting_sections.iter() {
+            if !value.setting_sections.contains_key(section_id) {
+                section.validate_section(&SectionManifestValue {
+                    settings: IndexMap::new(),
+                })?;
+            }
+        }
         Ok(())
     }
 
@@ -777,6 +785,15 @@ impl SectionManifest {
                 });
             }
         }
+        // a missing setting is the same as one without a value
+        for (setting_id, setting) in self.settings.iter() {
+            if !value.settings.contains_key(setting_id) {
+                setting.validate_setting(&None).map_err(|_| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Setting {} is required", setting_id),
+                })?;
+            }
+        }
         Ok(())
     }
 }
@@ -6,7 +6,7 @@ use ts_rs::TS;
 use crate::{
     error::{Error, ErrorKind},
     events::CausedBy,
-    macro_executor::MacroPID,
+    macro_executor::{MacroDiagnostic, MacroPID},
     traits::GameInstance,
 };
 
@@ -74,6 +74,7 @@ pub trait TMacro {
         &self,
         _name: &str,
         _args: Vec<String>,
+        _structured_args: Option<serde_json::Value>,
         _caused_by: CausedBy,
     ) -> Result<TaskEntry, Error> {
         Err(Error {
@@ -87,4 +88,16 @@ pub trait TMacro {
             source: eyre!("This instance does not support killing macro"),
         })
     }
+    /// Checks the macro `name` without running it, or `source` as a new macro if `name` is `None`.
+    /// Only syntax and imports are checked, not types
+    async fn validate_macro(
+        &self,
+        _name: Option<&str>,
+        _source: Option<String>,
+    ) -> Result<Vec<MacroDiagnostic>, Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support validating macro"),
+        })
+    }
 }
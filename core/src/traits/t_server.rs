@@ -67,10 +67,11 @@ impl ToString for State {
 }
 
 impl State {
+    /// `on_transit` is called with the current and the new state if the transition is valid
     pub fn try_new_state(
         &self,
         action: StateAction,
-        on_transit: Option<&dyn Fn(State)>,
+        on_transit: Option<&dyn Fn(State, State)>,
     ) -> Result<State, Error> {
         let state = match (*self, action) {
             (State::Starting, StateAction::UserStart) => {
@@ -99,7 +100,7 @@ impl State {
             (State::Error, StateAction::UserStop) => todo!(),
         }?;
         if let Some(on_transit) = on_transit {
-            on_transit(state);
+            on_transit(*self, state);
         }
         Ok(state)
     }
@@ -107,7 +108,7 @@ impl State {
     pub fn try_transition(
         &mut self,
         action: StateAction,
-        on_transit: Option<&dyn Fn(State)>,
+        on_transit: Option<&dyn Fn(State, State)>,
     ) -> Result<(), Error> {
         let new_state = self.try_new_state(action, on_transit)?;
         *self = new_state;
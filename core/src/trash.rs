@@ -0,0 +1,176 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+use ts_rs::TS;
+
+use crate::{
+    error::{Error, ErrorKind},
+    global_settings::GlobalSettings,
+    prelude::path_to_trash,
+    util::{rand_alphanumeric, resolve_path_conflict, scoped_join_win_safe},
+};
+
+/// Name of the metadata file stored next to each trashed item
+const TRASH_ENTRY_METADATA: &str = "entry.json";
+
+/// A file or directory that was soft deleted
+///
+/// Each entry lives in its own `.lodestone_trash/<id>/` directory, holding the trashed item
+/// under its original name along with a metadata file
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct TrashEntry {
+    pub id: String,
+    pub name: String,
+    pub original_path: PathBuf,
+    pub is_dir: bool,
+    /// Unix timestamp in seconds
+    pub deletion_time: i64,
+}
+
+/// Renames `from` to `to`, falling back to copy and delete if they are on different filesystems
+pub(crate) async fn move_path(from: PathBuf, to: PathBuf) -> Result<(), Error> {
+    if tokio::fs::rename(&from, &to).await.is_ok() {
+        return Ok(());
+    }
+    tokio::task::spawn_blocking(move || {
+        if from.is_dir() {
+            let mut options = fs_extra::dir::CopyOptions::new();
+            options.copy_inside = true;
+            fs_extra::dir::move_dir(&from, &to, &options).map(|_| ())
+        } else {
+            fs_extra::file::move_file(&from, &to, &fs_extra::file::CopyOptions::new()).map(|_| ())
+        }
+        .context(format!(
+            "Failed to move {} to {}",
+            from.display(),
+            to.display()
+        ))
+    })
+    .await
+    .context("Failed to join blocking task")??;
+    Ok(())
+}
+
+pub async fn move_to_trash(path: &Path) -> Result<TrashEntry, Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Cannot move {} to trash", path.display()),
+        })?
+        .to_string_lossy()
+        .to_string();
+    let deletion_time = chrono::Utc::now().timestamp();
+    let entry = TrashEntry {
+        id: format!("{}-{}", deletion_time, rand_alphanumeric(8)),
+        name,
+        original_path: path.to_owned(),
+        is_dir: path.is_dir(),
+        deletion_time,
+    };
+    let entry_dir = path_to_trash().join(&entry.id);
+    crate::util::fs::create_dir_all(&entry_dir).await?;
+    // the metadata is written first, an item moved into the trash without it couldn't be listed or restored
+    let result = async {
+        crate::util::fs::write_all(
+            entry_dir.join(TRASH_ENTRY_METADATA),
+            serde_json::to_string_pretty(&entry)
+                .context("Failed to serialize trash entry. This is a bug, please report it.")?,
+        )
+        .await?;
+        move_path(path.to_owned(), entry_dir.join(&entry.name)).await
+    }
+    .await;
+    if let Err(e) = result {
+        let _ = tokio::fs::remove_dir_all(&entry_dir).await;
+        return Err(e);
+    }
+    Ok(entry)
+}
+
+async fn read_trash_entry(entry_dir: &Path) -> Result<TrashEntry, Error> {
+    Ok(serde_json::from_str(
+        &crate::util::fs::read_to_string(entry_dir.join(TRASH_ENTRY_METADATA)).await?,
+    )
+    .context(format!(
+        "Failed to parse trash entry at {}",
+        entry_dir.display()
+    ))?)
+}
+
+/// Lists all trash entries, most recently deleted first
+pub async fn list_trash() -> Result<Vec<TrashEntry>, Error> {
+    let mut ret = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(path_to_trash())
+        .await
+        .context("Failed to read trash directory")?;
+    while let Some(dir_entry) = read_dir
+        .next_entry()
+        .await
+        .context("Failed to read trash directory")?
+    {
+        match read_trash_entry(&dir_entry.path()).await {
+            Ok(entry) => ret.push(entry),
+            Err(e) => error!("Skipping malformed trash entry : {e}"),
+        }
+    }
+    ret.sort_by(|a, b| b.deletion_time.cmp(&a.deletion_time));
+    Ok(ret)
+}
+
+/// Moves a trashed item back to where it was deleted from
+///
+/// If something now exists at the original path, the item is restored under a non-conflicting name
+pub async fn restore_from_trash(id: &str) -> Result<(TrashEntry, PathBuf), Error> {
+    let entry_dir = scoped_join_win_safe(path_to_trash(), id)?;
+    if !entry_dir.is_dir() {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Trash entry {} not found", id),
+        });
+    }
+    let entry = read_trash_entry(&entry_dir).await?;
+    let destination = resolve_path_conflict(entry.original_path.clone(), None);
+    if let Some(parent) = destination.parent() {
+        crate::util::fs::create_dir_all(parent).await?;
+    }
+    move_path(entry_dir.join(&entry.name), destination.clone()).await?;
+    crate::util::fs::remove_dir_all(&entry_dir).await?;
+    Ok((entry, destination))
+}
+
+/// Permanently deletes trash entries older than `retention`
+pub async fn purge_trash(retention: Duration) -> Result<(), Error> {
+    let cutoff = chrono::Utc::now().timestamp() - retention.as_secs() as i64;
+    for entry in list_trash().await? {
+        if entry.deletion_time < cutoff {
+            info!(
+                "Purging {} from trash, deleted from {}",
+                entry.name,
+                entry.original_path.display()
+            );
+            crate::util::fs::remove_dir_all(path_to_trash().join(&entry.id)).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn trash_purge_task(global_settings: Arc<Mutex<GlobalSettings>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        let retention_days = global_settings.lock().await.trash_retention_days();
+        if let Err(e) = purge_trash(Duration::from_secs(retention_days as u64 * 24 * 60 * 60)).await
+        {
+            error!("Failed to purge trash : {e}");
+        }
+    }
+}
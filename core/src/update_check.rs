@@ -0,0 +1,201 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use color_eyre::Report;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use ts_rs::TS;
+
+use crate::{
+    event_broadcaster::EventBroadcaster,
+    events::{CausedBy, CoreEvent, CoreEventInner, Event, EventInner},
+    global_settings::GlobalSettings,
+    prelude::VERSION,
+    types::Snowflake,
+};
+
+const RELEASE_URL: &str =
+    "https://api.github.com/repos/Lodestone-Team/lodestone_core/releases/latest";
+const UPDATE_GUIDE_URL: &str = "https://github.com/Lodestone-Team/lodestone/wiki/Updating";
+
+/// Wait before retrying a failed check, doubled on every failure up to the check interval
+const INITIAL_RETRY: Duration = Duration::from_secs(5 * 60);
+/// How often the settings are looked at again while checks are disabled
+const DISABLED_POLL: Duration = Duration::from_secs(60);
+/// Refreshes requested more often than this are answered from the cache
+const MIN_REFRESH: Duration = Duration::from_secs(60);
+/// A check taking longer than this fails, so a stalled connection doesn't hold up later checks
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct UpdateStatus {
+    #[ts(type = "string")]
+    pub current: Version,
+    /// Latest stable release, `None` until a check succeeds
+    #[ts(type = "string | null")]
+    pub latest: Option<Version>,
+    pub update_available: bool,
+    /// Where to read how to update
+    pub url: String,
+    /// Unix timestamp in seconds of the last check, `None` if the core never checked
+    pub checked_at: Option<i64>,
+    /// Why the last check failed, `latest` is kept from the last successful one
+    pub error: Option<String>,
+}
+
+async fn fetch_latest_release() -> Result<Version, Report> {
+    #[derive(Deserialize)]
+    struct Release {
+        tag_name: String,
+    }
+
+    let response = reqwest::Client::new()
+        .get(RELEASE_URL)
+        .timeout(FETCH_TIMEOUT)
+        .header("User-Agent", "lodestone_cli")
+        .send()
+        .await?;
+    response.error_for_status_ref()?;
+
+    let release: Release = response.json().await?;
+    // tag_name is prefixed with a v, so we need to remove it to get the version
+    Ok(Version::parse(release.tag_name.trim_start_matches('v'))?)
+}
+
+struct UpdateCache {
+    status: UpdateStatus,
+    last_check: Option<Instant>,
+    /// Newest version an `UpdateAvailable` event was sent for, so it's only sent once
+    announced: Option<Version>,
+}
+
+/// Periodically looks for a newer release of the core and caches the result
+#[derive(Clone)]
+pub struct UpdateChecker {
+    cache: Arc<Mutex<UpdateCache>>,
+    /// Held across a check, so concurrent checks don't fetch twice
+    /// while the cache stays readable
+    check_lock: Arc<Mutex<()>>,
+    event_broadcaster: EventBroadcaster,
+}
+
+impl UpdateChecker {
+    pub fn new(event_broadcaster: EventBroadcaster) -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(UpdateCache {
+                status: UpdateStatus {
+                    current: VERSION.with(|v| v.clone()),
+                    latest: None,
+                    update_available: false,
+                    url: UPDATE_GUIDE_URL.to_string(),
+                    checked_at: None,
+                    error: None,
+                },
+                last_check: None,
+                announced: None,
+            })),
+            check_lock: Arc::new(Mutex::new(())),
+            event_broadcaster,
+        }
+    }
+
+    async fn is_fresh(&self) -> bool {
+        self.cache
+            .lock()
+            .await
+            .last_check
+            .map_or(false, |last_check| last_check.elapsed() < MIN_REFRESH)
+    }
+
+    /// The cached status, checked again first if `refresh` is set and the cache isn't fresh
+    pub async fn status(&self, refresh: bool) -> UpdateStatus {
+        if refresh && !self.is_fresh().await {
+            let _check_lock = self.check_lock.lock().await;
+            // a concurrent refresh may have checked while this one waited
+            if !self.is_fresh().await {
+                return self.check_locked().await;
+            }
+        }
+        self.cache.lock().await.status.clone()
+    }
+
+    pub async fn check(&self) -> UpdateStatus {
+        let _check_lock = self.check_lock.lock().await;
+        self.check_locked().await
+    }
+
+    /// Must be called with `check_lock` held
+    async fn check_locked(&self) -> UpdateStatus {
+        // the cache isn't locked during the request, so the status can still be read
+        let fetched = fetch_latest_release().await;
+        let mut cache = self.cache.lock().await;
+        cache.last_check = Some(Instant::now());
+        cache.status.checked_at = Some(chrono::Utc::now().timestamp());
+        match fetched {
+            Ok(latest) => {
+                cache.status.error = None;
+                // we don't want to update to a pre-release
+                cache.status.update_available =
+                    latest.pre.is_empty() && latest > cache.status.current;
+                if cache.status.update_available && cache.announced.as_ref() != Some(&latest) {
+                    info!(
+                        "A new version of lodestone_core is available: {}, read how to update here: {}",
+                        latest, UPDATE_GUIDE_URL
+                    );
+                    self.event_broadcaster.send(Event {
+                        event_inner: EventInner::CoreEvent(CoreEvent {
+                            core_event_inner: CoreEventInner::UpdateAvailable {
+                                current: cache.status.current.clone(),
+                                latest: latest.clone(),
+                                url: UPDATE_GUIDE_URL.to_string(),
+                            },
+                        }),
+                        snowflake: Snowflake::default(),
+                        details: format!("Lodestone Core {} is available", latest),
+                        caused_by: CausedBy::System,
+                    });
+                    cache.announced = Some(latest.clone());
+                }
+                cache.status.latest = Some(latest);
+            }
+            Err(e) => {
+                warn!("Failed to check for core updates: {}", e);
+                cache.status.error = Some(e.to_string());
+            }
+        }
+        cache.status.clone()
+    }
+}
+
+/// Checks for updates every `update_check_interval_hours`, retrying failed checks sooner
+///
+/// A changed interval takes effect after the current wait
+pub async fn update_check_task(
+    update_checker: UpdateChecker,
+    global_settings: Arc<Mutex<GlobalSettings>>,
+) {
+    let mut retry = INITIAL_RETRY;
+    loop {
+        let interval = match global_settings.lock().await.update_check_interval_hours() {
+            Some(hours) => Duration::from_secs(hours as u64 * 60 * 60),
+            None => {
+                tokio::time::sleep(DISABLED_POLL).await;
+                continue;
+            }
+        };
+        let wait = if update_checker.check().await.error.is_some() {
+            let wait = retry.min(interval);
+            retry = (retry * 2).min(interval);
+            wait
+        } else {
+            retry = INITIAL_RETRY;
+            interval
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
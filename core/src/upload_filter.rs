@@ -0,0 +1,217 @@
+use std::path::{Component, Path, PathBuf};
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+/// Number of leading bytes of an upload needed to sniff its content type
+pub const SNIFF_LEN: usize = 16;
+
+/// Restricts what can be uploaded into a directory and its subdirectories
+///
+/// Entries of `allow` and `deny` are either file extensions (`jar`, `.exe`)
+/// or MIME types (`application/x-executable`, `image/*`).
+/// MIME types are sniffed from the content of the file, not its name
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, TS)]
+#[ts(export)]
+pub struct UploadRule {
+    /// Absolute path of the directory the rule applies to
+    pub directory: PathBuf,
+    /// If set, only files matching one of these are accepted
+    #[serde(default)]
+    pub allow: Option<Vec<String>>,
+    /// Files matching any of these are rejected, takes precedence over `allow`
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl UploadRule {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.directory.is_absolute() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Upload rule directory {} must be an absolute path",
+                    self.directory.display()
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Collects the first bytes of an upload as it is streamed in
+#[derive(Default)]
+pub struct UploadHead {
+    bytes: Vec<u8>,
+    checked: bool,
+}
+
+impl UploadHead {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the head once `SNIFF_LEN` bytes have been received, only once per upload
+    pub fn feed(&mut self, chunk: &[u8]) -> Option<&[u8]> {
+        if self.checked {
+            return None;
+        }
+        let needed = SNIFF_LEN - self.bytes.len();
+        self.bytes
+            .extend_from_slice(&chunk[..needed.min(chunk.len())]);
+        if self.bytes.len() < SNIFF_LEN {
+            return None;
+        }
+        self.checked = true;
+        Some(&self.bytes)
+    }
+
+    /// Returns the head if the upload ended before `SNIFF_LEN` bytes were received
+    pub fn finish(&mut self) -> Option<&[u8]> {
+        if self.checked {
+            return None;
+        }
+        self.checked = true;
+        Some(&self.bytes)
+    }
+}
+
+/// Guess the MIME type of a file from its first bytes
+pub fn sniff_mime(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x7fELF", "application/x-executable"),
+        (b"MZ", "application/x-msdownload"),
+        (b"\xfe\xed\xfa\xce", "application/x-mach-binary"),
+        (b"\xfe\xed\xfa\xcf", "application/x-mach-binary"),
+        (b"\xce\xfa\xed\xfe", "application/x-mach-binary"),
+        (b"\xcf\xfa\xed\xfe", "application/x-mach-binary"),
+        (b"\xca\xfe\xba\xbe", "application/java-vm"),
+        (b"#!", "text/x-shellscript"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"PK\x05\x06", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"Rar!\x1a\x07", "application/vnd.rar"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+    ];
+    SIGNATURES
+        .iter()
+        .find(|(signature, _)| head.starts_with(signature))
+        .map(|(_, mime)| *mime)
+}
+
+fn matches(pattern: &str, extension: Option<&str>, mime: Option<&str>) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    if let Some((pattern_type, pattern_subtype)) = pattern.split_once('/') {
+        match mime.and_then(|mime| mime.split_once('/')) {
+            Some((mime_type, mime_subtype)) => {
+                pattern_type == mime_type
+                    && (pattern_subtype == "*" || pattern_subtype == mime_subtype)
+            }
+            None => false,
+        }
+    } else {
+        extension.map_or(false, |extension| {
+            pattern.trim_start_matches('.') == extension
+        })
+    }
+}
+
+/// Resolves `.` and `..` without touching the file system,
+/// so a path like `/srv/other/../plugins/a.exe` is covered by the rule of `/srv/plugins`
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Check an upload to `path` against the most specific rule covering it
+///
+/// `head` should hold the first `SNIFF_LEN` bytes of the file, or all of it if it is shorter.
+/// Paths not covered by any rule are always allowed
+pub fn check_upload(rules: &[UploadRule], path: &Path, head: &[u8]) -> Result<(), Error> {
+    let path = &normalize(path);
+    let rule = match rules
+        .iter()
+        .filter(|rule| path.starts_with(normalize(&rule.directory)))
+        .max_by_key(|rule| rule.directory.components().count())
+    {
+        Some(rule) => rule,
+        None => return Ok(()),
+    };
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let mime = sniff_mime(head);
+    let is_match = |pattern: &String| matches(pattern, extension.as_deref(), mime);
+    let denied = rule.deny.iter().any(is_match);
+    let allowed = rule
+        .allow
+        .as_ref()
+        .map_or(true, |allow| allow.iter().any(is_match));
+    if denied || !allowed {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "{} ({}) is not allowed to be uploaded to {}",
+                path.file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default(),
+                mime.unwrap_or("unknown type"),
+                rule.directory.display()
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_upload() {
+        let rules = vec![
+            UploadRule {
+                directory: PathBuf::from("/srv/plugins"),
+                allow: None,
+                deny: vec!["exe".to_string(), "application/x-executable".to_string()],
+            },
+            UploadRule {
+                directory: PathBuf::from("/srv/plugins/images"),
+                allow: Some(vec!["image/*".to_string()]),
+                deny: vec![],
+            },
+        ];
+        let elf = b"\x7fELF\x02\x01\x01\x00";
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00";
+
+        // no rule covers the path
+        assert!(check_upload(&rules, Path::new("/srv/other/a.exe"), elf).is_ok());
+        assert!(check_upload(&rules, Path::new("/srv/plugins/a.jar"), b"PK\x03\x04").is_ok());
+        assert!(check_upload(&rules, Path::new("/srv/plugins/a.EXE"), b"").is_err());
+        // sniffed from the content, regardless of the name
+        assert!(check_upload(&rules, Path::new("/srv/plugins/a.jar"), elf).is_err());
+        // the most specific rule applies
+        assert!(check_upload(&rules, Path::new("/srv/plugins/images/a.txt"), png).is_ok());
+        assert!(check_upload(&rules, Path::new("/srv/plugins/images/a.png"), b"hi").is_err());
+        // `..` can't be used to get around a rule
+        assert!(check_upload(&rules, Path::new("/srv/other/../plugins/a.exe"), b"").is_err());
+        assert!(check_upload(&rules, Path::new("/srv/plugins/./images/../a.jar"), elf).is_err());
+    }
+}
@@ -10,7 +10,7 @@ use tokio::io::AsyncWriteExt;
 
 use futures_util::StreamExt;
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use ts_rs::TS;
 
 use flate2::read::GzDecoder;
@@ -22,7 +22,7 @@ pub struct Authentication {
     password: String,
 }
 
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
 use crate::prelude::path_to_tmp;
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
@@ -155,6 +155,52 @@ pub async fn list_dir(
     ret
 }
 
+/// Space to leave free on top of what an operation is estimated to write
+pub const DISK_SPACE_BUFFER: u64 = 256 * 1024 * 1024;
+
+/// Available space in bytes on the disk `path` is on, `None` if it can't be determined
+///
+/// `path` doesn't need to exist yet
+pub fn available_space(path: &Path) -> Option<u64> {
+    use sysinfo::{DiskExt, SystemExt};
+
+    let path = path.ancestors().find_map(|p| p.canonicalize().ok())?;
+    let mut sys = sysinfo::System::new();
+    sys.refresh_disks_list();
+    sys.disks()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().components().count())
+        .map(|disk| disk.available_space())
+}
+
+/// Fails with `ErrorKind::InsufficientStorage` unless `required` bytes and `DISK_SPACE_BUFFER`
+/// are available on the disk `path` is on
+///
+/// Passes if the available space can't be determined
+pub fn check_disk_space(path: &Path, required: u64) -> Result<(), Error> {
+    let required = required.saturating_add(DISK_SPACE_BUFFER);
+    match available_space(path) {
+        Some(available) if available < required => Err(Error {
+            kind: ErrorKind::InsufficientStorage,
+            source: eyre!(
+                "Not enough disk space, {} bytes are required but only {} bytes are available",
+                required,
+                available
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Creates `path` if it doesn't exist and fails unless a file can be created in it
+pub fn check_dir_writable(path: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(path)
+        .context(format!("Failed to create directory {}", path.display()))?;
+    tempfile::tempfile_in(path).context(format!("Directory {} is not writable", path.display()))?;
+    Ok(())
+}
+
 pub fn resolve_path_conflict(path: PathBuf, predicate: Option<&dyn Fn(&Path) -> bool>) -> PathBuf {
     let predicate = predicate.unwrap_or(&Path::exists);
     let name = path
@@ -254,6 +300,13 @@ pub fn unzip_file(
             std::fs::File::open(file).context(format!("Failed to open file {}", file.display()))?;
         let mut archive = zip::ZipArchive::new(zip)
             .context(format!("Failed to decompress file {}", file.display()))?;
+        let mut uncompressed_size: u64 = 0;
+        for i in 0..archive.len() {
+            if let Ok(entry) = archive.by_index_raw(i) {
+                uncompressed_size = uncompressed_size.saturating_add(entry.size());
+            }
+        }
+        check_disk_space(temp_dest, uncompressed_size)?;
         archive
             .extract(temp_dest)
             .context(format!("Failed to decompress file {}", file.display()))?;
@@ -316,6 +369,17 @@ pub fn zip_files(
     files: &[impl AsRef<Path>],
     dest: impl AsRef<Path>,
     overwrite_dest: bool,
+) -> Result<PathBuf, Error> {
+    zip_files_relative_to(files, None, dest, overwrite_dest)
+}
+
+/// Like `zip_files`, but with a `base` the entries are named by their path relative to it
+/// instead of relative to their own parent, keeping the directory structure under `base`
+pub fn zip_files_relative_to(
+    files: &[impl AsRef<Path>],
+    base: Option<&Path>,
+    dest: impl AsRef<Path>,
+    overwrite_dest: bool,
 ) -> Result<PathBuf, Error> {
     let dest = dest.as_ref();
     std::fs::create_dir_all(dest.parent().context("Failed to get destination parent")?)
@@ -332,38 +396,38 @@ pub fn zip_files(
     let mut writer = zip::ZipWriter::new(&tmp_archive);
     let options = zip::write::FileOptions::default().unix_permissions(0o775);
     for entry_path in files.iter().map(|f| f.as_ref()) {
+        let entry_base = match base {
+            Some(base) => base,
+            None => entry_path
+                .parent()
+                .context(format!("Failed to get parent for {}", entry_path.display()))?,
+        };
+        let entry_dest = entry_path.strip_prefix(entry_base).context(format!(
+            "Failed to strip prefix for {}",
+            entry_path.display()
+        ))?;
+        let entry_name = entry_dest
+            .to_str()
+            .ok_or_else(|| eyre!("Entry has abnormal name"))?;
         if entry_path.is_dir() {
-            writer
-                .add_directory(
-                    entry_path
-                        .file_name()
-                        .ok_or_else(|| eyre!("Entry has abnormal name"))?
-                        .to_str()
-                        .ok_or_else(|| eyre!("Entry has abnormal name"))?,
-                    options,
-                )
-                .context(format!(
+            // `base` itself has no entry, only its contents do
+            if !entry_name.is_empty() {
+                writer.add_directory(entry_name, options).context(format!(
                     "Failed to create {} in archive",
                     entry_path.display()
                 ))?;
+            }
 
             for child_entry in walkdir::WalkDir::new(entry_path)
                 .into_iter()
                 .filter_map(|e| e.ok())
             {
                 let child_entry_path = child_entry.path();
-                let child_entry_dest =
-                    child_entry_path
-                        .strip_prefix(entry_path.parent().context(format!(
-                            "Failed to get parent for {}",
-                            entry_path.display()
-                        ))?)
-                        .context(format!(
-                            "Failed to strip prefix for {}",
-                            child_entry_path.display()
-                        ))?;
+                let child_entry_dest = child_entry_path.strip_prefix(entry_base).context(
+                    format!("Failed to strip prefix for {}", child_entry_path.display()),
+                )?;
 
-                if child_entry_path.is_dir() {
+                if child_entry_path.is_dir() && !child_entry_dest.as_os_str().is_empty() {
                     writer
                         .add_directory(child_entry_dest.to_string_lossy(), options)
                         .context(format!(
@@ -397,12 +461,6 @@ pub fn zip_files(
         }
 
         if entry_path.is_file() {
-            let entry_name = entry_path
-                .file_name()
-                .ok_or_else(|| eyre!("File to zip has no name"))?
-                .to_str()
-                .ok_or_else(|| eyre!("File to zip has abnormal name"))?;
-
             writer.start_file(entry_name, options).context(format!(
                 "Failed to create {} in archive",
                 entry_path.display()
@@ -451,6 +509,214 @@ pub async fn zip_files_async(
         .context("Failed to spawn blocking task")?
 }
 
+/// Below this many files a parallel zip isn't worth the extra buffering and threads
+pub const PARALLEL_ZIP_MIN_FILES: usize = 64;
+
+/// Files larger than this are streamed into the archive by the writer rather than
+/// buffered in memory by a worker, so a few large files can't exhaust memory
+pub const PARALLEL_ZIP_MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Like `zip_files`, but compresses files on up to `workers` threads,
+/// capped to the available parallelism.
+///
+/// Each file is compressed into its own in-memory archive and copied into `dest` as is,
+/// so the central directory is still written by a single writer.
+/// Files over `PARALLEL_ZIP_MAX_FILE_SIZE` are compressed by the writer once the others are done.
+/// Falls back to `zip_files` for a single worker or fewer than `PARALLEL_ZIP_MIN_FILES` files
+pub fn zip_files_parallel(
+    files: &[impl AsRef<Path>],
+    dest: impl AsRef<Path>,
+    overwrite_dest: bool,
+    workers: usize,
+) -> Result<PathBuf, Error> {
+    let workers = workers.min(std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let mut directories = Vec::new();
+    let mut regular_files = Vec::new();
+    let mut large_files = Vec::new();
+    for entry_path in files.iter().map(|f| f.as_ref()) {
+        let entry_base = entry_path
+            .parent()
+            .context(format!("Failed to get parent for {}", entry_path.display()))?;
+        for child_entry in walkdir::WalkDir::new(entry_path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            let child_entry_path = child_entry.path();
+            let child_entry_name = child_entry_path
+                .strip_prefix(entry_base)
+                .context(format!(
+                    "Failed to strip prefix for {}",
+                    child_entry_path.display()
+                ))?
+                .to_string_lossy()
+                .into_owned();
+            if child_entry_path.is_dir() {
+                directories.push(child_entry_name);
+            } else if child_entry_path.is_file() {
+                let is_large = child_entry.metadata().map_or(false, |metadata| {
+                    metadata.len() > PARALLEL_ZIP_MAX_FILE_SIZE
+                });
+                if is_large {
+                    large_files.push((child_entry_path.to_path_buf(), child_entry_name));
+                } else {
+                    regular_files.push((child_entry_path.to_path_buf(), child_entry_name));
+                }
+            }
+        }
+    }
+    if workers <= 1 || regular_files.len() + large_files.len() < PARALLEL_ZIP_MIN_FILES {
+        return zip_files(files, dest, overwrite_dest);
+    }
+
+    let dest = dest.as_ref();
+    std::fs::create_dir_all(dest.parent().context("Failed to get destination parent")?)
+        .context(format!("Failed to create directory {}", dest.display()))?;
+    let lodestone_tmp = path_to_tmp().clone();
+    std::fs::create_dir_all(&lodestone_tmp).context(format!(
+        "Failed to create temporary directory {}",
+        lodestone_tmp.display()
+    ))?;
+    let tmp_archive = tempfile::NamedTempFile::new_in(lodestone_tmp)
+        .context("Failed to create temporary file for zipping")?;
+
+    let mut writer = zip::ZipWriter::new(&tmp_archive);
+    let options = zip::write::FileOptions::default().unix_permissions(0o775);
+    for directory in &directories {
+        writer
+            .add_directory(directory.as_str(), options)
+            .context(format!("Failed to create {} in archive", directory))?;
+    }
+
+    let compress = |(path, name): &(PathBuf, String)| -> Result<Vec<u8>, Error> {
+        let mut contents = Vec::new();
+        std::fs::File::open(path)
+            .context(format!("Failed to open {}", path.display()))?
+            .read_to_end(&mut contents)
+            .context(format!("Failed to read {}", path.display()))?;
+        let mut entry_writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        entry_writer
+            .start_file(name.as_str(), options)
+            .context(format!("Failed to create {} in archive", path.display()))?;
+        entry_writer
+            .write_all(&contents)
+            .context(format!("Failed to compress {}", path.display()))?;
+        Ok(entry_writer
+            .finish()
+            .context(format!("Failed to compress {}", path.display()))?
+            .into_inner())
+    };
+    let next_file = std::sync::atomic::AtomicUsize::new(0);
+    std::thread::scope(|scope| -> Result<(), Error> {
+        // bounded, so workers don't compress far ahead of the writer
+        let (result_tx, result_rx) = std::sync::mpsc::sync_channel(workers);
+        for _ in 0..workers {
+            let result_tx = result_tx.clone();
+            let (next_file, regular_files, compress) = (&next_file, &regular_files, &compress);
+            scope.spawn(move || loop {
+                let index = next_file.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let file = match regular_files.get(index) {
+                    Some(file) => file,
+                    None => break,
+                };
+                // the writer stopped on an error, nothing left to do
+                if result_tx.send(compress(file)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(result_tx);
+        for compressed in result_rx {
+            let mut entry_archive = zip::ZipArchive::new(std::io::Cursor::new(compressed?))
+                .context("Failed to read compressed entry")?;
+            let entry = entry_archive
+                .by_index_raw(0)
+                .context("Failed to read compressed entry")?;
+            let entry_name = entry.name().to_string();
+            writer
+                .raw_copy_file(entry)
+                .context(format!("Failed to write {} to archive", entry_name))?;
+        }
+        Ok(())
+    })?;
+    for (path, name) in &large_files {
+        writer
+            .start_file(name.as_str(), options)
+            .context(format!("Failed to create {} in archive", path.display()))?;
+        std::io::copy(
+            &mut std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?,
+            &mut writer,
+        )
+        .context(format!("Failed to compress {}", path.display()))?;
+    }
+
+    writer.finish().context("Zip failed")?;
+    let dest = if overwrite_dest {
+        dest.into()
+    } else {
+        resolve_path_conflict(dest.into(), None)
+    };
+
+    std::fs::rename(tmp_archive.path(), &dest).context(format!(
+        "Failed to move {} to {}",
+        tmp_archive.path().display(),
+        dest.display()
+    ))?;
+    Ok(dest)
+}
+
+pub async fn zip_files_parallel_async(
+    files: &[impl AsRef<Path>],
+    dest: impl AsRef<Path>,
+    overwrite_dest: bool,
+    workers: usize,
+) -> Result<PathBuf, Error> {
+    let _files = files
+        .iter()
+        .map(|f| f.as_ref().to_owned())
+        .collect::<Vec<_>>();
+    let _dest = dest.as_ref().to_owned();
+    tokio::task::spawn_blocking(move || {
+        zip_files_parallel(&_files, &_dest, overwrite_dest, workers)
+    })
+    .await
+    .context("Failed to spawn blocking task")?
+}
+
+/// Distinguishes a field set to null from a missing one, use with `#[serde(default)]`
+pub fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Matches `text` against `pattern`, where `*` in the pattern matches any sequence of characters
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` seen in the pattern, and the text position it was matched at
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // let the last `*` consume one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 pub fn rand_alphanumeric(len: usize) -> String {
     thread_rng().sample_iter(&Alphanumeric).take(len).collect()
 }
@@ -484,6 +750,7 @@ pub mod fs {
 
     use color_eyre::eyre::Context;
     use tokio::fs::File;
+    use tokio::io::AsyncWriteExt;
 
     use crate::error::Error;
 
@@ -505,6 +772,57 @@ pub mod fs {
         Ok(())
     }
 
+    /// Temporary sibling a file is written to before it is renamed into place
+    pub(crate) fn atomic_temp_path(file: &Path) -> std::path::PathBuf {
+        let mut file_name = file.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".tmp");
+        file.with_file_name(file_name)
+    }
+
+    /// Writes `data` to a sibling temporary file and renames it over `file`,
+    /// so a crash mid-write leaves either the old or the new content, never a truncated file
+    pub async fn write_atomic(file: impl AsRef<Path>, data: impl AsRef<[u8]>) -> Result<(), Error> {
+        let file = file.as_ref();
+        let temp_file = atomic_temp_path(file);
+        let mut handle = tokio::fs::File::create(&temp_file)
+            .await
+            .context(format!("Failed to create file at {}", temp_file.display()))?;
+        handle.write_all(data.as_ref()).await.context(format!(
+            "Failed to write to file at {}",
+            temp_file.display()
+        ))?;
+        handle
+            .sync_all()
+            .await
+            .context(format!("Failed to sync file at {}", temp_file.display()))?;
+        drop(handle);
+        rename(&temp_file, file).await
+    }
+
+    /// Blocking version of `write_atomic`, for code that runs before the runtime is up
+    pub fn write_atomic_sync(file: impl AsRef<Path>, data: impl AsRef<[u8]>) -> Result<(), Error> {
+        use std::io::Write;
+
+        let file = file.as_ref();
+        let temp_file = atomic_temp_path(file);
+        let mut handle = std::fs::File::create(&temp_file)
+            .context(format!("Failed to create file at {}", temp_file.display()))?;
+        handle.write_all(data.as_ref()).context(format!(
+            "Failed to write to file at {}",
+            temp_file.display()
+        ))?;
+        handle
+            .sync_all()
+            .context(format!("Failed to sync file at {}", temp_file.display()))?;
+        drop(handle);
+        std::fs::rename(&temp_file, file).context(format!(
+            "Failed to rename file {} to {}",
+            temp_file.display(),
+            file.display()
+        ))?;
+        Ok(())
+    }
+
     pub async fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
         let from = from.as_ref();
         let to = to.as_ref();
@@ -640,7 +958,10 @@ pub fn format_byte(mut bytes: u64) -> String {
 #[cfg(test)]
 mod tests {
     use crate::prelude::init_paths;
-    use crate::util::{resolve_path_conflict, unzip_file, zip_files, UnzipOption};
+    use crate::util::{
+        resolve_path_conflict, unzip_file, zip_files, zip_files_parallel, zip_files_relative_to,
+        UnzipOption, PARALLEL_ZIP_MIN_FILES,
+    };
     use std::collections::HashSet;
     use std::io::Read;
     use std::path::PathBuf;
@@ -708,6 +1029,29 @@ mod tests {
         assert!(dest_path.join("sample_1").join("sample.obj").is_file(),);
     }
 
+    #[tokio::test]
+    async fn test_write_atomic() {
+        use crate::util::fs::{atomic_temp_path, write_atomic};
+
+        let temp = tempdir::TempDir::new("test_write_atomic").unwrap();
+        let path = temp.path().join(".lodestone_config");
+        write_atomic(&path, "{\"name\": \"old\"}").await.unwrap();
+
+        // a crash mid-write only leaves a truncated temporary file behind
+        std::fs::write(atomic_temp_path(&path), "{\"name\": \"ne").unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(serde_json::from_str::<serde_json::Value>(&content).is_ok());
+        assert_eq!(content, "{\"name\": \"old\"}");
+
+        // the next write replaces the leftover
+        write_atomic(&path, "{\"name\": \"new\"}").await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"name\": \"new\"}"
+        );
+        assert!(!atomic_temp_path(&path).exists());
+    }
+
     #[test]
     fn test_resolve_path_conflict() {
         let temp_lodestone_path = tempfile::tempdir().unwrap();
@@ -831,4 +1175,134 @@ mod tests {
         buf_reader.read_to_string(&mut contents).unwrap();
         assert_eq!(contents.trim(), "test2_test2_test1");
     }
+
+    #[test]
+    fn test_zip_files_relative_to() {
+        let temp_lodestone_path = tempfile::tempdir().unwrap();
+        init_paths(temp_lodestone_path.path().to_path_buf());
+        let temp = tempfile::tempdir().unwrap();
+        let instance = temp.path().join("instance");
+        std::fs::create_dir_all(instance.join("world").join("region")).unwrap();
+        std::fs::write(instance.join("server.properties"), "motd=hi").unwrap();
+        std::fs::write(instance.join("world").join("level.dat"), "level").unwrap();
+        std::fs::write(
+            instance.join("world").join("region").join("r.0.0.mca"),
+            "region",
+        )
+        .unwrap();
+        std::fs::write(instance.join("eula.txt"), "eula=true").unwrap();
+        let entry_names = |archive: &PathBuf| {
+            let archive = zip::ZipArchive::new(std::fs::File::open(archive).unwrap()).unwrap();
+            archive
+                .file_names()
+                .map(str::to_string)
+                .collect::<HashSet<_>>()
+        };
+        let selected = [
+            instance.join("server.properties"),
+            instance.join("world").join("region"),
+        ];
+
+        // without a base each entry is named relative to its own parent
+        let dest = zip_files(&selected, temp.path().join("flat.zip"), false).unwrap();
+        assert_eq!(
+            entry_names(&dest),
+            HashSet::from([
+                "server.properties".to_string(),
+                "region/".to_string(),
+                "region/r.0.0.mca".to_string(),
+            ])
+        );
+
+        // with one the structure under it is kept, and unselected files are left out
+        let dest = zip_files_relative_to(
+            &selected,
+            Some(&instance),
+            temp.path().join("relative.zip"),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            entry_names(&dest),
+            HashSet::from([
+                "server.properties".to_string(),
+                "world/region/".to_string(),
+                "world/region/r.0.0.mca".to_string(),
+            ])
+        );
+        let unzipped = temp.path().join("unzipped");
+        unzip_file(&dest, UnzipOption::ToDir(unzipped.clone())).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(unzipped.join("world").join("region").join("r.0.0.mca"))
+                .unwrap(),
+            "region"
+        );
+
+        // selecting the base itself zips its contents without a top level directory
+        let dest = zip_files_relative_to(
+            &[&instance],
+            Some(&instance),
+            temp.path().join("whole.zip"),
+            false,
+        )
+        .unwrap();
+        let names = entry_names(&dest);
+        assert!(names.contains("eula.txt"));
+        assert!(names.contains("world/level.dat"));
+        assert!(!names.iter().any(|name| name.starts_with("instance")));
+
+        // files outside the base can't be named relative to it
+        assert!(zip_files_relative_to(
+            &[temp.path().join("flat.zip")],
+            Some(&instance),
+            temp.path().join("outside.zip"),
+            false,
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_zip_files_parallel() {
+        let temp_lodestone_path = tempfile::tempdir().unwrap();
+        init_paths(temp_lodestone_path.path().to_path_buf());
+        let temp = tempfile::tempdir().unwrap();
+        let world = temp.path().join("world");
+        std::fs::create_dir_all(world.join("region")).unwrap();
+        for i in 0..PARALLEL_ZIP_MIN_FILES * 2 {
+            std::fs::write(
+                world.join("region").join(format!("r.{i}.mca")),
+                format!("region {i}"),
+            )
+            .unwrap();
+        }
+        std::fs::write(world.join("level.dat"), "level").unwrap();
+        // streamed by the writer instead of a worker
+        let large_len = PARALLEL_ZIP_MAX_FILE_SIZE as usize + 1;
+        std::fs::write(world.join("large.dat"), vec![7u8; large_len]).unwrap();
+
+        let dest = zip_files_parallel(&[&world], temp.path().join("world.zip"), true, 4).unwrap();
+        let unzipped = temp.path().join("unzipped");
+        unzip_file(&dest, UnzipOption::ToDir(unzipped.clone())).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(unzipped.join("world").join("level.dat")).unwrap(),
+            "level"
+        );
+        assert_eq!(
+            std::fs::read(unzipped.join("world").join("large.dat")).unwrap(),
+            vec![7; large_len]
+        );
+        for i in 0..PARALLEL_ZIP_MIN_FILES * 2 {
+            assert_eq!(
+                std::fs::read_to_string(
+                    unzipped
+                        .join("world")
+                        .join("region")
+                        .join(format!("r.{i}.mca"))
+                )
+                .unwrap(),
+                format!("region {i}")
+            );
+        }
+    }
 }
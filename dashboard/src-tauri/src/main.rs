@@ -48,6 +48,7 @@ async fn main() {
         is_cli: false,
         is_desktop: true,
         lodestone_path: None,
+        recovery_mode: false,
     })
     .await;
     let shutdown_tx = std::sync::Mutex::new(Some(shutdown_tx));
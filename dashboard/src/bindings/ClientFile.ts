@@ -1,13 +1,4 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
-import type { FileType } from './FileType';
+import type { FileType } from "./FileType";
 
-export interface ClientFile {
-  name: string;
-  file_stem: string;
-  extension: string | null;
-  path: string;
-  size: number | null;
-  creation_time: number | null;
-  modification_time: number | null;
-  file_type: FileType;
-}
+export interface ClientFile { name: string, file_stem: string, extension: string | null, path: string, size: bigint | null, creation_time: bigint | null, modification_time: bigint | null, file_type: FileType, mode: string | null, symlink_target: string | null, }
\ No newline at end of file
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { CoreEventInner } from "./CoreEventInner";
+
+export interface CoreEvent { core_event_inner: CoreEventInner, }
\ No newline at end of file
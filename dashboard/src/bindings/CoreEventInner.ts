@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type CoreEventInner = { type: "CoreNameChanged", name: string, } | { type: "SettingsChanged", fields: Array<string>, } | { type: "UpdateAvailable", current: string, latest: string, url: string, };
\ No newline at end of file
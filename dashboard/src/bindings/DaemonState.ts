@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { MacroPID } from "./MacroPID";
+
+export type DaemonState = { type: "Starting" } | { type: "Running", pid: MacroPID, } | { type: "Restarting", backoff_secs: bigint, reason: string, } | { type: "Stopped" };
\ No newline at end of file
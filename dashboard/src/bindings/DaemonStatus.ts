@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { DaemonState } from "./DaemonState";
+
+export interface DaemonStatus { name: string, args: Array<string>, state: DaemonState, restart_count: number, }
\ No newline at end of file
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { EventType } from "./EventType";
+import type { InstanceUuid } from "./InstanceUuid";
+import type { UserId } from "./UserId";
+
+export interface EventPageQuery { before: bigint | null, limit: number | null, user_id: UserId | null, instance_uuid: InstanceUuid | null, kind: EventType | null, start: bigint | null, end: bigint | null, }
\ No newline at end of file
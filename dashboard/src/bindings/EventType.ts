@@ -1,8 +1,3 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export type EventType =
-  | 'InstanceEvent'
-  | 'UserEvent'
-  | 'MacroEvent'
-  | 'FSEvent'
-  | 'ProgressionEvent';
+export type EventType = "InstanceEvent" | "UserEvent" | "MacroEvent" | "FSEvent" | "ProgressionEvent" | "CoreEvent";
\ No newline at end of file
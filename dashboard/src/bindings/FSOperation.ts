@@ -1,10 +1,3 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export type FSOperation =
-  | 'Read'
-  | 'Write'
-  | { Move: { source: string } }
-  | 'Create'
-  | 'Delete'
-  | 'Upload'
-  | 'Download';
+export type FSOperation = "Read" | "Write" | { Move: { source: string, } } | { MoveMany: { sources: Array<string>, } } | "Create" | "Delete" | "Upload" | "Download";
\ No newline at end of file
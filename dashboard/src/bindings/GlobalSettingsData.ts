@@ -1,7 +1,10 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { CorsSettings } from "./CorsSettings";
+import type { InstancePermissionTemplate } from "./InstancePermissionTemplate";
+import type { MacroLimitPolicy } from "./MacroLimitPolicy";
+import type { OrphanPolicy } from "./OrphanPolicy";
+import type { PortRange } from "./PortRange";
+import type { TlsSettings } from "./TlsSettings";
+import type { UploadRule } from "./UploadRule";
 
-export interface GlobalSettingsData {
-  core_name: string;
-  safe_mode: boolean;
-  domain: string | null;
-}
+export interface GlobalSettingsData { core_name: string, safe_mode: boolean, domain: string | null, soft_delete: boolean, trash_retention_days: number, instance_stop_timeout: number, orphan_policy: OrphanPolicy, max_concurrent_macros: number, macro_limit_policy: MacroLimitPolicy, macro_history_size: number, macro_max_heap_mb: number, upload_rules: Array<UploadRule>, unrestricted_macro_imports: boolean, max_write_size: bigint, zip_workers: number, cors: CorsSettings, update_check_interval_hours: number | null, port_range: PortRange | null, instances_path: string | null, tls: TlsSettings | null, instance_permission_template: InstancePermissionTemplate, }
\ No newline at end of file
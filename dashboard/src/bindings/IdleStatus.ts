@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface IdleStatus { idle_minutes: number | null, player_count: number, seconds_until_shutdown: bigint | null, }
\ No newline at end of file
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { EnvPolicy } from "./EnvPolicy";
+
+export interface InstanceEnv { policy: EnvPolicy, vars: Record<string, string>, }
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type JarChecksum = { algorithm: "sha1", hash: string } | { algorithm: "sha256", hash: string } | { algorithm: "sha512", hash: string };
\ No newline at end of file
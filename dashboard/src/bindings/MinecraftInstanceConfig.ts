@@ -0,0 +1,8 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { ConsoleLogRotation } from "./ConsoleLogRotation";
+import type { Flavour } from "./Flavour";
+import type { IdleShutdown } from "./IdleShutdown";
+import type { JvmFlagsPreset } from "./JvmFlagsPreset";
+import type { StartupMilestone } from "./StartupMilestone";
+
+export interface MinecraftInstanceConfig { name: string, description: string, version: string, flavour: Flavour, port: number, min_ram: number, max_ram: number, cmd_args: Array<string>, jvm_flags: JvmFlagsPreset, auto_start: boolean, restart_on_crash: boolean, backup_period: number | null, idle_shutdown: IdleShutdown | null, console_log: ConsoleLogRotation | null, cpu_affinity: Array<number>, startup_milestones: Array<StartupMilestone> | null, }
\ No newline at end of file
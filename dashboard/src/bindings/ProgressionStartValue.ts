@@ -1,12 +1,4 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
-import type { InstanceUuid } from './InstanceUuid';
+import type { InstanceUuid } from "./InstanceUuid";
 
-export type ProgressionStartValue =
-  | {
-      type: 'InstanceCreation';
-      instance_uuid: InstanceUuid;
-      instance_name: string;
-      port: number;
-      game_type: string;
-    }
-  | { type: 'InstanceDelete'; instance_uuid: InstanceUuid };
+export type ProgressionStartValue = { type: "InstanceCreation", instance_uuid: InstanceUuid, } | { type: "InstanceDelete", instance_uuid: InstanceUuid, } | { type: "InstanceStart", instance_uuid: InstanceUuid, };
\ No newline at end of file
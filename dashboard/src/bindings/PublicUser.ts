@@ -1,11 +1,5 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
-import type { UserId } from './UserId';
-import type { UserPermission } from './UserPermission';
+import type { UserId } from "./UserId";
+import type { UserPermission } from "./UserPermission";
 
-export interface PublicUser {
-  uid: UserId;
-  username: string;
-  is_owner: boolean;
-  is_admin: boolean;
-  permissions: UserPermission;
-}
+export interface PublicUser { uid: UserId, username: string, is_owner: boolean, is_admin: boolean, is_read_only: boolean, permissions: UserPermission, }
\ No newline at end of file
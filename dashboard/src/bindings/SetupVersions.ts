@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { MinecraftVersions } from "./MinecraftVersions";
+
+export interface SetupVersions { versions: MinecraftVersions, loader_versions: Array<string>, fetched_at: bigint, stale: boolean, }
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface StartupMilestone { pattern: string, progress: number, progress_end: number | null, }
\ No newline at end of file
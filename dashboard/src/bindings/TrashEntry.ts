@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface TrashEntry { id: string, name: string, original_path: string, is_dir: boolean, deletion_time: bigint, }
\ No newline at end of file
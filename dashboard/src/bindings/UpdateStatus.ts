@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface UpdateStatus { current: string, latest: string | null, update_available: boolean, url: string, checked_at: bigint | null, error: string | null, }
\ No newline at end of file
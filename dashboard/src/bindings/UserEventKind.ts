@@ -1,8 +1,3 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export type UserEventKind =
-  | 'UserCreated'
-  | 'UserDeleted'
-  | 'UserLoggedIn'
-  | 'UserLoggedOut'
-  | 'PermissionChanged';
+export type UserEventKind = "UserCreated" | "UserDeleted" | "UserLoggedIn" | "UserLoggedOut" | "UsernameChanged" | "PermissionChanged" | "ReadOnlyChanged";
\ No newline at end of file
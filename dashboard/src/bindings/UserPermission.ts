@@ -1,20 +1,4 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
-import type { InstanceUuid } from './InstanceUuid';
+import type { InstanceUuid } from "./InstanceUuid";
 
-export interface UserPermission {
-  can_view_instance: Array<InstanceUuid>;
-  can_start_instance: Array<InstanceUuid>;
-  can_stop_instance: Array<InstanceUuid>;
-  can_access_instance_console: Array<InstanceUuid>;
-  can_access_instance_setting: Array<InstanceUuid>;
-  can_read_instance_resource: Array<InstanceUuid>;
-  can_write_instance_resource: Array<InstanceUuid>;
-  can_access_instance_macro: Array<InstanceUuid>;
-  can_read_instance_file: Array<InstanceUuid>;
-  can_write_instance_file: Array<InstanceUuid>;
-  can_create_instance: boolean;
-  can_delete_instance: boolean;
-  can_read_global_file: boolean;
-  can_write_global_file: boolean;
-  can_manage_permission: boolean;
-}
+export interface UserPermission { can_view_instance: Array<InstanceUuid>, can_start_instance: Array<InstanceUuid>, can_stop_instance: Array<InstanceUuid>, can_access_instance_console: Array<InstanceUuid>, can_access_instance_setting: Array<InstanceUuid>, can_read_instance_resource: Array<InstanceUuid>, can_write_instance_resource: Array<InstanceUuid>, can_access_instance_macro: Array<InstanceUuid>, can_read_instance_file: Array<InstanceUuid>, can_write_instance_file: Array<InstanceUuid>, can_create_instance: boolean, can_delete_instance: boolean, can_read_global_file: boolean, can_write_global_file: boolean, can_manage_permission: boolean, allowed_console_commands: Record<InstanceUuid, Array<string>>, }
\ No newline at end of file
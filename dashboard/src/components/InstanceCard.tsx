@@ -29,6 +29,8 @@ import { Menu, Transition } from '@headlessui/react';
 import Avatar from 'boring-avatars';
 import Button from './Atoms/Button';
 import { PortStatus } from 'bindings/PortStatus';
+import { ErrorKind } from 'bindings/ErrorKind';
+import ConfirmDialog from './Atoms/ConfirmDialog';
 
 // // for the css style of the double border when focused
 // const stateToBorderMap: { [key in InstanceState]: string[] } = {
@@ -77,6 +79,7 @@ export default function InstanceCard({
   onClick: cardOnClick,
 }: InstanceCardProps) {
   const [loading, setLoading] = useState(false);
+  const [showAcceptEula, setShowAcceptEula] = useState(false);
 
   const { core } = useContext(LodestoneContext);
   const { address } = core;
@@ -173,6 +176,12 @@ export default function InstanceCard({
         response.data;
       })
       .catch((error) => {
+        // new Minecraft instances, including ones from modpacks, can't start until the EULA is accepted
+        const kind: ErrorKind | undefined = error?.response?.data?.kind;
+        if (kind === 'EulaNotAccepted') {
+          setShowAcceptEula(true);
+          return;
+        }
         toast.error(errorToString(error));
       })
       .finally(() => {
@@ -180,10 +189,46 @@ export default function InstanceCard({
       });
   };
 
+  const acceptEulaAndStart = async () => {
+    try {
+      await axiosWrapper<void>({
+        method: 'post',
+        url: `/instance/${uuid}/accept_eula`,
+      });
+      await axiosWrapper<void>({
+        method: 'put',
+        url: `/instance/${uuid}/start`,
+      });
+    } catch (error) {
+      toast.error(errorToString(error));
+    } finally {
+      setShowAcceptEula(false);
+    }
+  };
+
   const stateColor = stateToLabelColor[state];
 
   return (
     <div className="my-2 border-b-2 border-gray-faded/30 pb-2">
+      <ConfirmDialog
+        title="Accept the Minecraft EULA"
+        type="info"
+        isOpen={showAcceptEula}
+        onClose={() => setShowAcceptEula(false)}
+        onConfirm={acceptEulaAndStart}
+        confirmButtonText="Accept and start"
+      >
+        {name} can&apos;t start until you agree to the{' '}
+        <a
+          href="https://aka.ms/MinecraftEULA"
+          target="_blank"
+          rel="noreferrer"
+          className="text-blue-200 hover:underline"
+        >
+          Minecraft End User License Agreement
+        </a>
+        .
+      </ConfirmDialog>
       <div
         className={clsx(
           'flex flex-row items-center gap-x-1.5',
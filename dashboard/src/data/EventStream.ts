@@ -295,6 +295,13 @@ export const useEventStream = () => {
                 fresh,
               });
             },
+            Notification: ({ level, title, body }) => {
+              if (!fresh) return;
+              const message = `${title}: ${body}`;
+              if (level === 'Error') toast.error(message);
+              else if (level === 'Warning') toast.warn(message);
+              else toast.info(message);
+            },
           }),
         ProgressionEvent: (progressionEvent) => {
           ongoingDispatch({
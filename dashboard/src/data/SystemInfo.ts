@@ -56,6 +56,7 @@ export interface CoreInfo {
   uuid: string;
   core_name: string;
   up_since: number;
+  recovery_mode: boolean;
 }
 
 /**